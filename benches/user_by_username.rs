@@ -0,0 +1,41 @@
+//! Benchmark of [`UserMap::user_by_username`] on a large user map.
+//!
+//! Run with `cargo bench --bench user_by_username`.
+
+use std::time::Instant;
+
+use narrowssh::workspace::UserMap;
+use uzers::{uid_t, User};
+
+/// Number of users in the map.
+const USERS: uid_t = 50_000;
+
+/// Number of lookups to time.
+const LOOKUPS: uid_t = 100_000;
+
+fn main() {
+    let map = UserMap::new(
+        (0..USERS).map(|uid| User::new(uid, &format!("user{uid}"), uid)),
+        0,
+    );
+
+    // Counting hits keeps the lookups from being optimized away
+    let mut found = 0_usize;
+
+    let start = Instant::now();
+    for i in 0..LOOKUPS {
+        let name = format!("user{}", i % USERS);
+        if let Ok(Some(_)) = map.user_by_username(&name) {
+            found += 1;
+        }
+    }
+    let elapsed = start.elapsed();
+
+    assert_eq!(found, LOOKUPS as usize);
+
+    println!(
+        "user_by_username: {LOOKUPS} lookups among {USERS} users in \
+        {elapsed:?} ({:?} per lookup)",
+        elapsed / LOOKUPS
+    );
+}
@@ -0,0 +1,271 @@
+//! Matching of commands against the allowlist of a user.
+//!
+//! Every entry of [`Control::commands`][crate::config::Control::commands] is
+//! a pattern that must match the whole command. Patterns use a small glob
+//! syntax:
+//!   - `*` matches any sequence of characters, including none,
+//!   - `?` matches exactly one character,
+//!   - every other character matches itself.
+//!
+//! There is no escaping: `*` and `?` are always wildcards, even in quotes.
+//! A pattern such as `rsync --server *` thus allows every command that
+//! starts with `rsync --server `, while `uptime` only allows `uptime` itself.
+//!
+//! A pattern matches a command if it matches the command string as the
+//! client sent it, or if it matches the words of the command, see below. In
+//! the former case, whitespace is significant and never normalized, and
+//! wildcards do not respect shell argument boundaries: `*` matches spaces,
+//! so `git *` allows `git push origin main`.
+//!
+//! Wildcards never match [`SHELL_METACHARACTERS`], however, so a wildcard
+//! cannot extend an allowed command into a second one, a redirection or a
+//! substitution. Such characters are only allowed where the pattern spells
+//! them out.
+//!
+//! # Words
+//!
+//! Both the pattern and the command are also split into words the way a
+//! POSIX shell would, see [`split_words`]:
+//!   - unquoted spaces and tabs separate words; runs of them count as one,
+//!   - text in single quotes is taken literally, including spaces,
+//!   - text in double quotes is taken literally except that `\` escapes
+//!     `"`, `\`, `$` and `` ` ``,
+//!   - outside of quotes, `\` escapes any character,
+//!   - quotes and escapes are removed, so `'a b'`, `"a b"` and `a\ b` are
+//!     all the single word `a b`.
+//!
+//! Splitting fails, and only the command string is matched, if a quote is
+//! not closed, if the text ends with a lone `\`, or if it contains one of
+//! the [`SHELL_METACHARACTERS`] that is neither in single quotes nor escaped
+//! with `\`.
+//!
+//! A pattern word that is a lone `*` matches one or more command words. Any
+//! other pattern word matches exactly one command word, with `*` and `?`
+//! confined to that word. Thus `rsync --server *` allows
+//! `rsync  --server -e.Lsf . "my dest"`, and `git-upload-pack '*'` allows
+//! both `git-upload-pack 'repo'` and `git-upload-pack repo`, which run the
+//! same program with the same arguments.
+//!
+//! Entries that start with [`DENY_PREFIX`] are deny patterns, e.g.
+//! `!git-shell *`. A command is allowed if at least one allow pattern matches
+//! it and no deny pattern does, wherever the entries appear in the list. Deny
+//! patterns thus always take precedence, and `["git-*", "!git-shell *"]`
+//! allows `git-upload-pack 'repo'` but not `git-shell -c id`. An allow
+//! pattern cannot start with `!`.
+//!
+//! # Enforcement
+//!
+//! Every managed key forces
+//! [`EXEC_COMMAND`][crate::authorized_keys::EXEC_COMMAND] with the
+//! allowlist of the user as arguments. sshd passes the command requested by
+//! the client in [`ORIGINAL_COMMAND_VAR`], which [`authorize`] checks before
+//! it is run.
+
+use anyhow::{bail, Result};
+
+#[cfg(test)]
+mod tests;
+
+/// Prefix of deny patterns, see the [module documentation][self].
+pub const DENY_PREFIX: char = '!';
+
+/// Environment variable in which sshd passes the command requested by the
+/// client to a forced command.
+pub const ORIGINAL_COMMAND_VAR: &str = "SSH_ORIGINAL_COMMAND";
+
+/// Characters that wildcards never match, see the
+/// [module documentation][self].
+pub const SHELL_METACHARACTERS: &[char] =
+    &[';', '&', '|', '<', '>', '$', '`', '(', ')', '\n', '\r'];
+
+/// Returns whether `command` is matched by glob `pattern` as a whole.
+///
+/// See the [module documentation][self] for the syntax.
+#[must_use]
+pub fn pattern_matches(pattern: &str, command: &str) -> bool {
+    glob_matches(pattern, command, SHELL_METACHARACTERS)
+}
+
+/// Returns whether the words of `command` are matched by the words of
+/// `pattern`.
+///
+/// Returns `false` if either cannot be split with [`split_words`]. See the
+/// [module documentation][self] for the rules.
+#[must_use]
+pub fn words_match(pattern: &str, command: &str) -> bool {
+    match (split_words(pattern), split_words(command)) {
+        (Some(pattern), Some(command)) => match_words(&pattern, &command),
+        _ => false,
+    }
+}
+
+/// Returns whether the `command` words are matched by the `pattern` words.
+fn match_words(pattern: &[String], command: &[String]) -> bool {
+    match pattern.split_first() {
+        None => command.is_empty(),
+        Some((word, rest)) if word == "*" => (1..=command.len())
+            .take_while(|&n| !command[n - 1].contains(SHELL_METACHARACTERS))
+            .any(|n| match_words(rest, &command[n..])),
+        Some((word, rest)) => match command.split_first() {
+            Some((first, others)) => {
+                glob_matches(word, first, SHELL_METACHARACTERS)
+                    && match_words(rest, others)
+            }
+            None => false,
+        },
+    }
+}
+
+/// Splits `text` into words the way a POSIX shell would.
+///
+/// Returns `None` if `text` cannot be split safely. See the
+/// [module documentation][self] for the quoting rules.
+#[must_use]
+pub fn split_words(text: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = text.chars();
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            ' ' | '\t' => {
+                words.extend(word.take());
+            }
+            '\'' => {
+                let current = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next()? {
+                        '\'' => break,
+                        ch => current.push(ch),
+                    }
+                }
+            }
+            '"' => {
+                let current = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => match chars.next()? {
+                            ch @ ('"' | '\\' | '$' | '`') => current.push(ch),
+                            ch => {
+                                current.push('\\');
+                                current.push(ch);
+                            }
+                        },
+                        ch if SHELL_METACHARACTERS.contains(&ch) => {
+                            return None
+                        }
+                        ch => current.push(ch),
+                    }
+                }
+            }
+            '\\' => word.get_or_insert_with(String::new).push(chars.next()?),
+            ch if SHELL_METACHARACTERS.contains(&ch) => return None,
+            ch => word.get_or_insert_with(String::new).push(ch),
+        }
+    }
+    words.extend(word);
+
+    Some(words)
+}
+
+/// Returns whether `text` is matched by glob `pattern` as a whole, where
+/// wildcards never match characters in `barred`.
+///
+/// The syntax is that of [`pattern_matches`], which bars
+/// [`SHELL_METACHARACTERS`].
+#[must_use]
+pub fn glob_matches(pattern: &str, text: &str, barred: &[char]) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let command: Vec<char> = text.chars().collect();
+
+    let (mut p, mut c) = (0, 0);
+
+    // Position after the last `*` and the command position it resumes from
+    let mut backtrack = None;
+
+    while c < command.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                backtrack = Some((p, c));
+            }
+            Some(&ch)
+                if ch == command[c]
+                    || (ch == '?' && !barred.contains(&command[c])) =>
+            {
+                p += 1;
+                c += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_c))
+                    if !barred.contains(&command[star_c]) =>
+                {
+                    // Let the last `*` swallow one more character
+                    p = star_p;
+                    c = star_c + 1;
+                    backtrack = Some((star_p, c));
+                }
+                _ => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&ch| ch == '*')
+}
+
+/// Returns the first allow pattern of `patterns` that matches `command`, if
+/// no deny pattern matches it as well.
+///
+/// A pattern matches if [`pattern_matches`] or [`words_match`] says so. See
+/// the [module documentation][self] for the rules.
+#[must_use]
+pub fn command_allowed<'a>(
+    patterns: &'a [String],
+    command: &str,
+) -> Option<&'a str> {
+    let denied = patterns
+        .iter()
+        .filter(|p| p.starts_with(DENY_PREFIX))
+        .map(|p| &p[DENY_PREFIX.len_utf8()..])
+        .any(|pattern| matches(pattern, command));
+    if denied {
+        return None;
+    }
+
+    patterns
+        .iter()
+        .map(String::as_str)
+        .filter(|p| !p.starts_with(DENY_PREFIX))
+        .find(|pattern| matches(pattern, command))
+}
+
+/// Returns the command that a forced command with allowlist `patterns` may
+/// run when the client requested `requested`.
+///
+/// `requested` is the value of [`ORIGINAL_COMMAND_VAR`], which is unset for
+/// interactive logins.
+///
+/// # Errors
+/// The function will fail if no command was requested or if
+/// [`command_allowed`] does not allow it.
+pub fn authorize<'a>(
+    patterns: &[String],
+    requested: Option<&'a str>,
+) -> Result<&'a str> {
+    let command = match requested {
+        Some(command) => command,
+        None => {
+            bail!("interactive logins are not allowed [request a command]")
+        }
+    };
+    if command_allowed(patterns, command).is_none() {
+        bail!("command {command:?} is not allowed");
+    }
+    Ok(command)
+}
+
+/// Returns whether `pattern` matches `command` as a string or by words.
+fn matches(pattern: &str, command: &str) -> bool {
+    pattern_matches(pattern, command) || words_match(pattern, command)
+}
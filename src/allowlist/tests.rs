@@ -0,0 +1,316 @@
+pub use super::*;
+
+/// Tests for [`pattern_matches`]
+mod pattern_matches {
+    use super::*;
+
+    #[test]
+    fn literal() {
+        assert!(pattern_matches("uptime", "uptime"));
+        assert!(!pattern_matches("uptime", "uptime -p"));
+        assert!(!pattern_matches("uptime", "uptim"));
+        assert!(!pattern_matches("uptime", ""));
+    }
+
+    #[test]
+    fn prefix() {
+        let pattern = "rsync --server *";
+        assert!(pattern_matches(pattern, "rsync --server ."));
+        assert!(pattern_matches(pattern, "rsync --server -e.Lsf . /srv"));
+        assert!(pattern_matches(pattern, "rsync --server "));
+        assert!(!pattern_matches(pattern, "rsync --server"));
+        assert!(!pattern_matches(pattern, "rsync --sender ."));
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(pattern_matches("*", ""));
+        assert!(pattern_matches("*", "anything at all"));
+        assert!(pattern_matches("git-*-pack '*'", "git-upload-pack 'repo'"));
+        assert!(!pattern_matches("git-*-pack '*'", "git-upload-pack repo"));
+        assert!(pattern_matches("ls ?", "ls a"));
+        assert!(!pattern_matches("ls ?", "ls ab"));
+        assert!(pattern_matches("a*b*c", "aXbYbZc"));
+        assert!(!pattern_matches("a*b*c", "aXbYbZ"));
+    }
+
+    #[test]
+    fn whitespace() {
+        assert!(!pattern_matches("uptime", "uptime "));
+        assert!(!pattern_matches("uptime", " uptime"));
+        assert!(!pattern_matches("rsync --server *", "rsync  --server ."));
+        assert!(!pattern_matches("rsync --server *", "rsync\t--server ."));
+        assert!(pattern_matches("git *", "git push origin main"));
+    }
+
+    #[test]
+    fn near_misses() {
+        assert!(!pattern_matches("rsync --server *", "rsync --serverX ."));
+        assert!(!pattern_matches("rsync --server *", "xrsync --server ."));
+        assert!(!pattern_matches("rsync --server *", "RSYNC --server ."));
+        assert!(!pattern_matches("backup", "backups"));
+        assert!(!pattern_matches("backup*", "restore"));
+    }
+
+    #[test]
+    fn metacharacters() {
+        let pattern = "rsync --server *";
+        assert!(!pattern_matches(pattern, "rsync --server .; rm -rf /"));
+        assert!(!pattern_matches(pattern, "rsync --server . && reboot"));
+        assert!(!pattern_matches(pattern, "rsync --server . | sh"));
+        assert!(!pattern_matches(pattern, "rsync --server $(id)"));
+        assert!(!pattern_matches(pattern, "rsync --server `id`"));
+        assert!(!pattern_matches(pattern, "rsync --server . > /etc/x"));
+        assert!(!pattern_matches(pattern, "rsync --server .\nreboot"));
+        assert!(!pattern_matches("ls ?", "ls ;"));
+        assert!(!pattern_matches("*;*", "a;b;c"));
+    }
+
+    #[test]
+    fn spelled_out_metacharacters() {
+        assert!(pattern_matches("cat *|wc -l", "cat log|wc -l"));
+        assert!(pattern_matches("*;b", "a;b"));
+        assert!(!pattern_matches("*;b", "a;x;b"));
+        assert!(pattern_matches("a;*;c", "a;b;c"));
+    }
+}
+
+/// Tests for [`command_allowed`]
+mod command_allowed {
+    use super::*;
+
+    fn commands() -> Vec<String> {
+        vec![String::from("uptime"), String::from("rsync --server *")]
+    }
+
+    #[test]
+    fn allowed() {
+        let commands = commands();
+        assert_eq!(
+            command_allowed(&commands, "rsync --server ."),
+            Some("rsync --server *")
+        );
+        assert_eq!(command_allowed(&commands, "uptime"), Some("uptime"));
+    }
+
+    #[test]
+    fn denied() {
+        let commands = commands();
+        assert_eq!(command_allowed(&commands, "rm -rf /"), None);
+        assert_eq!(command_allowed(&commands, "uptime; rm -rf /"), None);
+        assert_eq!(command_allowed(&[], "uptime"), None);
+    }
+
+    #[test]
+    fn deny() {
+        let commands = vec![
+            String::from("git-*"),
+            String::from("!git-shell*"),
+            String::from("uptime"),
+        ];
+        assert_eq!(command_allowed(&commands, "git-shell"), None);
+        assert_eq!(command_allowed(&commands, "git-shell -c id"), None);
+        assert_eq!(
+            command_allowed(&commands, "git-upload-pack 'repo'"),
+            Some("git-*")
+        );
+        assert_eq!(
+            command_allowed(&commands, "git-receive-pack 'repo'"),
+            Some("git-*")
+        );
+        assert_eq!(command_allowed(&commands, "uptime"), Some("uptime"));
+    }
+
+    #[test]
+    fn deny_before_allow() {
+        let commands =
+            vec![String::from("!git-shell*"), String::from("git-*")];
+        assert_eq!(command_allowed(&commands, "git-shell"), None);
+        assert_eq!(
+            command_allowed(&commands, "git-upload-pack"),
+            Some("git-*")
+        );
+    }
+
+    #[test]
+    fn deny_only() {
+        let commands = vec![String::from("!rm *")];
+        assert_eq!(command_allowed(&commands, "rm -rf /"), None);
+        assert_eq!(command_allowed(&commands, "ls"), None);
+        assert_eq!(command_allowed(&commands, "!rm *"), None);
+    }
+
+    #[test]
+    fn quoted_arguments() {
+        let commands = commands();
+        assert_eq!(
+            command_allowed(&commands, "rsync --server -e.Lsf . 'my dest'"),
+            Some("rsync --server *")
+        );
+        assert_eq!(
+            command_allowed(&commands, "rsync  --server\t-e.Lsf . dest"),
+            Some("rsync --server *")
+        );
+        assert_eq!(
+            command_allowed(&commands, "'rsync' \"--server\" ."),
+            Some("rsync --server *")
+        );
+        assert_eq!(command_allowed(&commands, "'uptime'"), Some("uptime"));
+        assert_eq!(command_allowed(&commands, "'uptime -p'"), None);
+        assert_eq!(command_allowed(&commands, "rsync --server 'a;b'"), None);
+    }
+
+    #[test]
+    fn quoted_deny() {
+        let commands =
+            vec![String::from("git-*"), String::from("!git-shell *")];
+        assert_eq!(command_allowed(&commands, "'git-shell' -c id"), None);
+        assert_eq!(command_allowed(&commands, "git-shell  -c id"), None);
+        assert_eq!(
+            command_allowed(&commands, "git-upload-pack repo"),
+            Some("git-*")
+        );
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let commands =
+            vec![String::from("git *"), String::from("git push *")];
+        assert_eq!(command_allowed(&commands, "git push x"), Some("git *"));
+    }
+}
+
+/// Tests for [`split_words`]
+mod split_words {
+    use super::*;
+
+    #[test]
+    fn plain() {
+        assert_eq!(
+            split_words("rsync --server ."),
+            Some(vec!["rsync".into(), "--server".into(), ".".into()])
+        );
+        assert_eq!(
+            split_words("  a \t b  "),
+            Some(vec!["a".into(), "b".into()])
+        );
+        assert_eq!(split_words(""), Some(vec![]));
+    }
+
+    #[test]
+    fn quotes() {
+        let expected = Some(vec!["cp".to_owned(), "my file".to_owned()]);
+        assert_eq!(split_words("cp 'my file'"), expected);
+        assert_eq!(split_words("cp \"my file\""), expected);
+        assert_eq!(split_words("cp my\\ file"), expected);
+        assert_eq!(split_words("cp my' 'fi\"le\""), expected);
+        assert_eq!(
+            split_words("echo ''"),
+            Some(vec!["echo".into(), String::new()])
+        );
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(
+            split_words(r#"a "\"\\\$\`\x""#),
+            Some(vec!["a".into(), r#""\$`\x"#.into()])
+        );
+        assert_eq!(split_words(r"'\'"), Some(vec![r"\".into()]));
+        assert_eq!(split_words(r"\;"), Some(vec![";".into()]));
+    }
+
+    #[test]
+    fn metacharacters() {
+        assert_eq!(
+            split_words("a 'b;c|d$e'"),
+            Some(vec!["a".into(), "b;c|d$e".into()])
+        );
+        assert_eq!(split_words("a;b"), None);
+        assert_eq!(split_words("a \"$(id)\""), None);
+        assert_eq!(split_words("a \"`id`\""), None);
+        assert_eq!(split_words("a\nb"), None);
+    }
+
+    #[test]
+    fn unbalanced() {
+        assert_eq!(split_words("a 'b"), None);
+        assert_eq!(split_words("a \"b"), None);
+        assert_eq!(split_words("a \\"), None);
+    }
+}
+
+/// Tests for [`words_match`]
+mod words_match {
+    use super::*;
+
+    #[test]
+    fn lone_wildcard() {
+        let pattern = "rsync --server *";
+        assert!(words_match(pattern, "rsync --server -e.Lsf . dest"));
+        assert!(words_match(pattern, "rsync   --server ."));
+        assert!(words_match(pattern, "rsync --server . \"my dest\""));
+        assert!(!words_match(pattern, "rsync --server"));
+        assert!(!words_match(pattern, "rsync --serverX ."));
+        assert!(!words_match(pattern, "rsync --server . 'a|b'"));
+        assert!(words_match("a * b", "a x y b"));
+        assert!(!words_match("a * b", "a b"));
+    }
+
+    #[test]
+    fn word_wildcards() {
+        assert!(words_match("git-*-pack '*'", "git-upload-pack 'repo'"));
+        assert!(words_match("git-*-pack '*'", "git-upload-pack repo"));
+        assert!(words_match("cp ? dest", "cp 'a' dest"));
+        assert!(!words_match("cp ? dest", "cp 'a b' dest"));
+        assert!(!words_match("git-*", "git-upload-pack repo"));
+        assert!(words_match("cp 'my *' dest", "cp 'my file' dest"));
+    }
+
+    #[test]
+    fn unsplittable() {
+        assert!(!words_match("cat *|wc -l", "cat log|wc -l"));
+        assert!(!words_match("a *", "a 'b"));
+    }
+}
+
+/// Tests for [`glob_matches`]
+mod glob_matches {
+    use super::*;
+
+    #[test]
+    fn barred() {
+        assert!(glob_matches("host*", "host$", &[]));
+        assert!(!glob_matches("host*", "host$", &['$']));
+        assert!(glob_matches("svc-?", "svc-a", &[]));
+        assert!(!glob_matches("svc-?", "svc-ab", &[]));
+    }
+}
+
+/// Tests for [`authorize`]
+mod authorize {
+    use super::*;
+
+    fn commands() -> Vec<String> {
+        vec![String::from("uptime"), String::from("!uptime -s")]
+    }
+
+    #[test]
+    fn allowed() -> Result<()> {
+        assert_eq!(authorize(&commands(), Some("uptime"))?, "uptime");
+        Ok(())
+    }
+
+    #[test]
+    fn denied() {
+        let error = authorize(&commands(), Some("uptime -s")).unwrap_err();
+        assert_eq!(error.to_string(), "command \"uptime -s\" is not allowed");
+        assert!(authorize(&commands(), Some("id")).is_err());
+    }
+
+    #[test]
+    fn interactive() {
+        let error = authorize(&commands(), None).unwrap_err();
+        assert!(error.to_string().starts_with("interactive"), "{error}");
+    }
+}
@@ -0,0 +1,164 @@
+//! Audit events describing changes made by narrowssh.
+//!
+//! Events are produced from an [`ApplyReport`] and handed to one or more
+//! [`Sink`]s. With the `syslog` feature, [`Syslog`] sends them to the system
+//! log.
+
+use std::fmt;
+use std::path::PathBuf;
+
+use anyhow::Result;
+
+use crate::refresh::{ApplyReport, Outcome};
+
+#[cfg(test)]
+mod tests;
+
+/// Kind of change recorded by an [`Event`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// A managed block was written.
+    Updated,
+
+    /// A managed block was removed.
+    Removed,
+}
+
+/// A change made to the `authorized_keys` file of a user.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    /// Name of the affected user.
+    pub user: String,
+
+    /// What was done.
+    pub action: Action,
+
+    /// The `authorized_keys` file that was changed.
+    pub path: PathBuf,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self.action {
+            Action::Updated => "updated managed block in",
+            Action::Removed => "removed managed block from",
+        };
+        write!(f, "user {}: {action} {}", self.user, self.path.display())
+    }
+}
+
+/// Destination of audit events.
+pub trait Sink {
+    /// Records `event`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the event could not be recorded.
+    fn emit(&mut self, event: &Event) -> Result<()>;
+}
+
+/// Returns the events of `report`, in order.
+///
+/// Users that were left unchanged produce no events.
+#[must_use]
+pub fn events(report: &ApplyReport) -> Vec<Event> {
+    report
+        .outcomes
+        .iter()
+        .filter_map(|(user, outcome)| {
+            let (action, path) = match outcome {
+                Outcome::Updated(path) => (Action::Updated, path),
+                Outcome::Removed(path) => (Action::Removed, path),
+                Outcome::Disabled | Outcome::Unchanged(_) => return None,
+            };
+            Some(Event {
+                user: user.name().to_string_lossy().into_owned(),
+                action,
+                path: path.clone(),
+            })
+        })
+        .collect()
+}
+
+/// Records every event of `events` in every sink of `sinks`.
+///
+/// All events are offered to all sinks even if some of them fail.
+///
+/// # Errors
+///
+/// Returns the first error reported by a sink.
+pub fn emit_all(sinks: &mut [Box<dyn Sink>], events: &[Event]) -> Result<()> {
+    let mut result = Ok(());
+
+    for sink in sinks.iter_mut() {
+        for event in events {
+            if let Err(error) = sink.emit(event) {
+                if result.is_ok() {
+                    result = Err(error);
+                }
+            }
+        }
+    }
+
+    result
+}
+
+/// Sink that sends events to the system log.
+///
+/// Events are logged with facility `LOG_AUTHPRIV` and severity `LOG_NOTICE`,
+/// as they record changes to SSH access.
+#[cfg(feature = "syslog")]
+#[derive(Debug)]
+pub struct Syslog {
+    _private: (),
+}
+
+#[cfg(feature = "syslog")]
+impl Syslog {
+    /// Opens a connection to the system log.
+    ///
+    /// Only one instance should exist at a time, as the connection is
+    /// process-wide.
+    #[must_use]
+    pub fn open() -> Self {
+        const IDENT: &[u8] = b"narrowssh\0";
+
+        // SAFETY: IDENT is NUL-terminated and lives for the whole program.
+        unsafe {
+            libc::openlog(
+                IDENT.as_ptr().cast(),
+                libc::LOG_PID,
+                libc::LOG_AUTHPRIV,
+            );
+        }
+        Self { _private: () }
+    }
+}
+
+#[cfg(feature = "syslog")]
+impl Sink for Syslog {
+    fn emit(&mut self, event: &Event) -> Result<()> {
+        const FORMAT: &[u8] = b"%s\0";
+
+        let message = std::ffi::CString::new(event.to_string())?;
+
+        // SAFETY: both strings are NUL-terminated and FORMAT consumes exactly
+        // one string argument.
+        unsafe {
+            libc::syslog(
+                libc::LOG_NOTICE,
+                FORMAT.as_ptr().cast(),
+                message.as_ptr(),
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "syslog")]
+impl Drop for Syslog {
+    fn drop(&mut self) {
+        // SAFETY: closelog has no preconditions.
+        unsafe { libc::closelog() };
+    }
+}
@@ -0,0 +1,156 @@
+pub use std::cell::RefCell;
+pub use std::path::Path;
+pub use std::rc::Rc;
+
+pub use anyhow::bail;
+
+pub use crate::authorized_keys::{BEGIN_MARKER, END_MARKER};
+pub use crate::config::{ControlManager, VisitOptions};
+pub use crate::refresh::apply_batch;
+pub use crate::workspace::mock::MockWorkspace;
+pub use crate::workspace::Workspace;
+
+pub use super::*;
+
+const KEY: &str =
+    "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIGd1bW15a2V5Zm9ydGVzdGluZ3B1cnBvc2Vz";
+
+/// Sink that records events into a shared list.
+struct Recorder(Rc<RefCell<Vec<Event>>>);
+
+impl Sink for Recorder {
+    fn emit(&mut self, event: &Event) -> Result<()> {
+        self.0.borrow_mut().push(event.clone());
+        Ok(())
+    }
+}
+
+/// Sink that always fails.
+struct Broken;
+
+impl Sink for Broken {
+    fn emit(&mut self, _event: &Event) -> Result<()> {
+        bail!("sink is broken");
+    }
+}
+
+/// Returns a new [`Recorder`] and the list it records into.
+fn recorder() -> (Box<dyn Sink>, Rc<RefCell<Vec<Event>>>) {
+    let events = Rc::new(RefCell::new(Vec::new()));
+    (Box::new(Recorder(Rc::clone(&events))), events)
+}
+
+/// Creates a workspace where alice needs an update, bob is up to date and
+/// carol is disabled with a managed block that refresh leaves alone.
+fn workspace() -> Result<(MockWorkspace, ControlManager)> {
+    let mut ws = MockWorkspace::new()?;
+
+    ws.add_user(0, "root", "root")?;
+    for (uid, name) in [(1000, "alice"), (1001, "bob"), (1002, "carol")] {
+        ws.add_user(uid, name, format!("home/{name}"))?;
+        ws.add_file(
+            format!("home/{name}/.narrowssh.conf"),
+            uid,
+            0o600,
+            format!("keys = [{KEY:?}]"),
+        )?;
+    }
+
+    #[rustfmt::skip]
+    let main = ws.add_file("etc/main.toml", 0, 0o600, r#"
+        ["*"]
+        enable = true
+
+        [carol]
+        enable = false
+    "#)?;
+    let control = ControlManager::load(&ws, main, &VisitOptions::default())?;
+
+    let stale = format!("{BEGIN_MARKER}\nold\n{END_MARKER}\n");
+    ws.add_file("home/carol/.ssh/authorized_keys", 1002, 0o600, stale)?;
+
+    let bob = ws.users().user_by_uid(1001).unwrap();
+    let batch = control.plan(&ws, &[bob], &VisitOptions::default());
+    apply_batch(&ws, &batch, false)?;
+
+    Ok((ws, control))
+}
+
+/// Tests for [`events`]
+mod events {
+    use super::*;
+
+    #[test]
+    fn changes_only() -> Result<()> {
+        let (ws, control) = workspace()?;
+        let users: Vec<_> = (1000..1003)
+            .map(|uid| ws.users().user_by_uid(uid).unwrap())
+            .collect();
+
+        let batch = control.plan(&ws, &users, &VisitOptions::default());
+        let report = apply_batch(&ws, &batch, false)?;
+
+        let events: Vec<_> = events(&report)
+            .into_iter()
+            .map(|e| (e.user, e.action))
+            .collect();
+        assert_eq!(events, [("alice".to_string(), Action::Updated)]);
+        Ok(())
+    }
+
+    #[test]
+    fn display() {
+        let event = Event {
+            user: "alice".to_string(),
+            action: Action::Updated,
+            path: Path::new("/home/alice/.ssh/authorized_keys").to_path_buf(),
+        };
+        assert_eq!(
+            event.to_string(),
+            "user alice: updated managed block in \
+             /home/alice/.ssh/authorized_keys"
+        );
+    }
+}
+
+/// Tests for [`emit_all`]
+mod emit_all {
+    use super::*;
+
+    #[test]
+    fn every_sink() -> Result<()> {
+        let (ws, control) = workspace()?;
+        let users: Vec<_> = (1000..1003)
+            .map(|uid| ws.users().user_by_uid(uid).unwrap())
+            .collect();
+
+        let batch = control.plan(&ws, &users, &VisitOptions::default());
+        let events = events(&apply_batch(&ws, &batch, false)?);
+
+        let (first, first_events) = recorder();
+        let (second, second_events) = recorder();
+        emit_all(&mut [first, second], &events)?;
+
+        assert_eq!(*first_events.borrow(), events);
+        assert_eq!(*second_events.borrow(), events);
+        Ok(())
+    }
+
+    #[test]
+    fn broken_sink() {
+        let event = Event {
+            user: "alice".to_string(),
+            action: Action::Removed,
+            path: Path::new("/home/alice/.ssh/authorized_keys").to_path_buf(),
+        };
+
+        let (sink, recorded) = recorder();
+        let result = emit_all(
+            &mut [Box::new(Broken), sink],
+            std::slice::from_ref(&event),
+        );
+
+        assert!(format!("{:#}", result.unwrap_err()).contains("broken"));
+        assert_eq!(*recorded.borrow(), [event]);
+    }
+}
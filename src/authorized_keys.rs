@@ -0,0 +1,983 @@
+//! Parser and generator of `authorized_keys(5)` contents.
+
+use std::collections::HashSet;
+use std::fmt::{self, Write};
+use std::ops::Range;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::config::{
+    CommandConflict, Control, KeysAction, LongLine, TooManyKeys,
+};
+
+#[cfg(test)]
+mod tests;
+
+/// Line that opens the section of `authorized_keys` managed by narrowssh.
+pub const BEGIN_MARKER: &str = "# BEGIN narrowssh";
+
+/// Line that closes the section of `authorized_keys` managed by narrowssh.
+pub const END_MARKER: &str = "# END narrowssh";
+
+/// Prefix of the line after [`BEGIN_MARKER`] that holds the block hash.
+///
+/// See [`block_hash`].
+pub const HASH_PREFIX: &str = "# narrowssh v=";
+
+/// Prefix of the line before the closing marker that records how
+/// `authorized_keys` ended before the managed block was appended.
+///
+/// See [`BlockMarkers::replace`].
+pub const ENDING_PREFIX: &str = "# narrowssh ending=";
+
+/// Command that every managed key is forced to run.
+///
+/// The allowlisted commands of the user are appended as shell-quoted
+/// arguments. The `exec` subcommand runs the command requested by the client
+/// only if [`authorize`][crate::allowlist::authorize] allows it.
+pub const EXEC_COMMAND: &str = "/usr/bin/narrowssh exec --";
+
+/// Command that keys of users with [`KeysAction::Deny`] are forced to run.
+pub const DENY_COMMAND: &str = "/bin/echo access revoked";
+
+/// Key types recognized at the start of a line without options.
+pub const KNOWN_KEY_TYPES: &[&str] = &[
+    "ssh-rsa",
+    "ssh-dss",
+    "ssh-ed25519",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp521",
+    "sk-ssh-ed25519@openssh.com",
+    "sk-ecdsa-sha2-nistp256@openssh.com",
+    "ssh-rsa-cert-v01@openssh.com",
+    "ssh-dss-cert-v01@openssh.com",
+    "ssh-ed25519-cert-v01@openssh.com",
+    "ecdsa-sha2-nistp256-cert-v01@openssh.com",
+    "ecdsa-sha2-nistp384-cert-v01@openssh.com",
+    "ecdsa-sha2-nistp521-cert-v01@openssh.com",
+    "sk-ssh-ed25519-cert-v01@openssh.com",
+    "sk-ecdsa-sha2-nistp256-cert-v01@openssh.com",
+];
+
+/// Returns whether `key_type` is one of [`KNOWN_KEY_TYPES`] or
+/// `extra_key_types`.
+#[must_use]
+pub fn is_known_key_type(key_type: &str, extra_key_types: &[String]) -> bool {
+    KNOWN_KEY_TYPES.contains(&key_type)
+        || extra_key_types.iter().any(|t| t == key_type)
+}
+
+/// Options of incoming keys that are preserved in the managed block.
+///
+/// These options can only restrict a key further. All other options are
+/// dropped because they could undo the restrictions imposed by narrowssh.
+const CARRIED_OPTIONS: &[&str] = &["from", "expiry-time", "verify-required"];
+
+/// Returns the options that lift restrictions of `restrict` as permitted by
+/// `control`.
+///
+/// Every forwarding and the terminal are disabled by `restrict` unless the
+/// corresponding field of [`Control`] is set. Nothing is permitted with
+/// [`KeysAction::Deny`].
+fn permit_options(control: &Control) -> Vec<KeyOption> {
+    if control.action == KeysAction::Deny {
+        return Vec::new();
+    }
+
+    [
+        (control.port_forwarding, "permit-port-forwarding"),
+        (control.x11_forwarding, "permit-X11-forwarding"),
+        (control.agent_forwarding, "permit-agent-forwarding"),
+        (control.pty, "pty"),
+    ]
+    .iter()
+    .filter(|(permitted, _)| *permitted)
+    .map(|(_, name)| KeyOption {
+        name: String::from(*name),
+        value: None,
+    })
+    .collect()
+}
+
+/// Marker that may precede a key line, e.g. `@cert-authority`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Marker {
+    /// The key is a certificate authority trusted to sign user certificates.
+    CertAuthority,
+
+    /// The key is revoked and must never be accepted.
+    Revoked,
+}
+
+impl Marker {
+    /// Returns the marker as it appears in `authorized_keys(5)`.
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::CertAuthority => "@cert-authority",
+            Self::Revoked => "@revoked",
+        }
+    }
+}
+
+impl std::str::FromStr for Marker {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        [Self::CertAuthority, Self::Revoked]
+            .into_iter()
+            .find(|m| m.as_str().eq_ignore_ascii_case(s))
+            .ok_or_else(|| anyhow!("unknown marker {s:?}"))
+    }
+}
+
+/// A single option of a key line, e.g. `restrict` or `command="ls"`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyOption {
+    /// Name of the option.
+    pub name: String,
+
+    /// Unescaped value of the option, if any.
+    pub value: Option<String>,
+}
+
+/// A single key line of an `authorized_keys(5)` file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyLine {
+    /// Marker preceding the options, if any.
+    pub marker: Option<Marker>,
+
+    /// Options preceding the key, in order of appearance.
+    pub options: Vec<KeyOption>,
+
+    /// Key type, e.g. `ssh-ed25519`.
+    pub key_type: String,
+
+    /// Base64-encoded public key.
+    pub blob: String,
+
+    /// Trailing comment, if any.
+    pub comment: Option<String>,
+}
+
+impl KeyLine {
+    /// Parses a single line of an `authorized_keys(5)` file.
+    ///
+    /// Same as [`KeyLine::parse_with`] without extra key types.
+    ///
+    /// # Errors
+    /// The function will fail if the line is not a well-formed key line.
+    pub fn parse(line: &str) -> Result<Self> {
+        Self::parse_with(line, &[])
+    }
+
+    /// Parses a single line of an `authorized_keys(5)` file, recognizing
+    /// `extra_key_types` in addition to [`KNOWN_KEY_TYPES`].
+    ///
+    /// A leading [`Marker`] is recognized by its `@` prefix. Options are only
+    /// recognized if the rest of the line does not begin with a recognized
+    /// key type, or with an unknown key type followed by a public key.
+    /// Public keys are told apart by their `AAAA` prefix, which every
+    /// base64-encoded SSH public key starts with.
+    ///
+    /// Control characters, such as newlines, are refused in every part of
+    /// the line, since they would let a key spill into a line of its own
+    /// once rendered.
+    ///
+    /// # Errors
+    /// The function will fail if the line is not a well-formed key line.
+    pub fn parse_with(
+        line: &str,
+        extra_key_types: &[String],
+    ) -> Result<Self> {
+        let line = line.trim();
+        let (first, rest) = split_token(line);
+
+        let (marker, line, (first, rest)) = if first.starts_with('@') {
+            (Some(first.parse()?), rest, split_token(rest))
+        } else {
+            (None, line, (first, rest))
+        };
+
+        if first.is_empty() {
+            bail!("key line is empty");
+        }
+
+        let unknown_key = !first.contains(['=', '"', ','])
+            && split_token(rest).0.starts_with("AAAA");
+
+        let (options, rest) =
+            if is_known_key_type(first, extra_key_types) || unknown_key {
+                (Vec::new(), line)
+            } else {
+                (parse_options(first)?, rest)
+            };
+
+        let (key_type, rest) = split_token(rest);
+        let (blob, rest) = split_token(rest);
+
+        if key_type.is_empty() {
+            bail!("key type is missing");
+        }
+        if blob.is_empty() {
+            bail!("public key is missing");
+        }
+        if !blob
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+/=".contains(c))
+        {
+            bail!("public key is not valid base64");
+        }
+
+        let comment = rest.trim();
+
+        check_no_control("key type", key_type)?;
+        for option in &options {
+            check_no_control("option name", &option.name)?;
+            if let Some(value) = &option.value {
+                check_no_control("option value", value)?;
+            }
+        }
+        check_no_control("key comment", comment)?;
+
+        Ok(Self {
+            marker,
+            options,
+            key_type: key_type.to_owned(),
+            blob: blob.to_owned(),
+            comment: if comment.is_empty() {
+                None
+            } else {
+                Some(comment.to_owned())
+            },
+        })
+    }
+
+    /// Returns the value of the first option called `name`, if any.
+    #[must_use]
+    pub fn option(&self, name: &str) -> Option<&KeyOption> {
+        self.options
+            .iter()
+            .find(|o| o.name.eq_ignore_ascii_case(name))
+    }
+}
+
+impl fmt::Display for KeyLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(marker) = self.marker {
+            write!(f, "{} ", marker.as_str())?;
+        }
+
+        for (i, option) in self.options.iter().enumerate() {
+            if i != 0 {
+                f.write_str(",")?;
+            }
+            f.write_str(&option.name)?;
+            if let Some(value) = &option.value {
+                write!(f, "=\"{}\"", value.replace('"', "\\\""))?;
+            }
+        }
+
+        if !self.options.is_empty() {
+            f.write_str(" ")?;
+        }
+
+        write!(f, "{} {}", self.key_type, self.blob)?;
+
+        if let Some(comment) = &self.comment {
+            write!(f, " {comment}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Ensures that `s`, described as `what`, holds no control characters.
+///
+/// # Errors
+/// The function will fail if `s` contains a control character, including
+/// line breaks and tabs.
+fn check_no_control(what: &str, s: &str) -> Result<()> {
+    if let Some(c) = s.chars().find(|c| c.is_control()) {
+        bail!("{what} {s:?} must not contain control character {c:?}");
+    }
+    Ok(())
+}
+
+/// Splits `s` at the first unquoted whitespace.
+///
+/// Leading whitespace of the remainder is discarded.
+fn split_token(s: &str) -> (&str, &str) {
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                return (&s[..i], s[i..].trim_start());
+            }
+            _ => {}
+        }
+    }
+
+    (s, "")
+}
+
+/// Parses a comma-separated list of key options.
+fn parse_options(s: &str) -> Result<Vec<KeyOption>> {
+    let mut result = Vec::new();
+    let mut chars = s.chars().peekable();
+
+    while chars.peek().is_some() {
+        let mut name = String::new();
+        let mut value = None;
+
+        while let Some(&c) = chars.peek() {
+            if c == ',' || c == '=' {
+                break;
+            }
+            name.push(c);
+            chars.next();
+        }
+
+        if name.is_empty() {
+            bail!("empty option in {s:?}");
+        }
+
+        if chars.peek() == Some(&'=') {
+            chars.next();
+            if chars.next() != Some('"') {
+                bail!("value of option {name:?} must be quoted");
+            }
+
+            let mut buffer = String::new();
+            loop {
+                match chars.next() {
+                    None => bail!("unterminated value of option {name:?}"),
+                    Some('"') => break,
+                    Some('\\') if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        buffer.push('"');
+                    }
+                    Some(c) => buffer.push(c),
+                }
+            }
+            value = Some(buffer);
+        }
+
+        match chars.next() {
+            None | Some(',') => {}
+            Some(c) => bail!("unexpected {c:?} after option {name:?}"),
+        }
+
+        result.push(KeyOption { name, value });
+    }
+
+    Ok(result)
+}
+
+/// Quotes `s` for use as a single word in a POSIX shell.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Returns the forced command for keys of a user with given `control`.
+///
+/// This is [`DENY_COMMAND`] if [`Control::action`] is [`KeysAction::Deny`].
+#[must_use]
+pub fn forced_command(control: &Control) -> String {
+    if control.action == KeysAction::Deny {
+        return String::from(DENY_COMMAND);
+    }
+
+    let mut result = String::from(EXEC_COMMAND);
+
+    for command in &control.commands {
+        result.push(' ');
+        result.push_str(&shell_quote(command));
+    }
+
+    result
+}
+
+/// Managed block rendered by [`render_managed_block`].
+#[derive(Clone, Debug)]
+pub struct ManagedBlock {
+    /// Contents of the block, including markers and the final newline.
+    pub text: String,
+
+    /// Problems that did not prevent rendering.
+    pub warnings: Vec<String>,
+
+    /// Hash of the key lines of the block, see [`block_hash`].
+    pub hash: String,
+}
+
+impl ManagedBlock {
+    /// Returns the number of key lines in the block, including `@revoked`
+    /// keys.
+    ///
+    /// Skipped and duplicate keys are not counted.
+    #[must_use]
+    pub fn keys(&self) -> usize {
+        // Every line but the markers and the hash holds a key
+        self.text.lines().count().saturating_sub(3)
+    }
+}
+
+/// Returns a hash of the key `lines` of a managed block.
+///
+/// `lines` are the rendered key lines between the hash line and the closing
+/// marker. They reflect the keys and every setting that affects them, such
+/// as the forced command and the permitted forwardings, so a change to
+/// either changes the hash, while settings that only affect how the file is
+/// written do not. The hash is stored in the block after [`HASH_PREFIX`] and
+/// can be extracted with [`read_block_hash`] to detect outdated blocks
+/// without comparing their contents.
+///
+/// The hash is 64-bit FNV-1a, which is stable across builds but offers no
+/// protection against deliberate collisions.
+#[must_use]
+pub fn block_hash(lines: &str) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in lines.as_bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    format!("{hash:016x}")
+}
+
+/// Renders the managed block for given public keys.
+///
+/// Every key is restricted and forced to run [`forced_command`]. Only the
+/// forwardings and the terminal enabled in [`Control`] are permitted again,
+/// see [`Control::port_forwarding`] and the following fields. Keys that
+/// already force a different command are handled according to
+/// [`Control::command_conflict`]. Lines longer than
+/// [`Control::max_line_length`] are handled according to
+/// [`Control::long_line`]. Keys beyond [`Control::max_keys`] are handled
+/// according to [`Control::too_many_keys`]; revoked keys are not counted.
+///
+/// Keys with the same marker, type and public key are only rendered once,
+/// keeping the options and comment of the first occurrence, and a warning is
+/// issued.
+///
+/// The block is delimited by [`BlockMarkers::of`] `control`. The line after
+/// the opening marker records the [`block_hash`] of the key lines.
+///
+/// Keys of types other than [`KNOWN_KEY_TYPES`] and
+/// [`Control::extra_key_types`] are rendered with a warning.
+///
+/// With [`KeysAction::Deny`], every key is forced to run [`DENY_COMMAND`]
+/// instead, overriding commands of its own without a warning.
+///
+/// Markers are preserved. Restrictions apply to `@cert-authority` keys as
+/// well, since sshd enforces them for every certificate signed by such a key.
+/// `@revoked` keys are copied without options, which sshd ignores for them.
+///
+/// # Errors
+/// The function will fail in these cases:
+///   - some key could not be parsed,
+///   - some command in [`Control::commands`] contains a control character,
+///   - some key forces a different command and the conflict policy is
+///     [`CommandConflict::Error`], or
+///   - some line is too long and the policy is [`LongLine::Error`], or
+///   - there are too many keys and the policy is [`TooManyKeys::Error`].
+pub fn render_managed_block(
+    keys: &[String],
+    control: &Control,
+) -> Result<ManagedBlock> {
+    for command in &control.commands {
+        check_no_control("command", command)?;
+    }
+    let command = forced_command(control);
+    let markers = BlockMarkers::of(control);
+    let mut lines = String::new();
+    let mut warnings = Vec::new();
+    let mut seen = HashSet::new();
+    let mut installed = 0;
+    let mut truncated = 0;
+
+    for (index, key) in keys.iter().enumerate() {
+        let key = KeyLine::parse_with(key, &control.extra_key_types)
+            .with_context(|| format!("parsing key #{}", index + 1))?;
+        let name =
+            key.comment.clone().unwrap_or_else(|| key.key_type.clone());
+
+        if !is_known_key_type(&key.key_type, &control.extra_key_types) {
+            warnings.push(format!(
+                "key {name} has unrecognized type {:?}; installed anyway \
+                [add the type to extra_key_types]",
+                key.key_type
+            ));
+        }
+
+        let identity = (key.marker, key.key_type.clone(), key.blob.clone());
+        if seen.contains(&identity) {
+            warnings.push(format!(
+                "key {name} is listed more than once; duplicates collapsed"
+            ));
+            continue;
+        }
+
+        if key.marker == Some(Marker::Revoked) {
+            seen.insert(identity);
+            let line = KeyLine {
+                options: Vec::new(),
+                ..key
+            };
+            writeln!(lines, "{line}")?;
+            continue;
+        }
+
+        if let Some(own) = key.option("command") {
+            let own = own.value.as_deref().unwrap_or_default();
+            if own != command && control.action == KeysAction::Allow {
+                match control.command_conflict {
+                    CommandConflict::Error => bail!(
+                        "key {name} already forces command {own:?} \
+                        [set command_conflict to skip or override]"
+                    ),
+                    CommandConflict::Skip => {
+                        warnings.push(format!(
+                            "key {name} skipped: \
+                            it already forces command {own:?}"
+                        ));
+                        continue;
+                    }
+                    CommandConflict::Override => {
+                        warnings.push(format!(
+                            "key {name}: command {own:?} \
+                            overridden by narrowssh"
+                        ));
+                    }
+                }
+            }
+        }
+
+        seen.insert(identity);
+
+        if control.max_keys.map_or(false, |max| installed >= max) {
+            truncated += 1;
+            continue;
+        }
+        installed += 1;
+
+        let mut options = vec![
+            KeyOption {
+                name: String::from("restrict"),
+                value: None,
+            },
+            KeyOption {
+                name: String::from("command"),
+                value: Some(command.clone()),
+            },
+        ];
+        options.extend(permit_options(control));
+        options.extend(key.options.into_iter().filter(|o| {
+            CARRIED_OPTIONS
+                .iter()
+                .any(|c| o.name.eq_ignore_ascii_case(c))
+        }));
+
+        let line = KeyLine { options, ..key }.to_string();
+        check_line_length(&line, &name, &command, control, &mut warnings)?;
+        writeln!(lines, "{line}")?;
+    }
+    check_key_count(installed, truncated, control, &mut warnings)?;
+
+    let hash = block_hash(&lines);
+    let text = format!(
+        "{}\n{HASH_PREFIX}{hash}\n{lines}{}\n",
+        markers.begin, markers.end
+    );
+
+    Ok(ManagedBlock {
+        text,
+        warnings,
+        hash,
+    })
+}
+
+/// Handles a rendered `line` of key `name` according to
+/// [`Control::long_line`] if it is too long.
+fn check_line_length(
+    line: &str,
+    name: &str,
+    command: &str,
+    control: &Control,
+    warnings: &mut Vec<String>,
+) -> Result<()> {
+    if line.len() > control.max_line_length {
+        let message = format!(
+            "line of key {name} is {} bytes long, over the limit of {} \
+            bytes, and may be truncated by sshd; forced command is {:?}",
+            line.len(),
+            control.max_line_length,
+            command
+        );
+        match control.long_line {
+            LongLine::Error => bail!(
+                "{message} [shorten the commands or set long_line to warn]"
+            ),
+            LongLine::Warn => warnings.push(message),
+        }
+    }
+    Ok(())
+}
+
+/// Handles `truncated` keys left out beyond [`Control::max_keys`] after
+/// `installed` keys according to [`Control::too_many_keys`].
+fn check_key_count(
+    installed: usize,
+    truncated: usize,
+    control: &Control,
+    warnings: &mut Vec<String>,
+) -> Result<()> {
+    if truncated != 0 {
+        let message = format!(
+            "user has {} keys, over the limit of {installed} keys",
+            installed + truncated
+        );
+        match control.too_many_keys {
+            TooManyKeys::Error => bail!(
+                "{message} [remove keys, raise max_keys or set too_many_keys \
+                to truncate]"
+            ),
+            TooManyKeys::Truncate => warnings.push(format!(
+                "{message}; the last {truncated} keys left out"
+            )),
+        }
+    }
+    Ok(())
+}
+
+/// Lines that open and close the managed block.
+///
+/// Control may replace the defaults [`BEGIN_MARKER`] and [`END_MARKER`], see
+/// [`Control::begin_marker`] and [`Control::end_marker`]. Every function that
+/// finds managed blocks comes in two flavors: a method that uses given
+/// markers, and a free function that uses the defaults.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockMarkers {
+    /// Line that opens the managed block.
+    pub begin: String,
+
+    /// Line that closes the managed block.
+    pub end: String,
+}
+
+impl Default for BlockMarkers {
+    fn default() -> Self {
+        Self {
+            begin: String::from(BEGIN_MARKER),
+            end: String::from(END_MARKER),
+        }
+    }
+}
+
+/// Ensures that `marker` can be used as a line of [`BlockMarkers`].
+///
+/// # Errors
+/// The function will fail if `marker` is not a single line that starts with
+/// `#` and has more than that, or if it ends with whitespace, which would be
+/// lost when lines are compared.
+pub fn validate_marker(marker: &str) -> Result<()> {
+    if !marker.starts_with('#') || marker.len() < 2 {
+        bail!("marker {marker:?} must be a comment starting with '#'");
+    }
+    if marker.contains(|c| c == '\n' || c == '\r') {
+        bail!("marker {marker:?} must be a single line");
+    }
+    if marker.trim_end() != marker {
+        bail!("marker {marker:?} must not end with whitespace");
+    }
+    Ok(())
+}
+
+impl BlockMarkers {
+    /// Returns the markers that `control` asks for.
+    #[must_use]
+    pub fn of(control: &Control) -> Self {
+        let default = Self::default();
+        Self {
+            begin: control.begin_marker.clone().unwrap_or(default.begin),
+            end: control.end_marker.clone().unwrap_or(default.end),
+        }
+    }
+
+    /// Finds the managed block in the contents of an `authorized_keys` file.
+    ///
+    /// The returned range spans from the start of the [`begin`][Self::begin]
+    /// line to the end of the [`end`][Self::end] line, including its newline
+    /// if present.
+    ///
+    /// # Errors
+    /// The function will fail if the markers are unbalanced or repeated.
+    pub fn locate(&self, content: &str) -> Result<Option<Range<usize>>> {
+        let mut begin = None;
+        let mut result = None;
+        let mut offset = 0;
+
+        while offset < content.len() {
+            let start = offset;
+            let line = match content[start..].find('\n') {
+                Some(end) => &content[start..=start + end],
+                None => &content[start..],
+            };
+            offset += line.len();
+
+            let line = line.trim_end();
+            if line == self.begin {
+                if begin.is_some() || result.is_some() {
+                    bail!("multiple managed blocks found");
+                }
+                begin = Some(start);
+            } else if line == self.end {
+                let start = begin.take().ok_or_else(|| {
+                    anyhow!("{:?} without {:?}", self.end, self.begin)
+                })?;
+                result = Some(start..offset);
+            }
+        }
+
+        if begin.is_some() {
+            bail!("{:?} without {:?}", self.begin, self.end);
+        }
+
+        Ok(result)
+    }
+
+    /// Returns the [`block_hash`] recorded in the managed block of
+    /// `content`.
+    ///
+    /// Returns `None` if there is no managed block or it records no hash,
+    /// e.g. because it was written by an older version of narrowssh.
+    ///
+    /// # Errors
+    /// The function will fail if [`locate`][Self::locate] complains.
+    pub fn read_hash(&self, content: &str) -> Result<Option<String>> {
+        let range = match self.locate(content)? {
+            Some(range) => range,
+            None => return Ok(None),
+        };
+
+        let line =
+            content[range].lines().nth(1).unwrap_or_default().trim_end();
+        if !line.starts_with(HASH_PREFIX) {
+            return Ok(None);
+        }
+
+        Ok(Some(line[HASH_PREFIX.len()..].to_owned()))
+    }
+
+    /// Returns `content` with its managed block replaced by `block`.
+    ///
+    /// An existing block is replaced in place, keeping its
+    /// [`ENDING_PREFIX`] line, if any. Otherwise `block` is appended in
+    /// canonical form: the line breaks at the end of `content` are replaced
+    /// by a single newline and a blank separator line, so files ending
+    /// in zero, one or more newlines converge, and the result ends with
+    /// exactly one newline. Unless `content` ended with exactly one newline,
+    /// the block records the original ending in an [`ENDING_PREFIX`] line so
+    /// that [`remove`][Self::remove] can restore `content` exactly. All
+    /// other bytes, including line endings and blank lines elsewhere, are
+    /// preserved, so replacing the block again changes nothing.
+    ///
+    /// # Errors
+    /// The function will fail if [`locate`][Self::locate] complains.
+    pub fn replace(&self, content: &str, block: &str) -> Result<String> {
+        let (block, _) = self.split_ending(block);
+
+        if let Some(range) = self.locate(content)? {
+            let (_, ending) = self.split_ending(&content[range.clone()]);
+            let mut result = String::with_capacity(content.len());
+            result.push_str(&content[..range.start]);
+            result.push_str(&with_ending(&block, ending.as_deref()));
+            result.push_str(&content[range.end..]);
+            return Ok(result);
+        }
+
+        if content.is_empty() {
+            return Ok(block);
+        }
+
+        let kept = content.trim_end_matches(|c| c == '\n' || c == '\r');
+        let ending = &content[kept.len()..];
+
+        let mut result = String::from(kept);
+        if !kept.is_empty() {
+            result.push('\n');
+            result.push_str(SEPARATOR);
+        }
+        let record = kept.is_empty() || ending != "\n";
+        result
+            .push_str(&with_ending(&block, Some(ending).filter(|_| record)));
+        Ok(result)
+    }
+
+    /// Returns `content` with its managed block removed.
+    ///
+    /// Only the block and the blank separator line right before it, as
+    /// inserted by [`replace`][Self::replace], are removed. If the block has
+    /// an [`ENDING_PREFIX`] line, the recorded ending replaces the newline
+    /// and the separator that `replace` added. Every other byte is left
+    /// untouched. Returns `None` if `content` has no managed block.
+    ///
+    /// # Errors
+    /// The function will fail if [`locate`][Self::locate] complains.
+    pub fn remove(&self, content: &str) -> Result<Option<String>> {
+        let range = match self.locate(content)? {
+            Some(range) => range,
+            None => return Ok(None),
+        };
+
+        let (_, ending) = self.split_ending(&content[range.clone()]);
+        let mut before = &content[..range.start];
+        let appended = format!("\n{SEPARATOR}");
+        let ending = match ending {
+            Some(ending) if before.is_empty() => ending,
+            Some(ending) if before.ends_with(&appended) => {
+                before = &before[..before.len() - appended.len()];
+                ending
+            }
+            _ => {
+                if before == SEPARATOR || before.ends_with(&appended) {
+                    before = &before[..before.len() - SEPARATOR.len()];
+                }
+                String::new()
+            }
+        };
+
+        let mut result = String::with_capacity(content.len());
+        result.push_str(before);
+        result.push_str(&ending);
+        result.push_str(&content[range.end..]);
+        Ok(Some(result))
+    }
+
+    /// Splits the [`ENDING_PREFIX`] line off `block`.
+    ///
+    /// Returns `block` without that line and the recorded ending, if any.
+    fn split_ending(&self, block: &str) -> (String, Option<String>) {
+        let end = line_start(block.trim_end_matches('\n'));
+        if end == 0 || block[end..].trim_end() != self.end {
+            return (block.to_owned(), None);
+        }
+
+        let start = line_start(&block[..end - 1]);
+        match parse_ending(block[start..end].trim_end()) {
+            Some(ending) => (
+                format!("{}{}", &block[..start], &block[end..]),
+                Some(ending),
+            ),
+            None => (block.to_owned(), None),
+        }
+    }
+}
+
+/// Blank line that [`BlockMarkers::replace`] inserts before an appended
+/// managed block.
+const SEPARATOR: &str = "\n";
+
+/// Returns `block` with an [`ENDING_PREFIX`] line recording `ending`
+/// before its closing marker, or `block` itself if `ending` is `None`.
+fn with_ending(block: &str, ending: Option<&str>) -> String {
+    let ending = match ending {
+        Some(ending) => ending,
+        None => return block.to_owned(),
+    };
+
+    let end = line_start(block.trim_end_matches('\n'));
+    format!(
+        "{}{ENDING_PREFIX}{}\n{}",
+        &block[..end],
+        escape_ending(ending),
+        &block[end..]
+    )
+}
+
+/// Returns the offset of the last line of `s`.
+fn line_start(s: &str) -> usize {
+    s.rfind('\n').map_or(0, |index| index + 1)
+}
+
+/// Encodes `ending`, which only consists of line breaks, for an
+/// [`ENDING_PREFIX`] line as a quoted string with `\r` and `\n` escapes.
+fn escape_ending(ending: &str) -> String {
+    let mut result = String::from("\"");
+    for c in ending.chars() {
+        result.push_str(match c {
+            '\r' => "\\r",
+            _ => "\\n",
+        });
+    }
+    result.push('"');
+    result
+}
+
+/// Decodes an [`ENDING_PREFIX`] line written by [`escape_ending`].
+///
+/// Returns `None` if `line` is not such a line.
+fn parse_ending(line: &str) -> Option<String> {
+    if !line.starts_with(ENDING_PREFIX) {
+        return None;
+    }
+    let quoted = &line[ENDING_PREFIX.len()..];
+    if quoted.len() < 2 || !quoted.starts_with('"') || !quoted.ends_with('"')
+    {
+        return None;
+    }
+
+    let mut result = String::new();
+    let mut chars = quoted[1..quoted.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.next()) {
+            ('\\', Some('n')) => result.push('\n'),
+            ('\\', Some('r')) => result.push('\r'),
+            _ => return None,
+        }
+    }
+    Some(result)
+}
+
+/// Finds the managed block with the default markers, see
+/// [`BlockMarkers::locate`].
+///
+/// # Errors
+/// The function will fail if the markers are unbalanced or repeated.
+pub fn locate_managed_block(content: &str) -> Result<Option<Range<usize>>> {
+    BlockMarkers::default().locate(content)
+}
+
+/// Returns the hash recorded in the managed block with the default markers,
+/// see [`BlockMarkers::read_hash`].
+///
+/// # Errors
+/// The function will fail if [`locate_managed_block`] complains.
+pub fn read_block_hash(content: &str) -> Result<Option<String>> {
+    BlockMarkers::default().read_hash(content)
+}
+
+/// Replaces the managed block with the default markers, see
+/// [`BlockMarkers::replace`].
+///
+/// # Errors
+/// The function will fail if [`locate_managed_block`] complains.
+pub fn replace_managed_block(content: &str, block: &str) -> Result<String> {
+    BlockMarkers::default().replace(content, block)
+}
+
+/// Removes the managed block with the default markers, see
+/// [`BlockMarkers::remove`].
+///
+/// # Errors
+/// The function will fail if [`locate_managed_block`] complains.
+pub fn remove_managed_block(content: &str) -> Result<Option<String>> {
+    BlockMarkers::default().remove(content)
+}
@@ -0,0 +1,778 @@
+#![allow(clippy::needless_raw_string_hashes)]
+
+pub use super::*;
+
+const BLOB: &str =
+    "AAAAC3NzaC1lZDI1NTE5AAAAIGd1bW15a2V5Zm9ydGVzdGluZ3B1cnBvc2Vz";
+
+const OTHER_BLOB: &str =
+    "AAAAC3NzaC1lZDI1NTE5AAAAIG90aGVya2V5Zm9ydGVzdGluZ3B1cnBvc2Vz";
+
+/// Constructs a [`Control`] allowing `commands`.
+fn control(commands: &[&str], command_conflict: CommandConflict) -> Control {
+    Control {
+        enable: true,
+        config: String::from("~/.narrowssh.conf"),
+        authorized_keys: String::from("~/.ssh/authorized_keys"),
+        commands: commands.iter().map(|&c| String::from(c)).collect(),
+        command_conflict,
+        ..Control::default()
+    }
+}
+
+/// Tests for [`KeyLine::parse`]
+mod parse {
+    use super::*;
+
+    #[test]
+    fn plain() -> Result<()> {
+        let key = KeyLine::parse(&format!("ssh-ed25519 {BLOB} alice@host"))?;
+
+        assert!(key.options.is_empty());
+        assert_eq!(key.key_type, "ssh-ed25519");
+        assert_eq!(key.blob, BLOB);
+        assert_eq!(key.comment.as_deref(), Some("alice@host"));
+        Ok(())
+    }
+
+    #[test]
+    fn no_comment() -> Result<()> {
+        let key = KeyLine::parse(&format!("  ssh-ed25519 {BLOB}  "))?;
+
+        assert_eq!(key.comment, None);
+        Ok(())
+    }
+
+    #[test]
+    fn options() -> Result<()> {
+        let key = KeyLine::parse(&format!(
+            r#"no-pty,command="echo \"a, b\" c",from="10.0.0.0/8" ssh-ed25519 {BLOB} my key"#
+        ))?;
+
+        assert_eq!(key.options.len(), 3);
+        assert_eq!(key.options[0].name, "no-pty");
+        assert_eq!(key.options[0].value, None);
+        assert_eq!(
+            key.option("command").and_then(|o| o.value.as_deref()),
+            Some(r#"echo "a, b" c"#)
+        );
+        assert_eq!(
+            key.option("from").unwrap().value.as_deref(),
+            Some("10.0.0.0/8")
+        );
+        assert_eq!(key.comment.as_deref(), Some("my key"));
+        Ok(())
+    }
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let line =
+            format!(r#"restrict,command="echo \"hi\"" ssh-ed25519 {BLOB} c"#);
+        assert_eq!(KeyLine::parse(&line)?.to_string(), line);
+        Ok(())
+    }
+
+    #[test]
+    fn markers() -> Result<()> {
+        let key = KeyLine::parse(&format!(
+            r#"@cert-authority from="*.example.com" ssh-ed25519 {BLOB} CA"#
+        ))?;
+        assert_eq!(key.marker, Some(Marker::CertAuthority));
+        assert_eq!(
+            key.option("from").unwrap().value.as_deref(),
+            Some("*.example.com")
+        );
+        assert_eq!(key.key_type, "ssh-ed25519");
+
+        let key = KeyLine::parse(&format!("@REVOKED ssh-ed25519 {BLOB}"))?;
+        assert_eq!(key.marker, Some(Marker::Revoked));
+        assert!(key.options.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn markers_round_trip() -> Result<()> {
+        for line in [
+            format!("@cert-authority ssh-ed25519 {BLOB} CA"),
+            format!(
+                r#"@cert-authority restrict,principals="a" ssh-ed25519 {BLOB}"#
+            ),
+            format!("@revoked ssh-ed25519 {BLOB} old"),
+        ] {
+            assert_eq!(KeyLine::parse(&line)?.to_string(), line);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_type() -> Result<()> {
+        let key = KeyLine::parse(&format!("ssh-future {BLOB} alice@host"))?;
+        assert!(key.options.is_empty());
+        assert_eq!(key.key_type, "ssh-future");
+        assert_eq!(key.blob, BLOB);
+
+        let key = KeyLine::parse(&format!("no-pty ssh-future {BLOB}"))?;
+        assert_eq!(key.options.len(), 1);
+        assert_eq!(key.key_type, "ssh-future");
+        Ok(())
+    }
+
+    #[test]
+    fn extra_type() -> Result<()> {
+        let extra = [String::from("restrict")];
+
+        let key = KeyLine::parse(&format!("restrict ssh-ed25519 {BLOB}"))?;
+        assert_eq!(key.key_type, "ssh-ed25519");
+
+        let key =
+            KeyLine::parse_with(&format!("restrict {BLOB} odd"), &extra)?;
+        assert!(key.options.is_empty());
+        assert_eq!(key.key_type, "restrict");
+        Ok(())
+    }
+
+    #[test]
+    fn malformed() {
+        for line in [
+            "",
+            "ssh-ed25519",
+            "ssh-ed25519 not*base64",
+            &format!(r#"command="unterminated ssh-ed25519 {BLOB}"#),
+            &format!(r#"command=unquoted ssh-ed25519 {BLOB}"#),
+            &format!(r#"no-pty,,no-pty ssh-ed25519 {BLOB}"#),
+            &format!(r#"@unknown ssh-ed25519 {BLOB}"#),
+            "@cert-authority",
+        ] {
+            assert!(KeyLine::parse(line).is_err(), "accepted {line:?}");
+        }
+    }
+
+    #[test]
+    fn control_characters() {
+        let evil = format!("ssh-ed25519 {OTHER_BLOB} evil");
+        for line in [
+            format!("ssh-ed25519 {BLOB} me\n{evil}"),
+            format!("ssh-ed25519 {BLOB} me\r{evil}"),
+            format!("ssh-ed25519 {BLOB} me\u{1b}[2K"),
+            format!("from=\"1.2.3.4\n{evil}\" ssh-ed25519 {BLOB}"),
+            format!("from=\"1.2.3.4\r\n{evil}\" ssh-ed25519 {BLOB}"),
+            format!("no-pty\u{7f} ssh-ed25519 {BLOB}"),
+            format!("ssh-ed25519\u{0} {BLOB}"),
+        ] {
+            let error = KeyLine::parse(&line).unwrap_err();
+            assert!(
+                error.to_string().contains("control character"),
+                "{line:?}: {error}"
+            );
+        }
+    }
+}
+
+/// Tests for [`render_managed_block`]
+mod render {
+    use super::*;
+
+    #[test]
+    fn injection() {
+        let control = control(&["backup"], CommandConflict::Error);
+        let evil = format!("ssh-ed25519 {OTHER_BLOB} evil");
+        for key in [
+            format!("ssh-ed25519 {BLOB} me\n{evil}"),
+            format!("from=\"1.2.3.4\n{evil}\" ssh-ed25519 {BLOB}"),
+        ] {
+            assert!(render_managed_block(&[key], &control).is_err());
+        }
+
+        let keys = [format!("ssh-ed25519 {BLOB} me")];
+        for command in ["backup\nssh-ed25519 AAAA evil", "backup\r", "a\tb"] {
+            let control = super::control(&[command], CommandConflict::Error);
+            let error = render_managed_block(&keys, &control).unwrap_err();
+            assert!(
+                error.to_string().contains("control character"),
+                "{command:?}: {error}"
+            );
+        }
+    }
+
+    #[test]
+    fn basic() -> Result<()> {
+        let control = control(&["rsync --server *"], CommandConflict::Error);
+        let keys = [format!("from=\"10.0.0.1\",pty ssh-ed25519 {BLOB} k1")];
+
+        let block = render_managed_block(&keys, &control)?;
+
+        assert_eq!(
+            block.text,
+            format!(
+                "{BEGIN_MARKER}\n{HASH_PREFIX}{}\n\
+                restrict,command=\"{EXEC_COMMAND} 'rsync --server *'\",\
+                from=\"10.0.0.1\" ssh-ed25519 {BLOB} k1\n\
+                {END_MARKER}\n",
+                block.hash
+            )
+        );
+        assert!(block.warnings.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn markers() -> Result<()> {
+        let control = control(&["backup"], CommandConflict::Error);
+        let keys = [
+            format!("@cert-authority ssh-ed25519 {BLOB} CA"),
+            format!("@revoked no-pty ssh-ed25519 {BLOB} old"),
+        ];
+
+        let block = render_managed_block(&keys, &control)?;
+
+        assert_eq!(
+            block.text,
+            format!(
+                "{BEGIN_MARKER}\n{HASH_PREFIX}{}\n\
+                @cert-authority restrict,command=\"{EXEC_COMMAND} 'backup'\" \
+                ssh-ed25519 {BLOB} CA\n\
+                @revoked ssh-ed25519 {BLOB} old\n\
+                {END_MARKER}\n",
+                block.hash
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn quoting() {
+        let control =
+            control(&["echo 'it' \"works\""], CommandConflict::Error);
+
+        assert_eq!(
+            forced_command(&control),
+            format!(r#"{EXEC_COMMAND} 'echo '\''it'\'' "works"'"#)
+        );
+    }
+
+    /// Renders a block containing a key that forces its own command.
+    fn render_conflicting(policy: CommandConflict) -> Result<ManagedBlock> {
+        let control = control(&["backup"], policy);
+        let keys = [
+            format!("command=\"rm -rf /\" ssh-ed25519 {BLOB} evil"),
+            format!("ssh-ed25519 {OTHER_BLOB} good"),
+        ];
+        render_managed_block(&keys, &control)
+    }
+
+    #[test]
+    fn conflict_error() {
+        let error = render_conflicting(CommandConflict::Error).unwrap_err();
+        assert!(error.to_string().contains("evil"));
+    }
+
+    #[test]
+    fn conflict_skip() -> Result<()> {
+        let block = render_conflicting(CommandConflict::Skip)?;
+
+        assert!(!block.text.contains("evil"));
+        assert!(block.text.contains("good"));
+        assert!(!block.text.contains("rm -rf"));
+        assert_eq!(block.warnings.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn conflict_override() -> Result<()> {
+        let block = render_conflicting(CommandConflict::Override)?;
+
+        assert!(block.text.contains("evil"));
+        assert!(block.text.contains("good"));
+        assert!(!block.text.contains("rm -rf"));
+        assert_eq!(block.warnings.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn same_command_is_no_conflict() -> Result<()> {
+        let control = control(&["backup"], CommandConflict::Error);
+        let keys = [format!(
+            "command=\"{}\" ssh-ed25519 {BLOB} k1",
+            forced_command(&control).replace('"', "\\\"")
+        )];
+
+        let block = render_managed_block(&keys, &control)?;
+
+        assert!(block.text.contains("k1"));
+        assert!(block.warnings.is_empty());
+        Ok(())
+    }
+
+    /// Renders a block with a command long enough to exceed 8 KiB.
+    fn render_long(policy: LongLine) -> Result<ManagedBlock> {
+        let long = "x".repeat(9000);
+        let mut control = control(&["backup", &long], CommandConflict::Error);
+        control.long_line = policy;
+        render_managed_block(&[format!("ssh-ed25519 {BLOB} k1")], &control)
+    }
+
+    #[test]
+    fn long_line_warn() -> Result<()> {
+        let block = render_long(LongLine::Warn)?;
+
+        assert!(block.text.contains("k1"));
+        assert_eq!(block.warnings.len(), 1);
+        assert!(block.warnings[0].contains("k1"));
+        assert!(block.warnings[0].contains("8192"));
+        assert!(block.warnings[0].contains("'backup'"));
+        Ok(())
+    }
+
+    #[test]
+    fn long_line_error() {
+        let error = render_long(LongLine::Error).unwrap_err();
+        assert!(error.to_string().contains("k1"));
+    }
+
+    /// Renders a block of `count` distinct keys and a revoked key, with at
+    /// most `max` keys handled according to `policy`.
+    fn render_many(
+        count: usize,
+        max: usize,
+        policy: TooManyKeys,
+    ) -> Result<ManagedBlock> {
+        let mut control = control(&["backup"], CommandConflict::Error);
+        control.max_keys = Some(max);
+        control.too_many_keys = policy;
+
+        let mut keys = vec![format!("@revoked ssh-ed25519 {OTHER_BLOB} old")];
+        keys.extend(
+            (0..count).map(|i| format!("ssh-ed25519 {BLOB}{i} k{i}")),
+        );
+        render_managed_block(&keys, &control)
+    }
+
+    #[test]
+    fn max_keys_boundary() -> Result<()> {
+        for policy in &[TooManyKeys::Error, TooManyKeys::Truncate] {
+            let block = render_many(3, 3, *policy)?;
+            assert!(block.text.contains("k2"));
+            assert!(block.text.contains("old"));
+            assert!(block.warnings.is_empty(), "{:?}", block.warnings);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn max_keys_error() {
+        let error = render_many(4, 3, TooManyKeys::Error).unwrap_err();
+        let message = error.to_string();
+        assert!(message.contains("4 keys"), "{message}");
+        assert!(message.contains("limit of 3 keys"), "{message}");
+    }
+
+    #[test]
+    fn max_keys_truncate() -> Result<()> {
+        let block = render_many(5, 3, TooManyKeys::Truncate)?;
+
+        assert!(block.text.contains("k2"));
+        assert!(!block.text.contains("k3"));
+        assert!(!block.text.contains("k4"));
+        assert!(block.text.contains("old"));
+        assert_eq!(block.warnings.len(), 1);
+        assert!(
+            block.warnings[0].contains("last 2 keys"),
+            "{:?}",
+            block.warnings
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn max_keys_unset() -> Result<()> {
+        let control = control(&["backup"], CommandConflict::Error);
+        let keys: Vec<_> = (0..100)
+            .map(|i| format!("ssh-ed25519 {BLOB}{i} k{i}"))
+            .collect();
+
+        let block = render_managed_block(&keys, &control)?;
+        assert!(block.text.contains("k99"));
+        assert!(block.warnings.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn duplicates() -> Result<()> {
+        let control = control(&["backup"], CommandConflict::Error);
+        let keys = [
+            format!("ssh-ed25519 {BLOB} first"),
+            format!("from=\"10.0.0.1\" ssh-ed25519 {BLOB} second"),
+            format!("ssh-ed25519 {BLOB}"),
+        ];
+
+        let block = render_managed_block(&keys, &control)?;
+
+        assert_eq!(
+            block.text,
+            format!(
+                "{BEGIN_MARKER}\n{HASH_PREFIX}{}\n\
+                restrict,command=\"{EXEC_COMMAND} 'backup'\" \
+                ssh-ed25519 {BLOB} first\n\
+                {END_MARKER}\n",
+                block.hash
+            )
+        );
+        assert_eq!(block.warnings.len(), 2);
+        assert!(block.warnings[0].contains("duplicates collapsed"));
+        assert_eq!(block.keys(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn duplicate_of_skipped() -> Result<()> {
+        let control = control(&["backup"], CommandConflict::Skip);
+        let keys = [
+            format!("command=\"ls\" ssh-ed25519 {BLOB} skipped"),
+            format!("ssh-ed25519 {BLOB} kept"),
+        ];
+
+        let block = render_managed_block(&keys, &control)?;
+
+        assert!(block.text.contains("kept"));
+        assert_eq!(block.warnings.len(), 1);
+        assert_eq!(block.keys(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn same_key_different_marker() -> Result<()> {
+        let control = control(&["backup"], CommandConflict::Error);
+        let keys = [
+            format!("ssh-ed25519 {BLOB} k1"),
+            format!("@revoked ssh-ed25519 {BLOB} k1"),
+        ];
+
+        let block = render_managed_block(&keys, &control)?;
+
+        assert_eq!(block.text.lines().count(), 5);
+        assert!(block.warnings.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_type() -> Result<()> {
+        let mut control = control(&["backup"], CommandConflict::Error);
+        let keys = [format!("ssh-future {BLOB} k1")];
+
+        let block = render_managed_block(&keys, &control)?;
+        assert!(block.text.contains(&format!(" ssh-future {BLOB} k1\n")));
+        assert_eq!(block.warnings.len(), 1);
+        assert!(block.warnings[0].contains("unrecognized type"));
+
+        control.extra_key_types = vec![String::from("ssh-future")];
+        let block = render_managed_block(&keys, &control)?;
+        assert!(block.text.contains(&format!(" ssh-future {BLOB} k1\n")));
+        assert!(block.warnings.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn long_line_custom_limit() -> Result<()> {
+        let mut control = control(&["backup"], CommandConflict::Error);
+        let keys = [format!("ssh-ed25519 {BLOB} k1")];
+
+        control.max_line_length = 100;
+        assert_eq!(render_managed_block(&keys, &control)?.warnings.len(), 1);
+
+        control.max_line_length = 1000;
+        assert!(render_managed_block(&keys, &control)?.warnings.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn permissions() -> Result<()> {
+        let keys = [format!("no-pty ssh-ed25519 {BLOB} k1")];
+
+        for bits in 0..16 {
+            let mut control = control(&["backup"], CommandConflict::Error);
+            control.port_forwarding = bits & 1 != 0;
+            control.x11_forwarding = bits & 2 != 0;
+            control.agent_forwarding = bits & 4 != 0;
+            control.pty = bits & 8 != 0;
+
+            let mut expected =
+                format!("restrict,command=\"{EXEC_COMMAND} 'backup'\"");
+            for (bit, option) in &[
+                (1, "permit-port-forwarding"),
+                (2, "permit-X11-forwarding"),
+                (4, "permit-agent-forwarding"),
+                (8, "pty"),
+            ] {
+                if bits & bit != 0 {
+                    expected.push(',');
+                    expected.push_str(option);
+                }
+            }
+            expected = format!("{expected} ssh-ed25519 {BLOB} k1\n");
+
+            let block = render_managed_block(&keys, &control)?;
+            assert!(block.text.contains(&expected), "{bits}: {}", block.text);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn deny() -> Result<()> {
+        let mut control = control(&["backup"], CommandConflict::Error);
+        control.action = KeysAction::Deny;
+        control.pty = true;
+        let keys = [
+            format!("ssh-ed25519 {BLOB} k1"),
+            format!("command=\"sh\",from=\"10.0.0.1\" ssh-ed25519 {OTHER_BLOB} k2"),
+        ];
+
+        let block = render_managed_block(&keys, &control)?;
+
+        assert_eq!(
+            block.text,
+            format!(
+                "{BEGIN_MARKER}\n{HASH_PREFIX}{}\n\
+                restrict,command=\"{DENY_COMMAND}\" ssh-ed25519 {BLOB} k1\n\
+                restrict,command=\"{DENY_COMMAND}\",from=\"10.0.0.1\" \
+                ssh-ed25519 {OTHER_BLOB} k2\n\
+                {END_MARKER}\n",
+                block.hash
+            )
+        );
+        assert!(block.warnings.is_empty());
+
+        let installed = replace_managed_block("mine\n", &block.text)?;
+        assert_eq!(
+            remove_managed_block(&installed)?.as_deref(),
+            Some("mine\n")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn permissions_change_hash() -> Result<()> {
+        let mut control = control(&["backup"], CommandConflict::Error);
+        let keys = [format!("ssh-ed25519 {BLOB} k1")];
+        let before = render_managed_block(&keys, &control)?.hash;
+
+        control.pty = true;
+        assert_ne!(render_managed_block(&keys, &control)?.hash, before);
+        Ok(())
+    }
+}
+
+/// Tests for [`replace_managed_block`]
+mod replace {
+    use super::*;
+
+    const BLOCK: &str = "# BEGIN narrowssh\nnew\n# END narrowssh\n";
+
+    #[test]
+    fn empty_file() -> Result<()> {
+        assert_eq!(replace_managed_block("", BLOCK)?, BLOCK);
+        Ok(())
+    }
+
+    #[test]
+    fn append() -> Result<()> {
+        assert_eq!(
+            replace_managed_block("mine\n", BLOCK)?,
+            format!("mine\n\n{BLOCK}")
+        );
+        assert_eq!(
+            replace_managed_block("mine", BLOCK)?,
+            "mine\n\n# BEGIN narrowssh\nnew\n\
+            # narrowssh ending=\"\"\n# END narrowssh\n"
+        );
+
+        // Replacing the block again keeps the recorded ending
+        let installed = replace_managed_block("mine", BLOCK)?;
+        let other = BLOCK.replace("new", "other");
+        let replaced = replace_managed_block(&installed, &other)?;
+        assert_eq!(replaced, installed.replace("new", "other"));
+        assert_eq!(replace_managed_block(&replaced, &other)?, replaced);
+        Ok(())
+    }
+
+    #[test]
+    fn replace() -> Result<()> {
+        let old = "a\n# BEGIN narrowssh\nold\n# END narrowssh\nb\n";
+        assert_eq!(
+            replace_managed_block(old, BLOCK)?,
+            format!("a\n{BLOCK}b\n")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn trailing_newlines() -> Result<()> {
+        for content in ["mine", "mine\n", "mine\n\n", "mine\r\n"] {
+            let installed = replace_managed_block(content, BLOCK)?;
+            assert!(installed.starts_with("mine\n\n# BEGIN narrowssh\nnew\n"));
+            assert!(installed.ends_with("\n# END narrowssh\n"));
+            assert_eq!(replace_managed_block(&installed, BLOCK)?, installed);
+        }
+        assert_eq!(
+            replace_managed_block("mine\n", BLOCK)?,
+            format!("mine\n\n{BLOCK}")
+        );
+        for content in ["\n", "\n\n"] {
+            let installed = replace_managed_block(content, BLOCK)?;
+            assert!(installed.starts_with("# BEGIN narrowssh\n"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn blank_lines_around_block() -> Result<()> {
+        let old =
+            "a\r\n\r\n\n# BEGIN narrowssh\r\nold\r\n# END narrowssh\r\n\n\nb";
+        let expected = format!("a\r\n\r\n\n{BLOCK}\n\nb");
+        assert_eq!(replace_managed_block(old, BLOCK)?, expected);
+        assert_eq!(replace_managed_block(&expected, BLOCK)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn unbalanced() {
+        for content in [
+            "# BEGIN narrowssh\nold\n",
+            "old\n# END narrowssh\n",
+            "# BEGIN narrowssh\n# END narrowssh\n# BEGIN narrowssh\n# END narrowssh\n",
+        ] {
+            assert!(replace_managed_block(content, BLOCK).is_err());
+        }
+    }
+}
+
+/// Tests for [`remove_managed_block`]
+mod remove {
+    use super::*;
+
+    const BLOCK: &str = "# BEGIN narrowssh\nold\n# END narrowssh\n";
+
+    #[test]
+    fn no_block() -> Result<()> {
+        assert_eq!(remove_managed_block("mine\n")?, None);
+        assert_eq!(remove_managed_block("")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn undoes_replace() -> Result<()> {
+        for content in
+            ["", "mine\n", "mine", "mine\n\n", "a\r\nb", "\n", "\n\n"]
+        {
+            let installed = replace_managed_block(content, BLOCK)?;
+            assert_eq!(
+                remove_managed_block(&installed)?.as_deref(),
+                Some(content)
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_surroundings() -> Result<()> {
+        for (content, expected) in [
+            (format!("a\n{BLOCK}b\n"), "a\nb\n"),
+            (format!("a\n\n{BLOCK}\nb\n\n"), "a\n\nb\n\n"),
+            (format!("\n{BLOCK}"), ""),
+        ] {
+            assert_eq!(
+                remove_managed_block(&content)?.as_deref(),
+                Some(expected),
+                "{content:?}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn byte_exact() -> Result<()> {
+        let head = "a\r\n\r\n\r\nb\r\n";
+        let tail = "c\r\n\r\n\r\nd";
+
+        let installed = replace_managed_block(head, BLOCK)?;
+        assert!(installed.starts_with("a\r\n\r\n\r\nb\n\n# BEGIN"));
+        let content = format!("{installed}{tail}");
+        assert_eq!(
+            remove_managed_block(&content)?.as_deref(),
+            Some(format!("{head}{tail}").as_str())
+        );
+
+        // Blank lines that replace did not insert are kept
+        let content = format!("{head}\r\n{BLOCK}{tail}");
+        assert_eq!(
+            remove_managed_block(&content)?.as_deref(),
+            Some(format!("{head}\r\n{tail}").as_str())
+        );
+        Ok(())
+    }
+}
+
+/// Tests for [`block_hash`] and [`read_block_hash`]
+mod hash {
+    use super::*;
+
+    #[test]
+    fn embedded() -> Result<()> {
+        let control = control(&["backup"], CommandConflict::Error);
+        let keys = [format!("ssh-ed25519 {BLOB} k1")];
+
+        let block = render_managed_block(&keys, &control)?;
+        let content = replace_managed_block("mine\n", &block.text)?;
+
+        let lines: Vec<_> = block.text.lines().collect();
+        let key_lines = format!("{}\n", lines[2..lines.len() - 1].join("\n"));
+        assert_eq!(block.hash, block_hash(&key_lines));
+        assert_eq!(block.hash.len(), 16);
+        assert_eq!(read_block_hash(&content)?, Some(block.hash));
+        Ok(())
+    }
+
+    #[test]
+    fn changes_with_inputs() -> Result<()> {
+        let keys = [format!("ssh-ed25519 {BLOB} k1")];
+        let backup = control(&["backup"], CommandConflict::Error);
+        let restore = control(&["restore"], CommandConflict::Error);
+
+        let hash = render_managed_block(&keys, &backup)?.hash;
+
+        assert_eq!(hash, render_managed_block(&keys, &backup)?.hash);
+        assert_ne!(hash, render_managed_block(&keys, &restore)?.hash);
+        assert_ne!(hash, render_managed_block(&keys[..0], &backup)?.hash);
+        Ok(())
+    }
+
+    #[test]
+    fn ignores_other_settings() -> Result<()> {
+        let keys = [format!("ssh-ed25519 {BLOB} k1")];
+        let control = control(&["backup"], CommandConflict::Error);
+        let hash = render_managed_block(&keys, &control)?.hash;
+
+        let other = Control {
+            authorized_keys_mode: Some(0o640),
+            authorized_keys_gid: Some(0),
+            too_many_keys: TooManyKeys::Error,
+            validate_sshd: true,
+            sshd_path: Some(String::from("/usr/sbin/sshd")),
+            history_keep: Some(3),
+            strict_user_config: true,
+            ..control
+        };
+        assert_eq!(render_managed_block(&keys, &other)?.hash, hash);
+        Ok(())
+    }
+
+    #[test]
+    fn missing() -> Result<()> {
+        assert_eq!(read_block_hash("mine\n")?, None);
+        assert_eq!(
+            read_block_hash("# BEGIN narrowssh\nkey\n# END narrowssh\n")?,
+            None
+        );
+        assert!(read_block_hash("# BEGIN narrowssh\n").is_err());
+        Ok(())
+    }
+}
@@ -3,53 +3,546 @@
 #![warn(clippy::style)]
 #![warn(clippy::pedantic)]
 
-use anyhow::{anyhow, bail, Result};
-use clap::{Parser, Subcommand};
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, UNIX_EPOCH};
 
-use narrowssh::config::ControlManager;
-use narrowssh::workspace::Workspace;
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+
+use narrowssh::allowlist::{
+    authorize, command_allowed, ORIGINAL_COMMAND_VAR,
+};
+use narrowssh::audit::{emit_all, events, Sink};
+use narrowssh::color::{is_terminal, Palette, Style};
+use narrowssh::config::{
+    AmbiguousNames, ControlManager, KeysAction, VisitOptions,
+};
+use narrowssh::explain::{control_status, explain_control};
+use narrowssh::export::export_control;
+use narrowssh::info::Info;
+use narrowssh::policy::Uninstall;
+use narrowssh::refresh::{
+    apply_batch, check_user, plan_entries, plan_prune, plan_users_with,
+    rollback_user, ApplyReport, Batch, Outcome, UserPlan,
+};
+use narrowssh::selection::{
+    coverage_warnings, every_user, resolve_users, users_from_list,
+    UserSelector,
+};
+use narrowssh::selftest::{selftest, Check};
+use narrowssh::sshd::{sshd_to_validate, validate_sshd};
+use narrowssh::status::{
+    control_age_warning, open_output, parse_age, user_statuses,
+    users_changed_since, write_jsonl, UserStatus,
+};
+use narrowssh::workspace::{RealWorkspace, Workspace};
+use uzers::User;
 
 /// Manage allowlisted SSH commands for one or more users.
 #[derive(Parser)]
 #[command(author, version, about, long_about)]
+#[allow(clippy::struct_excessive_bools)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
 
-    /// Affect user with given username instead of running user.
+    /// Affect given users instead of running user.
+    ///
+    /// Accepts a username, '#UID', '@GROUP' or a UID range 'LO-HI'.
     ///
-    /// Incompatible with --uid and --all-users.
+    /// Incompatible with --all-users. May be combined with --uid if it names
+    /// a single user, which must then have that UID.
     #[arg(short, long)]
-    user: Option<String>,
+    user: Option<UserSelector>,
 
     /// Affect user with given user ID instead of running user.
     ///
-    /// Incompatible with --user and --all-users.
+    /// Incompatible with --all-users. With --user, only checks that the
+    /// named user has this UID.
     #[arg(long)]
     uid: Option<u32>,
 
     /// Affect all users according to the control file.
     ///
+    /// Users disabled in control are left out, except for the uninstall
+    /// command, which selects every system user.
+    ///
     /// Incompatible with --user and --uid.
     #[arg(short, long)]
     all_users: bool,
+
+    /// Let --all-users select the running user too.
+    ///
+    /// By default, the running user is left out of --all-users so that
+    /// operators do not lock themselves out by accident.
+    #[arg(long, requires = "all_users")]
+    include_self: bool,
+
+    /// Affect users listed in FILE that are enabled in control.
+    ///
+    /// Every line holds a username or a UID. Lines that name no user are
+    /// reported as warnings and skipped.
+    ///
+    /// Incompatible with --user, --uid and --all-users.
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["user", "uid", "all_users"])]
+    users_from: Option<PathBuf>,
+
+    /// Ignore the control extensions directory.
+    ///
+    /// The directory is not accessed at all, leaving the main control file as
+    /// the only source of control settings.
+    ///
+    /// Same as --no-extensions-for control.
+    #[arg(long)]
+    no_extensions: bool,
+
+    /// Ignore extensions directories of control or user configuration.
+    ///
+    /// May be given more than once. Extensions of control and of user
+    /// configuration are toggled independently:
+    ///
+    /// - not given: control and user extensions are read,
+    ///
+    /// - control: only user extensions are read,
+    ///
+    /// - user: only control extensions are read,
+    ///
+    /// - control and user: no extensions are read.
+    ///
+    /// Ignored directories are not accessed at all.
+    #[arg(long, value_enum, value_name = "LOADER")]
+    no_extensions_for: Vec<Loader>,
+
+    /// Let control sections name users without their realm.
+    ///
+    /// A section naming 'alice' applies to system user 'alice@REALM' or
+    /// 'REALM\alice' when no user is named exactly 'alice'.
+    #[arg(long)]
+    realm: Option<String>,
+
+    /// Skip control sections that name a username shared by several users.
+    ///
+    /// Such sections apply to none of these users and a warning is printed.
+    /// These users are then skipped. By default, control fails to load.
+    #[arg(long)]
+    skip_ambiguous_users: bool,
+
+    /// Redirect user configuration and `authorized_keys` files into DIR.
+    ///
+    /// Every such path is prefixed with DIR, so that `~/.ssh/authorized_keys`
+    /// of alice becomes `DIR/home/alice/.ssh/authorized_keys`. Control files
+    /// are still read from their usual location.
+    #[arg(long, value_name = "DIR")]
+    target_root: Option<PathBuf>,
+
+    /// Accept user configuration files owned by root.
+    ///
+    /// By default, user configuration files must be owned by their user.
+    /// Files owned by other users are always rejected.
+    #[arg(long)]
+    allow_root_owned_config: bool,
+
+    /// Also reject control and user configuration with the setuid, setgid or
+    /// sticky bit set.
+    ///
+    /// Applies to files and their '.d' directories. By default, only group
+    /// and other permissions are checked.
+    #[arg(long)]
+    strict_perms: bool,
+
+    /// Sync the directory of every replaced `authorized_keys` to disk.
+    ///
+    /// This makes changes survive a power loss right after narrowssh exits,
+    /// at the cost of a disk flush per user. By default, only files owned by
+    /// root are synced, see `authorized_keys_owner`.
+    #[arg(long)]
+    durable: bool,
+
+    /// Never sync directories of replaced `authorized_keys`.
+    ///
+    /// Overrides the default of syncing files owned by root.
+    #[arg(long, conflicts_with = "durable")]
+    no_durable: bool,
+
+    /// Write the report of the command to FILE instead of standard output.
+    ///
+    /// FILE is created with mode 0644, or truncated if it exists. Warnings
+    /// and errors still go to standard error.
+    #[arg(long, value_name = "FILE")]
+    output: Option<PathBuf>,
+
+    /// Color warnings and errors on standard error.
+    ///
+    /// With auto, colors are used if standard error is a terminal and the
+    /// `NO_COLOR` environment variable is not set to a non-empty value.
+    #[arg(long, value_enum, value_name = "WHEN", default_value = "auto")]
+    color: ColorWhen,
+
+    /// Never color warnings and errors.
+    ///
+    /// Same as --color never.
+    #[arg(long, conflicts_with = "color")]
+    no_color: bool,
+
+    /// Send a record of every change to the system log.
+    ///
+    /// Records are logged with facility authpriv and severity notice.
+    #[cfg(feature = "syslog")]
+    #[arg(long)]
+    syslog: bool,
+
+    /// Refuse control files not signed by a key listed in FILE.
+    ///
+    /// FILE is an allowed signers file of ssh-keygen(1) that lists keys
+    /// under the principal 'narrowssh'. Every control file, including
+    /// extensions, must come with a detached signature in a '.sig' file
+    /// next to it, made with 'ssh-keygen -Y sign -n narrowssh'.
+    #[cfg(feature = "signatures")]
+    #[arg(long, value_name = "FILE")]
+    control_signers: Option<PathBuf>,
+
+    /// Act as if narrowssh was run by the user with given UID.
+    ///
+    /// Only affects the selection of users; no privileges are changed. Meant
+    /// for testing and only available in debug builds.
+    #[cfg(debug_assertions)]
+    #[arg(long, hide = true, value_name = "UID")]
+    assume_uid: Option<u32>,
+
+    /// Read control from FILE instead of the usual location.
+    ///
+    /// FILE must pass the same checks as the usual control file. Meant for
+    /// testing and only available in debug builds.
+    #[cfg(debug_assertions)]
+    #[arg(long, hide = true, value_name = "FILE")]
+    control_file: Option<PathBuf>,
+}
+
+impl Cli {
+    /// Returns the options that control files are loaded with.
+    fn control_options(&self) -> VisitOptions {
+        VisitOptions {
+            extensions: self.control_extensions(),
+            realm: self.realm.clone(),
+            strict_perms: self.strict_perms,
+            ambiguous_names: if self.skip_ambiguous_users {
+                AmbiguousNames::Skip
+            } else {
+                AmbiguousNames::Error
+            },
+            #[cfg(feature = "signatures")]
+            signers: self.control_signers.clone(),
+            ..VisitOptions::default()
+        }
+    }
+
+    /// Returns the options that user configuration is loaded with.
+    fn user_options(&self) -> VisitOptions {
+        VisitOptions {
+            extensions: !self.no_extensions_for.contains(&Loader::User),
+            target_root: self.target_root.clone(),
+            allow_root_owner: self.allow_root_owned_config,
+            strict_perms: self.strict_perms,
+            durable: match (self.durable, self.no_durable) {
+                (true, _) => Some(true),
+                (_, true) => Some(false),
+                _ => None,
+            },
+            ..VisitOptions::default()
+        }
+    }
+
+    /// Returns the palette of diagnostics on standard error.
+    fn palette(&self) -> Palette {
+        match self.color {
+            _ if self.no_color => Palette::new(false),
+            ColorWhen::Auto => {
+                Palette::auto(is_terminal(libc::STDERR_FILENO))
+            }
+            ColorWhen::Always => Palette::new(true),
+            ColorWhen::Never => Palette::new(false),
+        }
+    }
+
+    /// Returns the main control file, which is [`MAIN_CONTROL_FILE`] except
+    /// with `--control-file` in debug builds.
+    fn control_file(&self) -> &Path {
+        #[cfg(debug_assertions)]
+        if let Some(file) = &self.control_file {
+            return file;
+        }
+        Path::new(MAIN_CONTROL_FILE)
+    }
+
+    /// Returns whether the control extensions directory should be read.
+    fn control_extensions(&self) -> bool {
+        !self.no_extensions
+            && !self.no_extensions_for.contains(&Loader::Control)
+    }
 }
 
 #[derive(Subcommand)]
 enum Commands {
     /// Install or update allowlisted SSH commands for one or all users.
-    Refresh,
+    ///
+    /// A summary of pending changes is printed first. Users disabled in
+    /// control are skipped; prune or uninstall removes their managed blocks.
+    Refresh {
+        /// Print the summary of pending changes without applying them.
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Make no changes at all if any user cannot be refreshed.
+        ///
+        /// By default, such users are reported and skipped.
+        #[arg(long)]
+        transactional: bool,
+
+        /// Format of the summary printed with --dry-run.
+        ///
+        /// With json, the plan of every user is printed as an array of
+        /// objects with fields uid, username, action (install, remove, skip
+        /// or fail), `target_path`, change (create, update, remove or noop) and
+        /// `diff_summary`. Users that are left alone also have a reason:
+        /// disabled, `no_home`, `insecure_config`, `ambiguous_name` or
+        /// failed.
+        #[arg(
+            long,
+            value_enum,
+            default_value = "text",
+            requires = "dry_run"
+        )]
+        format: PlanFormat,
+
+        /// Refuse to run unless control equals the staged control at PATH.
+        ///
+        /// Both controls are loaded the same way and compared by the
+        /// effective settings of the selected users. Every difference is
+        /// listed.
+        #[arg(long, value_name = "PATH")]
+        require_control_matches: Option<PathBuf>,
+    },
+
+    /// Validate control and user configuration without writing anything.
+    Check {
+        /// Describe how control settings of every user were determined.
+        ///
+        /// Only applies to text output.
+        #[arg(long)]
+        explain: bool,
+
+        /// Output format.
+        ///
+        /// With jsonl, one JSON object is printed per user as soon as the user
+        /// is checked, and checking continues past failed users. With toml,
+        /// the effective control of the users is printed and user
+        /// configuration is not read.
+        #[arg(long, value_enum, default_value = "text")]
+        format: Format,
+
+        /// Warn if no control file was modified within AGE.
+        ///
+        /// AGE is a whole number followed by s, m, h or d, e.g. 30d.
+        #[arg(long, value_name = "AGE", value_parser = parse_age)]
+        max_age: Option<Duration>,
+
+        /// Only check users whose `authorized_keys` was modified within AGE.
+        ///
+        /// Enabled users without `authorized_keys` are checked, too. This is a
+        /// cheap way to find drift in incremental runs; changes to control or
+        /// user configuration are not detected. AGE is written like for
+        /// --max-age.
+        #[arg(long, value_name = "AGE", value_parser = parse_age)]
+        changed_within: Option<Duration>,
+    },
+
+    /// Print the effective control of every selected user.
+    ///
+    /// Tells whether each user is enabled and where its configuration and
+    /// `authorized_keys` are, and whether each setting comes from the '*'
+    /// fallback, an override for the user or the built-in default. No user
+    /// files are read or written.
+    Status,
+
+    /// Compare control with the control file at PATH.
+    ///
+    /// PATH is loaded like control and the effective settings of the
+    /// selected users are compared. Every difference is printed with the
+    /// value in control and the value in PATH.
+    Diff {
+        /// Control file to compare with.
+        path: PathBuf,
+
+        /// Also list the users that PATH enables or disables.
+        ///
+        /// Every system user is considered, regardless of --user, --uid and
+        /// --all-users.
+        #[arg(long)]
+        show_impact: bool,
+    },
+
+    /// Remove managed blocks of users that are disabled in control.
+    ///
+    /// Every system user is inspected, regardless of --user, --uid and
+    /// --all-users, so that users dropped from control are found, too.
+    Prune {
+        /// Print the summary of pending changes without applying them.
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Print the managed block that Refresh would write, without writing.
+    DumpKeys,
+
+    /// Print a control file reconstructed from installed managed blocks.
+    ///
+    /// The forced commands and permitted forwardings of every selected user
+    /// are read back from `authorized_keys`; users without a managed block
+    /// are exported as disabled. This is a best-effort export: settings that
+    /// leave no trace on disk are not recovered, so review the result before
+    /// using it as control.
+    Export,
+
+    /// Tell whether USER may run COMMAND according to control.
+    ///
+    /// COMMAND is matched against the allowed commands of USER in order.
+    /// Every pattern must match the whole command; '*' matches any text and
+    /// '?' matches a single character, except that neither matches shell
+    /// metacharacters such as ';' or '|'. Patterns also match if they match
+    /// word by word once shell quotes are removed from both, where a lone
+    /// '*' word matches one or more words. Patterns starting with '!' deny
+    /// the commands they match and take precedence over all other patterns.
+    /// The first matching pattern is printed. Exits with an error if the
+    /// command is denied.
+    Test {
+        /// Username, '#UID', '@GROUP' or UID range naming a single user.
+        user: UserSelector,
+
+        /// Command line as the client would send it.
+        command: String,
+    },
 
     /// Purge SSH allowlist setup from one or all users.
+    ///
+    /// The managed block is removed from `authorized_keys` of every selected
+    /// user, whether the user is enabled in control or not. With
+    /// --all-users, every system user is selected, not only enabled ones.
     Uninstall,
+
+    /// Restore the previous managed block of one or all users.
+    ///
+    /// The block most recently archived in `history_dir` is written back and
+    /// deleted from the archive, so that running this again steps further
+    /// back. The next refresh replaces the restored block again.
+    Rollback,
+
+    /// Check whether narrowssh is able to work on this host.
+    ///
+    /// Nothing is modified except for a temporary file in the control
+    /// directory, which is removed immediately.
+    Selftest,
+
+    /// Print the control file, defaults and features in effect.
+    Info,
+
+    /// Print the JSON Schema of control files.
+    #[command(hide = true)]
+    PrintConfigSchema,
+
+    /// Run the command requested over SSH if PATTERNS allow it.
+    ///
+    /// This is the forced command of every managed key and is not meant to
+    /// be run by hand. The command requested by the client is read from
+    /// `SSH_ORIGINAL_COMMAND`, matched against PATTERNS like with the test
+    /// command, and run with /bin/sh if it is allowed. Control is not read.
+    Exec {
+        /// Allowlisted commands of the user.
+        #[arg(last = true)]
+        patterns: Vec<String>,
+    },
+}
+
+/// Loaders of configuration files.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Loader {
+    /// Main control file.
+    Control,
+
+    /// Configuration of users.
+    User,
+}
+
+/// Settings of --color.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ColorWhen {
+    /// Color output to terminals unless `NO_COLOR` is set.
+    Auto,
+
+    /// Always color output.
+    Always,
+
+    /// Never color output.
+    Never,
+}
+
+/// Output formats of reporting commands.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// Human-readable text.
+    Text,
+
+    /// JSON Lines, one object per user.
+    Jsonl,
+
+    /// Effective control of the users as a control file.
+    ///
+    /// The output can be loaded as a control file and yields the same
+    /// settings for these users.
+    Toml,
+}
+
+/// Output formats of `refresh --dry-run`.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum PlanFormat {
+    /// Human-readable summary.
+    Text,
+
+    /// JSON array of plans, one object per user.
+    Json,
 }
 
 /// Absolute path to main control file.
 pub const MAIN_CONTROL_FILE: &str = "/etc/narrowssh/control.toml";
 
+/// Whether diagnostics on standard error are colored, see [`palette`].
+static COLOR: AtomicBool = AtomicBool::new(false);
+
+/// Returns the palette chosen with --color.
+fn palette() -> Palette {
+    Palette::new(COLOR.load(Ordering::Relaxed))
+}
+
+/// Prints a warning to standard error.
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        eprintln!(
+            "narrowssh: {} {}",
+            palette().paint(Style::Warning, "warning:"),
+            format_args!($($arg)*)
+        )
+    };
+}
+
 fn main() {
     if let Err(err) = try_main() {
-        eprintln!("narrowssh: {}", err);
+        eprintln!(
+            "narrowssh: {}",
+            palette().paint(Style::Error, &err.to_string())
+        );
         err.chain()
             .skip(1)
             .for_each(|cause| eprintln!("  - {}", cause));
@@ -59,74 +552,707 @@ fn main() {
 
 fn try_main() -> Result<()> {
     let cli = Cli::parse();
-    let ws = unsafe { narrowssh::workspace::RealWorkspace::new() };
+    COLOR.store(cli.palette().enabled(), Ordering::Relaxed);
+
+    if let Some(path) = &cli.output {
+        redirect_stdout(path)?;
+    }
+
+    if let Some(result) = run_standalone(&cli) {
+        return result;
+    }
+    let ws = workspace(&cli)?;
+
+    if let Commands::Selftest = cli.command {
+        return run_selftest(&ws, cli.control_file());
+    }
+
+    let control_options = cli.control_options();
+
+    let control = load_control(&ws, cli.control_file(), &control_options)?;
 
-    let users = resolve_users(&cli, &ws)?;
+    let mut sinks: Vec<Box<dyn Sink>> = Vec::new();
+    #[cfg(feature = "syslog")]
+    if cli.syslog {
+        sinks.push(Box::new(narrowssh::audit::Syslog::open()));
+    }
+
+    let user_options = cli.user_options();
 
-    println!("Affecting users {users:?}");
+    if let Commands::Prune { dry_run } = cli.command {
+        return prune(&ws, &control, &user_options, dry_run, &mut sinks);
+    }
+
+    if let Commands::Test { user, command } = &cli.command {
+        return test_command(&ws, &control, user, command);
+    }
+
+    let users = select_users(&ws, &cli, &control)?;
+    let users = changed_users(&ws, &cli, &control, users, &user_options);
+    require_staged(&ws, &cli, &control, &users, &control_options)?;
+
+    warn_control_age(&ws, &cli, &control)?;
 
     match &cli.command {
-        Commands::Refresh => {
-            println!("Refreshing");
+        Commands::Refresh {
+            format: PlanFormat::Json,
+            ..
+        } => print_plan(&ws, &control, &users, &user_options),
+        Commands::Refresh {
+            dry_run,
+            transactional,
+            ..
+        } => refresh(
+            &ws,
+            &control,
+            &users,
+            &user_options,
+            *dry_run,
+            *transactional,
+            &mut sinks,
+        ),
+        Commands::Check {
+            format: Format::Jsonl,
+            ..
+        } => check_jsonl(&ws, &control, &users, &user_options),
+        Commands::Check {
+            format: Format::Toml,
+            ..
+        } => {
+            let uids: Vec<_> = users.iter().map(|u| u.uid()).collect();
+            print!("{}", control.to_toml(&uids)?);
+            Ok(())
+        }
+        Commands::Check { explain, .. } => {
+            check(&ws, &control, &users, &user_options, *explain)
+        }
+        Commands::Status => status(&control, &users, &user_options),
+        Commands::Diff { path, show_impact } => {
+            let other = ControlManager::load(&ws, path, &control_options)
+                .with_context(|| {
+                    format!("loading control {}", path.display())
+                })?;
+            diff(&ws, &control, &other, &users, *show_impact)
+        }
+        Commands::DumpKeys => dump_keys(&ws, &control, &users, &user_options),
+        Commands::Export => {
+            print!("{}", export_control(&control, &users, &user_options)?);
+            Ok(())
         }
         Commands::Uninstall => {
-            println!("Uninstalling");
+            uninstall(&ws, &control, &users, &user_options, &mut sinks)
+        }
+        Commands::Rollback => {
+            rollback(&ws, &control, &users, &user_options, &mut sinks)
         }
+        Commands::PrintConfigSchema
+        | Commands::Info
+        | Commands::Exec { .. }
+        | Commands::Selftest
+        | Commands::Prune { .. }
+        | Commands::Test { .. } => unreachable!(),
     }
+}
 
+/// Prints the effective control of `users`.
+fn status(
+    control: &ControlManager,
+    users: &[&User],
+    options: &VisitOptions,
+) -> Result<()> {
+    for user in users {
+        print!("{}", control_status(control, user, options)?);
+    }
     Ok(())
 }
 
-/// Returns all users that should be affected.
+/// Prints the differences from `control` to `other` for `users`.
+fn diff<W: Workspace>(
+    ws: &W,
+    control: &ControlManager,
+    other: &ControlManager,
+    users: &[&User],
+    show_impact: bool,
+) -> Result<()> {
+    let uids: Vec<_> = users.iter().map(|u| u.uid()).collect();
+    for difference in control.diff(other, &uids)? {
+        println!("{difference}");
+    }
+
+    if show_impact {
+        let (enabled, disabled) = control.enabled_diff(other, ws);
+        for (verb, uids) in &[("enabled", enabled), ("disabled", disabled)] {
+            for &uid in uids {
+                let name = ws
+                    .users()
+                    .user_by_uid(uid)
+                    .map(|u| u.name().to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                println!("{verb}: {name} (UID {uid})");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Ensures that control matches `--require-control-matches` of the Refresh
+/// command, if given.
+fn require_staged<W: Workspace>(
+    ws: &W,
+    cli: &Cli,
+    control: &ControlManager,
+    users: &[&User],
+    options: &VisitOptions,
+) -> Result<()> {
+    if let Commands::Refresh {
+        require_control_matches: Some(path),
+        ..
+    } = &cli.command
+    {
+        let staged =
+            ControlManager::load(ws, path, options).with_context(|| {
+                format!("loading staged control {}", path.display())
+            })?;
+        let uids: Vec<_> = users.iter().map(|u| u.uid()).collect();
+        control.ensure_matches(&staged, &uids)?;
+    }
+    Ok(())
+}
+
+/// Narrows `users` down to those changed within `--changed-within` of the
+/// Check command, if given.
+fn changed_users<'a, W: Workspace>(
+    ws: &W,
+    cli: &Cli,
+    control: &ControlManager,
+    users: Vec<&'a User>,
+    options: &VisitOptions,
+) -> Vec<&'a User> {
+    match cli.command {
+        Commands::Check {
+            changed_within: Some(age),
+            ..
+        } => {
+            let since = ws.now().checked_sub(age).unwrap_or(UNIX_EPOCH);
+            users_changed_since(ws, control, &users, options, since)
+        }
+        _ => users,
+    }
+}
+
+/// Loads control from `file` and prints its warnings.
+fn load_control<W: Workspace>(
+    ws: &W,
+    file: &Path,
+    options: &VisitOptions,
+) -> Result<ControlManager> {
+    let control = ControlManager::load(ws, file, options)?;
+    for warning in control.warnings() {
+        warn!("{warning}");
+    }
+    Ok(control)
+}
+
+/// Warns if control is older than `--max-age` of the Check command.
+fn warn_control_age<W: Workspace>(
+    ws: &W,
+    cli: &Cli,
+    control: &ControlManager,
+) -> Result<()> {
+    if let Commands::Check {
+        max_age: Some(max_age),
+        ..
+    } = cli.command
+    {
+        if let Some(warning) = control_age_warning(ws, control, max_age)? {
+            warn!("{warning}");
+        }
+    }
+    Ok(())
+}
+
+/// Returns the workspace of the system, acting as the user given by
+/// `--assume-uid` in debug builds.
+fn workspace(cli: &Cli) -> Result<RealWorkspace> {
+    #[allow(unused_mut)] // Only changed in debug builds
+    let mut ws = RealWorkspace::try_new()?;
+
+    #[cfg(debug_assertions)]
+    if let Some(uid) = cli.assume_uid {
+        ws.assume_uid(uid)?;
+    }
+    #[cfg(not(debug_assertions))]
+    let _ = cli;
+
+    Ok(ws)
+}
+
+/// Runs the `selftest` command.
+fn run_selftest<W: Workspace>(ws: &W, control_file: &Path) -> Result<()> {
+    let scratch_dir = control_file.parent().unwrap_or(Path::new("/"));
+
+    let checks = selftest(ws, control_file, scratch_dir);
+    for check in &checks {
+        println!("{check}");
+    }
+
+    if !checks.iter().all(Check::passed) {
+        bail!("some checks failed");
+    }
+    Ok(())
+}
+
+/// Sends everything printed to standard output to the file at `path`.
 ///
-/// If `--all-users` is set, control file is read, parsed and discarded.
-fn resolve_users<'a, W>(cli: &Cli, ws: &'a W) -> Result<Vec<&'a uzers::User>>
-where
-    W: Workspace,
-{
-    // Count enabled user selection flags
-    if i32::from(cli.user.is_some())
-        + i32::from(cli.uid.is_some())
-        + i32::from(cli.all_users)
-        > 1
+/// See [`open_output`].
+fn redirect_stdout(path: &Path) -> Result<()> {
+    let file = open_output(path)?;
+    std::io::stdout().flush()?;
+
+    // SAFETY: both file descriptors are open for the duration of the call
+    if unsafe { libc::dup2(file.as_raw_fd(), libc::STDOUT_FILENO) } < 0 {
+        return Err(std::io::Error::last_os_error()).with_context(|| {
+            format!("redirecting output to {}", path.display())
+        });
+    }
+    Ok(())
+}
+
+/// Determines the users to operate on from the command line.
+fn select_users<'a, W: Workspace>(
+    ws: &'a W,
+    cli: &Cli,
+    control: &ControlManager,
+) -> Result<Vec<&'a User>> {
+    let selectors: Vec<_> = cli
+        .user
+        .iter()
+        .cloned()
+        .chain(cli.uid.map(UserSelector::Uid))
+        .collect();
+
+    if let Some(path) = &cli.users_from {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("reading {}", path.display()))?;
+        let list = users_from_list(ws, &content, control);
+        for warning in &list.warnings {
+            warn!("{}: {warning}", path.display());
+        }
+        return Ok(list.users);
+    }
+
+    // Uninstall cleans up after users that were disabled, too
+    if cli.all_users
+        && selectors.is_empty()
+        && matches!(cli.command, Commands::Uninstall)
     {
-        bail!("Only one of --user, --uid and --all-users is allowed");
+        return Ok(every_user(ws, cli.include_self));
     }
 
-    if let Some(username) = &cli.user {
-        return ws
-            .users()
-            .user_by_username(username)?
-            .map(|u| vec![u])
-            .ok_or(anyhow!("No such user exists"));
+    let users = resolve_users(
+        ws,
+        &selectors,
+        cli.all_users,
+        cli.include_self,
+        control,
+    )?;
+
+    // Named users are expected to be affected
+    if !selectors.is_empty() {
+        for warning in coverage_warnings(&users, control) {
+            warn!("{warning}");
+        }
     }
+    Ok(users)
+}
+
+/// Runs the `test` command.
+fn test_command<W: Workspace>(
+    ws: &W,
+    control: &ControlManager,
+    selector: &UserSelector,
+    command: &str,
+) -> Result<()> {
+    let users = selector
+        .matching(ws)
+        .with_context(|| format!("resolving {selector}"))?;
+    let user = match users.as_slice() {
+        [user] => user,
+        _ => bail!("{selector} must name exactly one user"),
+    };
+    let name = user.name().to_string_lossy();
+    let user_control = control.get_user_control(user.uid());
+    let commands = user_control.expand_commands(user)?.commands;
 
-    if let Some(uid) = cli.uid {
-        return ws
-            .users()
-            .user_by_uid(uid)
-            .map(|u| vec![u])
-            .ok_or(anyhow!("No such user exists"));
+    if !user_control.enable {
+        println!("deny: {name} is disabled in control");
+        bail!("command denied");
+    }
+    if user_control.action == KeysAction::Deny {
+        println!("deny: access of {name} is revoked in control");
+        bail!("command denied");
     }
 
-    if cli.all_users {
-        let control_manager = ControlManager::load(ws, MAIN_CONTROL_FILE)?;
+    if let Some(pattern) = command_allowed(&commands, command) {
+        println!("allow: {name} may run {command:?} by {pattern:?}");
+        return Ok(());
+    }
 
-        let result: Vec<_> = ws
-            .users()
-            .all_users()
-            .filter(|u| control_manager.get_user_control(u.uid()).enable)
-            .collect();
+    println!("deny: {name} may not run {command:?}");
+    bail!("command denied");
+}
 
-        if result.is_empty() {
-            bail!("All users are disabled in {}", MAIN_CONTROL_FILE);
+/// Runs the commands that need neither control nor the workspace.
+///
+/// Returns `None` for every other command.
+fn run_standalone(cli: &Cli) -> Option<Result<()>> {
+    match &cli.command {
+        Commands::PrintConfigSchema => {
+            Some(
+                serde_json::to_string_pretty(
+                    &narrowssh::schema::control_schema(),
+                )
+                .map(|schema| println!("{schema}"))
+                .map_err(Into::into),
+            )
         }
+        Commands::Info => {
+            let info = Info {
+                control_file: cli.control_file(),
+                extensions: cli.control_extensions(),
+                target_root: cli.target_root.as_deref(),
+            };
+            print!("{info}");
+            Some(Ok(()))
+        }
+        Commands::Exec { patterns } => Some(exec(patterns)),
+        _ => None,
+    }
+}
+
+/// Runs the `exec` command, replacing this process on success.
+fn exec(patterns: &[String]) -> Result<()> {
+    let requested = std::env::var(ORIGINAL_COMMAND_VAR).ok();
+    let command = authorize(patterns, requested.as_deref())?;
 
-        return Ok(result);
+    let error = std::process::Command::new("/bin/sh")
+        .arg("-c")
+        .arg(command)
+        .exec();
+    Err(error).context("could not run /bin/sh")
+}
+
+/// Runs the `refresh` command for `users`.
+fn refresh<W: Workspace>(
+    ws: &W,
+    control: &ControlManager,
+    users: &[&User],
+    options: &VisitOptions,
+    dry_run: bool,
+    transactional: bool,
+    sinks: &mut [Box<dyn Sink>],
+) -> Result<()> {
+    let batch = control.plan(ws, users, options);
+
+    for (user, error) in &batch.failures {
+        let name = user.name().to_string_lossy();
+        warn!("could not refresh user {name}: {error:#}");
+    }
+
+    println!("{}", batch.summary());
+    if !dry_run {
+        apply(ws, control, &batch, transactional, sinks)?;
+    }
+
+    if !batch.failures.is_empty() {
+        bail!("{} users could not be refreshed", batch.failures.len());
+    }
+    Ok(())
+}
+
+/// Runs `refresh --dry-run --format json` for `users`.
+fn print_plan<W: Workspace>(
+    ws: &W,
+    control: &ControlManager,
+    users: &[&User],
+    options: &VisitOptions,
+) -> Result<()> {
+    let batch = control.plan(ws, users, options);
+    println!("{}", serde_json::to_string_pretty(&plan_entries(&batch))?);
+
+    if !batch.failures.is_empty() {
+        bail!("{} users could not be planned", batch.failures.len());
     }
+    Ok(())
+}
+
+/// Runs the `uninstall` command for `users`.
+fn uninstall<W: Workspace>(
+    ws: &W,
+    control: &ControlManager,
+    users: &[&User],
+    options: &VisitOptions,
+    sinks: &mut [Box<dyn Sink>],
+) -> Result<()> {
+    let batch = plan_users_with(ws, control, users, options, &Uninstall);
+
+    for (user, error) in &batch.failures {
+        let name = user.name().to_string_lossy();
+        warn!("could not uninstall user {name}: {error:#}");
+    }
+
+    println!("{}", batch.summary());
+    apply(ws, control, &batch, false, sinks)?;
 
-    Ok(vec![ws
-        .users()
-        .user_by_uid(ws.users().current_uid())
-        .expect("Current user does not exist")])
+    if !batch.failures.is_empty() {
+        bail!("{} users could not be uninstalled", batch.failures.len());
+    }
+    Ok(())
+}
+
+/// Runs the `rollback` command.
+///
+/// Users that could not be rolled back are reported and skipped. Changes are
+/// recorded in `sinks`.
+fn rollback<W: Workspace>(
+    ws: &W,
+    control: &ControlManager,
+    users: &[&User],
+    options: &VisitOptions,
+    sinks: &mut [Box<dyn Sink>],
+) -> Result<()> {
+    let mut report = ApplyReport {
+        outcomes: Vec::new(),
+        warnings: Vec::new(),
+        skipped: Vec::new(),
+    };
+    let mut failures = 0;
+
+    for &user in users {
+        let name = user.name().to_string_lossy();
+        let user_control = control.get_user_control(user.uid());
+
+        match rollback_user(ws, user, &user_control, options) {
+            Ok(outcome) => {
+                if let Outcome::Updated(path) = &outcome {
+                    println!("{name}: restored {}", path.display());
+                }
+                report.outcomes.push((user, outcome));
+            }
+            Err(error) => {
+                warn!("could not roll back user {name}: {error:#}");
+                failures += 1;
+            }
+        }
+    }
+
+    if let Err(error) = emit_all(sinks, &events(&report)) {
+        warn!("could not record changes: {error:#}");
+    }
+
+    if failures > 0 {
+        bail!("{failures} users could not be rolled back");
+    }
+    Ok(())
+}
+
+/// Runs the `prune` command.
+fn prune<W: Workspace>(
+    ws: &W,
+    control: &ControlManager,
+    options: &VisitOptions,
+    dry_run: bool,
+    sinks: &mut [Box<dyn Sink>],
+) -> Result<()> {
+    let batch = plan_prune(ws, control, options);
+
+    for (user, error) in &batch.failures {
+        let name = user.name().to_string_lossy();
+        warn!("could not inspect user {name}: {error:#}");
+    }
+
+    println!("{}", batch.summary());
+    if !dry_run {
+        apply(ws, control, &batch, false, sinks)?;
+    }
+
+    if !batch.failures.is_empty() {
+        bail!("{} users could not be inspected", batch.failures.len());
+    }
+    Ok(())
+}
+
+/// Applies `batch` for the `refresh` command and reports the outcomes.
+///
+/// Changes are also recorded in `sinks`. Afterwards, sshd is validated as
+/// requested by control; a failure is reported but does not undo changes.
+fn apply<W: Workspace>(
+    ws: &W,
+    control: &ControlManager,
+    batch: &Batch,
+    transactional: bool,
+    sinks: &mut [Box<dyn Sink>],
+) -> Result<()> {
+    for UserPlan { user, plan } in &batch.plans {
+        let name = user.name().to_string_lossy();
+        for warning in &plan.warnings {
+            warn!("{name}: {warning}");
+        }
+    }
+
+    let report = apply_batch(ws, batch, transactional)?;
+    if let Err(error) = emit_all(sinks, &events(&report)) {
+        warn!("could not record changes: {error:#}");
+    }
+
+    let sshd = sshd_to_validate(&report, control);
+
+    for (user, warning) in &report.warnings {
+        let name = user.name().to_string_lossy();
+        warn!("{name}: {warning}");
+    }
+
+    for (user, outcome) in report.outcomes {
+        let name = user.name().to_string_lossy();
+
+        match outcome {
+            // Reported with the other skipped users below
+            Outcome::Disabled => {}
+            Outcome::Unchanged(path) => {
+                println!("{name}: {} is up to date", path.display());
+            }
+            Outcome::Updated(path) => {
+                println!("{name}: updated {}", path.display());
+            }
+            Outcome::Removed(path) => {
+                println!(
+                    "{name}: removed managed block from {}",
+                    path.display()
+                );
+            }
+        }
+    }
+
+    for skip in &report.skipped {
+        println!("{skip}");
+    }
+
+    let mut sshd_failed = false;
+    for sshd in sshd {
+        if let Err(error) = validate_sshd(ws, &sshd) {
+            eprintln!(
+                "narrowssh: {} sshd configuration test failed; \
+                changes were kept: {error:#}",
+                palette().paint(Style::Error, "ERROR:")
+            );
+            sshd_failed = true;
+        }
+    }
+    if sshd_failed {
+        bail!("sshd configuration test failed");
+    }
+
+    Ok(())
+}
+
+/// Runs the `check` command for `users`.
+fn check<W: Workspace>(
+    ws: &W,
+    control: &ControlManager,
+    users: &[&User],
+    options: &VisitOptions,
+    explain: bool,
+) -> Result<()> {
+    for conflict in control.conflicts() {
+        warn!("{conflict}");
+    }
+
+    let mut total = 0;
+    for user in users {
+        let name = user.name().to_string_lossy();
+
+        if explain {
+            print!("{}", explain_control(control, user)?);
+        }
+
+        let block = check_user(
+            ws,
+            user,
+            &control.get_user_control(user.uid()),
+            options,
+        )
+        .with_context(|| format!("could not check user {name}"))?;
+
+        match block {
+            None => println!("{name}: disabled in control"),
+            Some(block) => {
+                for warning in &block.warnings {
+                    warn!("{name}: {warning}");
+                }
+                println!("{name}: ok, {} keys", block.keys());
+                total += block.keys();
+            }
+        }
+    }
+
+    println!("{total} keys managed in total");
+    Ok(())
+}
+
+/// Runs the `check` command for `users` with JSON Lines output.
+fn check_jsonl<W: Workspace>(
+    ws: &W,
+    control: &ControlManager,
+    users: &[&User],
+    options: &VisitOptions,
+) -> Result<()> {
+    let mut failed = 0;
+    let mut total = 0;
+
+    let statuses = user_statuses(ws, control, users, options).inspect(
+        |status: &UserStatus| {
+            failed += usize::from(!status.ok());
+            total += status.keys.unwrap_or_default();
+        },
+    );
+    write_jsonl(statuses, std::io::stdout().lock())?;
+    eprintln!("narrowssh: {total} keys managed in total");
+
+    if failed != 0 {
+        bail!("{failed} users failed the check");
+    }
+    Ok(())
+}
+
+/// Runs the `dump-keys` command for `users`.
+fn dump_keys<W: Workspace>(
+    ws: &W,
+    control: &ControlManager,
+    users: &[&User],
+    options: &VisitOptions,
+) -> Result<()> {
+    for user in users {
+        let name = user.name().to_string_lossy();
+
+        let block = check_user(
+            ws,
+            user,
+            &control.get_user_control(user.uid()),
+            options,
+        )
+        .with_context(|| format!("could not render keys of user {name}"))?;
+
+        match block {
+            None => println!("# {name}: disabled in control"),
+            Some(block) => {
+                for warning in &block.warnings {
+                    warn!("{name}: {warning}");
+                }
+                println!("# {name}");
+                print!("{}", block.text);
+            }
+        }
+    }
+
+    Ok(())
 }
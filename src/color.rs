@@ -0,0 +1,87 @@
+//! Coloring of diagnostics written to terminals.
+
+use std::env;
+use std::os::unix::io::RawFd;
+
+#[cfg(test)]
+mod tests;
+
+/// Environment variable that disables colors when set to a non-empty value.
+///
+/// See <https://no-color.org> for the convention.
+pub const NO_COLOR: &str = "NO_COLOR";
+
+/// Kinds of text that are colored differently.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Style {
+    /// Label of a warning, shown in bold yellow.
+    Warning,
+
+    /// Text of an error, shown in bold red.
+    Error,
+}
+
+impl Style {
+    /// Returns the SGR parameters selecting this style.
+    fn sgr(self) -> &'static str {
+        match self {
+            Self::Warning => "1;33",
+            Self::Error => "1;31",
+        }
+    }
+}
+
+/// Decides whether text is colored with ANSI escape sequences.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Palette {
+    enabled: bool,
+}
+
+impl Palette {
+    /// Creates a palette that colors text if `enabled` is set.
+    ///
+    /// [`NO_COLOR`] is not consulted; use [`Palette::auto`] for that.
+    #[must_use]
+    pub fn new(enabled: bool) -> Self {
+        Self { enabled }
+    }
+
+    /// Creates a palette for output that goes to a terminal if `is_terminal`
+    /// is set.
+    ///
+    /// Text is colored only for terminals, and never if [`NO_COLOR`] is set
+    /// to a non-empty value.
+    #[must_use]
+    pub fn auto(is_terminal: bool) -> Self {
+        Self::new(is_terminal && !no_color())
+    }
+
+    /// Returns whether this palette colors text.
+    #[must_use]
+    pub fn enabled(self) -> bool {
+        self.enabled
+    }
+
+    /// Returns `text` colored in `style`, or unchanged if colors are disabled.
+    #[must_use]
+    pub fn paint(self, style: Style, text: &str) -> String {
+        if self.enabled {
+            format!("\x1b[{}m{text}\x1b[0m", style.sgr())
+        } else {
+            text.to_owned()
+        }
+    }
+}
+
+/// Returns whether the user asked for no colors with [`NO_COLOR`].
+#[must_use]
+pub fn no_color() -> bool {
+    env::var_os(NO_COLOR).map_or(false, |value| !value.is_empty())
+}
+
+/// Returns whether file descriptor `fd` refers to a terminal.
+#[must_use]
+pub fn is_terminal(fd: RawFd) -> bool {
+    // SAFETY: isatty only inspects the descriptor, which may be invalid
+    unsafe { libc::isatty(fd) == 1 }
+}
@@ -0,0 +1,43 @@
+pub use super::*;
+
+/// Tests for [`Palette`]
+mod palette {
+    use super::*;
+
+    #[test]
+    fn paint() {
+        let colored = Palette::new(true).paint(Style::Warning, "warning:");
+        assert_eq!(colored, "\x1b[1;33mwarning:\x1b[0m");
+
+        let plain = Palette::new(false).paint(Style::Error, "failed");
+        assert_eq!(plain, "failed");
+    }
+
+    #[test]
+    fn auto_honors_no_color() {
+        // The only test that touches NO_COLOR, so that tests running in
+        // parallel do not observe each other
+        env::set_var(NO_COLOR, "1");
+        let palette = Palette::auto(true);
+        assert!(!palette.enabled());
+        assert!(!palette.paint(Style::Warning, "warning:").contains('\x1b'));
+        assert!(!palette.paint(Style::Error, "failed").contains('\x1b'));
+
+        env::set_var(NO_COLOR, "");
+        assert!(Palette::auto(true).enabled());
+
+        env::remove_var(NO_COLOR);
+        assert!(Palette::auto(true).enabled());
+        assert!(!Palette::auto(false).enabled());
+    }
+}
+
+/// Tests for [`is_terminal`]
+mod is_terminal {
+    use super::*;
+
+    #[test]
+    fn invalid_descriptor() {
+        assert!(!is_terminal(-1));
+    }
+}
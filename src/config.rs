@@ -1,51 +1,201 @@
 //! Configuration structs and parser.
 
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::OsStr;
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::{MetadataExt, PermissionsExt};
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 
-use anyhow::{anyhow, bail, Context, Result};
-use serde::Deserialize;
-use uzers::uid_t;
+use anyhow::{anyhow, bail, Context, Error, Result};
+use serde::{Deserialize, Serialize};
+use uzers::{gid_t, uid_t, User};
 
-use crate::workspace::Workspace;
+use crate::authorized_keys::{validate_marker, BEGIN_MARKER, END_MARKER};
+use crate::schema::CONTROL_FIELDS;
+use crate::selection::UserSelector;
+use crate::workspace::{home_dir, UserMap, Workspace};
 
 #[cfg(test)]
 mod tests;
 
 /// Default value of `config` setting in control.
-const DEFAULT_USER_CONFIG: &str = "~/.narrowssh.conf";
+pub const DEFAULT_USER_CONFIG: &str = "~/.narrowssh.conf";
 
 /// Default value of `authorized_keys` setting in control.
-const DEFAULT_AUTHORIZED_KEYS: &str = "~/.ssh/authorized_keys";
+pub const DEFAULT_AUTHORIZED_KEYS: &str = "~/.ssh/authorized_keys";
+
+/// Directory of public key files read with [`Control::pub_keys_fallback`].
+pub const PUB_KEYS_DIR: &str = "~/.ssh";
+
+/// Prefix of names of control sections that define profiles.
+const PROFILE_PREFIX: &str = "profile:";
+
+/// Name of the control section that defines command groups, see
+/// [`Control::command_groups`].
+pub const COMMAND_GROUPS_SECTION: &str = "command-groups";
+
+/// Name of the top-level setting of the main control file that lists the
+/// fields its extensions may set.
+///
+/// For delegated administration, e.g. `allow_keys = ["commands"]` lets the
+/// team that manages `control.toml.d` change commands but nothing else.
+/// Defining command groups requires [`COMMAND_GROUPS_SECTION`] to be listed.
+/// Without this setting, extensions may set any field.
+pub const ALLOW_KEYS: &str = "allow_keys";
+
+/// Default value of `max_line_length` setting in control.
+///
+/// `sshd(8)` reads `authorized_keys` in lines of limited length, so longer
+/// lines risk being cut short.
+const DEFAULT_MAX_LINE_LENGTH: usize = 8192;
+
+/// Options for [`visit_config_files`].
+#[derive(Clone, Debug)]
+pub struct VisitOptions {
+    /// Whether the `{file}.d` directory should be visited.
+    ///
+    /// When disabled, `{file}.d` is never accessed, not even to check whether
+    /// it exists. This reduces the attack surface to a single file.
+    ///
+    /// Control and user configuration are loaded with separate options, so
+    /// their extensions may be toggled independently.
+    pub extensions: bool,
+
+    /// Required owner of `{file}.d` and its contents, if different from the
+    /// owner of `{file}`.
+    ///
+    /// This allows delegating extensions to another user. Note that
+    /// extensions may override any setting of the main file, so the delegate
+    /// is trusted as much as the owner of the main file.
+    pub extension_owner: Option<uid_t>,
+
+    /// Directory that replaces `/` in paths resolved from control settings.
+    ///
+    /// This redirects reads and writes of `config` and `authorized_keys` files
+    /// into a sandbox, see [`reroot`]. [`visit_config_files`] itself ignores
+    /// this option.
+    pub target_root: Option<PathBuf>,
+
+    /// Realm or domain that system usernames may be qualified with.
+    ///
+    /// When set, a control section naming `alice` applies to a system user
+    /// named `alice@{realm}` or `{realm}\alice` if no user is named exactly
+    /// `alice`. Realms are matched case-sensitively. [`visit_config_files`]
+    /// itself ignores this option.
+    pub realm: Option<String>,
+
+    /// Whether files owned by root are accepted in place of the expected
+    /// owner.
+    ///
+    /// This is meant for per-user configuration: root can already change any
+    /// file, so trusting root-owned files adds no trust. Files owned by any
+    /// other user are still rejected.
+    pub allow_root_owner: bool,
+
+    /// Whether files and `{file}.d` are also rejected if they have the
+    /// setuid, setgid or sticky bit set.
+    ///
+    /// These bits serve no purpose on configuration and suggest tampering.
+    pub strict_perms: bool,
+
+    /// Allowed signers file that control files must be signed with.
+    ///
+    /// When set, [`ControlManager::load`] refuses every control file that
+    /// lacks a valid detached signature, see [`crate::signature`]. User
+    /// configuration is not signed.
+    #[cfg(feature = "signatures")]
+    pub signers: Option<PathBuf>,
+
+    /// Whether directories are synced after files in them are replaced.
+    ///
+    /// Syncing makes replacements survive a power loss at the cost of a
+    /// disk flush per file. When unset, only `authorized_keys` files owned
+    /// by root are synced, since central directories hold the keys of many
+    /// users. [`visit_config_files`] itself ignores this option.
+    pub durable: Option<bool>,
+
+    /// Handling of control sections that name a username shared by several
+    /// users. [`visit_config_files`] itself ignores this option.
+    pub ambiguous_names: AmbiguousNames,
+}
+
+impl Default for VisitOptions {
+    fn default() -> Self {
+        Self {
+            extensions: true,
+            extension_owner: None,
+            target_root: None,
+            realm: None,
+            allow_root_owner: false,
+            strict_perms: false,
+            #[cfg(feature = "signatures")]
+            signers: None,
+            durable: None,
+            ambiguous_names: AmbiguousNames::default(),
+        }
+    }
+}
+
+/// Handling of control sections that name an ambiguous user, see
+/// [`VisitOptions::ambiguous_names`].
+///
+/// Usernames are not necessarily unique, and with a realm, `alice` may refer
+/// to both `alice@{realm}` and `{realm}\alice`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AmbiguousNames {
+    /// Refuse to load control.
+    #[default]
+    Error,
+
+    /// Apply the section to none of the users sharing the name, with a
+    /// warning, see [`ControlManager::warnings`]. These users are left
+    /// alone, see [`ControlManager::is_ambiguous`].
+    Skip,
+}
 
 /// Iterates over configuration file and its extensions and checks permissions.
 ///
 /// In particular, `file` and the contents of `{file}.d` directory, if any, are
 /// checked and passed to the consumer as [`Path`s][Path]. Readability of files
-/// is not tested.
+/// is not tested. `{file}.d` is skipped if [`VisitOptions::extensions`] is
+/// disabled.
 ///
 /// If `{file}` has an [extension][Path::extension()], only files with the same
-/// extension will be considered inside `{file}.d`.
+/// extension will be considered inside `{file}.d`. Extensions are visited in
+/// the order defined by [`sort_extensions`].
+///
+/// The extension owner is [`VisitOptions::extension_owner`] if set, or `owner`
+/// otherwise. Files and directories owned by root are accepted as well if
+/// [`VisitOptions::allow_root_owner`] is set.
 ///
 /// Symbolic links are always resolved.
 ///
+/// The permissions of `{file}.d` are checked before it is listed, so an
+/// insecure directory is always reported as a security violation, even if it
+/// could not be listed.
+///
 /// # Errors
 /// The function will fail in these cases:
 ///   - the consumer returns an error,
 ///   - some symbolic link could not be read,
 ///   - `{file}.d` exists but could not be read,
 ///   - `{file}.d` includes non-file extensions,
-///   - some file is not owned by `owner`,
-///   - `{file}.d` exists but is not owned by `owner`,
-///   - some file has some world or group permissions, or
-///   - `{file}.d` exists and has some world or group permissions.
+///   - `{file}` is not owned by `owner`,
+///   - some extension file is not owned by the extension owner,
+///   - `{file}.d` exists but is not owned by the extension owner,
+///   - some file has some world or group permissions,
+///   - `{file}.d` exists and has some world or group permissions, or
+///   - [`VisitOptions::strict_perms`] is set and some file or `{file}.d` has
+///     the setuid, setgid or sticky bit set.
 ///
 /// The checks above are evaluated lazily, so `consumer` may be invoked even if
 /// the function eventually fails.
 pub fn visit_config_files<P, C, W>(
     file: P,
     owner: uid_t,
+    options: &VisitOptions,
     mut consumer: C,
     ws: &W,
 ) -> Result<()>
@@ -55,292 +205,2529 @@ where
     W: Workspace,
 {
     // Runs safety checks.
-    let perm_check = |file: &Path, expect_dir: bool| -> Result<()> {
-        let suffix = "[security; refusing to proceed]";
+    let perm_check =
+        |file: &Path, expect_dir: bool, owner: uid_t| -> Result<()> {
+            let suffix = "[security; refusing to proceed]";
 
-        let metadata = std::fs::metadata(file)?;
+            let metadata = std::fs::metadata(file)?;
 
-        // Check file type
-        if expect_dir {
-            if !metadata.is_dir() {
-                bail!("not a (symlink to a) directory {suffix}");
+            // Check file type
+            if expect_dir {
+                if !metadata.is_dir() {
+                    bail!("not a (symlink to a) directory {suffix}");
+                }
+            } else if !metadata.is_file() {
+                bail!("not a (symlink to a) regular file {suffix}");
             }
-        } else if !metadata.is_file() {
-            bail!("not a (symlink to a) regular file {suffix}");
-        }
 
-        // Check permission bits
-        let mode = metadata.permissions().mode() & 0o777;
-        if mode & 0o077 != 0 {
-            bail!(
-                "file has permissions {mode:o}, change to {:o} {suffix}",
-                mode & 0o700
-            );
-        }
+            // Check permission bits
+            let mode = metadata.permissions().mode() & 0o777;
+            if mode & 0o077 != 0 {
+                bail!(
+                    "file has permissions {mode:o}, change to {:o} {suffix}",
+                    mode & 0o700
+                );
+            }
 
-        // Check owner
-        let actual_owner =
-            ws.get_mock_owner_uid(file).unwrap_or(metadata.uid());
-        if actual_owner != owner {
-            bail!(
-                "must be owned by UID {owner}, not {actual_owner} {suffix}"
-            );
-        }
+            let special = metadata.permissions().mode() & 0o7000;
+            if options.strict_perms && special != 0 {
+                bail!(
+                    "file has setuid, setgid or sticky bits {special:o}, \
+                    clear them {suffix}"
+                );
+            }
 
-        Ok(())
-    };
+            // Check owner
+            let actual_owner =
+                ws.get_mock_owner_uid(file).unwrap_or(metadata.uid());
+            let trusted_root = options.allow_root_owner && actual_owner == 0;
+            if actual_owner != owner && !trusted_root {
+                if options.allow_root_owner {
+                    bail!(
+                        "must be owned by UID {owner} or root, \
+                         not {actual_owner} {suffix}"
+                    );
+                }
+                bail!(
+                    "must be owned by UID {owner}, \
+                     not {actual_owner} {suffix}"
+                );
+            }
+
+            Ok(())
+        };
 
     // Prepare paths
     let main_file = file.as_ref();
-    let mut dir: std::ffi::OsString = main_file.into();
-    dir.push(".d");
-    let dir: std::path::PathBuf = dir.into();
+    let dir = extensions_dir(main_file);
 
     // Visit main file
     || -> Result<()> {
-        perm_check(main_file, false)?;
+        perm_check(main_file, false, owner)?;
         consumer(main_file)?;
         Ok(())
     }()
     .with_context(|| format!("loading main file {}", main_file.display()))?;
 
-    // Try listing extensions
-    let extensions = || -> Result<Option<Vec<PathBuf>>> {
-        return match std::fs::read_dir(&dir) {
-            Err(error) => {
-                return if error.kind() == std::io::ErrorKind::NotFound {
-                    // Extension directory does not exist - skip
-                    Ok(None)
-                } else {
-                    Err(error.into())
-                };
-            }
-            Ok(read_dir) => {
-                let mut entries = read_dir
-                    .map(|res| res.map(|e| e.path()))
-                    .collect::<Result<Vec<_>, std::io::Error>>(
-                )?;
-
-                if let Some(main_ext) = main_file.extension() {
-                    // Filter by extension
-                    entries.retain(|p| p.extension() == Some(main_ext));
-                    // Sort by name ensuring that "a.ext" < "a.a.ext"
-                    entries.sort_by_key(|p| p.with_extension(""));
-                } else {
-                    entries.sort();
-                }
+    if !options.extensions {
+        return Ok(());
+    }
 
-                Ok(Some(entries))
-            }
-        };
+    // Check extensions directory before listing it, so that insecure
+    // directories are reported as such even if they cannot be read
+    let extension_owner = options.extension_owner.unwrap_or(owner);
+    match std::fs::metadata(&dir) {
+        // Extension directory does not exist - skip
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(());
+        }
+        Err(error) => {
+            return Err(error).with_context(|| {
+                format!("listing extensions in {}", dir.display())
+            })
+        }
+        Ok(_) => {
+            perm_check(&dir, true, extension_owner).with_context(|| {
+                format!("checking extensions directory {}", dir.display())
+            })?;
+        }
+    }
+
+    // List extensions
+    let extensions = || -> Result<Vec<PathBuf>> {
+        let entries = std::fs::read_dir(&dir)?
+            .map(|res| res.map(|e| e.path()))
+            .collect::<Result<Vec<_>, std::io::Error>>()?;
+
+        Ok(select_extensions(entries, main_file.extension()))
     }()
     .with_context(|| format!("listing extensions in {}", dir.display()))?;
 
     // Visit extensions
-    if let Some(dir_iter) = extensions {
-        perm_check(&dir, true)?;
-        for entry in dir_iter {
-            || -> Result<()> {
-                perm_check(&entry, false)?;
-                consumer(&entry)?;
-                Ok(())
-            }()
-            .with_context(|| {
-                format!("loading extension file {}", entry.display())
-            })?;
-        }
+    for entry in extensions {
+        || -> Result<()> {
+            perm_check(&entry, false, extension_owner)?;
+            consumer(&entry)?;
+            Ok(())
+        }()
+        .with_context(|| {
+            format!("loading extension file {}", entry.display())
+        })?;
     }
 
     Ok(())
 }
 
-/// Complete parsed configuration.
-#[derive(Clone, Debug)]
-pub struct Config {
-    // TODO
-}
+/// Returns the extensions directory of `file`, i.e. `{file}.d`.
+///
+/// The directory is a sibling of `file`, so relative paths stay relative to
+/// the same directory as `file`, whatever the working directory is, and
+/// `.` or `..` components leading up to the file name are kept as they are.
+/// A trailing `/` of `file` is ignored.
+pub(crate) fn extensions_dir(file: &Path) -> PathBuf {
+    if let Some(name) = file.file_name() {
+        let mut name = name.to_os_string();
+        name.push(".d");
+        return file.with_file_name(name);
+    }
 
-/// A user's control settings.
-#[derive(Clone, Debug)]
-pub struct Control {
-    /// Killswitch for all functionality.
-    pub enable: bool,
+    let mut dir = file.as_os_str().to_os_string();
+    dir.push(".d");
+    dir.into()
+}
 
-    /// Path to user-defined config.
-    ///
-    /// The config extensions directory, referred to as `config.d`, is resolved
-    /// to `config + '.d/'`.
-    ///
-    /// Note that this file, `config.d` and its contents must be owned by the
-    /// user, and have modes `0600` or `0400` for files and `0700` or `0500`
-    /// for `config.d`; otherwise, all user configuration is ignored.
-    ///
-    /// This path must either begin with a `/` to denote an absolute path,
-    /// or with a `~` to denote a path relative to the home directory of the
-    /// user. This path cannot end with a `/`.
-    pub config: String,
+/// Returns the entries of an extensions directory that should be visited, in
+/// order.
+///
+/// Entries without a normal file name, such as `..`, are skipped; no
+/// filesystem is known to list them, but the extension filter would not
+/// handle them sensibly. If `main_ext` is set, only entries with that
+/// extension are kept. The result is ordered by [`sort_extensions`].
+fn select_extensions(
+    mut entries: Vec<PathBuf>,
+    main_ext: Option<&OsStr>,
+) -> Vec<PathBuf> {
+    entries.retain(|p| {
+        matches!(
+            p.components().next_back(),
+            Some(std::path::Component::Normal(_))
+        )
+    });
 
-    /// Path to the authorized_keys(5) file of this user.
-    ///
-    /// This path must either begin with a `/` to denote an absolute path,
-    /// or with a `~` to denote a path relative to the home directory of the
-    /// user. This path cannot end with a `/`.
-    pub authorized_keys: String,
-}
+    if let Some(main_ext) = main_ext {
+        // Filter by extension
+        entries.retain(|p| p.extension() == Some(main_ext));
+    }
+    sort_extensions(&mut entries, main_ext);
 
-/// Copy of `Control` struct with every field wrapped in an Option.
-#[derive(Debug, Deserialize)]
-struct IncompleteControl {
-    pub enable: Option<bool>,
-    pub config: Option<String>,
-    pub authorized_keys: Option<String>,
+    entries
 }
 
-impl Control {
-    fn fill_from(&mut self, source: &IncompleteControl) {
-        if let Some(enable) = source.enable {
-            self.enable = enable;
+/// Sorts extension files in the order they should be visited.
+///
+/// Files are ordered by their names with `main_ext` removed, so `10.conf`
+/// comes before `10.service.conf`. Names are compared byte by byte, except
+/// that runs of digits are compared by their numeric value, so `2.conf` comes
+/// before `10.conf`. Names that only differ in leading zeros are ordered
+/// bytewise, so the result never depends on the initial order of `entries`.
+fn sort_extensions(entries: &mut [PathBuf], main_ext: Option<&OsStr>) {
+    let stem = |path: &Path| -> Vec<u8> {
+        let name = path.file_name().unwrap_or_default().as_bytes();
+        match main_ext {
+            Some(ext) => name[..name.len() - ext.len() - 1].to_vec(),
+            None => name.to_vec(),
         }
+    };
 
-        if let Some(config) = &source.config {
-            self.config = config.clone();
-        }
+    entries.sort_by(|a, b| {
+        let (a_stem, b_stem) = (stem(a), stem(b));
+        natural_cmp(&a_stem, &b_stem)
+            .then_with(|| a_stem.cmp(&b_stem))
+            .then_with(|| a.cmp(b))
+    });
+}
 
-        if let Some(authorized_keys) = &source.authorized_keys {
-            self.authorized_keys = authorized_keys.clone();
+/// Compares strings treating runs of ASCII digits as numbers.
+fn natural_cmp(a: &[u8], b: &[u8]) -> Ordering {
+    /// Splits off the leading run of digits with leading zeros removed.
+    fn number(s: &[u8]) -> (&[u8], &[u8]) {
+        let end = s
+            .iter()
+            .position(|c| !c.is_ascii_digit())
+            .unwrap_or(s.len());
+        let zeros = s[..end].iter().take_while(|&&c| c == b'0').count();
+        (&s[zeros..end], &s[end..])
+    }
+
+    let (mut a, mut b) = (a, b);
+    loop {
+        match (a.first(), b.first()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y))
+                if x.is_ascii_digit() && y.is_ascii_digit() =>
+            {
+                let ((x, a_rest), (y, b_rest)) = (number(a), number(b));
+                let order = x.len().cmp(&y.len()).then_with(|| x.cmp(y));
+                if order != Ordering::Equal {
+                    return order;
+                }
+                a = a_rest;
+                b = b_rest;
+            }
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(y);
+                }
+                a = &a[1..];
+                b = &b[1..];
+            }
         }
     }
 }
 
-impl IncompleteControl {
-    fn fill_from(&mut self, source: &IncompleteControl) {
-        if let Some(enable) = source.enable {
-            self.enable = Some(enable);
-        }
+/// Reads a configuration file that must be valid UTF-8.
+///
+/// `kind` names the file in error messages.
+fn read_utf8(file: &Path, kind: &str) -> Result<String> {
+    let bytes = std::fs::read(file)?;
 
-        if let Some(config) = &source.config {
-            self.config = Some(config.clone());
-        }
+    String::from_utf8(bytes).map_err(|e| {
+        anyhow!(
+            "{kind} file {} is not valid UTF-8: invalid byte at offset {} \
+            (TOML must be UTF-8)",
+            file.display(),
+            e.utf8_error().valid_up_to()
+        )
+    })
+}
 
-        if let Some(authorized_keys) = &source.authorized_keys {
-            self.authorized_keys = Some(authorized_keys.clone());
+/// Parses the contents of a configuration file as TOML.
+///
+/// `kind` names the file in error messages, which callers prefix with the
+/// path of `file`. Content that fails to parse but looks like JSON gets a
+/// hint, since the TOML error alone is confusing.
+fn parse_toml<T>(file: &Path, content: &str, kind: &str) -> Result<T>
+where
+    T: serde::de::DeserializeOwned,
+{
+    toml::from_str(content).map_err(|error| {
+        if !content.trim_start().starts_with('{') {
+            return Error::from(error);
         }
-    }
+
+        let extension = match file.extension() {
+            Some(extension) => {
+                format!("has a .{} extension", extension.to_string_lossy())
+            }
+            None => String::from("has no extension"),
+        };
+        Error::from(error).context(format!(
+            "this looks like JSON but {extension} ({kind} files must be TOML)"
+        ))
+    })
 }
 
-/// Manages the control settings for all users.
-#[derive(Debug)]
-pub struct ControlManager {
-    /// Overrides for individual users.
-    users: HashMap<uid_t, IncompleteControl>,
+/// Complete parsed configuration of a user.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    /// Public keys to install, in `authorized_keys(5)` format.
+    ///
+    /// Keys from the main file and all its extensions are concatenated.
+    pub keys: Vec<String>,
 
-    /// Default values for all other users.
-    fallback: Control,
+    /// Names of command groups to enable, see [`Control::command_groups`].
+    ///
+    /// Names from the main file and all its extensions are concatenated.
+    pub enable_commands: Vec<String>,
 }
 
-impl ControlManager {
-    /// Loads the control data from the filesystem.
+/// Returns the TOML type of `value` for error messages.
+///
+/// Arrays are described by the type of their first non-string item, if any.
+fn describe_toml_type(value: &toml::Value) -> String {
+    let odd_item = value
+        .as_array()
+        .and_then(|items| items.iter().find(|item| !item.is_str()));
+
+    match odd_item {
+        Some(item) => format!("array containing {}", item.type_str()),
+        None => value.type_str().to_owned(),
+    }
+}
+
+/// Copy of `Config` struct with every field wrapped in an Option.
+#[derive(Debug, Deserialize)]
+struct IncompleteConfig {
+    pub keys: Option<Vec<String>>,
+    pub enable_commands: Option<Vec<String>>,
+}
+
+impl Config {
+    /// Loads the configuration of a user from the filesystem.
     ///
-    /// In particular, `from` and the contents of
-    /// `{from}.d` directory are read and parsed.
+    /// In particular, `from` and the contents of `{from}.d` directory are
+    /// read and parsed. All files must be owned by `owner`.
     ///
-    /// Symbolic links are always resolved.
+    /// Symbolic links are always resolved. See [`visit_config_files`] for the
+    /// meaning of `options`.
     ///
     /// # Errors
     /// The load will fail in these cases:
     ///   - some file could not be read,
     ///   - some file is not a valid TOML file,
-    ///   - some file is not structured as a control file, or
+    ///   - some file is not structured as a config file, or
     ///   - [`visit_config_files`] complains.
-    pub fn load<W, P>(ws: &W, from: P) -> Result<Self>
+    pub fn load<W, P>(
+        ws: &W,
+        from: P,
+        owner: uid_t,
+        options: &VisitOptions,
+    ) -> Result<Self>
     where
         W: Workspace,
         P: AsRef<Path>,
     {
-        let mut result = Self {
-            users: HashMap::new(),
-            fallback: Control {
-                enable: false,
-                config: String::from(DEFAULT_USER_CONFIG),
-                authorized_keys: String::from(DEFAULT_AUTHORIZED_KEYS),
-            },
-        };
+        let mut result = Self::default();
 
         let process = |file: &Path| -> Result<()> {
-            println!("Reading control {}", file.display());
+            let content = read_utf8(file, "user configuration")?;
+            let data: IncompleteConfig =
+                parse_toml(file, &content, "user configuration")?;
 
-            let content = std::fs::read_to_string(file)?;
-            let content = toml::from_str::<toml::Table>(&content)?;
+            if let Some(keys) = data.keys {
+                result.keys.extend(keys);
+            }
 
-            for (user, data) in content {
-                let data: IncompleteControl = data.try_into()?;
+            if let Some(names) = data.enable_commands {
+                result.enable_commands.extend(names);
+            }
 
-                Self::validate(&data)?;
+            Ok(())
+        };
 
-                if user == "*" {
-                    result.fallback.fill_from(&data);
-                    continue;
-                }
+        visit_config_files(from, owner, options, process, ws)
+            .context("could not load user configuration files")?;
 
-                let uid = if let Ok(uid) = user.parse::<uid_t>() {
-                    uid
-                } else {
-                    ws.users()
-                        .user_by_username(&user)?
-                        .ok_or(anyhow!("unknown user"))?
-                        .uid()
-                };
-
-                result
-                    .users
-                    .entry(uid)
-                    .and_modify(|ic| ic.fill_from(&data))
-                    .or_insert(data);
+        Ok(result)
+    }
+
+    /// Loads keys of a user from the `*.pub` files in `dir`.
+    ///
+    /// Every non-empty line that is not a `#` comment is a key. Files are
+    /// read in order of their names. `dir` and the files must be owned by
+    /// `owner` and must not be writable by group or others; symbolic links
+    /// are resolved and their targets checked. A missing `dir` yields no
+    /// keys. See [`Control::pub_keys_fallback`].
+    ///
+    /// # Errors
+    /// The load will fail if `dir` or some file could not be read, is of the
+    /// wrong type, or fails the checks above.
+    pub fn load_pub_keys<W: Workspace>(
+        ws: &W,
+        dir: &Path,
+        owner: uid_t,
+    ) -> Result<Self> {
+        let suffix = "[security; refusing to proceed]";
+        let check = |path: &Path, expect_dir: bool| -> Result<()> {
+            let metadata = std::fs::metadata(path)
+                .with_context(|| format!("inspecting {}", path.display()))?;
+            if metadata.is_dir() != expect_dir {
+                bail!("{} has the wrong file type {suffix}", path.display());
+            }
+
+            let actual_owner =
+                ws.get_mock_owner_uid(path).unwrap_or(metadata.uid());
+            if actual_owner != owner {
+                bail!(
+                    "{} must be owned by UID {owner}, not {actual_owner} \
+                    {suffix}",
+                    path.display()
+                );
             }
 
+            let mode = metadata.permissions().mode() & 0o777;
+            if mode & 0o022 != 0 {
+                bail!(
+                    "{} has permissions {mode:o}, change to {:o} {suffix}",
+                    path.display(),
+                    mode & !0o022
+                );
+            }
             Ok(())
         };
 
-        visit_config_files(from, 0, process, ws)
-            .context("could not load control configuration files")?;
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Self::default())
+            }
+            Err(error) => {
+                return Err(error)
+                    .with_context(|| format!("listing {}", dir.display()))
+            }
+        };
+        check(dir, true)?;
+
+        let mut files = entries
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<Result<Vec<_>, std::io::Error>>()
+            .with_context(|| format!("listing {}", dir.display()))?;
+        files.retain(|path| path.extension().map_or(false, |e| e == "pub"));
+        files.sort();
 
-        dbg!(&result);
+        let mut result = Self::default();
+        for file in files {
+            check(&file, false)?;
+            let content = std::fs::read_to_string(&file)
+                .with_context(|| format!("reading {}", file.display()))?;
+            result.keys.extend(
+                content
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(str::to_owned),
+            );
+        }
 
         Ok(result)
     }
+}
 
-    /// Validates additional constraints on [`IncompleteControl`] fields in
-    /// control files.
-    fn validate(data: &IncompleteControl) -> Result<()> {
-        fn validate_file_path(
-            path: &Option<String>,
-            name: &str,
-        ) -> Result<()> {
-            if let Some(path) = path {
-                match path.chars().next() {
-                    None => bail!("{name:?} fields in control files must not be empty"),
-                    Some('/') | Some('~') => {},
-                    _ => bail!("{name:?} fields in control files must begin with '/' or '~'"),
-                }
+/// Normalizes a path setting lexically.
+///
+/// Repeated and trailing separators and `.` segments are removed. Symbolic
+/// links are not consulted. For example, `~/./.ssh//authorized_keys` is
+/// normalized to `~/.ssh/authorized_keys`.
+///
+/// # Errors
+/// The function will fail if `path` contains `..` segments, which could be
+/// used to escape the intended directory.
+pub fn normalize_path(path: &str) -> Result<String> {
+    let mut segments = path.split('/');
+
+    // Keep the root marker: "" for '/', "~" for home and so on
+    let mut result = String::from(segments.next().unwrap_or_default());
+
+    for segment in segments {
+        match segment {
+            "" | "." => {}
+            ".." => bail!("path {path:?} must not contain '..' segments"),
+            _ => {
+                result.push('/');
+                result.push_str(segment);
             }
-            Ok(())
         }
+    }
+
+    if result.is_empty() && path.starts_with('/') {
+        result.push('/');
+    }
 
-        validate_file_path(&data.config, "config")?;
-        validate_file_path(&data.authorized_keys, "authorized_keys")?;
+    Ok(result)
+}
 
-        Ok(())
+/// Replaces `%`-tokens in `template` with the values returned by `token`.
+///
+/// `%%` always stands for a literal `%`. `token` returns [`None`] for tokens
+/// it does not know.
+fn substitute<F>(template: &str, mut token: F) -> Result<String>
+where
+    F: FnMut(char) -> Result<Option<String>>,
+{
+    let mut result = String::with_capacity(template.len());
+    let mut chars = template.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            result.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('%') => result.push('%'),
+            Some(other) => match token(other)? {
+                Some(value) => result.push_str(&value),
+                None => bail!("unknown token %{other} in {template:?}"),
+            },
+            None => bail!("incomplete token at the end of {template:?}"),
+        }
     }
 
-    /// Returns a [`Control`] structure for given user.
-    #[must_use]
-    pub fn get_user_control(&self, uid: uid_t) -> Control {
-        let mut result = self.fallback.clone();
+    Ok(result)
+}
 
-        if let Some(overrides) = self.users.get(&uid) {
-            result.fill_from(overrides);
+/// Returns the username of `user` for use in tokens.
+fn token_username(user: &User) -> Result<&str> {
+    user.name().to_str().ok_or_else(|| {
+        anyhow!("username {:?} is not valid UTF-8", user.name())
+    })
+}
+
+/// Expands `sshd_config(5)`-style tokens in a path setting of `user`.
+///
+/// `%u` is replaced with the username, `%U` with the UID and `%%` with a
+/// literal `%`.
+///
+/// # Errors
+/// The function will fail if `template` contains an unknown or incomplete
+/// token, or if the username cannot be used as a single path segment.
+fn expand_tokens(template: &str, user: &User) -> Result<String> {
+    substitute(template, |token| match token {
+        'U' => Ok(Some(user.uid().to_string())),
+        'u' => {
+            let name = token_username(user)?;
+            if name.is_empty()
+                || name == "."
+                || name == ".."
+                || name.contains('/')
+            {
+                bail!("username {name:?} cannot be used in a path");
+            }
+            Ok(Some(name.to_owned()))
         }
+        _ => Ok(None),
+    })
+}
 
-        result
+/// Expands `sshd_config(5)`-style tokens in an entry of [`Control::commands`]
+/// of `user`.
+///
+/// `%u` is replaced with the username, `%U` with the UID, `%h` with the home
+/// directory and `%%` with a literal `%`. Values are inserted verbatim, since
+/// the whole command is quoted when it is rendered.
+///
+/// # Errors
+/// The function will fail if `template` contains an unknown or incomplete
+/// token, or if a value is not valid UTF-8 or missing.
+pub fn expand_command(template: &str, user: &User) -> Result<String> {
+    substitute(template, |token| match token {
+        'U' => Ok(Some(user.uid().to_string())),
+        'u' => token_username(user).map(|name| Some(name.to_owned())),
+        'h' => {
+            let home = home_dir(user).ok_or_else(|| {
+                anyhow!("user {:?} has no home directory", user.name())
+            })?;
+            let home = home.to_str().ok_or_else(|| {
+                anyhow!(
+                    "home directory {} is not valid UTF-8",
+                    home.display()
+                )
+            })?;
+            Ok(Some(home.to_owned()))
+        }
+        _ => Ok(None),
+    })
+}
+
+/// Error of [`resolve_path`] for a user without a home directory.
+#[derive(Debug)]
+pub struct NoHomeDir {
+    /// Username, lossily converted to UTF-8.
+    pub user: String,
+}
+
+impl fmt::Display for NoHomeDir {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "user {:?} has no home directory", self.user)
+    }
+}
+
+impl std::error::Error for NoHomeDir {}
+
+/// Resolves a path setting of `user` into an absolute path.
+///
+/// A leading `~` is replaced with the home directory of `user`. The path is
+/// [normalized][normalize_path] first, then [tokens][expand_tokens] such as
+/// `%u` are expanded.
+///
+/// # Errors
+/// The function will fail with [`NoHomeDir`] if `template` begins with `~`
+/// but `user` has no home directory. It will also fail if `template` is
+/// neither absolute nor relative to home, if some token could not be
+/// expanded, or if [`normalize_path`] complains.
+pub fn resolve_path(template: &str, user: &User) -> Result<PathBuf> {
+    let template = &expand_tokens(&normalize_path(template)?, user)?;
+
+    if template.starts_with('~') {
+        let home = home_dir(user).ok_or_else(|| NoHomeDir {
+            user: user.name().to_string_lossy().into_owned(),
+        })?;
+
+        let rest = &template[1..];
+        if rest.is_empty() {
+            return Ok(home.to_path_buf());
+        }
+        if !rest.starts_with('/') {
+            bail!("path {template:?} must begin with \"~/\"");
+        }
+
+        return Ok(home.join(&rest[1..]));
+    }
+
+    if !template.starts_with('/') {
+        bail!("path {template:?} must begin with '/' or '~'");
+    }
+
+    Ok(PathBuf::from(template))
+}
+
+/// Moves absolute `path` into `root`, if any.
+///
+/// For example, `/home/alice/.ssh/authorized_keys` rerooted into `/tmp/box`
+/// becomes `/tmp/box/home/alice/.ssh/authorized_keys`.
+///
+/// # Errors
+/// The function will fail if `path` is not absolute or contains `..`
+/// components, which could escape `root`.
+pub fn reroot(path: &Path, root: Option<&Path>) -> Result<PathBuf> {
+    let root = match root {
+        Some(root) => root,
+        None => return Ok(path.to_path_buf()),
+    };
+
+    if !path.is_absolute() {
+        bail!("path {} is not absolute", path.display());
+    }
+
+    let mut result = root.to_path_buf();
+    for component in path.components() {
+        match component {
+            Component::RootDir | Component::CurDir => {}
+            Component::Normal(name) => result.push(name),
+            _ => bail!("path {} cannot be rerooted", path.display()),
+        }
+    }
+
+    Ok(result)
+}
+
+/// Handling of keys that already force a command of their own.
+///
+/// `sshd(8)` only honors one forced command per key line, so keys that force
+/// a command different from the one narrowssh generates cannot be installed
+/// as-is.
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum CommandConflict {
+    /// Refuse to install any keys for the user.
+    #[default]
+    Error,
+
+    /// Leave out conflicting keys with a warning.
+    Skip,
+
+    /// Replace the command of conflicting keys with a warning.
+    Override,
+}
+
+/// Handling of rendered key lines longer than [`Control::max_line_length`].
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum LongLine {
+    /// Install the line anyway with a warning.
+    #[default]
+    Warn,
+
+    /// Refuse to install any keys for the user.
+    Error,
+}
+
+/// Handling of users with more keys than [`Control::max_keys`].
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum TooManyKeys {
+    /// Refuse to install any keys for the user.
+    #[default]
+    Error,
+
+    /// Install the first keys up to the limit with a warning.
+    Truncate,
+}
+
+/// What the managed block of an enabled user grants.
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum KeysAction {
+    /// Keys may run the allowed [`Control::commands`].
+    #[default]
+    Allow,
+
+    /// Keys are installed, but every login only prints
+    /// [`DENY_COMMAND`][crate::authorized_keys::DENY_COMMAND]'s message and
+    /// ends.
+    ///
+    /// Unlike disabling the user, which removes the managed block, this keeps
+    /// the keys on record while access is revoked, e.g. for offboarding.
+    Deny,
+}
+
+impl KeysAction {
+    /// Returns whether this is the default, for use by serde.
+    #[allow(clippy::trivially_copy_pass_by_ref)] // Required by serde
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Owner of `authorized_keys` files written by narrowssh.
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum KeysOwner {
+    /// The file belongs to the user, as is usual for files in home
+    /// directories.
+    #[default]
+    User,
+
+    /// The file belongs to root, as is usual for central locations such as
+    /// `/etc/ssh/authorized_keys/%u`.
+    ///
+    /// Missing directories are created with mode `0711` so that `sshd(8)` can
+    /// reach the file with the privileges of the user.
+    Root,
+}
+
+/// A user's control settings.
+#[allow(clippy::struct_excessive_bools)] // Mirrors independent control fields
+#[derive(Clone, Debug, Serialize)]
+pub struct Control {
+    /// Killswitch for all functionality.
+    pub enable: bool,
+
+    /// Whether the keys of this user grant or deny access.
+    #[serde(skip_serializing_if = "KeysAction::is_default")]
+    pub action: KeysAction,
+
+    /// Path to user-defined config.
+    ///
+    /// The config extensions directory, referred to as `config.d`, is resolved
+    /// to `config + '.d/'`.
+    ///
+    /// Note that this file, `config.d` and its contents must be owned by the
+    /// user, and have modes `0600` or `0400` for files and `0700` or `0500`
+    /// for `config.d`; otherwise, all user configuration is ignored.
+    ///
+    /// This path must either begin with a `/` to denote an absolute path,
+    /// or with a `~` to denote a path relative to the home directory of the
+    /// user. This path cannot end with a `/`.
+    pub config: String,
+
+    /// Path to the `authorized_keys(5)` file of this user.
+    ///
+    /// This path must either begin with a `/` to denote an absolute path,
+    /// or with a `~` to denote a path relative to the home directory of the
+    /// user. This path cannot end with a `/`. Tokens `%u`, `%U` and `%%` are
+    /// expanded as in `sshd_config(5)`.
+    pub authorized_keys: String,
+
+    /// Owner of the `authorized_keys` file and of its directory if it has to
+    /// be created.
+    pub authorized_keys_owner: KeysOwner,
+
+    /// Permissions of the `authorized_keys` file.
+    ///
+    /// If unset, `0600` is used for [`KeysOwner::User`] and `0644` for
+    /// [`KeysOwner::Root`]. The file must not be writable by group or others.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub authorized_keys_mode: Option<u32>,
+
+    /// Group of the `authorized_keys` file and of its directory if it has to
+    /// be created.
+    ///
+    /// If unset, the primary group of the user is used for
+    /// [`KeysOwner::User`] and group 0 for [`KeysOwner::Root`]. The group must
+    /// exist when the file is written.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub authorized_keys_gid: Option<gid_t>,
+
+    /// Commands that keys of this user are allowed to run.
+    ///
+    /// Every key installed by narrowssh is forced to run
+    /// [`EXEC_COMMAND`][crate::authorized_keys::EXEC_COMMAND] with these
+    /// commands as arguments, which only runs the requested command if
+    /// [`authorize`][crate::allowlist::authorize] allows it.
+    ///
+    /// Tokens such as `%u` are expanded for every user when the managed
+    /// block is rendered, see [`expand_command`].
+    pub commands: Vec<String>,
+
+    /// Handling of keys that already force a command of their own.
+    pub command_conflict: CommandConflict,
+
+    /// Length in bytes above which a rendered key line is considered unsafe.
+    pub max_line_length: usize,
+
+    /// Handling of key lines longer than `max_line_length`.
+    pub long_line: LongLine,
+
+    /// Number of keys above which the keys of this user are suspicious.
+    ///
+    /// This guards against a compromised key source that floods
+    /// `authorized_keys`. Revoked keys grant nothing and are not counted.
+    /// Unlimited if unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_keys: Option<usize>,
+
+    /// Handling of users with more keys than `max_keys`.
+    pub too_many_keys: TooManyKeys,
+
+    /// Key types recognized in addition to
+    /// [`KNOWN_KEY_TYPES`][crate::authorized_keys::KNOWN_KEY_TYPES].
+    ///
+    /// Keys of other types are still installed, but with a warning.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub extra_key_types: Vec<String>,
+
+    /// Whether keys of this user may forward TCP ports.
+    #[serde(skip_serializing_if = "is_false")]
+    pub port_forwarding: bool,
+
+    /// Whether keys of this user may forward X11 connections.
+    #[serde(skip_serializing_if = "is_false")]
+    pub x11_forwarding: bool,
+
+    /// Whether keys of this user may forward the authentication agent.
+    #[serde(skip_serializing_if = "is_false")]
+    pub agent_forwarding: bool,
+
+    /// Whether keys of this user may allocate a terminal.
+    #[serde(skip_serializing_if = "is_false")]
+    pub pty: bool,
+
+    /// Whether to test the configuration of `sshd(8)` after the
+    /// `authorized_keys` file of this user changed.
+    ///
+    /// A failed test is reported, but changes are not rolled back. See
+    /// [`crate::sshd`].
+    #[serde(skip_serializing_if = "is_false")]
+    pub validate_sshd: bool,
+
+    /// Absolute path to `sshd(8)` for `validate_sshd`.
+    ///
+    /// If unset, [`DEFAULT_SSHD`][crate::sshd::DEFAULT_SSHD] is used. The file
+    /// must be owned by root.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sshd_path: Option<String>,
+
+    /// Absolute path to a directory where previous managed blocks are
+    /// archived.
+    ///
+    /// Before the managed block of this user is replaced or removed, it is
+    /// stored there, see [`crate::history`]. The directory must be owned by
+    /// root and not accessible by group or others; it is created if missing.
+    /// Nothing is archived if unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub history_dir: Option<String>,
+
+    /// Number of archived blocks kept for this user in `history_dir`.
+    ///
+    /// If unset, [`DEFAULT_HISTORY_KEEP`][crate::history::DEFAULT_HISTORY_KEEP]
+    /// is used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub history_keep: Option<usize>,
+
+    /// Line that opens the managed block in `authorized_keys`.
+    ///
+    /// If unset, [`BEGIN_MARKER`][crate::authorized_keys::BEGIN_MARKER] is
+    /// used. Blocks written with different markers are not recognized, so
+    /// changing the markers leaves existing blocks behind.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub begin_marker: Option<String>,
+
+    /// Line that closes the managed block in `authorized_keys`.
+    ///
+    /// If unset, [`END_MARKER`][crate::authorized_keys::END_MARKER] is used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end_marker: Option<String>,
+
+    /// Whether keys are taken from the `*.pub` files in [`PUB_KEYS_DIR`] of
+    /// this user if their configuration file does not exist.
+    ///
+    /// This lets users without a configuration file, e.g. in a self-service
+    /// refresh, have their own public keys installed with the commands
+    /// forced by control. The files are checked like configuration, see
+    /// [`Config::load_pub_keys`].
+    #[serde(skip_serializing_if = "is_false")]
+    pub pub_keys_fallback: bool,
+
+    /// Whether user configuration that cannot be loaded fails the refresh of
+    /// this user.
+    ///
+    /// By default, such configuration, e.g. an insecure `config.d`, is
+    /// ignored with a warning and the user gets no keys of their own, as if
+    /// the configuration was empty.
+    #[serde(skip_serializing_if = "is_false")]
+    pub strict_user_config: bool,
+
+    /// Names of command groups that the user may enable.
+    ///
+    /// Command groups are defined in the [`COMMAND_GROUPS_SECTION`] of
+    /// control, and users enable them with `enable_commands` in their
+    /// configuration. The commands of enabled groups are allowed in addition
+    /// to [`commands`][Self::commands].
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub command_groups: Vec<String>,
+
+    /// Commands of the groups in [`command_groups`][Self::command_groups],
+    /// by group name.
+    ///
+    /// Filled in by [`ControlManager::get_user_control`].
+    #[serde(skip)]
+    pub group_commands: BTreeMap<String, Vec<String>>,
+}
+
+/// Returns whether `value` is `false`, for use by serde.
+#[allow(clippy::trivially_copy_pass_by_ref)] // Required by serde
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+impl Default for Control {
+    /// Returns the built-in defaults used when no control file sets a field.
+    fn default() -> Self {
+        Self {
+            enable: false,
+            action: KeysAction::default(),
+            config: String::from(DEFAULT_USER_CONFIG),
+            authorized_keys: String::from(DEFAULT_AUTHORIZED_KEYS),
+            authorized_keys_owner: KeysOwner::default(),
+            authorized_keys_mode: None,
+            authorized_keys_gid: None,
+            commands: Vec::new(),
+            command_conflict: CommandConflict::default(),
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+            long_line: LongLine::default(),
+            max_keys: None,
+            too_many_keys: TooManyKeys::default(),
+            extra_key_types: Vec::new(),
+            port_forwarding: false,
+            x11_forwarding: false,
+            agent_forwarding: false,
+            pty: false,
+            validate_sshd: false,
+            sshd_path: None,
+            history_dir: None,
+            history_keep: None,
+            begin_marker: None,
+            end_marker: None,
+            pub_keys_fallback: false,
+            strict_user_config: false,
+            command_groups: Vec::new(),
+            group_commands: BTreeMap::new(),
+        }
+    }
+}
+
+/// Copy of `Control` struct with every field wrapped in an Option.
+///
+/// Additionally, `profile` names a profile to inherit unset fields from, and
+/// `commands_file` names a file of further commands. The latter is merged
+/// into `commands` as soon as the section is read, so it is always unset
+/// afterwards.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub(crate) struct IncompleteControl {
+    pub profile: Option<String>,
+    pub enable: Option<bool>,
+    pub action: Option<KeysAction>,
+    pub config: Option<String>,
+    pub authorized_keys: Option<String>,
+    pub authorized_keys_owner: Option<KeysOwner>,
+    pub authorized_keys_mode: Option<u32>,
+    pub authorized_keys_gid: Option<gid_t>,
+    pub commands: Option<Vec<String>>,
+    pub commands_file: Option<String>,
+    pub command_conflict: Option<CommandConflict>,
+    pub max_line_length: Option<usize>,
+    pub long_line: Option<LongLine>,
+    pub max_keys: Option<usize>,
+    pub too_many_keys: Option<TooManyKeys>,
+    pub extra_key_types: Option<Vec<String>>,
+    pub port_forwarding: Option<bool>,
+    pub x11_forwarding: Option<bool>,
+    pub agent_forwarding: Option<bool>,
+    pub pty: Option<bool>,
+    pub validate_sshd: Option<bool>,
+    pub sshd_path: Option<String>,
+    pub history_dir: Option<String>,
+    pub history_keep: Option<usize>,
+    pub begin_marker: Option<String>,
+    pub end_marker: Option<String>,
+    pub pub_keys_fallback: Option<bool>,
+    pub strict_user_config: Option<bool>,
+    pub command_groups: Option<Vec<String>>,
+}
+
+impl Control {
+    /// Returns this control with [`commands`][Self::commands] expanded for
+    /// `user`, see [`expand_command`].
+    ///
+    /// # Errors
+    /// The function will fail if some command could not be expanded.
+    pub fn expand_commands(&self, user: &User) -> Result<Self> {
+        let commands = self
+            .commands
+            .iter()
+            .map(|command| {
+                expand_command(command, user)
+                    .with_context(|| format!("expanding command {command:?}"))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            commands,
+            ..self.clone()
+        })
+    }
+
+    fn fill_from(&mut self, source: &IncompleteControl) {
+        if let Some(enable) = source.enable {
+            self.enable = enable;
+        }
+
+        if let Some(action) = source.action {
+            self.action = action;
+        }
+
+        if let Some(config) = &source.config {
+            self.config.clone_from(config);
+        }
+
+        if let Some(authorized_keys) = &source.authorized_keys {
+            self.authorized_keys.clone_from(authorized_keys);
+        }
+
+        if let Some(owner) = source.authorized_keys_owner {
+            self.authorized_keys_owner = owner;
+        }
+
+        if let Some(mode) = source.authorized_keys_mode {
+            self.authorized_keys_mode = Some(mode);
+        }
+
+        if let Some(gid) = source.authorized_keys_gid {
+            self.authorized_keys_gid = Some(gid);
+        }
+
+        if let Some(commands) = &source.commands {
+            self.commands.clone_from(commands);
+        }
+
+        if let Some(command_conflict) = source.command_conflict {
+            self.command_conflict = command_conflict;
+        }
+
+        if let Some(max_line_length) = source.max_line_length {
+            self.max_line_length = max_line_length;
+        }
+
+        if let Some(long_line) = source.long_line {
+            self.long_line = long_line;
+        }
+
+        if let Some(max_keys) = source.max_keys {
+            self.max_keys = Some(max_keys);
+        }
+
+        if let Some(too_many_keys) = source.too_many_keys {
+            self.too_many_keys = too_many_keys;
+        }
+
+        if let Some(extra_key_types) = &source.extra_key_types {
+            self.extra_key_types.clone_from(extra_key_types);
+        }
+
+        if let Some(port_forwarding) = source.port_forwarding {
+            self.port_forwarding = port_forwarding;
+        }
+
+        if let Some(x11_forwarding) = source.x11_forwarding {
+            self.x11_forwarding = x11_forwarding;
+        }
+
+        if let Some(agent_forwarding) = source.agent_forwarding {
+            self.agent_forwarding = agent_forwarding;
+        }
+
+        if let Some(pty) = source.pty {
+            self.pty = pty;
+        }
+
+        if let Some(validate_sshd) = source.validate_sshd {
+            self.validate_sshd = validate_sshd;
+        }
+
+        if let Some(sshd_path) = &source.sshd_path {
+            self.sshd_path = Some(sshd_path.clone());
+        }
+
+        if let Some(history_dir) = &source.history_dir {
+            self.history_dir = Some(history_dir.clone());
+        }
+
+        if let Some(history_keep) = source.history_keep {
+            self.history_keep = Some(history_keep);
+        }
+
+        if let Some(begin_marker) = &source.begin_marker {
+            self.begin_marker = Some(begin_marker.clone());
+        }
+
+        if let Some(end_marker) = &source.end_marker {
+            self.end_marker = Some(end_marker.clone());
+        }
+
+        if let Some(pub_keys_fallback) = source.pub_keys_fallback {
+            self.pub_keys_fallback = pub_keys_fallback;
+        }
+
+        if let Some(strict_user_config) = source.strict_user_config {
+            self.strict_user_config = strict_user_config;
+        }
+
+        if let Some(command_groups) = &source.command_groups {
+            self.command_groups.clone_from(command_groups);
+        }
+    }
+}
+
+impl IncompleteControl {
+    fn fill_from(&mut self, source: &IncompleteControl) {
+        if let Some(profile) = &source.profile {
+            self.profile = Some(profile.clone());
+        }
+
+        if let Some(enable) = source.enable {
+            self.enable = Some(enable);
+        }
+
+        if let Some(action) = source.action {
+            self.action = Some(action);
+        }
+
+        if let Some(config) = &source.config {
+            self.config = Some(config.clone());
+        }
+
+        if let Some(authorized_keys) = &source.authorized_keys {
+            self.authorized_keys = Some(authorized_keys.clone());
+        }
+
+        if let Some(owner) = source.authorized_keys_owner {
+            self.authorized_keys_owner = Some(owner);
+        }
+
+        if let Some(mode) = source.authorized_keys_mode {
+            self.authorized_keys_mode = Some(mode);
+        }
+
+        if let Some(gid) = source.authorized_keys_gid {
+            self.authorized_keys_gid = Some(gid);
+        }
+
+        if let Some(commands) = &source.commands {
+            self.commands = Some(commands.clone());
+        }
+
+        if let Some(command_conflict) = source.command_conflict {
+            self.command_conflict = Some(command_conflict);
+        }
+
+        if let Some(max_line_length) = source.max_line_length {
+            self.max_line_length = Some(max_line_length);
+        }
+
+        if let Some(long_line) = source.long_line {
+            self.long_line = Some(long_line);
+        }
+
+        if let Some(max_keys) = source.max_keys {
+            self.max_keys = Some(max_keys);
+        }
+
+        if let Some(too_many_keys) = source.too_many_keys {
+            self.too_many_keys = Some(too_many_keys);
+        }
+
+        if let Some(extra_key_types) = &source.extra_key_types {
+            self.extra_key_types = Some(extra_key_types.clone());
+        }
+
+        if let Some(port_forwarding) = source.port_forwarding {
+            self.port_forwarding = Some(port_forwarding);
+        }
+
+        if let Some(x11_forwarding) = source.x11_forwarding {
+            self.x11_forwarding = Some(x11_forwarding);
+        }
+
+        if let Some(agent_forwarding) = source.agent_forwarding {
+            self.agent_forwarding = Some(agent_forwarding);
+        }
+
+        if let Some(pty) = source.pty {
+            self.pty = Some(pty);
+        }
+
+        if let Some(validate_sshd) = source.validate_sshd {
+            self.validate_sshd = Some(validate_sshd);
+        }
+
+        if let Some(sshd_path) = &source.sshd_path {
+            self.sshd_path = Some(sshd_path.clone());
+        }
+
+        if let Some(history_dir) = &source.history_dir {
+            self.history_dir = Some(history_dir.clone());
+        }
+
+        if let Some(history_keep) = source.history_keep {
+            self.history_keep = Some(history_keep);
+        }
+
+        if let Some(begin_marker) = &source.begin_marker {
+            self.begin_marker = Some(begin_marker.clone());
+        }
+
+        if let Some(end_marker) = &source.end_marker {
+            self.end_marker = Some(end_marker.clone());
+        }
+
+        if let Some(pub_keys_fallback) = source.pub_keys_fallback {
+            self.pub_keys_fallback = Some(pub_keys_fallback);
+        }
+
+        if let Some(strict_user_config) = source.strict_user_config {
+            self.strict_user_config = Some(strict_user_config);
+        }
+
+        if let Some(command_groups) = &source.command_groups {
+            self.command_groups = Some(command_groups.clone());
+        }
+    }
+
+    /// Returns the names of fields that are set, except `profile`.
+    fn set_fields(&self) -> Vec<&'static str> {
+        [
+            ("enable", self.enable.is_some()),
+            ("action", self.action.is_some()),
+            ("config", self.config.is_some()),
+            ("authorized_keys", self.authorized_keys.is_some()),
+            (
+                "authorized_keys_owner",
+                self.authorized_keys_owner.is_some(),
+            ),
+            ("authorized_keys_mode", self.authorized_keys_mode.is_some()),
+            ("authorized_keys_gid", self.authorized_keys_gid.is_some()),
+            ("commands", self.commands.is_some()),
+            ("command_conflict", self.command_conflict.is_some()),
+            ("max_line_length", self.max_line_length.is_some()),
+            ("long_line", self.long_line.is_some()),
+            ("max_keys", self.max_keys.is_some()),
+            ("too_many_keys", self.too_many_keys.is_some()),
+            ("extra_key_types", self.extra_key_types.is_some()),
+            ("port_forwarding", self.port_forwarding.is_some()),
+            ("x11_forwarding", self.x11_forwarding.is_some()),
+            ("agent_forwarding", self.agent_forwarding.is_some()),
+            ("pty", self.pty.is_some()),
+            ("validate_sshd", self.validate_sshd.is_some()),
+            ("sshd_path", self.sshd_path.is_some()),
+            ("history_dir", self.history_dir.is_some()),
+            ("history_keep", self.history_keep.is_some()),
+            ("begin_marker", self.begin_marker.is_some()),
+            ("end_marker", self.end_marker.is_some()),
+            ("pub_keys_fallback", self.pub_keys_fallback.is_some()),
+            ("strict_user_config", self.strict_user_config.is_some()),
+            ("command_groups", self.command_groups.is_some()),
+        ]
+        .iter()
+        .filter(|(_, set)| *set)
+        .map(|(name, _)| *name)
+        .collect()
+    }
+}
+
+/// Users that a section of a control file applies to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    /// All users, in a section called `*`.
+    All,
+
+    /// Users matched by a group, range or pattern [selector][UserSelector].
+    Selected(Vec<uid_t>),
+
+    /// A single user named by username or UID.
+    User(uid_t),
+
+    /// No users; the section defines a profile with given name that other
+    /// sections may inherit with the `profile` setting.
+    Profile(String),
+}
+
+impl Target {
+    /// Returns whether the section applies to the user with given UID.
+    #[must_use]
+    pub fn matches(&self, uid: uid_t) -> bool {
+        match self {
+            Self::All => true,
+            Self::Selected(uids) => uids.contains(&uid),
+            Self::User(target) => *target == uid,
+            Self::Profile(_) => false,
+        }
+    }
+
+    /// Returns the precedence of such sections; higher values win.
+    #[must_use]
+    pub fn precedence(&self) -> u8 {
+        match self {
+            Self::All | Self::Profile(_) => 0,
+            Self::Selected(_) => 1,
+            Self::User(_) => 2,
+        }
+    }
+}
+
+/// A section of a control file, recorded for diagnostics.
+#[derive(Clone, Debug)]
+pub struct Section {
+    /// File that contains the section.
+    pub file: PathBuf,
+
+    /// Name of the section as written, e.g. `alice`, `@admins` or `*`.
+    pub name: String,
+
+    /// Users the section applies to.
+    pub target: Target,
+
+    /// Settings that appear in the section.
+    pub fields: Vec<String>,
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "section {:?} in {}", self.name, self.file.display())
+    }
+}
+
+/// Sections defined by a single control file, see [`describe_file`].
+#[derive(Clone, Debug)]
+pub struct FileDescription {
+    /// The described file.
+    pub file: PathBuf,
+
+    /// Sections of the file, in order.
+    pub sections: Vec<Section>,
+}
+
+impl fmt::Display for FileDescription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = self.sections.iter().map(|s| &*s.name).collect();
+        write!(
+            f,
+            "{} defines sections [{}]",
+            self.file.display(),
+            names.join(", ")
+        )
+    }
+}
+
+/// Describes a single control file without loading the rest of control.
+///
+/// `path` passes the same security checks as the main control file in
+/// [`visit_config_files`] and must be owned by root, but its own `{path}.d`
+/// directory is ignored, so that a drop-in file may be described on its own.
+/// Sections are parsed and validated like in [`ControlManager::load`], but
+/// they are neither merged nor checked against profiles of other files.
+///
+/// Only [`VisitOptions::realm`] of `options` matters.
+///
+/// # Errors
+/// The function will fail if [`visit_config_files`] complains, or if the file
+/// could not be parsed or has an invalid section.
+pub fn describe_file<W, P>(
+    path: P,
+    ws: &W,
+    options: &VisitOptions,
+) -> Result<FileDescription>
+where
+    W: Workspace,
+    P: AsRef<Path>,
+{
+    let options = VisitOptions {
+        extensions: false,
+        extension_owner: None,
+        allow_root_owner: false,
+        ..options.clone()
+    };
+    let mut sections = Vec::new();
+
+    let process = |file: &Path| -> Result<()> {
+        let content = read_utf8(file, "control")?;
+        let parsed = ControlManager::default()
+            .read_sections(ws, file, &content, &options, true, &mut None)?;
+        sections =
+            parsed.into_iter().map(|(section, _, _)| section).collect();
+        Ok(())
+    };
+
+    visit_config_files(path.as_ref(), 0, &options, process, ws)
+        .context("could not describe control file")?;
+
+    Ok(FileDescription {
+        file: path.as_ref().to_path_buf(),
+        sections,
+    })
+}
+
+/// Two group, range or pattern sections that set a field of a user
+/// differently.
+///
+/// The later section wins, so the result depends on the order of sections
+/// and files, which is rarely intended.
+#[derive(Clone, Debug)]
+pub struct Conflict {
+    /// UID of the affected user.
+    pub uid: uid_t,
+
+    /// Name of the setting.
+    pub field: String,
+
+    /// Section whose value is overridden.
+    pub overridden: Section,
+
+    /// Section whose value wins.
+    pub winner: Section,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} of UID {} is set differently by {} and {}; \
+            the latter wins only because it comes later",
+            self.field, self.uid, self.overridden, self.winner
+        )
+    }
+}
+
+/// A setting of a user that two [`ControlManager`]s disagree on, see
+/// [`ControlManager::diff`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct ControlDifference {
+    /// UID of the affected user.
+    pub uid: uid_t,
+
+    /// Name of the setting.
+    ///
+    /// The commands of the command groups offered to the user are compared
+    /// as a setting named [`COMMAND_GROUPS_SECTION`].
+    pub field: String,
+
+    /// Value in the first control, if set.
+    pub ours: Option<toml::Value>,
+
+    /// Value in the second control, if set.
+    pub theirs: Option<toml::Value>,
+}
+
+impl fmt::Display for ControlDifference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |value: &Option<toml::Value>| match value {
+            Some(value) => value.to_string(),
+            None => String::from("(unset)"),
+        };
+        write!(
+            f,
+            "UID {}: {}: {} -> {}",
+            self.uid,
+            self.field,
+            show(&self.ours),
+            show(&self.theirs)
+        )
+    }
+}
+
+/// Returns the user named `name`, possibly qualified with `realm`.
+///
+/// An exact match wins. Otherwise, `name@{realm}` and `{realm}\name` are
+/// tried; see [`VisitOptions::realm`].
+///
+/// # Errors
+/// The function will fail if the matching username is not unique, or if both
+/// qualified forms exist as different users.
+fn find_user<'a>(
+    users: &'a UserMap,
+    name: &str,
+    realm: Option<&str>,
+) -> Result<Option<&'a User>> {
+    if let Some(user) = users.user_by_username(name)? {
+        return Ok(Some(user));
+    }
+
+    let realm = match realm {
+        Some(realm) => realm,
+        None => return Ok(None),
+    };
+
+    let suffixed = users.user_by_username(format!("{name}@{realm}"))?;
+    let prefixed = users.user_by_username(format!("{realm}\\{name}"))?;
+
+    match (suffixed, prefixed) {
+        (Some(a), Some(b)) if a.uid() != b.uid() => {
+            bail!("both {name}@{realm} and {realm}\\{name} exist")
+        }
+        (Some(user), _) | (None, Some(user)) => Ok(Some(user)),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Returns the UIDs of all users that [`find_user`] could mean by `name`.
+fn sharing_name(
+    users: &UserMap,
+    name: &str,
+    realm: Option<&str>,
+) -> Vec<uid_t> {
+    let mut names = vec![name.to_owned()];
+    if let Some(realm) = realm {
+        names.push(format!("{name}@{realm}"));
+        names.push(format!("{realm}\\{name}"));
+    }
+
+    users
+        .all_users()
+        .filter(|u| names.iter().any(|n| u.name() == OsStr::new(n)))
+        .map(User::uid)
+        .collect()
+}
+
+/// Manages the control settings for all users.
+///
+/// Settings of a user are taken from sections naming the user, then from
+/// group, range and pattern sections, then from `*` sections. Among sections
+/// of the same kind, later sections win.
+///
+/// Pattern sections such as `svc-*` apply to all users whose username
+/// matches the glob, see [`UserSelector::Pattern`]. Being more specific than
+/// `*`, they win over it.
+#[derive(Debug, Default)]
+pub struct ControlManager {
+    /// Overrides for individual users.
+    users: HashMap<uid_t, IncompleteControl>,
+
+    /// Overrides from group, range and pattern sections.
+    selected: HashMap<uid_t, IncompleteControl>,
+
+    /// Default values for all other users.
+    fallback: Control,
+
+    /// Files that were read, in order.
+    files: Vec<PathBuf>,
+
+    /// Sections that were read, in order.
+    sections: Vec<Section>,
+
+    /// Order-dependent conflicts between group, range and pattern sections.
+    conflicts: Vec<Conflict>,
+
+    /// Commands of every command group, by group name.
+    command_groups: BTreeMap<String, Vec<String>>,
+
+    /// Problems that did not prevent loading, in order.
+    warnings: Vec<String>,
+
+    /// Users named by sections that were skipped as ambiguous.
+    ambiguous: BTreeSet<uid_t>,
+}
+
+impl ControlManager {
+    /// Ensures that known fields of `section` have the expected TOML types.
+    ///
+    /// This produces clearer messages than deserialization, naming `file`,
+    /// the section `name`, the field, and both the expected and the found
+    /// type. Unknown fields are left for deserialization to report.
+    ///
+    /// # Errors
+    /// Fails if some known field has a value of the wrong type.
+    fn check_types(
+        file: &Path,
+        name: &str,
+        section: &toml::Table,
+    ) -> Result<()> {
+        for (key, value) in section {
+            let field = CONTROL_FIELDS.iter().find(|f| f.name == key);
+
+            if let Some(field) = field {
+                if !field.field_type.accepts(value) {
+                    bail!(
+                        "{}: in section {name:?}, field {key:?}: expected {}, \
+                         found {}",
+                        file.display(),
+                        field.field_type.toml_type(),
+                        describe_toml_type(value),
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads a single control `file`, checking its signature if required.
+    ///
+    /// # Errors
+    /// The function will fail if `file` could not be read or is not valid
+    /// UTF-8, or if its signature is missing or invalid while
+    /// [`VisitOptions`] require signatures.
+    fn read_file(file: &Path, options: &VisitOptions) -> Result<String> {
+        let content = read_utf8(file, "control")?;
+
+        #[cfg(feature = "signatures")]
+        if let Some(signers) = &options.signers {
+            crate::signature::verify_signature(
+                file,
+                content.as_bytes(),
+                signers,
+            )?;
+        }
+        #[cfg(not(feature = "signatures"))]
+        let _ = options;
+
+        Ok(content)
+    }
+
+    /// Parses the sections of a single control `file` with given `content`.
+    ///
+    /// Every section is returned in order along with its settings and the
+    /// raw values of its fields. Profiles are not applied. Command groups
+    /// defined by the [`COMMAND_GROUPS_SECTION`] are added to this control
+    /// instead, replacing groups of the same name. So are warnings about
+    /// sections skipped according to [`VisitOptions::ambiguous_names`].
+    ///
+    /// The [`ALLOW_KEYS`] setting of the `main` file is stored in
+    /// `allow_keys`. Extension files are restricted to the fields it lists.
+    ///
+    /// # Errors
+    /// The function will fail if `content` could not be parsed, if some
+    /// section is invalid, or if an extension sets a field that
+    /// `allow_keys` does not list.
+    fn read_sections<W: Workspace>(
+        &mut self,
+        ws: &W,
+        file: &Path,
+        content: &str,
+        options: &VisitOptions,
+        main: bool,
+        allow_keys: &mut Option<Vec<String>>,
+    ) -> Result<Vec<(Section, IncompleteControl, toml::Table)>> {
+        let mut content: toml::Table = parse_toml(file, content, "control")?;
+
+        if let Some(value) = content.remove(ALLOW_KEYS) {
+            if !main {
+                bail!("{ALLOW_KEYS:?} may only be set in the main file");
+            }
+            *allow_keys = Some(Self::parse_allow_keys(value)?);
+        }
+
+        let mut result = Vec::new();
+        for (name, data) in content {
+            if let Some(allowed) = allow_keys.as_ref().filter(|_| !main) {
+                Self::check_allowed(&name, &data, allowed)?;
+            }
+
+            if name == COMMAND_GROUPS_SECTION {
+                let groups: BTreeMap<String, Vec<String>> =
+                    data.try_into()
+                        .with_context(|| format!("in section {name:?}"))?;
+                if groups.contains_key("") {
+                    bail!(
+                        "in section {name:?}: group name must not be empty"
+                    );
+                }
+                self.command_groups.extend(groups);
+                continue;
+            }
+
+            let table = data.as_table().cloned().unwrap_or_default();
+            Self::check_types(file, &name, &table)?;
+            let mut data: IncompleteControl = data.try_into()?;
+
+            Self::validate(&mut data)?;
+            Self::read_commands_file(ws, &mut data)
+                .with_context(|| format!("in section {name:?}"))?;
+
+            let target = self
+                .parse_target(ws, &name, options)
+                .with_context(|| format!("in section {name:?}"))?;
+
+            let section = Section {
+                file: file.to_path_buf(),
+                name,
+                target,
+                fields: table.keys().cloned().collect(),
+            };
+            result.push((section, data, table));
+        }
+
+        Ok(result)
+    }
+
+    /// Parses the value of the [`ALLOW_KEYS`] setting.
+    ///
+    /// # Errors
+    /// The function will fail if `value` is not an array of field names.
+    fn parse_allow_keys(value: toml::Value) -> Result<Vec<String>> {
+        let names: Vec<String> = value.try_into().with_context(|| {
+            format!("{ALLOW_KEYS:?} must list field names")
+        })?;
+
+        for name in &names {
+            let known = name == COMMAND_GROUPS_SECTION
+                || CONTROL_FIELDS.iter().any(|f| f.name == name);
+            if !known {
+                bail!("{ALLOW_KEYS:?} lists unknown field {name:?}");
+            }
+        }
+
+        Ok(names)
+    }
+
+    /// Ensures that the section called `name` of an extension only sets
+    /// fields listed in `allowed`, see [`ALLOW_KEYS`].
+    ///
+    /// # Errors
+    /// The function will fail if some field is not allowed.
+    fn check_allowed(
+        name: &str,
+        data: &toml::Value,
+        allowed: &[String],
+    ) -> Result<()> {
+        let forbidden = if name == COMMAND_GROUPS_SECTION {
+            Some(name).filter(|n| !allowed.iter().any(|a| a == n))
+        } else {
+            data.as_table().and_then(|table| {
+                table
+                    .keys()
+                    .map(String::as_str)
+                    .find(|key| !allowed.iter().any(|a| a == key))
+            })
+        };
+
+        if let Some(field) = forbidden {
+            let allowed = if allowed.is_empty() {
+                String::from("no fields")
+            } else {
+                allowed.join(", ")
+            };
+            bail!(
+                "in section {name:?}: {field:?} may not be set by extensions \
+                [the main file only allows {allowed}]"
+            );
+        }
+        Ok(())
+    }
+
+    /// Loads the control data from the filesystem.
+    ///
+    /// In particular, `from` and the contents of
+    /// `{from}.d` directory are read and parsed.
+    ///
+    /// Symbolic links are always resolved. See [`visit_config_files`] for the
+    /// meaning of `options`.
+    ///
+    /// # Errors
+    /// The load will fail in these cases:
+    ///   - some file could not be read,
+    ///   - some file is not a valid TOML file,
+    ///   - some file is not structured as a control file,
+    ///   - some file lacks a valid signature while signatures are
+    ///     required, or
+    ///   - [`visit_config_files`] complains.
+    pub fn load<W, P>(ws: &W, from: P, options: &VisitOptions) -> Result<Self>
+    where
+        W: Workspace,
+        P: AsRef<Path>,
+    {
+        let mut result = Self::default();
+
+        // Values set by group, range and pattern sections:
+        // (UID, field) -> (section index, value), and the conflicts found so
+        // far as section indices
+        let mut claims =
+            HashMap::<(uid_t, String), (usize, toml::Value)>::new();
+        let mut conflicts = Vec::new();
+
+        let mut profiles = BTreeMap::new();
+        let mut deferred = Vec::new();
+        let mut allow_keys = None;
+
+        let process = |file: &Path| -> Result<()> {
+            let main = result.files.is_empty();
+            result.files.push(file.to_path_buf());
+
+            let content = Self::read_file(file, options)?;
+            let sections = result.read_sections(
+                ws,
+                file,
+                &content,
+                options,
+                main,
+                &mut allow_keys,
+            )?;
+
+            for (section, data, table) in sections {
+                if let Target::Selected(uids) = &section.target {
+                    let index = result.sections.len();
+                    for &uid in uids {
+                        for (field, value) in &table {
+                            let key = (uid, field.clone());
+                            let claim = (index, value.clone());
+                            if let Some((other, old)) =
+                                claims.insert(key, claim)
+                            {
+                                if old != *value {
+                                    conflicts.push((
+                                        uid,
+                                        field.clone(),
+                                        other,
+                                        index,
+                                    ));
+                                }
+                            }
+                        }
+                    }
+                }
+
+                if let Target::Profile(profile) = &section.target {
+                    profiles
+                        .entry(profile.clone())
+                        .and_modify(|ic: &mut IncompleteControl| {
+                            ic.fill_from(&data);
+                        })
+                        .or_insert(data);
+                } else {
+                    // Profiles may be defined later, so apply sections last
+                    deferred.push((result.sections.len(), data));
+                }
+
+                result.sections.push(section);
+            }
+
+            Ok(())
+        };
+
+        visit_config_files(from, 0, options, process, ws)
+            .context("could not load control configuration files")?;
+
+        // Catch problems in profiles that no section uses, too
+        for (name, data) in &profiles {
+            Self::apply_profile(&profiles, data)
+                .and_then(|data| result.check_command_groups(&data))
+                .with_context(|| {
+                    format!("in {}", result.describe_profile(name))
+                })?;
+        }
+
+        for (index, data) in deferred {
+            result
+                .check_command_groups(&data)
+                .with_context(|| format!("in {}", result.sections[index]))?;
+            let section = &mut result.sections[index];
+            let data = Self::apply_profile(&profiles, &data)
+                .with_context(|| format!("in {section}"))?;
+
+            for field in data.set_fields() {
+                if !section.fields.iter().any(|f| f == field) {
+                    section.fields.push(field.to_owned());
+                }
+            }
+
+            let target = section.target.clone();
+            result.apply(&target, data);
+        }
+
+        // Conflicts do not matter if the user overrides the field anyway
+        for (uid, field, overridden, winner) in conflicts {
+            let overridden_by_user = result.sections.iter().any(|s| {
+                s.target == Target::User(uid) && s.fields.contains(&field)
+            });
+            if !overridden_by_user {
+                result.conflicts.push(Conflict {
+                    uid,
+                    field,
+                    overridden: result.sections[overridden].clone(),
+                    winner: result.sections[winner].clone(),
+                });
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Ensures that the command groups named by `data` are defined.
+    ///
+    /// # Errors
+    /// The function will fail if some group is not defined in any
+    /// [`COMMAND_GROUPS_SECTION`].
+    fn check_command_groups(&self, data: &IncompleteControl) -> Result<()> {
+        for name in data.command_groups.iter().flatten() {
+            if !self.command_groups.contains_key(name) {
+                bail!(
+                    "unknown command group {name:?} \
+                    [define it in the {COMMAND_GROUPS_SECTION:?} section]"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns `data` with the fields of its [profile][Target::Profile]
+    /// filled in.
+    ///
+    /// Fields of `data` take precedence over those of the profile, and fields
+    /// of a profile take precedence over those of the profile it inherits.
+    ///
+    /// # Errors
+    /// The function will fail if some profile does not exist or if profiles
+    /// inherit each other in a cycle.
+    fn apply_profile(
+        profiles: &BTreeMap<String, IncompleteControl>,
+        data: &IncompleteControl,
+    ) -> Result<IncompleteControl> {
+        let mut chain: Vec<&str> = Vec::new();
+        let mut next = data.profile.as_deref();
+
+        while let Some(name) = next {
+            if chain.contains(&name) {
+                chain.push(name);
+                bail!("profiles inherit each other: {}", chain.join(" -> "));
+            }
+            chain.push(name);
+
+            next = profiles
+                .get(name)
+                .ok_or_else(|| Self::unknown_profile(profiles, name))?
+                .profile
+                .as_deref();
+        }
+
+        let mut result = IncompleteControl::default();
+        for name in chain.iter().rev() {
+            result.fill_from(&profiles[*name]);
+        }
+        result.fill_from(data);
+
+        Ok(result)
+    }
+
+    /// Describes the first section that defines profile `name`.
+    fn describe_profile(&self, name: &str) -> String {
+        let target = Target::Profile(name.to_owned());
+        match self.sections.iter().find(|s| s.target == target) {
+            Some(section) => section.to_string(),
+            None => format!("profile {name:?}"),
+        }
+    }
+
+    /// Returns the error for a reference to profile `name` that is not
+    /// among `profiles`.
+    fn unknown_profile(
+        profiles: &BTreeMap<String, IncompleteControl>,
+        name: &str,
+    ) -> Error {
+        if profiles.is_empty() {
+            return anyhow!(
+                "unknown profile {name:?} [no profiles are defined; \
+                define it in section \"{PROFILE_PREFIX}{name}\"]"
+            );
+        }
+
+        let defined: Vec<_> = profiles.keys().map(String::as_str).collect();
+        anyhow!(
+            "unknown profile {name:?} [defined profiles: {}]",
+            defined.join(", ")
+        )
+    }
+
+    /// Applies `data` from a section with given `target`.
+    fn apply(&mut self, target: &Target, data: IncompleteControl) {
+        match target {
+            Target::All => self.fallback.fill_from(&data),
+            Target::Selected(uids) => {
+                for &uid in uids {
+                    self.selected
+                        .entry(uid)
+                        .and_modify(|ic| ic.fill_from(&data))
+                        .or_insert_with(|| data.clone());
+                }
+            }
+            Target::User(uid) => {
+                self.users
+                    .entry(*uid)
+                    .and_modify(|ic| ic.fill_from(&data))
+                    .or_insert(data);
+            }
+            Target::Profile(_) => unreachable!("profiles are not applied"),
+        }
+    }
+
+    /// Determines the users that the section called `name` applies to.
+    ///
+    /// Section names are `*`, `profile:{name}`, numeric UIDs and anything
+    /// accepted by [`UserSelector`]. Usernames are resolved within the realm
+    /// of `options`. Sections naming an ambiguous user are handled according
+    /// to [`VisitOptions::ambiguous_names`]; a skipped section applies to
+    /// nobody, a warning is recorded and the users sharing the name are
+    /// marked as ambiguous.
+    fn parse_target<W: Workspace>(
+        &mut self,
+        ws: &W,
+        name: &str,
+        options: &VisitOptions,
+    ) -> Result<Target> {
+        if name == "*" {
+            return Ok(Target::All);
+        }
+
+        if name.starts_with(PROFILE_PREFIX) {
+            let profile = &name[PROFILE_PREFIX.len()..];
+            if profile.is_empty() {
+                bail!("profile name must not be empty");
+            }
+            return Ok(Target::Profile(profile.to_owned()));
+        }
+
+        if let Ok(uid) = name.parse::<uid_t>() {
+            return Ok(Target::User(uid));
+        }
+
+        let selector: UserSelector = name.parse()?;
+        match &selector {
+            UserSelector::Uid(uid) => Ok(Target::User(*uid)),
+            UserSelector::Name(name) => {
+                match find_user(ws.users(), name, options.realm.as_deref()) {
+                    Ok(user) => Ok(Target::User(
+                        user.ok_or(anyhow!("unknown user"))?.uid(),
+                    )),
+                    Err(error)
+                        if options.ambiguous_names
+                            == AmbiguousNames::Skip =>
+                    {
+                        self.warnings.push(format!(
+                            "section {name:?} applies to nobody: {error}"
+                        ));
+                        self.ambiguous.extend(sharing_name(
+                            ws.users(),
+                            name,
+                            options.realm.as_deref(),
+                        ));
+                        Ok(Target::Selected(Vec::new()))
+                    }
+                    Err(error) => Err(error),
+                }
+            }
+            UserSelector::Group(_)
+            | UserSelector::Range(..)
+            | UserSelector::Pattern(_) => Ok(Target::Selected(
+                selector.matching(ws)?.iter().map(|u| u.uid()).collect(),
+            )),
+        }
+    }
+
+    /// Validates additional constraints on [`IncompleteControl`] fields in
+    /// control files.
+    ///
+    /// Paths are replaced with their [normalized][normalize_path] form.
+    fn validate(data: &mut IncompleteControl) -> Result<()> {
+        fn validate_file_path(
+            path: Option<&mut String>,
+            name: &str,
+        ) -> Result<()> {
+            if let Some(path) = path {
+                match path.chars().next() {
+                    None => bail!("{name:?} fields in control files must not be empty"),
+                    Some('/') | Some('~') => {},
+                    _ => bail!("{name:?} fields in control files must begin with '/' or '~'"),
+                }
+                *path = normalize_path(path).with_context(|| {
+                    format!("invalid {name:?} field in control file")
+                })?;
+            }
+            Ok(())
+        }
+
+        validate_file_path(data.config.as_mut(), "config")?;
+        validate_file_path(data.authorized_keys.as_mut(), "authorized_keys")?;
+
+        if let Some(path) = &data.sshd_path {
+            if !path.starts_with('/') {
+                bail!("\"sshd_path\" {path:?} must be an absolute path");
+            }
+        }
+
+        for (name, marker) in [
+            ("begin_marker", &data.begin_marker),
+            ("end_marker", &data.end_marker),
+        ] {
+            if let Some(marker) = marker {
+                validate_marker(marker)
+                    .with_context(|| format!("invalid {name:?} field"))?;
+            }
+        }
+        let begin = data.begin_marker.as_deref().unwrap_or(BEGIN_MARKER);
+        let end = data.end_marker.as_deref().unwrap_or(END_MARKER);
+        if begin == end {
+            bail!("\"begin_marker\" and \"end_marker\" must differ");
+        }
+
+        if let Some(mode) = data.authorized_keys_mode {
+            if mode & !0o777 != 0 {
+                bail!(
+                    "\"authorized_keys_mode\" {mode:o} is not a valid mode"
+                );
+            }
+            if mode & 0o022 != 0 {
+                bail!(
+                    "\"authorized_keys_mode\" {mode:o} must not allow \
+                    writing by group or others"
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Merges the commands listed in `commands_file` of `data`, if set, into
+    /// its `commands`.
+    ///
+    /// The file must be absolute and passes the same security checks as
+    /// control files, see [`visit_config_files`]; its extensions directory is
+    /// ignored. Every line holds one command. Empty lines and lines starting
+    /// with `#` are skipped, and surrounding whitespace is removed. Commands
+    /// from the file follow inline `commands` of the same section.
+    ///
+    /// # Errors
+    /// The function will fail if the path is not absolute, or if the file
+    /// could not be read or fails the security checks.
+    fn read_commands_file<W: Workspace>(
+        ws: &W,
+        data: &mut IncompleteControl,
+    ) -> Result<()> {
+        let path = match data.commands_file.take() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if !path.starts_with('/') {
+            bail!("\"commands_file\" {path:?} must be an absolute path");
+        }
+
+        let mut commands = data.commands.take().unwrap_or_default();
+
+        let process = |file: &Path| -> Result<()> {
+            let content = read_utf8(file, "commands file")?;
+            commands.extend(
+                content
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(str::to_owned),
+            );
+            Ok(())
+        };
+
+        let options = VisitOptions {
+            extensions: false,
+            ..VisitOptions::default()
+        };
+        visit_config_files(&path, 0, &options, process, ws)
+            .context("could not load commands file")?;
+
+        data.commands = Some(commands);
+        Ok(())
+    }
+
+    /// Returns the [`Control`] of users not mentioned in any section.
+    ///
+    /// These are the defaults set by `"*"` sections.
+    #[must_use]
+    pub fn fallback(&self) -> &Control {
+        &self.fallback
+    }
+
+    /// Returns the control files that were read, in order.
+    #[must_use]
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    /// Returns the sections of control files that were read, in order.
+    #[must_use]
+    pub fn sections(&self) -> &[Section] {
+        &self.sections
+    }
+
+    /// Returns problems that did not prevent loading, such as sections
+    /// skipped according to [`VisitOptions::ambiguous_names`].
+    #[must_use]
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Returns whether `uid` is named by a section that was skipped
+    /// according to [`VisitOptions::ambiguous_names`].
+    ///
+    /// Such users lack the settings meant for them, so they should be left
+    /// alone.
+    #[must_use]
+    pub fn is_ambiguous(&self, uid: uid_t) -> bool {
+        self.ambiguous.contains(&uid)
+    }
+
+    /// Returns order-dependent conflicts between group, range and pattern
+    /// sections.
+    ///
+    /// Conflicting fields that a user-specific section sets are not reported.
+    #[must_use]
+    pub fn conflicts(&self) -> &[Conflict] {
+        &self.conflicts
+    }
+
+    /// Returns a [`Control`] structure for given user.
+    #[must_use]
+    pub fn get_user_control(&self, uid: uid_t) -> Control {
+        let mut result = self.fallback.clone();
+
+        if let Some(overrides) = self.selected.get(&uid) {
+            result.fill_from(overrides);
+        }
+
+        if let Some(overrides) = self.users.get(&uid) {
+            result.fill_from(overrides);
+        }
+
+        result.group_commands = result
+            .command_groups
+            .iter()
+            .filter_map(|name| {
+                let commands = self.command_groups.get(name)?;
+                Some((name.clone(), commands.clone()))
+            })
+            .collect();
+
+        result
+    }
+
+    /// Returns the commands of every command group, by group name.
+    #[must_use]
+    pub fn command_groups(&self) -> &BTreeMap<String, Vec<String>> {
+        &self.command_groups
+    }
+
+    /// Renders the effective control of `uids` as a control file.
+    ///
+    /// The document holds a `"*"` section with every field of the
+    /// [fallback][Self::fallback], followed by a section named by UID for
+    /// every user in `uids` whose control differs from it. Users' sections
+    /// only list the fields that differ, and appear in UID order, so the
+    /// output is canonical. Loading the document reproduces
+    /// [`get_user_control`][Self::get_user_control] for every user in
+    /// `uids`; groups, ranges and profiles are resolved in the process.
+    /// Command groups, if any, are listed last in a
+    /// [`COMMAND_GROUPS_SECTION`].
+    ///
+    /// # Errors
+    /// The function will fail if some control cannot be serialized.
+    pub fn to_toml(&self, uids: &[uid_t]) -> Result<String> {
+        use std::fmt::Write as _;
+
+        let fallback = control_table(&self.fallback)?;
+        let mut result = format!("[\"*\"]\n{}", toml::to_string(&fallback)?);
+
+        let mut uids = uids.to_vec();
+        uids.sort_unstable();
+        uids.dedup();
+
+        for uid in uids {
+            let mut table = control_table(&self.get_user_control(uid))?;
+            table.retain(|field, value| fallback.get(field) != Some(value));
+
+            if !table.is_empty() {
+                write!(result, "\n[{uid}]\n{}", toml::to_string(&table)?)?;
+            }
+        }
+
+        if !self.command_groups.is_empty() {
+            write!(
+                result,
+                "\n[{COMMAND_GROUPS_SECTION}]\n{}",
+                toml::to_string(&self.command_groups)?
+            )?;
+        }
+
+        Ok(result)
+    }
+
+    /// Compares the effective control of `uids` with that of `other`.
+    ///
+    /// Every setting that differs is reported once per user, in UID order
+    /// and then by field name. Sections, profiles and files are not
+    /// compared, only their outcome, so equivalent controls written
+    /// differently have no differences.
+    ///
+    /// # Errors
+    /// The function will fail if some control cannot be serialized.
+    pub fn diff(
+        &self,
+        other: &Self,
+        uids: &[uid_t],
+    ) -> Result<Vec<ControlDifference>> {
+        let mut uids = uids.to_vec();
+        uids.sort_unstable();
+        uids.dedup();
+
+        let mut result = Vec::new();
+        for uid in uids {
+            let ours = comparable_table(&self.get_user_control(uid))?;
+            let theirs = comparable_table(&other.get_user_control(uid))?;
+
+            let fields: BTreeSet<_> =
+                ours.keys().chain(theirs.keys()).collect();
+            for field in fields {
+                let (ours, theirs) = (ours.get(field), theirs.get(field));
+                if ours != theirs {
+                    result.push(ControlDifference {
+                        uid,
+                        field: field.clone(),
+                        ours: ours.cloned(),
+                        theirs: theirs.cloned(),
+                    });
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Returns the users of `ws` that `other` enables or disables.
+    ///
+    /// The first list holds the UIDs of users that are disabled in this
+    /// control but enabled in `other`, the second those of users that are
+    /// enabled here but disabled in `other`, both in UID order. Unlike
+    /// [`diff`][Self::diff], other settings are ignored, so users whose
+    /// commands change, say, are in neither list.
+    #[must_use]
+    pub fn enabled_diff<W: Workspace>(
+        &self,
+        other: &Self,
+        ws: &W,
+    ) -> (Vec<uid_t>, Vec<uid_t>) {
+        let mut uids: Vec<_> =
+            ws.users().all_users().map(User::uid).collect();
+        uids.sort_unstable();
+
+        let mut enabled = Vec::new();
+        let mut disabled = Vec::new();
+        for uid in uids {
+            let ours = self.get_user_control(uid).enable;
+            match (ours, other.get_user_control(uid).enable) {
+                (false, true) => enabled.push(uid),
+                (true, false) => disabled.push(uid),
+                _ => {}
+            }
+        }
+
+        (enabled, disabled)
+    }
+
+    /// Ensures that the effective control of `uids` equals that of `staged`.
+    ///
+    /// This guards change-control workflows, where only a reviewed copy of
+    /// control may be applied. See [`diff`][Self::diff] for what is
+    /// compared.
+    ///
+    /// # Errors
+    /// The function will fail if some setting differs, listing every
+    /// [`ControlDifference`] from this control to `staged`, or if some
+    /// control cannot be serialized.
+    pub fn ensure_matches(
+        &self,
+        staged: &Self,
+        uids: &[uid_t],
+    ) -> Result<()> {
+        let differences = self.diff(staged, uids)?;
+        if differences.is_empty() {
+            return Ok(());
+        }
+
+        let mut message = format!(
+            "control differs from the staged control in {} setting(s):",
+            differences.len()
+        );
+        for difference in &differences {
+            message.push_str("\n    ");
+            message.push_str(&difference.to_string());
+        }
+        bail!(message)
+    }
+
+    /// Loads control again from the same main file, e.g. in a long-running
+    /// process that was told that control changed.
+    ///
+    /// The load is as secure as [`load`][Self::load] with `options`, which
+    /// should be those of the original load. On success, this control is
+    /// replaced and the changes are returned; the effective control of every
+    /// user of `ws` is compared, see [`diff`][Self::diff]. On failure, this
+    /// control is left intact.
+    ///
+    /// # Errors
+    /// The function will fail if this control was not loaded from a file, for
+    /// the reasons listed for [`load`][Self::load], or if some control cannot
+    /// be serialized.
+    pub fn reload<W: Workspace>(
+        &mut self,
+        ws: &W,
+        options: &VisitOptions,
+    ) -> Result<ControlChange> {
+        let main = self
+            .files
+            .first()
+            .cloned()
+            .context("control was not loaded from a file")?;
+        let reloaded = Self::load(ws, &main, options)?;
+
+        let uids: Vec<_> = ws.users().all_users().map(User::uid).collect();
+        let change = ControlChange {
+            differences: self.diff(&reloaded, &uids)?,
+            added_files: reloaded
+                .files
+                .iter()
+                .filter(|f| !self.files.contains(f))
+                .cloned()
+                .collect(),
+            removed_files: self
+                .files
+                .iter()
+                .filter(|f| !reloaded.files.contains(f))
+                .cloned()
+                .collect(),
+        };
+
+        *self = reloaded;
+        Ok(change)
+    }
+}
+
+/// Changes found by [`ControlManager::reload`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ControlChange {
+    /// Settings of users that changed, from old to new values.
+    pub differences: Vec<ControlDifference>,
+
+    /// Control files that are read now but were not before.
+    pub added_files: Vec<PathBuf>,
+
+    /// Control files that were read before but are not now.
+    pub removed_files: Vec<PathBuf>,
+}
+
+impl ControlChange {
+    /// Returns whether the effective control of no user changed.
+    ///
+    /// Files may still have been added or removed without effect.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.differences.is_empty()
+    }
+}
+
+/// Converts `control` into a table of every field for comparison, see
+/// [`ControlManager::diff`].
+fn comparable_table(control: &Control) -> Result<toml::Table> {
+    let mut table = control_table(control)?;
+    if !control.group_commands.is_empty() {
+        table.insert(
+            String::from(COMMAND_GROUPS_SECTION),
+            toml::Value::try_from(&control.group_commands)?,
+        );
+    }
+    Ok(table)
+}
+
+/// Converts `control` into a control section that sets every field.
+///
+/// An unset [`Control::authorized_keys_mode`],
+/// [`Control::authorized_keys_gid`] or [`Control::sshd_path`] is left out,
+/// as TOML cannot express it. Users can only have it unset if the fallback
+/// does, too.
+fn control_table(control: &Control) -> Result<toml::Table> {
+    let mut table = toml::Table::try_from(control)?;
+    table
+        .entry("action")
+        .or_insert_with(|| toml::Value::String(String::from("allow")));
+    for field in &["extra_key_types", "command_groups"] {
+        table
+            .entry(*field)
+            .or_insert_with(|| toml::Value::Array(Vec::new()));
+    }
+    for field in &[
+        "port_forwarding",
+        "x11_forwarding",
+        "agent_forwarding",
+        "pty",
+        "validate_sshd",
+        "pub_keys_fallback",
+        "strict_user_config",
+    ] {
+        table.entry(*field).or_insert(toml::Value::Boolean(false));
     }
+    Ok(table)
 }
@@ -1,6 +1,11 @@
+#![allow(clippy::bool_assert_comparison)]
+#![allow(clippy::needless_raw_string_hashes)]
+#![allow(clippy::unnecessary_wraps)]
+
 pub use std::path::PathBuf;
 
-pub use crate::workspace::mock::MockWorkspace;
+pub use crate::workspace::mock::{set_perms, MockWorkspace};
+pub use uzers::os::unix::UserExt;
 
 pub use super::*;
 
@@ -15,6 +20,22 @@ mod visit_config_files {
         file: P,
         owner: uid_t,
         ws: &W,
+        paths: I,
+    ) -> Result<()>
+    where
+        P: AsRef<Path>,
+        W: Workspace,
+        I: Iterator<Item = &'a PathBuf>,
+    {
+        must_visit_with(file, owner, &VisitOptions::default(), ws, paths)
+    }
+
+    /// Same as [`must_visit`] with explicit [`VisitOptions`].
+    fn must_visit_with<'a, P, W, I>(
+        file: P,
+        owner: uid_t,
+        options: &VisitOptions,
+        ws: &W,
         mut paths: I,
     ) -> Result<()>
     where
@@ -25,6 +46,7 @@ mod visit_config_files {
         visit_config_files(
             file,
             owner,
+            options,
             |p| {
                 assert_eq!(
                     paths.next().map(|x| x.canonicalize().unwrap()),
@@ -39,13 +61,28 @@ mod visit_config_files {
         Ok(())
     }
 
+    /// Invokes [`visit_config_files`] and returns the error it fails with.
+    fn failure<P, W>(file: P, owner: uid_t, ws: &W) -> String
+    where
+        P: AsRef<Path>,
+        W: Workspace,
+    {
+        let options = VisitOptions::default();
+        match visit_config_files(file, owner, &options, |_| Ok(()), ws) {
+            Ok(()) => panic!("visit succeeded"),
+            Err(error) => format!("{error:#}"),
+        }
+    }
+
     /// Invokes [`visit_config_files`] and ensures it returns an [`Err`].
     fn must_fail<P, W>(file: P, owner: uid_t, ws: &W) -> Result<()>
     where
         P: AsRef<Path>,
         W: Workspace,
     {
-        assert!(visit_config_files(file, owner, |_| Ok(()), ws).is_err());
+        let options = VisitOptions::default();
+        assert!(visit_config_files(file, owner, &options, |_| Ok(()), ws)
+            .is_err());
         Ok(())
     }
 
@@ -76,6 +113,34 @@ mod visit_config_files {
         must_visit(&main, 1234, &ws, [&main].into_iter())
     }
 
+    /// Returns `path` relative to the current working directory.
+    fn relative_to_cwd(path: &Path) -> Result<PathBuf> {
+        let cwd = std::env::current_dir()?.canonicalize()?;
+        let mut result: PathBuf =
+            cwd.components().skip(1).map(|_| "..").collect();
+        result.push(path.strip_prefix("/")?);
+        Ok(result)
+    }
+
+    #[test]
+    fn relative_main_file() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+
+        ws.add_user(1234, "alice", "home/alice")?;
+        let main =
+            ws.add_file("etc/main.conf", 1234, 0o600, "I am contents")?;
+        ws.add_dir("etc/main.conf.d/", 1234, 0o700)?;
+        let xt =
+            ws.add_file("etc/main.conf.d/xtra.conf", 1234, 0o600, "X")?;
+
+        let relative = relative_to_cwd(&main.canonicalize()?)?;
+        assert!(relative.is_relative());
+        must_visit(&relative, 1234, &ws, [&main, &xt].into_iter())?;
+
+        let dotted = relative.parent().unwrap().join("./../etc/main.conf");
+        must_visit(&dotted, 1234, &ws, [&main, &xt].into_iter())
+    }
+
     #[test]
     fn main_file_and_extension() -> Result<()> {
         let mut ws = MockWorkspace::new()?;
@@ -126,6 +191,32 @@ mod visit_config_files {
         )
     }
 
+    #[test]
+    fn extension_order_numeric() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+
+        ws.add_user(1234, "alice", "home/alice")?;
+        let main =
+            ws.add_file("etc/main.conf", 1234, 0o600, "I am contents")?;
+        ws.add_dir("etc/main.conf.d/", 1234, 0o700)?;
+
+        let mut add_ext =
+            |s| ws.add_file(format!("etc/main.conf.d/{s}"), 1234, 0o600, "X");
+
+        let x1 = add_ext("10.service.conf")?;
+        let x2 = add_ext("10.conf")?;
+        let x3 = add_ext("2.conf")?;
+        let x4 = add_ext("a.b.conf")?;
+        let x5 = add_ext("a.conf")?;
+
+        must_visit(
+            &main,
+            1234,
+            &ws,
+            [&main, &x3, &x2, &x1, &x5, &x4].into_iter(),
+        )
+    }
+
     #[test]
     fn ignore_unrelated_files() -> Result<()> {
         let mut ws = MockWorkspace::new()?;
@@ -173,6 +264,157 @@ mod visit_config_files {
         must_visit(&main, 1234, &ws, [&main, &xt].into_iter())
     }
 
+    #[test]
+    fn extensions_disabled() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+
+        ws.add_user(1234, "alice", "home/alice")?;
+        let main =
+            ws.add_file("etc/main.conf", 1234, 0o600, "I am contents")?;
+        ws.add_dir("etc/main.conf.d/", 1234, 0o700)?;
+        ws.add_file("etc/main.conf.d/xtra.conf", 1234, 0o600, "X")?;
+
+        let options = VisitOptions {
+            extensions: false,
+            ..VisitOptions::default()
+        };
+        must_visit_with(&main, 1234, &options, &ws, [&main].into_iter())
+    }
+
+    #[test]
+    fn extensions_disabled_skips_broken_dir() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+
+        ws.add_user(1234, "alice", "home/alice")?;
+        ws.add_user(5678, "mallory", "home/mallory")?;
+        let main =
+            ws.add_file("etc/main.conf", 1234, 0o600, "I am contents")?;
+        ws.add_dir("etc/main.conf.d/", 5678, 0o777)?;
+
+        let options = VisitOptions {
+            extensions: false,
+            ..VisitOptions::default()
+        };
+        must_visit_with(&main, 1234, &options, &ws, [&main].into_iter())
+    }
+
+    #[test]
+    fn extension_owner() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+
+        ws.add_user(0, "root", "root")?;
+        ws.add_user(1234, "alice", "home/alice")?;
+        let main = ws.add_file("etc/main.conf", 0, 0o600, "I am contents")?;
+        ws.add_dir("etc/main.conf.d/", 1234, 0o700)?;
+        let xt =
+            ws.add_file("etc/main.conf.d/xtra.conf", 1234, 0o600, "X")?;
+
+        let options = VisitOptions {
+            extension_owner: Some(1234),
+            ..VisitOptions::default()
+        };
+        must_visit_with(&main, 0, &options, &ws, [&main, &xt].into_iter())?;
+
+        // Same layout is rejected without delegation
+        must_fail(&main, 0, &ws)
+    }
+
+    #[test]
+    fn extension_owner_mismatch() -> Result<()> {
+        let options = VisitOptions {
+            extension_owner: Some(1234),
+            ..VisitOptions::default()
+        };
+
+        // Extension file owned by someone else
+        let mut ws = MockWorkspace::new()?;
+        ws.add_user(0, "root", "root")?;
+        ws.add_user(1234, "alice", "home/alice")?;
+        ws.add_user(5678, "mallory", "home/mallory")?;
+        let main = ws.add_file("etc/main.conf", 0, 0o600, "I am contents")?;
+        ws.add_dir("etc/main.conf.d/", 1234, 0o700)?;
+        ws.add_file("etc/main.conf.d/xtra.conf", 5678, 0o600, "X")?;
+        assert!(
+            visit_config_files(&main, 0, &options, |_| Ok(()), &ws).is_err()
+        );
+
+        // Extension directory owned by the main owner
+        let mut ws = MockWorkspace::new()?;
+        ws.add_user(0, "root", "root")?;
+        ws.add_user(1234, "alice", "home/alice")?;
+        let main = ws.add_file("etc/main.conf", 0, 0o600, "I am contents")?;
+        ws.add_dir("etc/main.conf.d/", 0, 0o700)?;
+        ws.add_file("etc/main.conf.d/xtra.conf", 1234, 0o600, "X")?;
+        assert!(
+            visit_config_files(&main, 0, &options, |_| Ok(()), &ws).is_err()
+        );
+
+        // Main file owned by the delegate
+        let mut ws = MockWorkspace::new()?;
+        ws.add_user(0, "root", "root")?;
+        ws.add_user(1234, "alice", "home/alice")?;
+        let main =
+            ws.add_file("etc/main.conf", 1234, 0o600, "I am contents")?;
+        assert!(
+            visit_config_files(&main, 0, &options, |_| Ok(()), &ws).is_err()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn root_owner() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+
+        ws.add_user(0, "root", "root")?;
+        ws.add_user(1234, "alice", "home/alice")?;
+        let main =
+            ws.add_file("home/alice/.conf", 0, 0o600, "I am contents")?;
+        ws.add_dir("home/alice/.conf.d/", 1234, 0o700)?;
+        let xt =
+            ws.add_file("home/alice/.conf.d/xtra.conf", 0, 0o600, "X")?;
+
+        let options = VisitOptions {
+            allow_root_owner: true,
+            ..VisitOptions::default()
+        };
+        must_visit_with(
+            &main,
+            1234,
+            &options,
+            &ws,
+            [&main, &xt].into_iter(),
+        )?;
+
+        // Same layout is rejected without the option
+        assert!(
+            failure(&main, 1234, &ws).contains("must be owned by UID 1234")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn root_owner_other_user() -> Result<()> {
+        let options = VisitOptions {
+            allow_root_owner: true,
+            ..VisitOptions::default()
+        };
+
+        let mut ws = MockWorkspace::new()?;
+        ws.add_user(0, "root", "root")?;
+        ws.add_user(1234, "alice", "home/alice")?;
+        ws.add_user(5678, "mallory", "home/mallory")?;
+        let main =
+            ws.add_file("home/alice/.conf", 5678, 0o600, "I am contents")?;
+
+        let error =
+            visit_config_files(&main, 1234, &options, |_| Ok(()), &ws)
+                .unwrap_err();
+        assert!(format!("{error:#}")
+            .contains("must be owned by UID 1234 or root, not 5678"));
+        Ok(())
+    }
+
     // Main file
     mod main {
         use super::*;
@@ -231,14 +473,60 @@ mod visit_config_files {
 
             Ok(())
         }
+
+        #[test]
+        fn setgid() -> Result<()> {
+            let mut ws = MockWorkspace::new()?;
+
+            ws.add_user(1234, "alice", "home/alice")?;
+            let main = ws.add_file("etc/main.conf", 1234, 0o2600, "M")?;
+            let strict = VisitOptions {
+                strict_perms: true,
+                ..VisitOptions::default()
+            };
+
+            must_visit(&main, 1234, &ws, [&main].into_iter())?;
+            let error =
+                visit_config_files(&main, 1234, &strict, |_| Ok(()), &ws)
+                    .unwrap_err();
+            assert!(
+                error.root_cause().to_string().contains("2000"),
+                "{error:#}"
+            );
+            assert!(format!("{error:#}").contains("[security"), "{error:#}");
+            Ok(())
+        }
+
+        #[test]
+        fn strict_secure() -> Result<()> {
+            let mut ws = MockWorkspace::new()?;
+
+            ws.add_user(1234, "alice", "home/alice")?;
+            let main = ws.add_file("etc/main.conf", 1234, 0o600, "M")?;
+            let strict = VisitOptions {
+                strict_perms: true,
+                ..VisitOptions::default()
+            };
+
+            must_visit_with(&main, 1234, &strict, &ws, [&main].into_iter())
+        }
     }
 
     // Extensions directory
     mod dir {
         use super::*;
 
+        /// Returns whether permission bits are ignored for the running user.
+        fn is_root() -> bool {
+            uzers::get_current_uid() == 0
+        }
+
         #[test]
         fn unreadable() -> Result<()> {
+            if is_root() {
+                return Ok(());
+            }
+
             let mut ws = MockWorkspace::new()?;
 
             ws.add_user(1234, "alice", "home/alice")?;
@@ -246,7 +534,79 @@ mod visit_config_files {
                 ws.add_file("etc/main.conf", 1234, 0o600, "I am contents")?;
             ws.add_dir("etc/main.conf.d", 1234, 0o000)?;
 
-            must_fail(&main, 1234, &ws)
+            let error = failure(&main, 1234, &ws);
+            assert!(error.starts_with("listing extensions"), "{error}");
+            assert!(!error.contains("[security"), "{error}");
+            Ok(())
+        }
+
+        #[test]
+        fn sticky() -> Result<()> {
+            let mut ws = MockWorkspace::new()?;
+
+            ws.add_user(1234, "alice", "home/alice")?;
+            let main =
+                ws.add_file("etc/main.conf", 1234, 0o600, "I am contents")?;
+            ws.add_dir("etc/main.conf.d", 1234, 0o1700)?;
+            let strict = VisitOptions {
+                strict_perms: true,
+                ..VisitOptions::default()
+            };
+
+            must_visit(&main, 1234, &ws, [&main].into_iter())?;
+            let error =
+                visit_config_files(&main, 1234, &strict, |_| Ok(()), &ws)
+                    .unwrap_err();
+            let error = format!("{error:#}");
+            assert!(error.starts_with("checking extensions"), "{error}");
+            assert!(error.contains("sticky bits 1000"), "{error}");
+            Ok(())
+        }
+
+        #[test]
+        fn insecure_and_unreadable() -> Result<()> {
+            let mut ws = MockWorkspace::new()?;
+
+            ws.add_user(1234, "alice", "home/alice")?;
+            let main =
+                ws.add_file("etc/main.conf", 1234, 0o600, "I am contents")?;
+            ws.add_dir("etc/main.conf.d", 1234, 0o070)?;
+
+            let error = failure(&main, 1234, &ws);
+            assert!(error.starts_with("checking extensions"), "{error}");
+            assert!(error.contains("has permissions 70"), "{error}");
+            Ok(())
+        }
+
+        #[test]
+        fn insecure_and_readable() -> Result<()> {
+            let mut ws = MockWorkspace::new()?;
+
+            ws.add_user(1234, "alice", "home/alice")?;
+            let main =
+                ws.add_file("etc/main.conf", 1234, 0o600, "I am contents")?;
+            ws.add_dir("etc/main.conf.d", 1234, 0o755)?;
+
+            let error = failure(&main, 1234, &ws);
+            assert!(error.starts_with("checking extensions"), "{error}");
+            assert!(error.contains("has permissions 755"), "{error}");
+            Ok(())
+        }
+
+        #[test]
+        fn hijacked_and_unreadable() -> Result<()> {
+            let mut ws = MockWorkspace::new()?;
+
+            ws.add_user(1234, "alice", "home/alice")?;
+            ws.add_user(5678, "mallory", "home/mallory")?;
+            let main =
+                ws.add_file("etc/main.conf", 1234, 0o600, "I am contents")?;
+            ws.add_dir("etc/main.conf.d", 5678, 0o000)?;
+
+            let error = failure(&main, 1234, &ws);
+            assert!(error.starts_with("checking extensions"), "{error}");
+            assert!(error.contains("must be owned by UID 1234"), "{error}");
+            Ok(())
         }
 
         #[test]
@@ -410,7 +770,7 @@ mod load_control {
             )?;
         }
 
-        ControlManager::load(&ws, main)
+        ControlManager::load(&ws, main, &VisitOptions::default())
     }
 
     #[test]
@@ -565,33 +925,480 @@ mod load_control {
     }
 
     #[test]
-    fn invalid_toml() -> Result<()> {
-        assert!(load("Not a valid TOML", []).is_err());
+    fn commands() -> Result<()> {
+        #[rustfmt::skip]
+        let cm = load(r#"
+            ["*"]
+            commands = ["uptime"]
+
+            [alice]
+            commands = ["rsync --server *", "backup"]
+            command_conflict = "skip"
+        "#, [])?;
+
+        let alice_cfg = cm.get_user_control(1000);
+        assert_eq!(alice_cfg.commands, ["rsync --server *", "backup"]);
+        assert_eq!(alice_cfg.command_conflict, CommandConflict::Skip);
+
+        let bob_cfg = cm.get_user_control(1001);
+        assert_eq!(bob_cfg.commands, ["uptime"]);
+        assert_eq!(bob_cfg.command_conflict, CommandConflict::Error);
+
         Ok(())
     }
 
     #[test]
-    fn empty_path() -> Result<()> {
+    fn invalid_command_conflict() -> Result<()> {
         #[rustfmt::skip]
         assert!(load(r#"
             [alice]
-            config = ""
+            command_conflict = "ignore"
         "#, []).is_err());
         Ok(())
     }
 
     #[test]
-    fn relative_path() -> Result<()> {
+    fn authorized_keys_owner() -> Result<()> {
         #[rustfmt::skip]
-        assert!(load(r#"
+        let cm = load(r#"
+            ["*"]
+            authorized_keys = "/etc/ssh/authorized_keys/%u"
+            authorized_keys_owner = "root"
+
             [alice]
-            config = "relative/path"
-        "#, []).is_err());
+            authorized_keys = "~/.ssh/authorized_keys"
+            authorized_keys_owner = "user"
+            authorized_keys_mode = 0o400
+        "#, [])?;
+
+        let alice_cfg = cm.get_user_control(1000);
+        assert_eq!(alice_cfg.authorized_keys_owner, KeysOwner::User);
+        assert_eq!(alice_cfg.authorized_keys_mode, Some(0o400));
+
+        let bob_cfg = cm.get_user_control(1001);
+        assert_eq!(bob_cfg.authorized_keys, "/etc/ssh/authorized_keys/%u");
+        assert_eq!(bob_cfg.authorized_keys_owner, KeysOwner::Root);
+        assert_eq!(bob_cfg.authorized_keys_mode, None);
+
         Ok(())
     }
 
     #[test]
-    fn path_starts_with_dot() -> Result<()> {
+    fn invalid_authorized_keys_mode() -> Result<()> {
+        for mode in ["0o666", "0o620", "0o1644"] {
+            let content = format!("[alice]\nauthorized_keys_mode = {mode}");
+            assert!(load(&content, []).is_err(), "accepted {mode}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn markers() -> Result<()> {
+        #[rustfmt::skip]
+        let cm = load(r##"
+            [alice]
+            begin_marker = "# >>> keys by ops"
+            end_marker = "# <<< keys by ops"
+        "##, [])?;
+
+        let alice_cfg = cm.get_user_control(1000);
+        assert_eq!(
+            alice_cfg.begin_marker.as_deref(),
+            Some("# >>> keys by ops")
+        );
+        assert_eq!(
+            alice_cfg.end_marker.as_deref(),
+            Some("# <<< keys by ops")
+        );
+        assert_eq!(cm.get_user_control(1001).begin_marker, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_markers() -> Result<()> {
+        for marker in [
+            r#""BEGIN keys""#,
+            r##""#""##,
+            r##""# BEGIN\n# keys""##,
+            r##""# BEGIN ""##,
+            r##""# END narrowssh""##,
+        ] {
+            let content = format!("[alice]\nbegin_marker = {marker}");
+            assert!(load(&content, []).is_err(), "accepted {marker}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn command_groups() -> Result<()> {
+        #[rustfmt::skip]
+        let cm = load(r#"
+            [command-groups]
+            backup = ["/usr/bin/backup *"]
+            restore = ["/usr/bin/restore"]
+
+            [alice]
+            command_groups = ["backup", "restore"]
+        "#, [r#"
+            [command-groups]
+            restore = ["/usr/bin/restore --safe"]
+        "#])?;
+
+        assert_eq!(cm.command_groups().len(), 2);
+        let alice_cfg = cm.get_user_control(1000);
+        assert_eq!(alice_cfg.command_groups, ["backup", "restore"]);
+        assert_eq!(alice_cfg.group_commands["backup"], ["/usr/bin/backup *"]);
+        assert_eq!(
+            alice_cfg.group_commands["restore"],
+            ["/usr/bin/restore --safe"]
+        );
+        assert!(cm.get_user_control(1001).group_commands.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_command_group() -> Result<()> {
+        #[rustfmt::skip]
+        assert!(load(r#"
+            [command-groups]
+            backup = ["/usr/bin/backup *"]
+
+            [alice]
+            command_groups = ["backup", "shell"]
+        "#, []).is_err());
+
+        #[rustfmt::skip]
+        assert!(load(r#"
+            ["profile:unused"]
+            command_groups = ["shell"]
+        "#, []).is_err());
+
+        #[rustfmt::skip]
+        assert!(load(r#"
+            [command-groups]
+            backup = "/usr/bin/backup *"
+        "#, []).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn allow_keys() -> Result<()> {
+        let main = "allow_keys = [\"commands\"]\n[\"*\"]\nenable = true";
+
+        let cm = load(main, ["[alice]\ncommands = [\"backup\"]"])?;
+        assert_eq!(cm.get_user_control(1000).commands, ["backup"]);
+        assert!(cm.get_user_control(1000).enable);
+
+        let error = load(main, ["[alice]\nenable = false"]).unwrap_err();
+        assert!(
+            format!("{error:#}").contains("\"enable\" may not be set"),
+            "{error:#}"
+        );
+
+        for ext in [
+            "[alice]\ncommands = [\"backup\"]\nauthorized_keys = \"/tmp/k\"",
+            "[command-groups]\nshell = [\"*\"]",
+            "allow_keys = [\"enable\"]",
+        ] {
+            assert!(load(main, [ext]).is_err(), "accepted {ext}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_allow_keys() {
+        for value in ["\"commands\"", "[\"comands\"]", "[1]"] {
+            let main = format!("allow_keys = {value}");
+            assert!(load(&main, []).is_err(), "accepted {value}");
+        }
+    }
+
+    #[test]
+    fn profile() -> Result<()> {
+        #[rustfmt::skip]
+        let cm = load(r#"
+            ["*"]
+            commands = ["uptime"]
+
+            [alice]
+            profile = "webops"
+            command_conflict = "override"
+
+            ["profile:webops"]
+            enable = true
+            commands = ["deploy"]
+            command_conflict = "skip"
+        "#, [
+            r#"
+                [bob]
+                profile = "webops"
+                commands = ["deploy", "restart"]
+            "#,
+        ])?;
+
+        let alice_cfg = cm.get_user_control(1000);
+        assert_eq!(alice_cfg.enable, true);
+        assert_eq!(alice_cfg.commands, ["deploy"]);
+        assert_eq!(alice_cfg.command_conflict, CommandConflict::Override);
+
+        let bob_cfg = cm.get_user_control(1001);
+        assert_eq!(bob_cfg.enable, true);
+        assert_eq!(bob_cfg.commands, ["deploy", "restart"]);
+        assert_eq!(bob_cfg.command_conflict, CommandConflict::Skip);
+
+        let charlie_cfg = cm.get_user_control(1002);
+        assert_eq!(charlie_cfg.enable, false);
+        assert_eq!(charlie_cfg.commands, ["uptime"]);
+        Ok(())
+    }
+
+    #[test]
+    fn profile_inheritance() -> Result<()> {
+        #[rustfmt::skip]
+        let cm = load(r#"
+            ["profile:base"]
+            enable = true
+            commands = ["uptime"]
+
+            ["profile:webops"]
+            profile = "base"
+            commands = ["deploy"]
+
+            [alice]
+            profile = "webops"
+        "#, [])?;
+
+        let alice_cfg = cm.get_user_control(1000);
+        assert_eq!(alice_cfg.enable, true);
+        assert_eq!(alice_cfg.commands, ["deploy"]);
+        Ok(())
+    }
+
+    #[test]
+    fn profile_cycle() -> Result<()> {
+        #[rustfmt::skip]
+        assert!(load(r#"
+            ["profile:webops"]
+            profile = "webops"
+        "#, []).is_err());
+
+        #[rustfmt::skip]
+        let error = load(r#"
+            ["profile:a"]
+            profile = "b"
+
+            ["profile:b"]
+            profile = "a"
+
+            [alice]
+            profile = "a"
+        "#, []).unwrap_err();
+        let message = format!("{error:#}");
+        assert!(
+            message.contains("a -> b -> a")
+                || message.contains("b -> a -> b"),
+            "{message}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_profile() -> Result<()> {
+        #[rustfmt::skip]
+        let error = load(r#"
+            [alice]
+            profile = "nope"
+        "#, []).unwrap_err();
+        let message = format!("{error:#}");
+        assert!(message.contains("unknown profile \"nope\""), "{message}");
+        assert!(message.contains("no profiles are defined"), "{message}");
+        assert!(message.contains("main.toml"), "{message}");
+
+        #[rustfmt::skip]
+        let error = load(r#"
+            ["profile:web"]
+            commands = ["deploy"]
+
+            ["profile:backup"]
+            profile = "webpos"
+        "#, []).unwrap_err();
+        let message = format!("{error:#}");
+        assert!(
+            message.contains("section \"profile:backup\" in"),
+            "{message}"
+        );
+        assert!(message.contains("main.toml"), "{message}");
+        assert!(
+            message.contains("[defined profiles: backup, web]"),
+            "{message}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_toml() -> Result<()> {
+        let error = load("Not a valid TOML", []).unwrap_err();
+        assert!(!format!("{error:#}").contains("JSON"));
+        Ok(())
+    }
+
+    #[test]
+    fn json_in_toml() {
+        let error =
+            load(r#"{ "alice": { "enable": true } }"#, []).unwrap_err();
+        let message = format!("{error:#}");
+        assert!(
+            message.contains("looks like JSON but has a .toml extension"),
+            "{message}"
+        );
+    }
+
+    /// Loads control that reads commands of alice from a file with `mode`.
+    fn load_commands_file(mode: u32) -> Result<ControlManager> {
+        let mut ws = MockWorkspace::new()?;
+
+        ws.add_user(0, "root", "root")?;
+        ws.add_user(1000, "alice", "home/alice")?;
+
+        #[rustfmt::skip]
+        let commands = ws.add_file("etc/commands/alice.txt", 0, mode, "
+            # Maintenance
+            restart
+
+            status --verbose
+        ")?;
+        let main = ws.add_file(
+            "etc/main.toml",
+            0,
+            0o600,
+            format!(
+                "[alice]\ncommands = [\"backup\"]\ncommands_file = {:?}",
+                commands.display().to_string()
+            ),
+        )?;
+
+        ControlManager::load(&ws, main, &VisitOptions::default())
+    }
+
+    #[test]
+    fn commands_file() -> Result<()> {
+        let cm = load_commands_file(0o600)?;
+
+        assert_eq!(
+            cm.get_user_control(1000).commands,
+            ["backup", "restart", "status --verbose"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn insecure_commands_file() {
+        let error = load_commands_file(0o644).unwrap_err();
+
+        assert!(
+            format!("{error:#}").contains("could not load commands file"),
+            "{error:#}"
+        );
+    }
+
+    #[test]
+    fn relative_commands_file() {
+        let error = load("[alice]\ncommands_file = \"commands.txt\"", [])
+            .unwrap_err();
+
+        assert!(format!("{error:#}").contains("absolute"), "{error:#}");
+    }
+
+    /// Loads a control file with a single `section` and returns the error.
+    fn type_error(section: &str) -> String {
+        let error = load(format!("[alice]\n{section}"), []).unwrap_err();
+        format!("{error:#}")
+    }
+
+    #[test]
+    fn wrong_type_enable() {
+        let message = type_error("enable = \"true\"");
+
+        assert!(message.contains("etc/main.toml"), "{message}");
+        assert!(
+            message.contains(
+                r#"in section "alice", field "enable": expected boolean, found string"#
+            ),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn wrong_type_config() {
+        let message = type_error("config = 123");
+
+        assert!(message.contains("etc/main.toml"), "{message}");
+        assert!(
+            message.contains(
+                r#"in section "alice", field "config": expected string, found integer"#
+            ),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn wrong_type_in_array() {
+        let message = type_error("commands = [\"backup\", 1979-05-27]");
+
+        assert!(
+            message.contains(
+                "expected array of strings, found array containing datetime"
+            ),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn invalid_utf8() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+
+        ws.add_user(0, "root", "root")?;
+        let main = ws.add_path_and("etc/main.toml", 0, |c| {
+            std::fs::create_dir_all(c.path().parent().unwrap())?;
+            std::fs::write(c.path(), b"[alice]\nenable = true # \xff\xfe\n")?;
+            set_perms(c.path(), 0o600)
+        })?;
+
+        let error =
+            ControlManager::load(&ws, &main, &VisitOptions::default())
+                .unwrap_err();
+        let message = format!("{error:#}");
+
+        assert!(message.contains("not valid UTF-8"), "{message}");
+        assert!(message.contains("offset 24"), "{message}");
+        assert!(message.contains(&main.display().to_string()), "{message}");
+        Ok(())
+    }
+
+    #[test]
+    fn empty_path() -> Result<()> {
+        #[rustfmt::skip]
+        assert!(load(r#"
+            [alice]
+            config = ""
+        "#, []).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn relative_path() -> Result<()> {
+        #[rustfmt::skip]
+        assert!(load(r#"
+            [alice]
+            config = "relative/path"
+        "#, []).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn path_starts_with_dot() -> Result<()> {
         #[rustfmt::skip]
         assert!(load(r#"
             [alice]
@@ -599,4 +1406,1225 @@ mod load_control {
         "#, []).is_err());
         Ok(())
     }
+
+    #[test]
+    fn fallback() -> Result<()> {
+        #[rustfmt::skip]
+        let cm = load(r#"
+            ["*"]
+            enable = true
+            config = "~/config.conf"
+
+            [alice]
+            enable = false
+        "#, [r#"
+            ["*"]
+            authorized_keys = "/etc/keys/%u"
+        "#])?;
+
+        let fallback = cm.fallback();
+        assert_eq!(fallback.enable, true);
+        assert_eq!(fallback.config, "~/config.conf");
+        assert_eq!(fallback.authorized_keys, "/etc/keys/%u");
+        assert_eq!(cm.get_user_control(1003).config, fallback.config);
+        Ok(())
+    }
+}
+
+/// Tests for [`normalize_path`] and [`resolve_path`]
+mod paths {
+    use super::*;
+
+    #[test]
+    fn normalize() -> Result<()> {
+        for (input, expected) in [
+            ("/etc/keys", "/etc/keys"),
+            ("~/.ssh/authorized_keys", "~/.ssh/authorized_keys"),
+            ("~/./.ssh//authorized_keys", "~/.ssh/authorized_keys"),
+            ("//etc///keys", "/etc/keys"),
+            ("/etc/./keys/.", "/etc/keys"),
+            ("/etc/keys/", "/etc/keys"),
+            ("~", "~"),
+            ("~/", "~"),
+            ("/", "/"),
+            ("/./", "/"),
+        ] {
+            assert_eq!(normalize_path(input)?, expected, "for {input:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn traversal() {
+        for input in ["/etc/../shadow", "~/..", "~/.ssh/../../bob", "/.."] {
+            assert!(normalize_path(input).is_err(), "accepted {input:?}");
+        }
+    }
+
+    #[test]
+    fn reroot() -> Result<()> {
+        let path = Path::new("/home/alice/.ssh/authorized_keys");
+
+        assert_eq!(super::reroot(path, None)?, path);
+        assert_eq!(
+            super::reroot(path, Some(Path::new("/tmp/box")))?,
+            Path::new("/tmp/box/home/alice/.ssh/authorized_keys")
+        );
+
+        for input in ["relative/path", "/home/../etc/shadow"] {
+            assert!(
+                super::reroot(Path::new(input), Some(Path::new("/tmp")))
+                    .is_err(),
+                "accepted {input:?}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn resolve() -> Result<()> {
+        let alice =
+            User::new(1000, "alice", 1000).with_home_dir("/home/alice");
+
+        assert_eq!(
+            resolve_path("~/./.ssh//authorized_keys", &alice)?,
+            PathBuf::from("/home/alice/.ssh/authorized_keys")
+        );
+        assert_eq!(resolve_path("~", &alice)?, PathBuf::from("/home/alice"));
+        assert_eq!(resolve_path("/etc/k", &alice)?, PathBuf::from("/etc/k"));
+        assert!(resolve_path("~/../bob", &alice).is_err());
+        assert!(resolve_path("~bob/keys", &alice).is_err());
+        assert!(resolve_path("relative", &alice).is_err());
+
+        let homeless = User::new(1001, "homeless", 1001).with_home_dir("");
+        assert!(resolve_path("~/keys", &homeless).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn tokens() -> Result<()> {
+        let alice =
+            User::new(1000, "alice", 1000).with_home_dir("/home/alice");
+
+        assert_eq!(
+            resolve_path("/etc/ssh/keys/%u", &alice)?,
+            PathBuf::from("/etc/ssh/keys/alice")
+        );
+        assert_eq!(
+            resolve_path("~/keys-%U-100%%", &alice)?,
+            PathBuf::from("/home/alice/keys-1000-100%")
+        );
+        assert!(resolve_path("/etc/%h", &alice).is_err());
+        assert!(resolve_path("/etc/keys%", &alice).is_err());
+        assert!(resolve_path("%u/keys", &alice).is_err());
+
+        let dots = User::new(1001, "..", 1001);
+        assert!(resolve_path("/etc/ssh/keys/%u", &dots).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn normalized_on_load() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+        ws.add_user(0, "root", "root")?;
+        ws.add_user(1000, "alice", "home/alice")?;
+
+        #[rustfmt::skip]
+        let main = ws.add_file("etc/main.toml", 0, 0o600, r#"
+            [alice]
+            config = "~/./conf//narrowssh.conf"
+            authorized_keys = "/srv//keys/./alice"
+        "#)?;
+
+        let cm = ControlManager::load(&ws, main, &VisitOptions::default())?;
+        let alice_cfg = cm.get_user_control(1000);
+
+        assert_eq!(alice_cfg.config, "~/conf/narrowssh.conf");
+        assert_eq!(alice_cfg.authorized_keys, "/srv/keys/alice");
+        Ok(())
+    }
+
+    #[test]
+    fn traversal_on_load() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+        ws.add_user(0, "root", "root")?;
+        ws.add_user(1000, "alice", "home/alice")?;
+
+        #[rustfmt::skip]
+        let main = ws.add_file("etc/main.toml", 0, 0o600, r#"
+            [alice]
+            authorized_keys = "~/../bob/.ssh/authorized_keys"
+        "#)?;
+
+        assert!(ControlManager::load(&ws, main, &VisitOptions::default())
+            .is_err());
+        Ok(())
+    }
+}
+
+/// Tests for [`expand_command`] and [`Control::expand_commands`]
+mod commands {
+    use super::*;
+
+    #[test]
+    fn tokens() -> Result<()> {
+        let alice =
+            User::new(1000, "alice", 1000).with_home_dir("/home/alice");
+
+        assert_eq!(
+            expand_command("/usr/bin/backup --user %u", &alice)?,
+            "/usr/bin/backup --user alice"
+        );
+        assert_eq!(
+            expand_command("ls %h --uid=%U 100%%", &alice)?,
+            "ls /home/alice --uid=1000 100%"
+        );
+        assert!(expand_command("echo %x", &alice).is_err());
+        assert!(expand_command("echo 100%", &alice).is_err());
+
+        let homeless = User::new(1001, "homeless", 1001).with_home_dir("");
+        assert!(expand_command("ls %h", &homeless).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn expanded_per_user() -> Result<()> {
+        let mut control = ControlManager::default().get_user_control(1000);
+        control.commands = vec![
+            String::from("/usr/bin/backup --user %u"),
+            String::from("uptime"),
+        ];
+        let alice = User::new(1000, "alice", 1000);
+        let bob = User::new(1001, "bob", 1001);
+
+        assert_eq!(
+            control.expand_commands(&alice)?.commands,
+            ["/usr/bin/backup --user alice", "uptime"]
+        );
+        assert_eq!(
+            control.expand_commands(&bob)?.commands,
+            ["/usr/bin/backup --user bob", "uptime"]
+        );
+
+        control.commands.push(String::from("echo %x"));
+        let error = control.expand_commands(&alice).unwrap_err();
+        assert!(format!("{error:#}").contains("unknown token %x"));
+        Ok(())
+    }
+}
+
+/// Tests for group and range sections in [`ControlManager::load`]
+mod selector_sections {
+    use super::*;
+
+    #[test]
+    fn patterns() -> Result<()> {
+        #[rustfmt::skip]
+        let cm = load(r#"
+            ["*"]
+            commands = ["uptime"]
+
+            ["svc-*"]
+            enable = true
+            commands = ["backup"]
+
+            [svc-b]
+            pty = true
+        "#)?;
+
+        for uid in [3000, 3001] {
+            let control = cm.get_user_control(uid);
+            assert!(control.enable, "UID {uid}");
+            assert_eq!(control.commands, ["backup"]);
+        }
+        assert!(!cm.get_user_control(3000).pty);
+        assert!(cm.get_user_control(3001).pty);
+
+        let alice = cm.get_user_control(1000);
+        assert!(!alice.enable);
+        assert_eq!(alice.commands, ["uptime"]);
+
+        let section = &cm.sections()[1];
+        assert_eq!(section.target, Target::Selected(vec![3000, 3001]));
+        Ok(())
+    }
+
+    fn load(main: &str) -> Result<ControlManager> {
+        let mut ws = MockWorkspace::new()?;
+
+        ws.add_user(0, "root", "root")?;
+        ws.add_user(1000, "alice", "home/alice")?;
+        ws.add_user(1001, "bob", "home/bob")?;
+        ws.add_user(2000, "carol", "home/carol")?;
+        ws.add_user(3000, "svc-a", "home/svc-a")?;
+        ws.add_user(3001, "svc-b", "home/svc-b")?;
+        ws.add_group(100, "admins", &["alice"]);
+        ws.add_group(101, "devs", &["alice", "bob"]);
+
+        let main = ws.add_file("etc/main.toml", 0, 0o600, main)?;
+        ControlManager::load(&ws, main, &VisitOptions::default())
+    }
+
+    #[test]
+    fn precedence() -> Result<()> {
+        #[rustfmt::skip]
+        let cm = load(r#"
+            [alice]
+            commands = ["mine"]
+
+            ["@devs"]
+            enable = true
+            commands = ["dev"]
+
+            ["1000-1999"]
+            config = "/etc/range.conf"
+
+            ["*"]
+            commands = ["everyone"]
+            config = "/etc/all.conf"
+        "#)?;
+
+        let alice_cfg = cm.get_user_control(1000);
+        assert_eq!(alice_cfg.enable, true);
+        assert_eq!(alice_cfg.commands, ["mine"]);
+        assert_eq!(alice_cfg.config, "/etc/range.conf");
+
+        let bob_cfg = cm.get_user_control(1001);
+        assert_eq!(bob_cfg.commands, ["dev"]);
+
+        let carol_cfg = cm.get_user_control(2000);
+        assert_eq!(carol_cfg.enable, false);
+        assert_eq!(carol_cfg.commands, ["everyone"]);
+        assert_eq!(carol_cfg.config, "/etc/all.conf");
+
+        assert!(cm.conflicts().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn huge_range() -> Result<()> {
+        let cm = load(&format!("[\"1001-{}\"]\nenable = true", uid_t::MAX))?;
+
+        assert_eq!(cm.get_user_control(1000).enable, false);
+        assert_eq!(cm.get_user_control(1001).enable, true);
+        assert_eq!(cm.get_user_control(2000).enable, true);
+        assert_eq!(cm.get_user_control(uid_t::MAX).enable, false);
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_group() -> Result<()> {
+        let error = load("[\"@nobody\"]\nenable = true").unwrap_err();
+        let message = format!("{error:#}");
+        assert!(message.contains("main.toml"), "{message}");
+        assert!(message.contains("section \"@nobody\""), "{message}");
+        assert!(
+            message.contains("No such group \"nobody\" exists"),
+            "{message}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn conflicting_groups() -> Result<()> {
+        #[rustfmt::skip]
+        let cm = load(r#"
+            ["@admins"]
+            commands = ["admin"]
+            enable = true
+
+            ["@devs"]
+            commands = ["dev"]
+            enable = true
+        "#)?;
+
+        let conflicts = cm.conflicts();
+        assert_eq!(conflicts.len(), 1, "{conflicts:?}");
+        assert_eq!(conflicts[0].uid, 1000);
+        assert_eq!(conflicts[0].field, "commands");
+        assert_eq!(conflicts[0].overridden.name, "@admins");
+        assert_eq!(conflicts[0].winner.name, "@devs");
+
+        let message = conflicts[0].to_string();
+        assert!(message.contains("@admins"), "{message}");
+        assert!(message.contains("@devs"), "{message}");
+        assert!(message.contains("commands"), "{message}");
+        Ok(())
+    }
+
+    #[test]
+    fn conflicting_group_and_range() -> Result<()> {
+        #[rustfmt::skip]
+        let cm = load(r#"
+            ["1000-1001"]
+            command_conflict = "skip"
+
+            ["@admins"]
+            command_conflict = "override"
+        "#)?;
+
+        let conflicts = cm.conflicts();
+        assert_eq!(conflicts.len(), 1, "{conflicts:?}");
+        assert_eq!(conflicts[0].uid, 1000);
+        assert_eq!(conflicts[0].overridden.name, "1000-1001");
+        Ok(())
+    }
+
+    #[test]
+    fn conflict_resolved_by_user() -> Result<()> {
+        #[rustfmt::skip]
+        let cm = load(r#"
+            ["@admins"]
+            commands = ["admin"]
+
+            ["@devs"]
+            commands = ["dev"]
+
+            [alice]
+            commands = ["mine"]
+        "#)?;
+
+        assert!(cm.conflicts().is_empty());
+        Ok(())
+    }
+}
+
+/// Tests for [`select_extensions`]
+mod select_extensions {
+    use super::*;
+
+    #[test]
+    fn skips_unnamed() {
+        let entries =
+            ["/etc/c.d/b.toml", "/etc/c.d/..", "/etc/c.d/a.toml", "/"]
+                .iter()
+                .map(PathBuf::from)
+                .collect();
+
+        assert_eq!(
+            select_extensions(entries, None),
+            [
+                PathBuf::from("/etc/c.d/a.toml"),
+                PathBuf::from("/etc/c.d/b.toml")
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_unnamed_with_extension() {
+        let entries = ["/etc/c.d/..", "/etc/c.d/a.toml", "/etc/c.d/a.txt"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+
+        assert_eq!(
+            select_extensions(entries, Some(OsStr::new("toml"))),
+            [PathBuf::from("/etc/c.d/a.toml")]
+        );
+    }
+}
+
+/// Tests for [`extensions_dir`]
+mod extensions_dir {
+    use super::*;
+
+    #[test]
+    fn paths() {
+        for (file, dir) in &[
+            (
+                "/etc/narrowssh/control.toml",
+                "/etc/narrowssh/control.toml.d",
+            ),
+            ("control.toml", "control.toml.d"),
+            ("./control.toml", "./control.toml.d"),
+            ("../etc/control.toml", "../etc/control.toml.d"),
+            ("etc/control.toml/", "etc/control.toml.d"),
+            ("/", "/.d"),
+        ] {
+            assert_eq!(extensions_dir(Path::new(file)), Path::new(dir));
+        }
+    }
+}
+
+/// Tests for [`sort_extensions`]
+mod sort_extensions {
+    use super::*;
+
+    /// Sorts every rotation of `names`, both reversed and not, and checks
+    /// that the result is always `names`.
+    fn must_sort(names: &[&str], main_ext: Option<&str>) {
+        let expected: Vec<_> =
+            names.iter().map(|n| PathBuf::from("/d").join(n)).collect();
+
+        for reverse in [false, true] {
+            for shift in 0..expected.len() {
+                let mut entries = expected.clone();
+                if reverse {
+                    entries.reverse();
+                }
+                entries.rotate_left(shift);
+
+                sort_extensions(&mut entries, main_ext.map(OsStr::new));
+                assert_eq!(entries, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn multi_dotted() {
+        must_sort(
+            &["2.conf", "10.conf", "10.service.conf", "a.conf", "a.b.conf"],
+            Some("conf"),
+        );
+    }
+
+    #[test]
+    fn numbers() {
+        must_sort(
+            &[
+                "001.toml", "01.toml", "1.toml", "2.toml", "9a.toml",
+                "10.toml",
+            ],
+            Some("toml"),
+        );
+        must_sort(&["x1y2", "x1y10", "x2", "x10y1"], None);
+    }
+
+    #[test]
+    fn separators() {
+        must_sort(
+            &[
+                "02.conf",
+                "02-a.conf",
+                "02.a.conf",
+                "02.conf.conf",
+                "02~a.conf",
+            ],
+            Some("conf"),
+        );
+    }
+}
+
+/// Tests for [`VisitOptions::realm`]
+mod realm {
+    use super::*;
+
+    fn load(realm: Option<&str>, main: &str) -> Result<ControlManager> {
+        let mut ws = MockWorkspace::new()?;
+
+        ws.add_user(0, "root", "root")?;
+        ws.add_user(1000, "alice@EXAMPLE.COM", "home/alice")?;
+        ws.add_user(1001, "EXAMPLE.COM\\bob", "home/bob")?;
+        ws.add_user(1002, "carol", "home/carol")?;
+        ws.add_user(1003, "carol@EXAMPLE.COM", "home/carol2")?;
+
+        let main = ws.add_file("etc/main.toml", 0, 0o600, main)?;
+        let options = VisitOptions {
+            realm: realm.map(str::to_owned),
+            ..VisitOptions::default()
+        };
+        ControlManager::load(&ws, main, &options)
+    }
+
+    #[test]
+    fn suffix_and_prefix() -> Result<()> {
+        let cm = load(
+            Some("EXAMPLE.COM"),
+            "[alice]\nenable = true\n[bob]\nenable = true",
+        )?;
+
+        assert_eq!(cm.get_user_control(1000).enable, true);
+        assert_eq!(cm.get_user_control(1001).enable, true);
+        Ok(())
+    }
+
+    #[test]
+    fn disabled_by_default() {
+        assert!(load(None, "[alice]\nenable = true").is_err());
+    }
+
+    #[test]
+    fn other_realm() {
+        assert!(load(Some("OTHER.ORG"), "[alice]\nenable = true").is_err());
+    }
+
+    #[test]
+    fn exact_match_wins() -> Result<()> {
+        let cm = load(Some("EXAMPLE.COM"), "[carol]\nenable = true")?;
+
+        assert_eq!(cm.get_user_control(1002).enable, true);
+        assert_eq!(cm.get_user_control(1003).enable, false);
+        Ok(())
+    }
+
+    #[test]
+    fn qualified_name() -> Result<()> {
+        let cm = load(None, "[\"alice@EXAMPLE.COM\"]\nenable = true")?;
+
+        assert_eq!(cm.get_user_control(1000).enable, true);
+        Ok(())
+    }
+}
+
+/// Tests for [`VisitOptions::ambiguous_names`]
+mod ambiguous_names {
+    use super::*;
+
+    fn load(
+        ambiguous_names: AmbiguousNames,
+        main: &str,
+    ) -> Result<ControlManager> {
+        let mut ws = MockWorkspace::new()?;
+
+        ws.add_user(0, "root", "root")?;
+        ws.add_user(1000, "alice", "home/alice")?;
+        ws.add_user(1001, "alice", "home/alice2")?;
+        ws.add_user(1002, "bob", "home/bob")?;
+
+        let main = ws.add_file("etc/main.toml", 0, 0o600, main)?;
+        let options = VisitOptions {
+            ambiguous_names,
+            ..VisitOptions::default()
+        };
+        ControlManager::load(&ws, main, &options)
+    }
+
+    #[test]
+    fn error_by_default() {
+        let error = load(AmbiguousNames::default(), "[alice]\nenable = true")
+            .unwrap_err();
+
+        assert!(format!("{error:#}").contains("not unique"));
+    }
+
+    #[test]
+    fn skip() -> Result<()> {
+        let cm = load(
+            AmbiguousNames::Skip,
+            "[alice]\nenable = true\n[bob]\nenable = true",
+        )?;
+
+        assert_eq!(cm.get_user_control(1000).enable, false);
+        assert_eq!(cm.get_user_control(1001).enable, false);
+        assert_eq!(cm.get_user_control(1002).enable, true);
+        assert_eq!(cm.warnings().len(), 1);
+        assert!(cm.warnings()[0].contains("\"alice\""));
+        assert!(cm.is_ambiguous(1000));
+        assert!(cm.is_ambiguous(1001));
+        assert!(!cm.is_ambiguous(1002));
+        Ok(())
+    }
+
+    #[test]
+    fn no_warnings_without_ambiguity() -> Result<()> {
+        let cm = load(AmbiguousNames::Skip, "[bob]\nenable = true")?;
+
+        assert!(cm.warnings().is_empty());
+        assert!(!cm.is_ambiguous(1002));
+        Ok(())
+    }
+}
+
+/// Tests for [`describe_file`]
+mod describe_file {
+    use super::*;
+
+    /// Creates a control layout with a main file and a drop-in file.
+    fn workspace() -> Result<(MockWorkspace, PathBuf, PathBuf)> {
+        let mut ws = MockWorkspace::new()?;
+
+        ws.add_user(0, "root", "root")?;
+        ws.add_user(1000, "alice", "home/alice")?;
+        ws.add_group(100, "devs", &["alice"]);
+
+        #[rustfmt::skip]
+        let main = ws.add_file("etc/main.toml", 0, 0o600, r#"
+            ["*"]
+            enable = false
+        "#)?;
+        ws.add_dir("etc/main.toml.d/", 0, 0o700)?;
+        #[rustfmt::skip]
+        let dropin = ws.add_file("etc/main.toml.d/10.toml", 0, 0o600, r#"
+            [alice]
+            enable = true
+
+            ["@devs"]
+            commands = ["build"]
+
+            ["profile:ops"]
+            commands = ["restart"]
+        "#)?;
+
+        Ok((ws, main, dropin))
+    }
+
+    #[test]
+    fn dropin() -> Result<()> {
+        let (ws, _, dropin) = workspace()?;
+
+        let description =
+            describe_file(&dropin, &ws, &VisitOptions::default())?;
+
+        let names: Vec<_> =
+            description.sections.iter().map(|s| &*s.name).collect();
+        assert_eq!(names, ["@devs", "alice", "profile:ops"]);
+
+        let targets: Vec<_> =
+            description.sections.iter().map(|s| &s.target).collect();
+        assert_eq!(
+            targets,
+            [
+                &Target::Selected(vec![1000]),
+                &Target::User(1000),
+                &Target::Profile(String::from("ops")),
+            ]
+        );
+        assert_eq!(description.sections[1].fields, ["enable"]);
+        assert_eq!(
+            description.to_string(),
+            format!(
+                "{} defines sections [@devs, alice, profile:ops]",
+                dropin.display()
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn ignores_extensions() -> Result<()> {
+        let (ws, main, _) = workspace()?;
+
+        let description =
+            describe_file(&main, &ws, &VisitOptions::default())?;
+
+        let names: Vec<_> =
+            description.sections.iter().map(|s| &*s.name).collect();
+        assert_eq!(names, ["*"]);
+        Ok(())
+    }
+
+    #[test]
+    fn insecure() -> Result<()> {
+        let (mut ws, _, _) = workspace()?;
+        let file = ws.add_file("etc/main.toml.d/20.toml", 0, 0o644, "")?;
+
+        assert!(describe_file(&file, &ws, &VisitOptions::default()).is_err());
+        Ok(())
+    }
+}
+
+/// Tests for [`ControlManager::to_toml`]
+mod to_toml {
+    use super::*;
+
+    /// Creates a workspace with a few users and a group.
+    fn workspace() -> Result<MockWorkspace> {
+        let mut ws = MockWorkspace::new()?;
+
+        ws.add_user(0, "root", "root")?;
+        ws.add_user(1000, "alice", "home/alice")?;
+        ws.add_user(1001, "bob", "home/bob")?;
+        ws.add_user(1002, "carol", "home/carol")?;
+        ws.add_user(2000, "dan", "home/dan")?;
+        ws.add_group(100, "devs", &["bob", "carol"]);
+
+        Ok(ws)
+    }
+
+    /// Returns the control of `uid` in a comparable form.
+    fn effective(control: &ControlManager, uid: uid_t) -> serde_json::Value {
+        serde_json::to_value(control.get_user_control(uid)).unwrap()
+    }
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let mut ws = workspace()?;
+
+        #[rustfmt::skip]
+        let main = ws.add_file("etc/main.toml", 0, 0o600, r#"
+            ["*"]
+            enable = true
+            commands = ["backup"]
+            extra_key_types = ["ssh-future"]
+
+            ["profile:ops"]
+            commands = ["restart", "status"]
+            authorized_keys_owner = "root"
+
+            ["@devs"]
+            profile = "ops"
+
+            ["1000-1999"]
+            max_line_length = 4096
+
+            [carol]
+            extra_key_types = []
+            authorized_keys_mode = 0o640
+            long_line = "error"
+
+            [dan]
+            enable = false
+            command_groups = ["deploy"]
+
+            [command-groups]
+            deploy = ["deploy *"]
+        "#)?;
+        let original =
+            ControlManager::load(&ws, main, &VisitOptions::default())?;
+
+        let uids = [2000, 0, 1000, 1001, 1002, 3000];
+        let dumped = original.to_toml(&uids)?;
+
+        let copy = ws.add_file("etc/copy.toml", 0, 0o600, &dumped)?;
+        let options = VisitOptions {
+            extensions: false,
+            ..VisitOptions::default()
+        };
+        let reloaded = ControlManager::load(&ws, copy, &options)?;
+
+        for uid in uids {
+            assert_eq!(
+                effective(&reloaded, uid),
+                effective(&original, uid),
+                "UID {uid} in\n{dumped}"
+            );
+            assert_eq!(
+                reloaded.get_user_control(uid).group_commands,
+                original.get_user_control(uid).group_commands,
+            );
+        }
+
+        // Output is canonical
+        assert_eq!(reloaded.to_toml(&uids)?, dumped);
+        Ok(())
+    }
+
+    #[test]
+    fn bool_overrides() -> Result<()> {
+        let mut ws = workspace()?;
+
+        #[rustfmt::skip]
+        let main = ws.add_file("etc/main.toml", 0, 0o600, r#"
+            ["*"]
+            enable = true
+            pty = true
+            pub_keys_fallback = true
+            strict_user_config = true
+
+            [alice]
+            pty = false
+            pub_keys_fallback = false
+            strict_user_config = false
+        "#)?;
+        let original =
+            ControlManager::load(&ws, main, &VisitOptions::default())?;
+        assert!(!original.get_user_control(1000).pub_keys_fallback);
+
+        let uids = [1000, 1001];
+        let dumped = original.to_toml(&uids)?;
+        let copy = ws.add_file("etc/copy.toml", 0, 0o600, &dumped)?;
+        let reloaded =
+            ControlManager::load(&ws, copy, &VisitOptions::default())?;
+
+        for uid in uids {
+            assert_eq!(
+                effective(&reloaded, uid),
+                effective(&original, uid),
+                "UID {uid} in\n{dumped}"
+            );
+        }
+        assert!(original.diff(&reloaded, &uids)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn layout() -> Result<()> {
+        let mut ws = workspace()?;
+
+        #[rustfmt::skip]
+        let main = ws.add_file("etc/main.toml", 0, 0o600, r#"
+            ["*"]
+            enable = true
+
+            [bob]
+            commands = ["backup"]
+        "#)?;
+        let control =
+            ControlManager::load(&ws, main, &VisitOptions::default())?;
+
+        let dumped = control.to_toml(&[1001, 1000, 1001])?;
+
+        assert!(dumped.starts_with("[\"*\"]\n"), "{dumped}");
+        assert!(dumped.contains("\nenable = true\n"), "{dumped}");
+        assert!(dumped.ends_with("\n[1001]\ncommands = [\"backup\"]\n"));
+        assert!(!dumped.contains("[1000]"));
+        Ok(())
+    }
+}
+
+/// Tests for [`ControlManager::diff`] and
+/// [`ControlManager::ensure_matches`]
+mod diff {
+    use super::*;
+
+    /// Loads the live and the staged control from `live` and `staged`.
+    fn load(
+        live: &str,
+        staged: &str,
+    ) -> Result<(ControlManager, ControlManager)> {
+        let mut ws = MockWorkspace::new()?;
+        ws.add_user(1000, "alice", "home/alice")?;
+        ws.add_user(1001, "bob", "home/bob")?;
+
+        let live = ws.add_file("etc/live.toml", 0, 0o600, live)?;
+        let staged = ws.add_file("etc/staged.toml", 0, 0o600, staged)?;
+        let options = VisitOptions {
+            extensions: false,
+            ..VisitOptions::default()
+        };
+
+        Ok((
+            ControlManager::load(&ws, live, &options)?,
+            ControlManager::load(&ws, staged, &options)?,
+        ))
+    }
+
+    #[test]
+    fn matching() -> Result<()> {
+        #[rustfmt::skip]
+        let (live, staged) = load(r#"
+            ["*"]
+            enable = true
+            commands = ["backup"]
+        "#, r#"
+            ["profile:backup"]
+            enable = true
+            commands = ["backup"]
+
+            [alice]
+            profile = "backup"
+
+            [bob]
+            profile = "backup"
+        "#)?;
+
+        assert!(live.diff(&staged, &[1000, 1001])?.is_empty());
+        live.ensure_matches(&staged, &[1000, 1001])?;
+        Ok(())
+    }
+
+    #[test]
+    fn differing() -> Result<()> {
+        #[rustfmt::skip]
+        let (live, staged) = load(r#"
+            ["*"]
+            enable = true
+            commands = ["backup"]
+
+            [bob]
+            authorized_keys_mode = 0o640
+        "#, r#"
+            ["*"]
+            enable = true
+            commands = ["backup", "restore"]
+        "#)?;
+
+        let differences = live.diff(&staged, &[1001, 1000, 1001])?;
+        let summary: Vec<_> = differences
+            .iter()
+            .map(|d| (d.uid, d.field.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (1000, "commands"),
+                (1001, "authorized_keys_mode"),
+                (1001, "commands")
+            ]
+        );
+        assert_eq!(differences[1].theirs, None);
+        assert_eq!(
+            differences[0].to_string(),
+            r#"UID 1000: commands: ["backup"] -> ["backup", "restore"]"#
+        );
+
+        let error = live.ensure_matches(&staged, &[1000]).unwrap_err();
+        assert!(error.to_string().contains("1 setting(s)"), "{error}");
+
+        // Users that are not compared do not matter
+        assert!(live.diff(&staged, &[])?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn command_groups() -> Result<()> {
+        #[rustfmt::skip]
+        let (live, staged) = load(r#"
+            [command-groups]
+            backup = ["backup *"]
+
+            [alice]
+            command_groups = ["backup"]
+        "#, r#"
+            [command-groups]
+            backup = ["backup", "restore"]
+
+            [alice]
+            command_groups = ["backup"]
+        "#)?;
+
+        let differences = live.diff(&staged, &[1000, 1001])?;
+        assert_eq!(differences.len(), 1);
+        assert_eq!(differences[0].uid, 1000);
+        assert_eq!(differences[0].field, COMMAND_GROUPS_SECTION);
+        Ok(())
+    }
+}
+
+/// Tests for [`Config::load`]
+mod load_config {
+    use super::*;
+
+    #[test]
+    fn json() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+        ws.add_user(1000, "alice", "home/alice")?;
+        let file = ws.add_file(
+            "home/alice/.narrowssh.conf",
+            1000,
+            0o600,
+            r#"{ "keys": [] }"#,
+        )?;
+
+        let error = Config::load(&ws, file, 1000, &VisitOptions::default())
+            .unwrap_err();
+        let message = format!("{error:#}");
+        assert!(
+            message.contains("looks like JSON but has a .conf extension"),
+            "{message}"
+        );
+        Ok(())
+    }
+}
+
+/// Tests for [`Config::load_pub_keys`]
+mod load_pub_keys {
+    use super::*;
+
+    /// Creates a workspace with user alice and her `.ssh` directory.
+    fn workspace() -> Result<MockWorkspace> {
+        let mut ws = MockWorkspace::new()?;
+        ws.add_user(1000, "alice", "home/alice")?;
+        ws.add_dir("home/alice/.ssh", 1000, 0o700)?;
+        Ok(ws)
+    }
+
+    #[test]
+    fn pub_files() -> Result<()> {
+        let mut ws = workspace()?;
+        ws.add_file(
+            "home/alice/.ssh/id_rsa.pub",
+            1000,
+            0o644,
+            "ssh-rsa B\n",
+        )?;
+        ws.add_file(
+            "home/alice/.ssh/id_ed25519.pub",
+            1000,
+            0o644,
+            "# laptop\nssh-ed25519 A alice@laptop\n\n",
+        )?;
+        ws.add_file("home/alice/.ssh/id_rsa", 1000, 0o600, "PRIVATE")?;
+        ws.add_file("home/alice/.ssh/authorized_keys", 1000, 0o600, "x")?;
+
+        let config =
+            Config::load_pub_keys(&ws, &ws.path("home/alice/.ssh"), 1000)?;
+
+        assert_eq!(config.keys, ["ssh-ed25519 A alice@laptop", "ssh-rsa B"]);
+        assert!(config.enable_commands.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn missing_dir() -> Result<()> {
+        let ws = MockWorkspace::new()?;
+
+        let config = Config::load_pub_keys(&ws, &ws.path("nowhere"), 1000)?;
+
+        assert!(config.keys.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn foreign_file() -> Result<()> {
+        let mut ws = workspace()?;
+        ws.add_file("home/alice/.ssh/id_rsa.pub", 1001, 0o644, "ssh-rsa B")?;
+
+        let error =
+            Config::load_pub_keys(&ws, &ws.path("home/alice/.ssh"), 1000)
+                .unwrap_err();
+
+        assert!(error.to_string().contains("[security"), "{error}");
+        Ok(())
+    }
+
+    #[test]
+    fn writable_file() -> Result<()> {
+        let mut ws = workspace()?;
+        ws.add_file("home/alice/.ssh/id_rsa.pub", 1000, 0o664, "ssh-rsa B")?;
+
+        let error =
+            Config::load_pub_keys(&ws, &ws.path("home/alice/.ssh"), 1000)
+                .unwrap_err();
+
+        assert!(error.to_string().contains("change to 644"), "{error}");
+        Ok(())
+    }
+
+    #[test]
+    fn writable_dir() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+        ws.add_dir("home/alice/.ssh", 1000, 0o777)?;
+
+        assert!(Config::load_pub_keys(
+            &ws,
+            &ws.path("home/alice/.ssh"),
+            1000
+        )
+        .is_err());
+        Ok(())
+    }
+}
+
+/// Tests for [`ControlManager::enabled_diff`]
+mod enabled_diff {
+    use super::*;
+
+    #[test]
+    fn fallback_and_user() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+        ws.add_user(1000, "alice", "home/alice")?;
+        ws.add_user(1001, "bob", "home/bob")?;
+        ws.add_user(1002, "carol", "home/carol")?;
+        ws.add_user(1003, "dave", "home/dave")?;
+
+        #[rustfmt::skip]
+        let old = ws.add_file("etc/old.toml", 0, 0o600, r#"
+            ["*"]
+            enable = false
+
+            [alice]
+            enable = true
+
+            [bob]
+            enable = true
+            commands = ["backup"]
+        "#)?;
+        #[rustfmt::skip]
+        let new = ws.add_file("etc/new.toml", 0, 0o600, r#"
+            ["*"]
+            enable = true
+
+            [alice]
+            enable = false
+
+            [bob]
+            commands = ["restore"]
+
+            [dave]
+            enable = false
+        "#)?;
+
+        let options = VisitOptions {
+            extensions: false,
+            ..VisitOptions::default()
+        };
+        let old = ControlManager::load(&ws, old, &options)?;
+        let new = ControlManager::load(&ws, new, &options)?;
+
+        // Bob stays enabled with other commands, dave stays disabled
+        assert_eq!(old.enabled_diff(&new, &ws), (vec![1002], vec![1000]));
+        assert_eq!(new.enabled_diff(&old, &ws), (vec![1000], vec![1002]));
+        assert_eq!(old.enabled_diff(&old, &ws), (vec![], vec![]));
+        Ok(())
+    }
+}
+
+/// Tests for [`ControlManager::reload`]
+mod reload {
+    use super::*;
+
+    /// Creates a workspace with users alice and bob, where alice is enabled
+    /// by the main control file.
+    fn workspace() -> Result<(MockWorkspace, PathBuf)> {
+        let mut ws = MockWorkspace::new()?;
+        ws.add_user(1000, "alice", "home/alice")?;
+        ws.add_user(1001, "bob", "home/bob")?;
+        ws.add_dir("etc/main.toml.d", 0, 0o700)?;
+        let main = ws.add_file(
+            "etc/main.toml",
+            0,
+            0o600,
+            "[alice]\nenable = true\ncommands = [\"backup\"]",
+        )?;
+        Ok((ws, main))
+    }
+
+    #[test]
+    fn reports_changes() -> Result<()> {
+        let (mut ws, main) = workspace()?;
+        let options = VisitOptions::default();
+        let mut control = ControlManager::load(&ws, &main, &options)?;
+
+        let unchanged = control.reload(&ws, &options)?;
+        assert_eq!(unchanged, ControlChange::default());
+
+        std::fs::write(
+            &main,
+            "[alice]\nenable = true\ncommands = [\"sync\"]",
+        )?;
+        let ext = ws.add_file(
+            "etc/main.toml.d/bob.toml",
+            0,
+            0o600,
+            "[bob]\npty = true",
+        )?;
+
+        let change = control.reload(&ws, &options)?;
+        let summary: Vec<_> = change
+            .differences
+            .iter()
+            .map(|d| (d.uid, d.field.as_str()))
+            .collect();
+        assert_eq!(summary, [(1000, "commands"), (1001, "pty")]);
+        assert_eq!(change.added_files, [ext]);
+        assert!(change.removed_files.is_empty());
+
+        assert_eq!(control.get_user_control(1000).commands, ["sync"]);
+        assert!(control.get_user_control(1001).pty);
+        Ok(())
+    }
+
+    #[test]
+    fn failure_keeps_control() -> Result<()> {
+        let (ws, main) = workspace()?;
+        let options = VisitOptions::default();
+        let mut control = ControlManager::load(&ws, &main, &options)?;
+
+        std::fs::write(&main, "[alice]\nenable = \"yes\"")?;
+        assert!(control.reload(&ws, &options).is_err());
+
+        assert!(control.get_user_control(1000).enable);
+        assert_eq!(control.get_user_control(1000).commands, ["backup"]);
+        assert_eq!(control.files(), [main]);
+        Ok(())
+    }
+
+    #[test]
+    fn not_loaded() -> Result<()> {
+        let (ws, _) = workspace()?;
+        let mut control = ControlManager::default();
+        assert!(control.reload(&ws, &VisitOptions::default()).is_err());
+        Ok(())
+    }
 }
@@ -0,0 +1,152 @@
+//! Human-readable narrative of how control settings were determined.
+
+use std::fmt::Write;
+
+use anyhow::Result;
+use uzers::User;
+
+use crate::config::{
+    reroot, resolve_path, ControlManager, Section, Target, VisitOptions,
+};
+use crate::schema::CONTROL_FIELDS;
+
+#[cfg(test)]
+mod tests;
+
+/// Returns a description of how the control of `user` was determined.
+///
+/// The description lists the control files that were read, the sections that
+/// apply to `user` and, for every setting, its effective value and the
+/// section it came from, following the precedence described in
+/// [`ControlManager`].
+///
+/// # Errors
+/// The function will fail if the control of `user` cannot be serialized.
+pub fn explain_control(
+    control: &ControlManager,
+    user: &User,
+) -> Result<String> {
+    let uid = user.uid();
+    let mut result = String::new();
+
+    writeln!(
+        result,
+        "user {} (UID {uid}):",
+        user.name().to_string_lossy()
+    )?;
+
+    writeln!(result, "  control files read:")?;
+    for file in control.files() {
+        writeln!(result, "    {}", file.display())?;
+    }
+
+    let matching: Vec<_> = control
+        .sections()
+        .iter()
+        .filter(|s| s.target.matches(uid))
+        .collect();
+
+    writeln!(result, "  matching sections:")?;
+    if matching.is_empty() {
+        writeln!(result, "    none")?;
+    }
+    for section in &matching {
+        writeln!(result, "    {section}")?;
+    }
+
+    let values = serde_json::to_value(control.get_user_control(uid))?;
+
+    writeln!(result, "  settings:")?;
+    for field in CONTROL_FIELDS {
+        // Profiles are resolved into other settings
+        if field.name == "profile" {
+            continue;
+        }
+
+        let value = values
+            .get(field.name)
+            .map_or_else(|| String::from("unset"), ToString::to_string);
+
+        let source = winner(&matching, field.name).map_or_else(
+            || String::from("built-in default"),
+            |s| format!("set by {s}"),
+        );
+
+        writeln!(result, "    {} = {value}: {source}", field.name)?;
+    }
+
+    Ok(result)
+}
+
+/// Returns a summary of the effective control of `user`.
+///
+/// The summary tells whether `user` is enabled and where its configuration
+/// and `authorized_keys` are, with paths resolved for `user` and moved below
+/// [`VisitOptions::target_root`]. Every setting names its origin: the `*`
+/// fallback, an override by a section that selects `user`, or the built-in
+/// default. Unlike [`explain_control`], only these settings are described.
+///
+/// # Errors
+/// The function will fail only if formatting fails. Paths that cannot be
+/// resolved are reported in the summary.
+pub fn control_status(
+    control: &ControlManager,
+    user: &User,
+    options: &VisitOptions,
+) -> Result<String> {
+    let uid = user.uid();
+    let user_control = control.get_user_control(uid);
+    let mut result = String::new();
+
+    let state = if user_control.enable {
+        "enabled"
+    } else {
+        "disabled"
+    };
+    writeln!(
+        result,
+        "{} (UID {uid}): {state}",
+        user.name().to_string_lossy()
+    )?;
+
+    let matching: Vec<_> = control
+        .sections()
+        .iter()
+        .filter(|s| s.target.matches(uid))
+        .collect();
+
+    let resolve = |template: &str| {
+        resolve_path(template, user)
+            .and_then(|path| reroot(&path, options.target_root.as_deref()))
+            .map_or_else(
+                |error| format!("unresolved ({error:#})"),
+                |path| path.display().to_string(),
+            )
+    };
+
+    let settings = [
+        ("enable", user_control.enable.to_string()),
+        ("config", resolve(&user_control.config)),
+        ("authorized_keys", resolve(&user_control.authorized_keys)),
+    ];
+    for (name, value) in &settings {
+        let source = match winner(&matching, name) {
+            None => String::from("built-in default"),
+            Some(s) if s.target == Target::All => format!("fallback, {s}"),
+            Some(s) => format!("override, {s}"),
+        };
+        writeln!(result, "  {name} = {value} ({source})")?;
+    }
+
+    Ok(result)
+}
+
+/// Returns the section among `matching` that determines `field`, if any.
+fn winner<'a>(matching: &[&'a Section], field: &str) -> Option<&'a Section> {
+    // On ties, max_by_key picks the last section, which wins
+    matching
+        .iter()
+        .filter(|s| s.fields.iter().any(|f| f == field))
+        .max_by_key(|s| s.target.precedence())
+        .copied()
+}
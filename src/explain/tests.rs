@@ -0,0 +1,131 @@
+#![allow(clippy::needless_raw_string_hashes)]
+
+pub use crate::config::VisitOptions;
+pub use crate::workspace::mock::MockWorkspace;
+pub use crate::workspace::Workspace;
+
+pub use super::*;
+
+#[test]
+fn narrative() -> Result<()> {
+    let mut ws = MockWorkspace::new()?;
+
+    ws.add_user(0, "root", "root")?;
+    ws.add_user(1000, "alice", "home/alice")?;
+    ws.add_user(1001, "bob", "home/bob")?;
+
+    #[rustfmt::skip]
+    let main = ws.add_file("etc/main.toml", 0, 0o600, r#"
+        ["*"]
+        config = "~/config.conf"
+        commands = ["uptime"]
+
+        [alice]
+        commands = ["backup"]
+
+        [bob]
+        enable = true
+    "#)?;
+    ws.add_dir("etc/main.toml.d", 0, 0o700)?;
+    #[rustfmt::skip]
+    let ext = ws.add_file("etc/main.toml.d/01.toml", 0, 0o600, r#"
+        [alice]
+        enable = true
+
+        ["*"]
+        enable = false
+    "#)?;
+
+    let cm = ControlManager::load(&ws, &main, &VisitOptions::default())?;
+    let alice = ws.users().user_by_uid(1000).unwrap();
+    let text = explain_control(&cm, alice)?;
+
+    let main = main.display();
+    let ext = ext.display();
+
+    assert!(text.contains(&format!("    {main}\n")), "{text}");
+    assert!(text.contains(&format!("    {ext}\n")), "{text}");
+    assert!(text.contains(&format!("section \"alice\" in {main}")));
+    assert!(!text.contains("\"bob\""), "{text}");
+
+    assert!(
+        text.contains(&format!(
+            "enable = true: set by section \"alice\" in {ext}"
+        )),
+        "{text}"
+    );
+    assert!(
+        text.contains(&format!(
+            "config = \"~/config.conf\": set by section \"*\" in {main}"
+        )),
+        "{text}"
+    );
+    assert!(
+        text.contains(&format!(
+            "commands = [\"backup\"]: set by section \"alice\" in {main}"
+        )),
+        "{text}"
+    );
+    assert!(
+        text.contains("command_conflict = \"error\": built-in default"),
+        "{text}"
+    );
+    Ok(())
+}
+
+#[test]
+fn status() -> Result<()> {
+    let mut ws = MockWorkspace::new()?;
+
+    ws.add_user(1000, "alice", "home/alice")?;
+    ws.add_user(1001, "bob", "home/bob")?;
+
+    #[rustfmt::skip]
+    let main = ws.add_file("etc/main.toml", 0, 0o600, r#"
+        ["*"]
+        enable = true
+        config = "~/config.conf"
+
+        [bob]
+        enable = false
+    "#)?;
+
+    let cm = ControlManager::load(&ws, &main, &VisitOptions::default())?;
+    let options = VisitOptions::default();
+    let main = main.display();
+
+    let alice = ws.users().user_by_uid(1000).unwrap();
+    let text = control_status(&cm, alice, &options)?;
+    assert!(text.starts_with("alice (UID 1000): enabled\n"), "{text}");
+    assert!(
+        text.contains(&format!(
+            "  enable = true (fallback, section \"*\" in {main})\n"
+        )),
+        "{text}"
+    );
+    assert!(
+        text.contains(&format!(
+            "  config = {} (fallback, section \"*\" in {main})\n",
+            ws.path("home/alice/config.conf").display()
+        )),
+        "{text}"
+    );
+    assert!(
+        text.contains(&format!(
+            "  authorized_keys = {} (built-in default)\n",
+            ws.path("home/alice/.ssh/authorized_keys").display()
+        )),
+        "{text}"
+    );
+
+    let bob = ws.users().user_by_uid(1001).unwrap();
+    let text = control_status(&cm, bob, &options)?;
+    assert!(text.starts_with("bob (UID 1001): disabled\n"), "{text}");
+    assert!(
+        text.contains(&format!(
+            "  enable = false (override, section \"bob\" in {main})\n"
+        )),
+        "{text}"
+    );
+    Ok(())
+}
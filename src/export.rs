@@ -0,0 +1,217 @@
+//! Reconstruction of control from managed blocks installed on disk.
+//!
+//! A managed block records what narrowssh enforced, but not why: the forced
+//! command and the permitted forwardings survive, while the sections,
+//! profiles and fallbacks that produced them do not. Neither do settings
+//! that leave no trace in `authorized_keys`, such as `command_conflict` or
+//! the location of user configuration. The export is therefore a
+//! best-effort starting point that must be reviewed before use.
+
+use std::fs;
+use std::io::ErrorKind;
+
+use anyhow::{bail, Context, Result};
+use uzers::User;
+
+use crate::authorized_keys::{
+    BlockMarkers, KeyLine, Marker, DENY_COMMAND, EXEC_COMMAND,
+};
+use crate::config::{
+    reroot, resolve_path, Control, ControlManager, VisitOptions,
+};
+
+#[cfg(test)]
+mod tests;
+
+/// Comment that opens the output of [`export_control`].
+const HEADER: &str = "\
+# Control reconstructed by narrowssh from installed managed blocks.
+# This is a best-effort export: review it before use.
+";
+
+/// Key options written by narrowssh and the control fields they stem from.
+const PERMIT_FIELDS: &[(&str, &str)] = &[
+    ("permit-port-forwarding", "port_forwarding"),
+    ("permit-X11-forwarding", "x11_forwarding"),
+    ("permit-agent-forwarding", "agent_forwarding"),
+    ("pty", "pty"),
+];
+
+/// Splits the arguments of a forced command written by narrowssh into words.
+///
+/// This undoes the quoting applied by
+/// [`forced_command`][crate::authorized_keys::forced_command]: words are
+/// separated by spaces and consist of single-quoted strings and `\'`.
+///
+/// # Errors
+/// The function will fail if `args` is not quoted this way.
+pub fn split_words(args: &str) -> Result<Vec<String>> {
+    let mut result = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = args.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' => result.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => bail!("unterminated quote in {args:?}"),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some('\'') => word.get_or_insert_with(String::new).push('\''),
+                _ => bail!("unexpected escape in {args:?}"),
+            },
+            c => bail!("unexpected unquoted {c:?} in {args:?}"),
+        }
+    }
+    result.extend(word);
+
+    Ok(result)
+}
+
+/// Returns the control fields enforced by the key lines of a managed block.
+///
+/// `block` is the text of the block including markers; comments and revoked
+/// keys are ignored. The result contains `action` or `commands`, and the
+/// permitted forwardings. An empty table is returned if the block holds no
+/// keys to observe, as is the case for users without keys.
+///
+/// # Errors
+/// The function will fail if a key line cannot be parsed, if a forced
+/// command was not written by narrowssh, or if keys disagree on the
+/// enforced settings.
+pub fn observe_block(block: &str) -> Result<toml::Table> {
+    let mut result: Option<toml::Table> = None;
+
+    for line in block.lines() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let key = KeyLine::parse_with(line, &[])
+            .with_context(|| format!("parsing key line {line:?}"))?;
+        if key.marker == Some(Marker::Revoked) {
+            continue;
+        }
+
+        let fields = observe_key(&key)?;
+        match &result {
+            Some(first) if *first != fields => bail!(
+                "key {} is installed with different settings than the keys \
+                before it",
+                key.comment.as_deref().unwrap_or(&key.key_type)
+            ),
+            Some(_) => {}
+            None => result = Some(fields),
+        }
+    }
+
+    Ok(result.unwrap_or_default())
+}
+
+/// Returns the control fields enforced by a single `key`.
+fn observe_key(key: &KeyLine) -> Result<toml::Table> {
+    let mut result = toml::Table::new();
+
+    let command = key
+        .option("command")
+        .and_then(|o| o.value.as_deref())
+        .context("key has no forced command")?;
+
+    if command == DENY_COMMAND {
+        result.insert("action".into(), "deny".into());
+    } else if command == EXEC_COMMAND
+        || command.starts_with(&format!("{EXEC_COMMAND} "))
+    {
+        let commands = split_words(&command[EXEC_COMMAND.len()..])?;
+        result.insert("commands".into(), commands.into());
+    } else {
+        bail!("forced command {command:?} was not written by narrowssh");
+    }
+
+    for (option, field) in PERMIT_FIELDS {
+        if key.option(option).is_some() {
+            result.insert((*field).into(), true.into());
+        }
+    }
+
+    Ok(result)
+}
+
+/// Returns the section of `user` observed in `authorized_keys`.
+fn export_user(
+    user: &User,
+    control: &Control,
+    options: &VisitOptions,
+) -> Result<toml::Table> {
+    let path = resolve_path(&control.authorized_keys, user)?;
+    let path = reroot(&path, options.target_root.as_deref())?;
+
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(error) if error.kind() == ErrorKind::NotFound => String::new(),
+        Err(error) => {
+            return Err(error)
+                .with_context(|| format!("reading {}", path.display()))
+        }
+    };
+
+    let markers = BlockMarkers::of(control);
+    let range = markers.locate(&content)?;
+    let mut result = match &range {
+        Some(range) => observe_block(&content[range.clone()])
+            .with_context(|| format!("reading {}", path.display()))?,
+        None => toml::Table::new(),
+    };
+    result.insert("enable".into(), range.is_some().into());
+
+    // Where the block was found is not recorded in the block itself
+    result.insert(
+        "authorized_keys".into(),
+        control.authorized_keys.clone().into(),
+    );
+    for (field, marker) in &[
+        ("begin_marker", &control.begin_marker),
+        ("end_marker", &control.end_marker),
+    ] {
+        if let Some(marker) = marker {
+            result.insert((*field).into(), marker.clone().into());
+        }
+    }
+
+    Ok(result)
+}
+
+/// Returns a control file reproducing the managed blocks of `users`.
+///
+/// The `authorized_keys` of every user is located according to `control`
+/// and its managed block, if any, is translated into a section named after
+/// the user. Users without a managed block are exported as disabled. See the
+/// [module documentation][self] for what cannot be recovered.
+///
+/// # Errors
+/// The function will fail if `authorized_keys` of a user cannot be located
+/// or read, or if its managed block cannot be interpreted, see
+/// [`observe_block`].
+pub fn export_control(
+    control: &ControlManager,
+    users: &[&User],
+    options: &VisitOptions,
+) -> Result<String> {
+    let mut sections = toml::Table::new();
+
+    for user in users {
+        let name = user.name().to_string_lossy();
+        let section =
+            export_user(user, &control.get_user_control(user.uid()), options)
+                .with_context(|| format!("could not export user {name}"))?;
+        sections.insert(name.into_owned(), section.into());
+    }
+
+    Ok(format!("{HEADER}\n{}", toml::to_string(&sections)?))
+}
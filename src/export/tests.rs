@@ -0,0 +1,147 @@
+pub use crate::authorized_keys::{forced_command, EXEC_COMMAND};
+pub use crate::config::{KeysAction, VisitOptions};
+pub use crate::refresh::{plan_user, refresh_user, Change};
+pub use crate::workspace::mock::MockWorkspace;
+pub use crate::workspace::Workspace;
+
+pub use super::*;
+
+const KEY: &str =
+    "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIGd1bW15a2V5Zm9ydGVzdGluZ3B1cnBvc2Vz";
+
+/// Tests for [`split_words`]
+mod split_words {
+    use super::*;
+
+    #[test]
+    fn inverts_forced_command() -> Result<()> {
+        let commands = vec![
+            String::from("backup"),
+            String::from("rsync --server *"),
+            String::from("it's"),
+            String::new(),
+        ];
+        let control = Control {
+            commands: commands.clone(),
+            ..Control::default()
+        };
+
+        let command = forced_command(&control);
+        assert_eq!(split_words(&command[EXEC_COMMAND.len()..])?, commands);
+        Ok(())
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(split_words(" 'unterminated").is_err());
+        assert!(split_words(" unquoted").is_err());
+        assert!(split_words(" \\n").is_err());
+    }
+}
+
+/// Tests for [`observe_block`]
+mod observe_block {
+    use super::*;
+
+    #[test]
+    fn deny() -> Result<()> {
+        let control = Control {
+            enable: true,
+            action: KeysAction::Deny,
+            pty: true,
+            ..Control::default()
+        };
+        let block = crate::authorized_keys::render_managed_block(
+            &[String::from(KEY)],
+            &control,
+        )?;
+
+        let fields = observe_block(&block.text)?;
+        assert_eq!(fields.get("action"), Some(&"deny".into()));
+        assert_eq!(fields.get("pty"), None);
+        assert_eq!(fields.get("commands"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn foreign_command() {
+        let block = format!("command=\"/bin/sh\" {KEY}\n");
+        assert!(observe_block(&block).is_err());
+    }
+
+    #[test]
+    fn disagreeing_keys() {
+        let block = format!(
+            "command=\"{EXEC_COMMAND} 'a'\" {KEY} one\n\
+            command=\"{EXEC_COMMAND} 'b'\" {KEY} two\n"
+        );
+        assert!(observe_block(&block).is_err());
+    }
+}
+
+/// Tests for [`export_control`]
+mod export_control {
+    use super::*;
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+        ws.add_user(1000, "alice", "home/alice")?;
+        ws.add_user(1001, "bob", "home/bob")?;
+        ws.add_file(
+            "home/alice/.narrowssh.conf",
+            1000,
+            0o600,
+            format!("keys = [{KEY:?}]"),
+        )?;
+
+        let main = ws.add_file(
+            "etc/main.toml",
+            0,
+            0o600,
+            "[\"*\"]\nenable = true\ncommands = [\"backup\", \"it's *\"]\n\
+            pty = true\nagent_forwarding = true\n[bob]\nenable = false",
+        )?;
+        let options = VisitOptions::default();
+        let control = ControlManager::load(&ws, main, &options)?;
+        let alice = ws.users().user_by_uid(1000).unwrap().clone();
+        let bob = ws.users().user_by_uid(1001).unwrap().clone();
+        refresh_user(&ws, &alice, &control.get_user_control(1000), &options)?;
+
+        let exported = export_control(&control, &[&alice, &bob], &options)?;
+        assert!(exported.starts_with(HEADER));
+
+        let main = ws.add_file("etc/exported.toml", 0, 0o600, exported)?;
+        let exported = ControlManager::load(&ws, main, &options)?;
+
+        let restored = exported.get_user_control(1000);
+        assert!(restored.enable);
+        assert_eq!(restored.commands, ["backup", "it's *"]);
+        assert!(restored.pty);
+        assert!(restored.agent_forwarding);
+        assert!(!restored.port_forwarding);
+        assert!(!exported.get_user_control(1001).enable);
+
+        let plan = plan_user(&ws, &alice, &restored, &options)?;
+        assert_eq!(plan.change, Change::None);
+        Ok(())
+    }
+
+    #[test]
+    fn no_keys_file() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+        ws.add_user(1000, "alice", "home/alice")?;
+        let control = ControlManager::default();
+        let alice = ws.users().user_by_uid(1000).unwrap();
+
+        let exported =
+            export_control(&control, &[alice], &VisitOptions::default())?;
+        let table: toml::Table = toml::from_str(&exported)?;
+        assert_eq!(
+            table["alice"]["enable"],
+            toml::Value::Boolean(false),
+            "{exported}"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,206 @@
+//! Archive of managed blocks that narrowssh replaced or removed.
+//!
+//! With [`Control::history_dir`] set, the previous managed block of a user is
+//! stored before every change, so that a bad refresh can be investigated and
+//! undone with [`rollback_user`][crate::refresh::rollback_user]. Entries of
+//! a user live in a subdirectory named after the UID and are named after the
+//! time of the change, so that their names sort chronologically.
+
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use anyhow::{bail, Context, Result};
+use uzers::uid_t;
+
+#[cfg(doc)]
+use crate::config::Control;
+use crate::workspace::Workspace;
+
+#[cfg(test)]
+mod tests;
+
+/// Number of entries kept per user when [`Control::history_keep`] is unset.
+pub const DEFAULT_HISTORY_KEEP: usize = 10;
+
+/// File name extension of history entries.
+const ENTRY_EXTENSION: &str = "block";
+
+/// Returns the directory that holds the entries of the user with `uid`.
+#[must_use]
+pub fn user_dir(history_dir: &Path, uid: uid_t) -> PathBuf {
+    history_dir.join(uid.to_string())
+}
+
+/// Ensures that `dir` may hold history entries.
+///
+/// `dir` must be an absolute path. If it exists, it must be a directory owned
+/// by root and not accessible by group or others, since entries reveal the
+/// keys of users and are restored as they are.
+///
+/// # Errors
+/// The function will fail if `dir` is not acceptable or could not be
+/// inspected.
+pub fn check_dir<W: Workspace>(ws: &W, dir: &Path) -> Result<()> {
+    let suffix = "[security; refusing to use it]";
+
+    if !dir.is_absolute() {
+        bail!("history directory {} must be absolute", dir.display());
+    }
+
+    let metadata = match std::fs::symlink_metadata(dir) {
+        Ok(metadata) => metadata,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(())
+        }
+        Err(error) => {
+            return Err(error)
+                .with_context(|| format!("inspecting {}", dir.display()))
+        }
+    };
+
+    if !metadata.is_dir() {
+        bail!("{} must be a directory {suffix}", dir.display());
+    }
+
+    let owner = ws.get_mock_owner_uid(dir).unwrap_or_else(|| metadata.uid());
+    if owner != 0 {
+        bail!(
+            "{} must be owned by root, not UID {owner} {suffix}",
+            dir.display()
+        );
+    }
+
+    if metadata.permissions().mode() & 0o077 != 0 {
+        bail!(
+            "{} must not be accessible by group or others {suffix}",
+            dir.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Returns the history entries of the user with `uid`, oldest first.
+///
+/// # Errors
+/// The function will fail if the directory of the user exists but could not
+/// be read.
+pub fn entries(history_dir: &Path, uid: uid_t) -> Result<Vec<PathBuf>> {
+    let dir = user_dir(history_dir, uid);
+
+    let read_dir = match std::fs::read_dir(&dir) {
+        Ok(read_dir) => read_dir,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(Vec::new())
+        }
+        Err(error) => {
+            return Err(error)
+                .with_context(|| format!("reading {}", dir.display()))
+        }
+    };
+
+    let mut result = Vec::new();
+    for entry in read_dir {
+        let path = entry
+            .with_context(|| format!("reading {}", dir.display()))?
+            .path();
+        if path.extension().map_or(false, |e| e == ENTRY_EXTENSION) {
+            result.push(path);
+        }
+    }
+    result.sort();
+
+    Ok(result)
+}
+
+/// Returns the most recent history entry of the user with `uid` along with
+/// the managed block that it holds.
+///
+/// # Errors
+/// The function will fail if [`check_dir`] or [`entries`] complain, or if the
+/// entry could not be read.
+pub fn latest<W: Workspace>(
+    ws: &W,
+    history_dir: &Path,
+    uid: uid_t,
+) -> Result<Option<(PathBuf, String)>> {
+    check_dir(ws, history_dir)?;
+
+    let path = match entries(history_dir, uid)?.pop() {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+
+    let block = std::fs::read_to_string(&path)
+        .with_context(|| format!("reading {}", path.display()))?;
+    Ok(Some((path, block)))
+}
+
+/// Stores `block` as the newest history entry of the user with `uid`.
+///
+/// `history_dir` and the directory of the user are created as needed, owned
+/// by root with mode `0700`. Entries are written with mode `0600`. Afterwards,
+/// all but the newest `keep` entries of the user are deleted, though the new
+/// entry is always kept.
+///
+/// Returns the path of the new entry.
+///
+/// # Errors
+/// The function will fail if [`check_dir`] complains or if the entry could
+/// not be written. Failures to delete old entries are ignored.
+pub fn archive<W: Workspace>(
+    ws: &W,
+    history_dir: &Path,
+    uid: uid_t,
+    block: &str,
+    keep: usize,
+) -> Result<PathBuf> {
+    check_dir(ws, history_dir)?;
+
+    let dir = user_dir(history_dir, uid);
+    for dir in &[history_dir, &dir] {
+        if !ws.lexists(dir) {
+            std::fs::DirBuilder::new()
+                .mode(0o700)
+                .create(dir)
+                .with_context(|| format!("creating {}", dir.display()))?;
+            std::fs::set_permissions(dir, PermissionsExt::from_mode(0o700))?;
+            ws.set_owner(dir, 0, 0)?;
+        }
+    }
+
+    let mut stamp = ws
+        .now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let mut path = dir.join(format!("{stamp:020}.{ENTRY_EXTENSION}"));
+    while ws.lexists(&path) {
+        stamp += 1;
+        path = dir.join(format!("{stamp:020}.{ENTRY_EXTENSION}"));
+    }
+
+    let (mut file, temp) = ws.create_temp_in(&dir, 0o600)?;
+    let result = || -> Result<()> {
+        file.write_all(block.as_bytes())?;
+        file.sync_all()?;
+        ws.set_owner(&temp, 0, 0)?;
+        ws.rename(&temp, &path)?;
+        Ok(())
+    }();
+    if let Err(error) = result {
+        let _ = std::fs::remove_file(&temp);
+        return Err(error)
+            .with_context(|| format!("writing {}", path.display()));
+    }
+
+    let entries = entries(history_dir, uid)?;
+    let excess = entries.len().saturating_sub(keep.max(1));
+    for old in &entries[..excess] {
+        let _ = std::fs::remove_file(old);
+    }
+
+    Ok(path)
+}
@@ -0,0 +1,145 @@
+pub use std::os::unix::fs::PermissionsExt;
+pub use std::time::{Duration, SystemTime};
+
+pub use crate::workspace::mock::MockWorkspace;
+
+pub use super::*;
+
+/// Returns the mode bits of the FS object at `path`.
+fn mode(path: &Path) -> Result<u32> {
+    Ok(std::fs::metadata(path)?.permissions().mode() & 0o777)
+}
+
+/// Tests for [`check_dir`]
+mod check_dir {
+    use super::*;
+
+    #[test]
+    fn missing() -> Result<()> {
+        let ws = MockWorkspace::new()?;
+
+        check_dir(&ws, &ws.path("history"))
+    }
+
+    #[test]
+    fn relative() -> Result<()> {
+        let ws = MockWorkspace::new()?;
+
+        assert!(check_dir(&ws, Path::new("history")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn owned_by_user() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+        let dir = ws.add_dir("history", 1000, 0o700)?;
+
+        let error = check_dir(&ws, &dir).unwrap_err();
+        assert!(error.to_string().contains("owned by root"), "{error}");
+        Ok(())
+    }
+
+    #[test]
+    fn accessible_by_others() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+        let dir = ws.add_dir("history", 0, 0o755)?;
+
+        assert!(check_dir(&ws, &dir).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn file() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+        let file = ws.add_file("history", 0, 0o600, "")?;
+
+        assert!(check_dir(&ws, &file).is_err());
+        Ok(())
+    }
+}
+
+/// Tests for [`archive`] and [`latest`]
+mod archive {
+    use super::*;
+
+    #[test]
+    fn creates_dirs() -> Result<()> {
+        let ws = MockWorkspace::new()?;
+        let dir = ws.path("history");
+
+        let entry = archive(&ws, &dir, 1000, "block\n", 3)?;
+
+        assert_eq!(entry.parent(), Some(user_dir(&dir, 1000).as_path()));
+        assert_eq!(std::fs::read_to_string(&entry)?, "block\n");
+        assert_eq!(mode(&entry)?, 0o600);
+        assert_eq!(ws.get_mock_owner_uid(&entry), Some(0));
+        for dir in &[dir.clone(), user_dir(&dir, 1000)] {
+            assert_eq!(mode(dir)?, 0o700);
+            assert_eq!(ws.get_mock_owner_uid(dir), Some(0));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn latest_wins() -> Result<()> {
+        let ws = MockWorkspace::new()?;
+        let dir = ws.path("history");
+
+        ws.set_now(SystemTime::UNIX_EPOCH + Duration::from_secs(100));
+        archive(&ws, &dir, 1000, "first\n", 3)?;
+        ws.set_now(SystemTime::UNIX_EPOCH + Duration::from_secs(50));
+        archive(&ws, &dir, 1001, "other\n", 3)?;
+        ws.set_now(SystemTime::UNIX_EPOCH + Duration::from_secs(200));
+        let second = archive(&ws, &dir, 1000, "second\n", 3)?;
+
+        let (entry, block) = latest(&ws, &dir, 1000)?.unwrap();
+        assert_eq!(entry, second);
+        assert_eq!(block, "second\n");
+        assert!(latest(&ws, &dir, 1002)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn same_time() -> Result<()> {
+        let ws = MockWorkspace::new()?;
+        let dir = ws.path("history");
+        ws.set_now(SystemTime::UNIX_EPOCH + Duration::from_secs(100));
+
+        archive(&ws, &dir, 1000, "first\n", 3)?;
+        archive(&ws, &dir, 1000, "second\n", 3)?;
+
+        assert_eq!(entries(&dir, 1000)?.len(), 2);
+        assert_eq!(latest(&ws, &dir, 1000)?.unwrap().1, "second\n");
+        Ok(())
+    }
+
+    #[test]
+    fn retention() -> Result<()> {
+        let ws = MockWorkspace::new()?;
+        let dir = ws.path("history");
+
+        for (index, block) in ["a\n", "b\n", "c\n", "d\n"].iter().enumerate()
+        {
+            let secs = u64::try_from(index)? + 1;
+            ws.set_now(SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+            archive(&ws, &dir, 1000, block, 2)?;
+        }
+
+        let blocks: Vec<_> = entries(&dir, 1000)?
+            .iter()
+            .map(std::fs::read_to_string)
+            .collect::<std::io::Result<_>>()?;
+        assert_eq!(blocks, ["c\n", "d\n"]);
+        Ok(())
+    }
+
+    #[test]
+    fn insecure_dir() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+        let dir = ws.add_dir("history", 1000, 0o700)?;
+
+        assert!(archive(&ws, &dir, 1000, "block\n", 3).is_err());
+        assert!(entries(&dir, 1000)?.is_empty());
+        Ok(())
+    }
+}
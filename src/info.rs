@@ -0,0 +1,78 @@
+//! Operating parameters of narrowssh, as shown by the `info` command.
+
+use std::fmt;
+use std::path::Path;
+
+use crate::authorized_keys::EXEC_COMMAND;
+use crate::config::{
+    extensions_dir, DEFAULT_AUTHORIZED_KEYS, DEFAULT_USER_CONFIG,
+};
+use crate::sshd::DEFAULT_SSHD;
+
+#[cfg(test)]
+mod tests;
+
+/// Returns the names of optional features compiled in.
+#[must_use]
+pub fn features() -> Vec<&'static str> {
+    let mut result = Vec::new();
+    if cfg!(feature = "syslog") {
+        result.push("syslog");
+    }
+    if cfg!(feature = "signatures") {
+        result.push("signatures");
+    }
+    if cfg!(feature = "sshd-syntax") {
+        result.push("sshd-syntax");
+    }
+    result
+}
+
+/// Compile-time defaults and runtime settings in effect.
+///
+/// The [`Display`][fmt::Display] implementation prints one `name: value`
+/// line per parameter.
+#[derive(Clone, Debug)]
+pub struct Info<'a> {
+    /// Main control file in use.
+    pub control_file: &'a Path,
+
+    /// Whether the control extensions directory is read.
+    pub extensions: bool,
+
+    /// Sandbox for user files, see
+    /// [`VisitOptions::target_root`][crate::config::VisitOptions::target_root].
+    pub target_root: Option<&'a Path>,
+}
+
+impl fmt::Display for Info<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "version: {}", env!("CARGO_PKG_VERSION"))?;
+        writeln!(f, "control file: {}", self.control_file.display())?;
+        if self.extensions {
+            writeln!(
+                f,
+                "control extensions: {}",
+                extensions_dir(self.control_file).display()
+            )?;
+        } else {
+            writeln!(f, "control extensions: disabled")?;
+        }
+        writeln!(f, "trusted owner of control: root (UID 0)")?;
+        writeln!(f, "default user config: {DEFAULT_USER_CONFIG}")?;
+        writeln!(f, "default authorized_keys: {DEFAULT_AUTHORIZED_KEYS}")?;
+        writeln!(f, "forced command: {EXEC_COMMAND}")?;
+        writeln!(f, "default sshd: {DEFAULT_SSHD}")?;
+        match self.target_root {
+            Some(root) => writeln!(f, "target root: {}", root.display())?,
+            None => writeln!(f, "target root: none")?,
+        }
+
+        let features = features();
+        if features.is_empty() {
+            writeln!(f, "features: none")
+        } else {
+            writeln!(f, "features: {}", features.join(", "))
+        }
+    }
+}
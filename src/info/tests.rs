@@ -0,0 +1,44 @@
+pub use std::path::PathBuf;
+
+pub use super::*;
+
+/// Tests for [`Info`]
+mod display {
+    use super::*;
+
+    #[test]
+    fn defaults() {
+        let control_file = PathBuf::from("/etc/narrowssh/control.toml");
+        let info = Info {
+            control_file: &control_file,
+            extensions: true,
+            target_root: None,
+        };
+        let text = info.to_string();
+
+        assert!(text.contains("control file: /etc/narrowssh/control.toml\n"));
+        assert!(text
+            .contains("control extensions: /etc/narrowssh/control.toml.d\n"));
+        assert!(text.contains("default user config: ~/.narrowssh.conf\n"));
+        assert!(text
+            .contains("default authorized_keys: ~/.ssh/authorized_keys\n"));
+        assert!(text.contains("target root: none\n"));
+        assert!(text.lines().all(|line| line.contains(": ")));
+    }
+
+    #[test]
+    fn overrides() {
+        let control_file = PathBuf::from("/srv/narrowssh/test.toml");
+        let target_root = PathBuf::from("/mnt/image");
+        let info = Info {
+            control_file: &control_file,
+            extensions: false,
+            target_root: Some(&target_root),
+        };
+        let text = info.to_string();
+
+        assert!(text.contains("control file: /srv/narrowssh/test.toml\n"));
+        assert!(text.contains("control extensions: disabled\n"));
+        assert!(text.contains("target root: /mnt/image\n"));
+    }
+}
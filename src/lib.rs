@@ -3,5 +3,24 @@
 #![warn(clippy::style)]
 #![warn(clippy::pedantic)]
 
+pub mod allowlist;
+pub mod audit;
+pub mod authorized_keys;
+pub mod color;
 pub mod config;
+pub mod explain;
+pub mod export;
+pub mod history;
+pub mod info;
+pub mod policy;
+pub mod refresh;
+pub mod schema;
+pub mod selection;
+pub mod selftest;
+#[cfg(feature = "signatures")]
+pub mod signature;
+pub mod sshd;
+#[cfg(feature = "sshd-syntax")]
+pub mod sshd_syntax;
+pub mod status;
 pub mod workspace;
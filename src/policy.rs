@@ -0,0 +1,100 @@
+//! Decisions about the managed block of a user, separated from I/O.
+//!
+//! A [`Policy`] receives the control and the configured keys of a user and
+//! tells what the managed block should become. Planning, see
+//! [`plan_user_with`][crate::refresh::plan_user_with], takes care of reading
+//! and writing files, so policies can be tested without a filesystem.
+
+use anyhow::Result;
+use uzers::User;
+
+use crate::authorized_keys::{render_managed_block, ManagedBlock};
+use crate::config::Control;
+
+#[cfg(test)]
+mod tests;
+
+/// What a [`Policy`] wants done with the managed block of a user.
+#[derive(Clone, Debug)]
+pub enum PolicyAction {
+    /// The managed block should be this one.
+    Install(ManagedBlock),
+
+    /// There should be no managed block.
+    Remove,
+
+    /// The managed block, if any, should be left as it is.
+    Keep,
+}
+
+/// Decides the managed block of a user.
+pub trait Policy {
+    /// Returns the intended managed block of `user`.
+    ///
+    /// `keys` are the keys configured by the user. They are only loaded for
+    /// users with [`Control::enable`] set and are empty otherwise.
+    ///
+    /// # Errors
+    /// An error is returned if no decision can be made, e.g. because the
+    /// managed block could not be rendered.
+    fn decide(
+        &self,
+        user: &User,
+        control: &Control,
+        keys: &[String],
+    ) -> Result<PolicyAction>;
+
+    /// Returns whether [`decide`][Self::decide] needs the keys configured by
+    /// users.
+    ///
+    /// If not, user configuration is not loaded at all, so that it cannot
+    /// make planning fail.
+    fn reads_keys(&self) -> bool {
+        true
+    }
+}
+
+/// Policy of the `refresh` command.
+///
+/// Enabled users get their keys installed with [`render_managed_block`],
+/// with tokens in their commands expanded by [`Control::expand_commands`].
+/// Disabled users are skipped, so that their `authorized_keys` is not even
+/// read; [`Uninstall`] removes their managed blocks.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Refresh;
+
+impl Policy for Refresh {
+    fn decide(
+        &self,
+        user: &User,
+        control: &Control,
+        keys: &[String],
+    ) -> Result<PolicyAction> {
+        if !control.enable {
+            return Ok(PolicyAction::Keep);
+        }
+        render_managed_block(keys, &control.expand_commands(user)?)
+            .map(PolicyAction::Install)
+    }
+}
+
+/// Policy of the `uninstall` command: every managed block is removed.
+///
+/// User configuration is not read.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Uninstall;
+
+impl Policy for Uninstall {
+    fn decide(
+        &self,
+        _user: &User,
+        _control: &Control,
+        _keys: &[String],
+    ) -> Result<PolicyAction> {
+        Ok(PolicyAction::Remove)
+    }
+
+    fn reads_keys(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,279 @@
+pub use crate::authorized_keys::BEGIN_MARKER;
+pub use crate::config::{ControlManager, VisitOptions};
+pub use crate::refresh::{apply_plan, plan_user_with, Change};
+pub use crate::workspace::mock::MockWorkspace;
+pub use crate::workspace::Workspace;
+
+pub use super::*;
+
+const KEY: &str =
+    "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIGd1bW15a2V5Zm9ydGVzdGluZ3B1cnBvc2Vz";
+
+/// Installs only the first configured key of every user.
+struct FirstKeyOnly;
+
+impl Policy for FirstKeyOnly {
+    fn decide(
+        &self,
+        _user: &User,
+        control: &Control,
+        keys: &[String],
+    ) -> Result<PolicyAction> {
+        match keys.first() {
+            Some(key) => {
+                render_managed_block(std::slice::from_ref(key), control)
+                    .map(PolicyAction::Install)
+            }
+            None => Ok(PolicyAction::Remove),
+        }
+    }
+}
+
+/// Returns the [`Control`] of an enabled user.
+fn enabled() -> Control {
+    let mut control = ControlManager::default().get_user_control(1000);
+    control.enable = true;
+    control.commands = vec![String::from("backup")];
+    control
+}
+
+/// Returns user alice.
+fn alice() -> User {
+    User::new(1000, "alice", 1000)
+}
+
+/// Tests for [`Policy::decide`] of the built-in policies
+mod built_in {
+    use super::*;
+
+    #[test]
+    fn refresh_enabled() -> Result<()> {
+        let keys = [String::from(KEY)];
+        match Refresh.decide(&alice(), &enabled(), &keys)? {
+            PolicyAction::Install(block) => {
+                assert!(block.text.starts_with(BEGIN_MARKER));
+                assert!(block.text.contains(KEY));
+            }
+            PolicyAction::Remove | PolicyAction::Keep => {
+                panic!("block not installed")
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn refresh_disabled() -> Result<()> {
+        let control = Control {
+            enable: false,
+            ..enabled()
+        };
+        let action = Refresh.decide(&alice(), &control, &[])?;
+        assert!(matches!(action, PolicyAction::Keep));
+        Ok(())
+    }
+
+    #[test]
+    fn refresh_expands_commands() -> Result<()> {
+        let keys = [String::from(KEY)];
+        let control = Control {
+            commands: vec![String::from("/usr/bin/backup --user %u")],
+            ..enabled()
+        };
+        let bob = User::new(1001, "bob", 1001);
+
+        let options = |user: &User| -> Result<String> {
+            match Refresh.decide(user, &control, &keys)? {
+                PolicyAction::Install(block) => Ok(block
+                    .text
+                    .lines()
+                    .find(|line| line.contains(KEY))
+                    .unwrap()
+                    .to_owned()),
+                PolicyAction::Remove | PolicyAction::Keep => {
+                    panic!("block not installed")
+                }
+            }
+        };
+
+        let for_alice = options(&alice())?;
+        let for_bob = options(&bob)?;
+        assert!(for_alice.starts_with(
+            "restrict,command=\"/usr/bin/narrowssh exec -- \
+            '/usr/bin/backup --user alice'\" "
+        ));
+        assert!(for_bob.starts_with(
+            "restrict,command=\"/usr/bin/narrowssh exec -- \
+            '/usr/bin/backup --user bob'\" "
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn uninstall() -> Result<()> {
+        let keys = [String::from(KEY)];
+        let action = Uninstall.decide(&alice(), &enabled(), &keys)?;
+        assert!(matches!(action, PolicyAction::Remove));
+        Ok(())
+    }
+}
+
+/// Tests for a custom [`Policy`]
+mod custom {
+    use super::*;
+
+    #[test]
+    fn decide() -> Result<()> {
+        let keys = [format!("{KEY} first"), format!("{KEY} second")];
+        match FirstKeyOnly.decide(&alice(), &enabled(), &keys)? {
+            PolicyAction::Install(block) => {
+                assert!(block.text.contains(" first\n"));
+                assert!(!block.text.contains("second"));
+            }
+            PolicyAction::Remove | PolicyAction::Keep => {
+                panic!("block not installed")
+            }
+        }
+
+        let action = FirstKeyOnly.decide(&alice(), &enabled(), &[])?;
+        assert!(matches!(action, PolicyAction::Remove));
+        Ok(())
+    }
+
+    #[test]
+    fn planned() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+        ws.add_user(1000, "alice", "home/alice")?;
+        ws.add_file(
+            "home/alice/.narrowssh.conf",
+            1000,
+            0o600,
+            format!("keys = [\"{KEY} first\", \"{KEY} second\"]"),
+        )?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+        let options = VisitOptions::default();
+
+        let plan =
+            plan_user_with(&ws, alice, &enabled(), &options, &FirstKeyOnly)?;
+        assert_eq!(plan.change, Change::New);
+        assert!(plan.contents().contains(" first\n"));
+        assert!(!plan.contents().contains("second"));
+
+        let plan =
+            plan_user_with(&ws, alice, &enabled(), &options, &Uninstall)?;
+        assert_eq!(plan.change, Change::None);
+        Ok(())
+    }
+
+    #[test]
+    fn uninstall_installed() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+        ws.add_user(1000, "alice", "home/alice")?;
+        ws.add_file(
+            "home/alice/.narrowssh.conf",
+            1000,
+            0o600,
+            format!("keys = [{KEY:?}]"),
+        )?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+        let options = VisitOptions::default();
+
+        let plan =
+            plan_user_with(&ws, alice, &enabled(), &options, &Refresh)?;
+        apply_plan(&ws, &plan)?;
+
+        let plan =
+            plan_user_with(&ws, alice, &enabled(), &options, &Uninstall)?;
+        assert_eq!(plan.change, Change::Removed);
+        assert_eq!(plan.contents(), "");
+        Ok(())
+    }
+}
+
+/// Tests for uninstalling after [`Refresh`]
+mod round_trip {
+    use super::*;
+
+    /// Refreshes and then uninstalls alice, whose `authorized_keys` holds
+    /// `original`, and returns the resulting file contents.
+    fn refresh_and_uninstall(original: &str) -> Result<String> {
+        let mut ws = MockWorkspace::new()?;
+        ws.add_user(1000, "alice", "home/alice")?;
+        ws.add_file(
+            "home/alice/.narrowssh.conf",
+            1000,
+            0o600,
+            format!("keys = [{KEY:?}]"),
+        )?;
+        ws.add_file(
+            "home/alice/.ssh/authorized_keys",
+            1000,
+            0o600,
+            original,
+        )?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+        let options = VisitOptions::default();
+
+        let plan =
+            plan_user_with(&ws, alice, &enabled(), &options, &Refresh)?;
+        apply_plan(&ws, &plan)?;
+        let path = ws.path("home/alice/.ssh/authorized_keys");
+        assert!(std::fs::read_to_string(&path)?.contains(BEGIN_MARKER));
+
+        let plan =
+            plan_user_with(&ws, alice, &enabled(), &options, &Uninstall)?;
+        apply_plan(&ws, &plan)?;
+        Ok(std::fs::read_to_string(&path)?)
+    }
+
+    #[test]
+    fn own_keys() -> Result<()> {
+        let original = format!("{KEY} laptop\n# spare\n{KEY} phone\n");
+        assert_eq!(refresh_and_uninstall(&original)?, original);
+        Ok(())
+    }
+
+    #[test]
+    fn single_line() -> Result<()> {
+        assert_eq!(refresh_and_uninstall("mine\n")?, "mine\n");
+        Ok(())
+    }
+
+    #[test]
+    fn no_final_newline() -> Result<()> {
+        assert_eq!(refresh_and_uninstall("mine")?, "mine");
+        assert_eq!(refresh_and_uninstall("a\r\n\r\nb")?, "a\r\n\r\nb");
+        Ok(())
+    }
+
+    #[test]
+    fn empty() -> Result<()> {
+        assert_eq!(refresh_and_uninstall("")?, "");
+        Ok(())
+    }
+
+    #[test]
+    fn nothing_installed() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+        ws.add_user(1000, "alice", "home/alice")?;
+        ws.add_file(
+            "home/alice/.ssh/authorized_keys",
+            1000,
+            0o600,
+            "mine\n",
+        )?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+
+        let plan = plan_user_with(
+            &ws,
+            alice,
+            &enabled(),
+            &VisitOptions::default(),
+            &Uninstall,
+        )?;
+        assert_eq!(plan.change, Change::None);
+        apply_plan(&ws, &plan)?;
+        let path = ws.path("home/alice/.ssh/authorized_keys");
+        assert_eq!(std::fs::read_to_string(path)?, "mine\n");
+        Ok(())
+    }
+}
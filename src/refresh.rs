@@ -0,0 +1,1498 @@
+//! Installation of managed blocks into `authorized_keys` files.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Error, Result};
+use serde::Serialize;
+use uzers::{gid_t, uid_t, User};
+
+use crate::authorized_keys::{
+    render_managed_block, BlockMarkers, ManagedBlock,
+};
+use crate::config::{
+    reroot, resolve_path, Config, Control, ControlManager, KeysOwner,
+    NoHomeDir, VisitOptions, PUB_KEYS_DIR,
+};
+use crate::history::{self, DEFAULT_HISTORY_KEEP};
+use crate::policy::{Policy, PolicyAction, Refresh, Uninstall};
+use crate::workspace::{home_dir, Workspace};
+
+#[cfg(test)]
+mod tests;
+
+/// Result of [`refresh_user`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// The user is disabled in control; nothing was done.
+    Disabled,
+
+    /// The managed block was already up to date.
+    Unchanged(PathBuf),
+
+    /// The managed block was written.
+    Updated(PathBuf),
+
+    /// The managed block was removed.
+    Removed(PathBuf),
+}
+
+/// Report of [`refresh_user`].
+#[derive(Clone, Debug)]
+pub struct Report {
+    /// What happened to the user.
+    pub outcome: Outcome,
+
+    /// Problems that did not prevent the refresh.
+    pub warnings: Vec<String>,
+}
+
+/// Ownership and permissions of an `authorized_keys` file and its directory.
+#[derive(Clone, Copy, Debug)]
+struct Placement {
+    uid: uid_t,
+    gid: gid_t,
+    file_mode: u32,
+    dir_mode: u32,
+
+    /// Whether the directory is synced after the file is replaced.
+    durable: bool,
+}
+
+impl Placement {
+    /// Determines the placement of the `authorized_keys` file of `user`.
+    ///
+    /// Replacements are made durable as [`VisitOptions::durable`] says.
+    fn new(user: &User, control: &Control, options: &VisitOptions) -> Self {
+        let durable = options
+            .durable
+            .unwrap_or(control.authorized_keys_owner == KeysOwner::Root);
+        match control.authorized_keys_owner {
+            KeysOwner::User => Self {
+                uid: user.uid(),
+                gid: control
+                    .authorized_keys_gid
+                    .unwrap_or_else(|| user.primary_group_id()),
+                file_mode: control.authorized_keys_mode.unwrap_or(0o600),
+                dir_mode: 0o700,
+                durable,
+            },
+            KeysOwner::Root => Self {
+                uid: 0,
+                gid: control.authorized_keys_gid.unwrap_or(0),
+                file_mode: control.authorized_keys_mode.unwrap_or(0o644),
+                dir_mode: 0o711,
+                durable,
+            },
+        }
+    }
+}
+
+/// Renders the managed block of `user` without writing anything.
+///
+/// The keys of the user are loaded from [`Control::config`], moved into
+/// [`VisitOptions::target_root`] if set, along with the command groups the
+/// user enables. Returns `None` for users with [`Control::enable`] unset.
+/// User configuration that cannot be loaded is ignored with a warning unless
+/// [`Control::strict_user_config`] is set.
+///
+/// # Errors
+/// The check will fail in these cases:
+///   - some path could not be resolved,
+///   - user configuration could not be loaded, or enables a command group
+///     not in [`Control::command_groups`], and
+///     [`Control::strict_user_config`] is set,
+///   - some command could not be expanded, see
+///     [`Control::expand_commands`], or
+///   - the managed block could not be rendered.
+pub fn check_user<W>(
+    ws: &W,
+    user: &User,
+    control: &Control,
+    options: &VisitOptions,
+) -> Result<Option<ManagedBlock>>
+where
+    W: Workspace,
+{
+    if !control.enable {
+        return Ok(None);
+    }
+
+    let mut warnings = Vec::new();
+    let config = load_config(ws, user, control, options, &mut warnings)?;
+    let control = enable_command_groups(
+        control,
+        &config.enable_commands,
+        &mut warnings,
+    )?;
+    let mut block =
+        render_managed_block(&config.keys, &control.expand_commands(user)?)?;
+    warnings.append(&mut block.warnings);
+    #[cfg(feature = "sshd-syntax")]
+    warnings.extend(crate::sshd_syntax::check_block(&block.text));
+    block.warnings = warnings;
+
+    Ok(Some(block))
+}
+
+/// Loads the configuration of `user` from [`Control::config`], moved into
+/// [`VisitOptions::target_root`] if set.
+///
+/// With [`Control::pub_keys_fallback`] set, a user without a configuration
+/// file gets the keys of their [`PUB_KEYS_DIR`] instead, see
+/// [`Config::load_pub_keys`].
+///
+/// Unless [`Control::strict_user_config`] is set, configuration that cannot
+/// be loaded is ignored: a warning is pushed to `warnings` and an empty
+/// configuration is returned. The path of the configuration is set by
+/// control, so it must always resolve.
+fn load_config<W>(
+    ws: &W,
+    user: &User,
+    control: &Control,
+    options: &VisitOptions,
+    warnings: &mut Vec<String>,
+) -> Result<Config>
+where
+    W: Workspace,
+{
+    let root = options.target_root.as_deref();
+    let config_path = reroot(&resolve_path(&control.config, user)?, root)?;
+
+    let config = if control.pub_keys_fallback && !ws.lexists(&config_path) {
+        let dir = reroot(&resolve_path(PUB_KEYS_DIR, user)?, root)?;
+        Config::load_pub_keys(ws, &dir, user.uid())
+    } else {
+        Config::load(ws, config_path, user.uid(), options)
+    };
+
+    match config {
+        Ok(config) => Ok(config),
+        Err(error) if control.strict_user_config => {
+            Err(error.context(SkipReason::InsecureConfig))
+        }
+        Err(error) => {
+            warnings.push(format!(
+                "user configuration ignored: {error:#} \
+                [set strict_user_config to fail instead]"
+            ));
+            Ok(Config::default())
+        }
+    }
+}
+
+/// Returns `control` with the commands of the command groups `names` added
+/// to [`Control::commands`].
+///
+/// Users may only enable groups listed in [`Control::command_groups`].
+/// Unless [`Control::strict_user_config`] is set, other groups are ignored
+/// with a warning pushed to `warnings`.
+///
+/// # Errors
+/// The function will fail if [`Control::strict_user_config`] is set and some
+/// group is not available to the user.
+fn enable_command_groups(
+    control: &Control,
+    names: &[String],
+    warnings: &mut Vec<String>,
+) -> Result<Control> {
+    let mut result = control.clone();
+
+    for name in names {
+        let commands = match control.group_commands.get(name) {
+            Some(commands) => commands,
+            None if control.strict_user_config => {
+                bail!("command group {name:?} is not available to the user")
+            }
+            None => {
+                warnings.push(format!(
+                    "command group {name:?} ignored: \
+                    not available to the user [see command_groups]"
+                ));
+                continue;
+            }
+        };
+
+        for command in commands {
+            if !result.commands.contains(command) {
+                result.commands.push(command.clone());
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+/// Change that [`refresh_user`] would make to the managed block of a user.
+///
+/// Serialized as `noop`, `create`, `update` or `remove`, see
+/// [`PlanEntry`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum Change {
+    /// The managed block is up to date, absent as requested, or left alone.
+    #[serde(rename = "noop")]
+    None,
+
+    /// A managed block would be added to `authorized_keys`.
+    #[serde(rename = "create")]
+    New,
+
+    /// The existing managed block would be replaced, or `authorized_keys`
+    /// would be written again with the ownership and permissions it lacks.
+    #[serde(rename = "update")]
+    Updated,
+
+    /// The managed block would be removed.
+    #[serde(rename = "remove")]
+    Removed,
+}
+
+/// Number of lines that a [`Plan`] adds to and removes from
+/// `authorized_keys`.
+///
+/// Lines are compared as multisets, so reordered lines do not count. A
+/// changed line counts as one added and one removed line.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DiffStat {
+    /// Number of lines only present after the change.
+    pub added: usize,
+
+    /// Number of lines only present before the change.
+    pub removed: usize,
+}
+
+impl DiffStat {
+    /// Compares the contents of a file `before` and `after` a change.
+    #[must_use]
+    pub fn between(before: &str, after: &str) -> Self {
+        // Occurrences of every line before and after
+        let mut counts: HashMap<&str, (usize, usize)> = HashMap::new();
+        for line in before.lines() {
+            counts.entry(line).or_default().0 += 1;
+        }
+        for line in after.lines() {
+            counts.entry(line).or_default().1 += 1;
+        }
+
+        let mut result = Self::default();
+        for &(before, after) in counts.values() {
+            result.added += after.saturating_sub(before);
+            result.removed += before.saturating_sub(after);
+        }
+        result
+    }
+}
+
+impl fmt::Display for DiffStat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "+{} -{} lines", self.added, self.removed)
+    }
+}
+
+/// Pending refresh of a user computed by [`plan_user`].
+#[derive(Clone, Debug)]
+pub struct Plan {
+    /// Whether the user is enabled in control.
+    pub enable: bool,
+
+    /// Change that the refresh would make.
+    pub change: Change,
+
+    /// Path to the `authorized_keys` file, if it is relevant.
+    pub path: Option<PathBuf>,
+
+    /// Problems that would not prevent the refresh.
+    pub warnings: Vec<String>,
+
+    /// Whether the policy asked for a managed block, see [`PolicyAction`].
+    pub install: bool,
+
+    /// Whether the policy asked to leave the managed block alone, in which
+    /// case `authorized_keys` was not inspected, see [`PolicyAction::Keep`].
+    pub skip: bool,
+
+    /// Lines that the refresh would change in `authorized_keys`.
+    pub diff: DiffStat,
+
+    /// Contents of `authorized_keys` after the refresh.
+    contents: String,
+
+    /// Ownership and permissions of `authorized_keys`.
+    placement: Placement,
+
+    /// Sandbox that contains `path`, see [`VisitOptions::target_root`].
+    target_root: Option<PathBuf>,
+
+    /// Archiving of the managed block that is about to be replaced, see
+    /// [`Control::history_dir`].
+    history: Option<Archive>,
+}
+
+/// Previous managed block of a user to store with [`history::archive`].
+#[derive(Clone, Debug)]
+struct Archive {
+    dir: PathBuf,
+    uid: uid_t,
+    block: String,
+    keep: usize,
+}
+
+impl Archive {
+    /// Prepares archiving the managed block of `user` found in `current`.
+    ///
+    /// Returns `None` if [`Control::history_dir`] is unset or there is no
+    /// managed block.
+    ///
+    /// # Errors
+    /// The function will fail if the history directory could not be moved
+    /// into [`VisitOptions::target_root`] or the markers are malformed.
+    fn new(
+        user: &User,
+        control: &Control,
+        options: &VisitOptions,
+        current: &str,
+    ) -> Result<Option<Self>> {
+        let dir = match &control.history_dir {
+            Some(dir) => {
+                reroot(Path::new(dir), options.target_root.as_deref())?
+            }
+            None => return Ok(None),
+        };
+
+        let markers = BlockMarkers::of(control);
+        Ok(markers.locate(current)?.map(|range| Self {
+            dir,
+            uid: user.uid(),
+            block: current[range].to_owned(),
+            keep: control.history_keep.unwrap_or(DEFAULT_HISTORY_KEEP),
+        }))
+    }
+}
+
+impl Plan {
+    /// Returns the contents of `authorized_keys` after the refresh.
+    ///
+    /// The contents are only meaningful if [`Plan::change`] is not
+    /// [`Change::None`].
+    #[must_use]
+    pub fn contents(&self) -> &str {
+        &self.contents
+    }
+}
+
+/// Computes the refresh of `user` without writing anything.
+///
+/// The managed block of enabled users is prepared by [`check_user`] and
+/// compared with the current contents of [`Control::authorized_keys`].
+/// Disabled users are skipped without inspecting their files. Paths
+/// are moved into [`VisitOptions::target_root`] if set. An up-to-date file
+/// is written again if it lacks the ownership or permissions required by
+/// control.
+///
+/// If `authorized_keys` is a symbolic link, its target is checked before
+/// anything is read through it.
+///
+/// # Errors
+/// The function will fail in these cases:
+///   - [`check_user`] complains,
+///   - the path of `authorized_keys` of an enabled user could not be
+///     resolved or is refused by [`check_target`],
+///   - [`Control::authorized_keys_gid`] is not a known group,
+///   - `authorized_keys` is a symbolic link to a file outside of the home
+///     directory of the user or owned by someone else, or
+///   - `authorized_keys` could not be read or contains malformed markers.
+pub fn plan_user<W>(
+    ws: &W,
+    user: &User,
+    control: &Control,
+    options: &VisitOptions,
+) -> Result<Plan>
+where
+    W: Workspace,
+{
+    plan_user_with(ws, user, control, options, &Refresh)
+}
+
+/// Computes the changes to the managed block of `user` that `policy` asks
+/// for, without writing anything.
+///
+/// The keys of users with [`Control::enable`] set are loaded and handed to
+/// [`Policy::decide`], unless [`Policy::reads_keys`] tells otherwise. Otherwise, this works like [`plan_user`], which uses
+/// the [`Refresh`] policy.
+///
+/// # Errors
+/// The function will fail if the keys could not be loaded, if `policy`
+/// fails, or for the reasons listed for [`plan_user`].
+pub fn plan_user_with<W>(
+    ws: &W,
+    user: &User,
+    control: &Control,
+    options: &VisitOptions,
+    policy: &dyn Policy,
+) -> Result<Plan>
+where
+    W: Workspace,
+{
+    if let Some(gid) = control.authorized_keys_gid {
+        if ws.groups().group_by_gid(gid).is_none() {
+            bail!("authorized_keys_gid {gid} is not a known group");
+        }
+    }
+
+    let mut plan = Plan {
+        enable: control.enable,
+        change: Change::None,
+        path: None,
+        warnings: Vec::new(),
+        install: false,
+        skip: false,
+        diff: DiffStat::default(),
+        contents: String::new(),
+        placement: Placement::new(user, control, options),
+        target_root: options.target_root.clone(),
+        history: None,
+    };
+
+    let config = if control.enable && policy.reads_keys() {
+        load_config(ws, user, control, options, &mut plan.warnings)?
+    } else {
+        Config::default()
+    };
+    let enabled = enable_command_groups(
+        control,
+        &config.enable_commands,
+        &mut plan.warnings,
+    )?;
+    let block = match policy.decide(user, &enabled, &config.keys)? {
+        PolicyAction::Install(block) => Some(block),
+        PolicyAction::Remove => None,
+        PolicyAction::Keep => {
+            plan.skip = true;
+            return Ok(plan);
+        }
+    };
+    #[cfg(feature = "sshd-syntax")]
+    if let Some(block) = &block {
+        plan.warnings
+            .extend(crate::sshd_syntax::check_block(&block.text));
+    }
+
+    let path = resolve_path(&control.authorized_keys, user)
+        .and_then(|path| check_target(&path).map(|()| path))
+        .and_then(|path| reroot(&path, options.target_root.as_deref()));
+
+    let path = match (&block, path) {
+        (_, Ok(path)) => path,
+        (Some(_), Err(error)) => return Err(error),
+        // Disabled users cannot have a block where nothing can be found
+        (None, Err(_)) => return Ok(plan),
+    };
+
+    check_link(ws, user, &plan.placement, &path, options)?;
+
+    let current = match std::fs::read_to_string(&path) {
+        Ok(content) => Some(content),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => None,
+        Err(error) => {
+            return Err(error)
+                .with_context(|| format!("reading {}", path.display()))
+        }
+    };
+
+    if let Some(current) = &current {
+        plan.history = Archive::new(user, control, options, current)?;
+    }
+
+    let markers = BlockMarkers::of(control);
+    plan.install = block.is_some();
+    if let Some(block) = block {
+        let current = current.unwrap_or_default();
+        plan.contents = markers.replace(&current, &block.text)?;
+        plan.warnings.extend(block.warnings);
+        plan.diff = DiffStat::between(&current, &plan.contents);
+        plan.change = if plan.contents != current {
+            if markers.locate(&current)?.is_some() {
+                Change::Updated
+            } else {
+                Change::New
+            }
+        } else if misplaced(ws, &plan.placement, &path)? {
+            // Only ownership and permissions change; nothing to archive
+            plan.history = None;
+            Change::Updated
+        } else {
+            Change::None
+        };
+    } else if let Some(current) = current {
+        if let Some(contents) = markers.remove(&current)? {
+            plan.diff = DiffStat::between(&current, &contents);
+            plan.contents = contents;
+            plan.change = Change::Removed;
+        }
+    }
+
+    plan.path = Some(path);
+    Ok(plan)
+}
+
+/// Returns whether the existing file at `path` lacks the ownership or
+/// permissions of `placement`, so that it must be written again even though
+/// its contents are up to date.
+///
+/// # Errors
+/// The function will fail if `path` could not be inspected.
+fn misplaced<W>(ws: &W, placement: &Placement, path: &Path) -> Result<bool>
+where
+    W: Workspace,
+{
+    let metadata = match std::fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(false)
+        }
+        Err(error) => {
+            return Err(error)
+                .with_context(|| format!("inspecting {}", path.display()))
+        }
+    };
+
+    let uid = ws
+        .get_mock_owner_uid(path)
+        .unwrap_or_else(|| metadata.uid());
+    let gid = ws
+        .get_mock_group_gid(path)
+        .unwrap_or_else(|| metadata.gid());
+    Ok(uid != placement.uid
+        || gid != placement.gid
+        || metadata.mode() & 0o7777 != placement.file_mode)
+}
+
+/// Directories whose contents are never written as `authorized_keys`, see
+/// [`check_target`].
+pub const PROTECTED_TREES: &[&str] = &[
+    "/bin",
+    "/boot",
+    "/dev",
+    "/lib",
+    "/lib32",
+    "/lib64",
+    "/proc",
+    "/sbin",
+    "/sys",
+    "/usr",
+    "/etc/cron.d",
+    "/etc/pam.d",
+    "/etc/sudoers.d",
+];
+
+/// Directories whose direct children are never written as `authorized_keys`,
+/// see [`check_target`].
+///
+/// Subdirectories are fine, e.g. `/etc/ssh/authorized_keys/%u`.
+pub const PROTECTED_PARENTS: &[&str] = &["/", "/etc", "/etc/ssh"];
+
+/// Ensures that the resolved `authorized_keys` path is a sane write target.
+///
+/// This is a last line of defense against misconfiguration, such as a home
+/// directory of `/` or a pathological token expansion. `path` must not lie
+/// in [`PROTECTED_TREES`], nor directly in one of [`PROTECTED_PARENTS`].
+/// `path` is checked before it is moved into
+/// [`VisitOptions::target_root`], so that sandboxed runs refuse the same
+/// paths.
+///
+/// # Errors
+/// The function will fail if `path` is a protected location or has no file
+/// name.
+pub fn check_target(path: &Path) -> Result<()> {
+    let parent = match (path.parent(), path.file_name()) {
+        (Some(parent), Some(_)) => parent,
+        _ => bail!("refusing to write {}: not a file", path.display()),
+    };
+
+    if PROTECTED_PARENTS.iter().any(|p| parent == Path::new(p)) {
+        bail!(
+            "refusing to write {}: files directly in {} are never \
+            authorized_keys",
+            path.display(),
+            parent.display()
+        );
+    }
+
+    if let Some(tree) = PROTECTED_TREES.iter().find(|t| path.starts_with(t)) {
+        bail!(
+            "refusing to write {}: {tree} is a protected system directory",
+            path.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Ensures that `path`, if it is a symbolic link, cannot be abused.
+///
+/// A link must resolve to a file owned by the owner from `placement` that
+/// lies inside the home directory of `user` if `path` does, or inside the
+/// directory of `path` otherwise, e.g. a central directory of keys. This
+/// prevents reading another user's or a system file through the link. Links
+/// that do not resolve are harmless, since writes replace the link itself.
+///
+/// # Errors
+/// The function will fail if the link escapes its allowed directory, if the
+/// target has a different owner, or if some path could not be inspected.
+fn check_link<W>(
+    ws: &W,
+    user: &User,
+    placement: &Placement,
+    path: &Path,
+    options: &VisitOptions,
+) -> Result<()>
+where
+    W: Workspace,
+{
+    let suffix = "[security; refusing to proceed]";
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => {}
+        Ok(_) => return Ok(()),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(())
+        }
+        Err(error) => {
+            return Err(error)
+                .with_context(|| format!("inspecting {}", path.display()))
+        }
+    }
+
+    // Resolve both paths alike, so that they agree on the root
+    let target = ws.canonicalize(path);
+    let dangling = target
+        .as_ref()
+        .err()
+        .and_then(|error| error.downcast_ref::<std::io::Error>())
+        .map_or(false, |error| error.kind() == std::io::ErrorKind::NotFound);
+    if dangling {
+        return Ok(());
+    }
+    let target = target?;
+
+    let home = match home_dir(user) {
+        Some(home) => Some(reroot(home, options.target_root.as_deref())?),
+        None => None,
+    };
+    let allowed = match home {
+        Some(home) if path.starts_with(&home) => home,
+        _ => path
+            .parent()
+            .context("path has no parent directory")?
+            .into(),
+    };
+    let allowed = ws.canonicalize(&allowed)?;
+
+    if !target.starts_with(&allowed) {
+        bail!(
+            "{} is a symbolic link to {}, which is outside of {} {suffix}",
+            path.display(),
+            target.display(),
+            allowed.display()
+        );
+    }
+
+    let owner = match ws.get_mock_owner_uid(&target) {
+        Some(owner) => owner,
+        None => std::fs::metadata(&target)?.uid(),
+    };
+    if owner != placement.uid {
+        bail!(
+            "{} is a symbolic link to {}, which must be owned by UID {}, \
+            not {owner} {suffix}",
+            path.display(),
+            target.display(),
+            placement.uid
+        );
+    }
+
+    Ok(())
+}
+
+/// Installs or updates the managed block of `user`.
+///
+/// The changes are computed by [`plan_user`]. Contents of `authorized_keys`
+/// outside of the managed block are preserved. The file and its directory are
+/// created if necessary. Ownership and permissions follow
+/// [`Control::authorized_keys_owner`] and [`Control::authorized_keys_mode`]:
+/// by default, both belong to the user and are accessible only by the user.
+///
+/// Users with [`Control::enable`] unset are skipped and their
+/// `authorized_keys` is left alone, see [`Refresh`]; [`plan_user_with`] and
+/// [`Uninstall`] remove their managed blocks. Afterwards, an installed file
+/// is checked with [`strict_modes_warnings`].
+///
+/// # Errors
+/// The refresh will fail if [`plan_user`] complains or if `authorized_keys`
+/// could not be written.
+pub fn refresh_user<W>(
+    ws: &W,
+    user: &User,
+    control: &Control,
+    options: &VisitOptions,
+) -> Result<Report>
+where
+    W: Workspace,
+{
+    let plan = plan_user(ws, user, control, options)?;
+    let outcome = apply_plan(ws, &plan)?;
+
+    let mut warnings = plan.warnings;
+    if let Some(path) = installed(plan.install, &outcome) {
+        warnings.extend(strict_modes_warnings(
+            ws,
+            user,
+            path,
+            options.target_root.as_deref(),
+        ));
+    }
+
+    Ok(Report { outcome, warnings })
+}
+
+/// Returns the `authorized_keys` file that holds a managed block after
+/// `outcome`, if `install` tells that the plan asked for one.
+///
+/// Only such files are checked with [`strict_modes_warnings`]; a file that
+/// an uninstall left alone may not even exist.
+fn installed(install: bool, outcome: &Outcome) -> Option<&Path> {
+    match outcome {
+        Outcome::Updated(path) | Outcome::Unchanged(path) if install => {
+            Some(path)
+        }
+        _ => None,
+    }
+}
+
+/// Writes the changes described by `plan`.
+///
+/// When the plan targets a sandbox, missing directories inside the sandbox
+/// are created as well, with default ownership and permissions. With
+/// [`Control::history_dir`] set, a managed block that is replaced or removed
+/// is archived first.
+///
+/// # Errors
+/// The function will fail if the previous managed block could not be
+/// archived or `authorized_keys` could not be written. Nothing is written
+/// in the former case.
+pub fn apply_plan<W>(ws: &W, plan: &Plan) -> Result<Outcome>
+where
+    W: Workspace,
+{
+    let path = match (&plan.path, plan.change) {
+        (Some(path), Change::None) if plan.enable => {
+            return Ok(Outcome::Unchanged(path.clone()))
+        }
+        (Some(path), Change::New | Change::Updated | Change::Removed) => path,
+        _ => return Ok(Outcome::Disabled),
+    };
+
+    if plan.target_root.is_some() {
+        if let Some(home) = path.parent().and_then(Path::parent) {
+            std::fs::create_dir_all(home).with_context(|| {
+                format!("creating sandbox directory {}", home.display())
+            })?;
+        }
+    }
+
+    if let (Some(archive), Change::Updated | Change::Removed) =
+        (&plan.history, plan.change)
+    {
+        history::archive(
+            ws,
+            &archive.dir,
+            archive.uid,
+            &archive.block,
+            archive.keep,
+        )
+        .context("archiving the previous managed block")?;
+    }
+
+    write_contents(ws, plan.placement, path, &plan.contents)
+        .with_context(|| format!("updating {}", path.display()))?;
+
+    Ok(if plan.change == Change::Removed {
+        Outcome::Removed(path.clone())
+    } else {
+        Outcome::Updated(path.clone())
+    })
+}
+
+/// Restores the most recently archived managed block of `user`.
+///
+/// The block is taken from [`Control::history_dir`] and replaces the current
+/// managed block of `user`, if any. Contents of `authorized_keys` outside of
+/// the managed block are preserved. The entry is deleted afterwards, so that
+/// repeated rollbacks step further back in history; the replaced block is not
+/// archived. Note that the next refresh replaces the restored block again.
+///
+/// # Errors
+/// The function will fail in these cases:
+///   - [`Control::history_dir`] is unset or not acceptable, see
+///     [`history::check_dir`],
+///   - no block of `user` is archived, or the archived block lacks the
+///     markers of the user,
+///   - the path of `authorized_keys` is refused, see [`plan_user`], or
+///   - `authorized_keys` could not be read or written.
+pub fn rollback_user<W>(
+    ws: &W,
+    user: &User,
+    control: &Control,
+    options: &VisitOptions,
+) -> Result<Outcome>
+where
+    W: Workspace,
+{
+    let history_dir = match &control.history_dir {
+        Some(dir) => reroot(Path::new(dir), options.target_root.as_deref())?,
+        None => bail!("no history is kept [set history_dir in control]"),
+    };
+    let (entry, block) = history::latest(ws, &history_dir, user.uid())?
+        .context("no managed block was archived")?;
+
+    let markers = BlockMarkers::of(control);
+    if markers.locate(&block)? != Some(0..block.len()) {
+        bail!("{} is not a managed block", entry.display());
+    }
+
+    let path = resolve_path(&control.authorized_keys, user)?;
+    check_target(&path)?;
+    let path = reroot(&path, options.target_root.as_deref())?;
+
+    let placement = Placement::new(user, control, options);
+    check_link(ws, user, &placement, &path, options)?;
+
+    let current = match std::fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            String::new()
+        }
+        Err(error) => {
+            return Err(error)
+                .with_context(|| format!("reading {}", path.display()))
+        }
+    };
+
+    let contents = markers.replace(&current, &block)?;
+    write_contents(ws, placement, &path, &contents)
+        .with_context(|| format!("updating {}", path.display()))?;
+
+    std::fs::remove_file(&entry)
+        .with_context(|| format!("deleting {}", entry.display()))?;
+
+    Ok(Outcome::Updated(path))
+}
+
+/// Returns a warning for every object that makes sshd ignore `path` as the
+/// `authorized_keys` file of `user`.
+///
+/// With `StrictModes`, which is the default, `sshd(8)` refuses key files if
+/// the file or one of its directories is writable by group or others, or
+/// owned by someone other than the user or root. The file and its
+/// directories up to and including the home directory of `user` are
+/// inspected. If `path` lies outside of the home directory, only the file
+/// and its own directory are.
+///
+/// `target_root` is the sandbox that contains `path`, see
+/// [`VisitOptions::target_root`]. Objects that cannot be inspected are
+/// reported as well.
+pub fn strict_modes_warnings<W>(
+    ws: &W,
+    user: &User,
+    path: &Path,
+    target_root: Option<&Path>,
+) -> Vec<String>
+where
+    W: Workspace,
+{
+    let home = home_dir(user).and_then(|home| reroot(home, target_root).ok());
+    let dirs: Vec<&Path> = match &home {
+        Some(home) if path.starts_with(home) => path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(home))
+            .collect(),
+        _ => path.parent().into_iter().collect(),
+    };
+
+    let mut warnings = Vec::new();
+    for object in std::iter::once(path).chain(dirs) {
+        let metadata = match std::fs::metadata(object) {
+            Ok(metadata) => metadata,
+            Err(error) => {
+                warnings.push(format!(
+                    "could not inspect {} for sshd StrictModes: {error}",
+                    object.display()
+                ));
+                continue;
+            }
+        };
+
+        let owner = ws
+            .get_mock_owner_uid(object)
+            .unwrap_or_else(|| metadata.uid());
+        if owner != user.uid() && owner != 0 {
+            warnings.push(format!(
+                "{} is owned by UID {owner}, so sshd will ignore {} \
+                [StrictModes]",
+                object.display(),
+                path.display()
+            ));
+        }
+
+        let mode = metadata.permissions().mode();
+        if mode & 0o022 != 0 {
+            warnings.push(format!(
+                "{} is writable by group or others (mode {:o}), so sshd will \
+                ignore {} [StrictModes]",
+                object.display(),
+                mode & 0o7777,
+                path.display()
+            ));
+        }
+    }
+
+    warnings
+}
+
+/// Replaces the file at `path` with `contents` atomically.
+///
+/// With [`Placement::durable`], the directory is synced afterwards so that
+/// the rename survives a power loss.
+fn write_contents<W>(
+    ws: &W,
+    placement: Placement,
+    path: &Path,
+    contents: &str,
+) -> Result<()>
+where
+    W: Workspace,
+{
+    // Create missing directory
+    let dir = path.parent().context("path has no parent directory")?;
+    if !ws.lexists(dir) {
+        let mode = placement.dir_mode;
+        std::fs::DirBuilder::new().mode(mode).create(dir)?;
+        std::fs::set_permissions(dir, PermissionsExt::from_mode(mode))?;
+        ws.set_owner(dir, placement.uid, placement.gid)?;
+    }
+
+    // Write to a temporary file and move it into place
+    let (mut file, temp) = ws.create_temp_in(dir, 0o600)?;
+
+    let result = || -> Result<()> {
+        file.write_all(contents.as_bytes())?;
+        file.set_permissions(PermissionsExt::from_mode(placement.file_mode))?;
+        file.sync_all()?;
+
+        ws.set_owner(&temp, placement.uid, placement.gid)?;
+        ws.rename(&temp, path)?;
+        Ok(())
+    }();
+
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+        return result;
+    }
+
+    if placement.durable {
+        ws.sync_dir(dir)?;
+    }
+    Ok(())
+}
+
+/// Aggregate of [`Plan`s][Plan] of many users.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    /// Number of users considered.
+    pub total: usize,
+
+    /// Number of users enabled in control.
+    pub enabled: usize,
+
+    /// Number of users that would get a new managed block.
+    pub new: usize,
+
+    /// Number of users whose managed block would be replaced.
+    pub updated: usize,
+
+    /// Number of users whose managed block would be removed.
+    pub removed: usize,
+}
+
+impl Summary {
+    /// Accounts for `plan` in the summary.
+    pub fn add(&mut self, plan: &Plan) {
+        self.total += 1;
+        self.enabled += usize::from(plan.enable);
+
+        match plan.change {
+            Change::None => {}
+            Change::New => self.new += 1,
+            Change::Updated => self.updated += 1,
+            Change::Removed => self.removed += 1,
+        }
+    }
+
+    /// Returns the number of users with pending changes.
+    #[must_use]
+    pub fn pending(&self) -> usize {
+        self.new + self.updated + self.removed
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} users total, {} enabled, {} with pending changes \
+            ({} new, {} updated, {} removed)",
+            self.total,
+            self.enabled,
+            self.pending(),
+            self.new,
+            self.updated,
+            self.removed
+        )
+    }
+}
+
+/// [`Plan`] of a particular user.
+#[derive(Clone, Debug)]
+pub struct UserPlan<'a> {
+    /// The user.
+    pub user: &'a User,
+
+    /// Pending refresh of the user.
+    pub plan: Plan,
+}
+
+/// Plans of many users computed by [`plan_users`].
+#[derive(Debug)]
+pub struct Batch<'a> {
+    /// Users that were planned successfully, in order.
+    pub plans: Vec<UserPlan<'a>>,
+
+    /// Users that could not be planned, in order.
+    pub failures: Vec<(&'a User, Error)>,
+}
+
+impl<'a> Batch<'a> {
+    /// Returns the [`Summary`] of successful plans.
+    #[must_use]
+    pub fn summary(&self) -> Summary {
+        let mut result = Summary::default();
+        for UserPlan { plan, .. } in &self.plans {
+            result.add(plan);
+        }
+        result
+    }
+
+    /// Returns the `authorized_keys` paths that the plans of several users
+    /// resolve to, along with these users in order.
+    ///
+    /// Every plan is computed from the file as it was before any write, so
+    /// applying two plans for the same file would silently discard the
+    /// changes of the first one. This usually means that a central
+    /// `authorized_keys` path lacks a token such as `%u`.
+    ///
+    /// Users that neither want a managed block nor change the file are left
+    /// out, such as disabled users without a block, which commonly share a
+    /// home directory like `/`.
+    #[must_use]
+    pub fn shared_paths(&self) -> Vec<(&Path, Vec<&'a User>)> {
+        let mut users: BTreeMap<&Path, Vec<&'a User>> = BTreeMap::new();
+        for UserPlan { user, plan } in &self.plans {
+            if !plan.install && plan.change == Change::None {
+                continue;
+            }
+            if let Some(path) = &plan.path {
+                users.entry(path).or_default().push(user);
+            }
+        }
+        users.into_iter().filter(|(_, u)| u.len() > 1).collect()
+    }
+}
+
+/// What a [`PlanEntry`] intends for the managed block of a user.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlanAction {
+    /// The managed block should be present and up to date.
+    Install,
+
+    /// The managed block should be absent.
+    Remove,
+
+    /// The managed block is left as it is, e.g. for a disabled user.
+    Skip,
+
+    /// The user could not be planned.
+    Fail,
+}
+
+/// Machine-readable description of the plan of a single user.
+///
+/// See [`plan_entries`].
+#[derive(Clone, Debug, Serialize)]
+pub struct PlanEntry {
+    /// User ID.
+    pub uid: uid_t,
+
+    /// Username, lossily converted to UTF-8.
+    pub username: String,
+
+    /// What should become of the managed block.
+    pub action: PlanAction,
+
+    /// Path to the `authorized_keys` file, if it is known.
+    pub target_path: Option<PathBuf>,
+
+    /// Change that applying the plan would make.
+    pub change: Change,
+
+    /// Lines that would change, e.g. `+4 -1 lines`; see [`DiffStat`].
+    pub diff_summary: String,
+
+    /// Problems that would not prevent the refresh.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+
+    /// Reason why the user could not be planned, if it could not.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+
+    /// Why the user is left alone, if they are.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<SkipReason>,
+}
+
+/// Describes every user of `batch`, for consumption by other programs.
+///
+/// Planned users come first, followed by users that could not be planned,
+/// which have [`PlanAction::Fail`] and [`Change::None`].
+#[must_use]
+pub fn plan_entries(batch: &Batch) -> Vec<PlanEntry> {
+    let name = |user: &User| user.name().to_string_lossy().into_owned();
+
+    let planned =
+        batch.plans.iter().map(|UserPlan { user, plan }| PlanEntry {
+            uid: user.uid(),
+            username: name(user),
+            action: if plan.install {
+                PlanAction::Install
+            } else if plan.skip {
+                PlanAction::Skip
+            } else {
+                PlanAction::Remove
+            },
+            target_path: plan.path.clone(),
+            change: plan.change,
+            diff_summary: plan.diff.to_string(),
+            warnings: plan.warnings.clone(),
+            error: None,
+            reason: if !plan.enable && plan.change == Change::None {
+                Some(SkipReason::Disabled)
+            } else {
+                None
+            },
+        });
+
+    let failed = batch.failures.iter().map(|(user, error)| PlanEntry {
+        uid: user.uid(),
+        username: name(user),
+        action: PlanAction::Fail,
+        target_path: None,
+        change: Change::None,
+        diff_summary: DiffStat::default().to_string(),
+        warnings: Vec::new(),
+        error: Some(format!("{error:#}")),
+        reason: Some(SkipReason::of(error)),
+    });
+
+    planned.chain(failed).collect()
+}
+
+impl ControlManager {
+    /// Computes the refresh of `users` without writing anything.
+    ///
+    /// This is the first stage of a refresh; the second one is
+    /// [`apply_batch`]. See [`plan_users`].
+    pub fn plan<'a, W>(
+        &self,
+        ws: &W,
+        users: &[&'a User],
+        options: &VisitOptions,
+    ) -> Batch<'a>
+    where
+        W: Workspace,
+    {
+        plan_users(ws, self, users, options)
+    }
+}
+
+/// Computes the refresh of `users` with [`plan_user`] without writing
+/// anything.
+///
+/// Users that cannot be planned are collected in [`Batch::failures`] rather
+/// than stopping the whole batch.
+pub fn plan_users<'a, W>(
+    ws: &W,
+    control: &ControlManager,
+    users: &[&'a User],
+    options: &VisitOptions,
+) -> Batch<'a>
+where
+    W: Workspace,
+{
+    plan_users_with(ws, control, users, options, &Refresh)
+}
+
+/// Same as [`plan_users`], but plans every user with
+/// [`plan_user_with`] and `policy`.
+///
+/// Users that [`ControlManager::is_ambiguous`] reports are not planned and
+/// fail with [`SkipReason::AmbiguousName`].
+pub fn plan_users_with<'a, W>(
+    ws: &W,
+    control: &ControlManager,
+    users: &[&'a User],
+    options: &VisitOptions,
+    policy: &dyn Policy,
+) -> Batch<'a>
+where
+    W: Workspace,
+{
+    let mut result = Batch {
+        plans: Vec::new(),
+        failures: Vec::new(),
+    };
+
+    for &user in users {
+        if control.is_ambiguous(user.uid()) {
+            let error =
+                anyhow!("a control section naming the user was skipped")
+                    .context(SkipReason::AmbiguousName);
+            result.failures.push((user, error));
+            continue;
+        }
+
+        match plan_user_with(
+            ws,
+            user,
+            &control.get_user_control(user.uid()),
+            options,
+            policy,
+        ) {
+            Ok(plan) => result.plans.push(UserPlan { user, plan }),
+            Err(error) => result.failures.push((user, error)),
+        }
+    }
+
+    result
+}
+
+/// Finds users that are disabled in control but still have a managed block.
+///
+/// Every system user is considered, so that users that were dropped from
+/// control after their block had been installed are found, too. The result
+/// only holds plans that remove a managed block, in UID order; apply it with
+/// [`apply_batch`].
+///
+/// Users whose `authorized_keys` could not be inspected are collected in
+/// [`Batch::failures`].
+pub fn plan_prune<'a, W>(
+    ws: &'a W,
+    control: &ControlManager,
+    options: &VisitOptions,
+) -> Batch<'a>
+where
+    W: Workspace,
+{
+    let candidates: Vec<_> = ws
+        .users()
+        .all_users()
+        .filter(|u| !control.get_user_control(u.uid()).enable)
+        .collect();
+
+    let mut result =
+        plan_users_with(ws, control, &candidates, options, &Uninstall);
+    result.plans.retain(|p| p.plan.change == Change::Removed);
+    result
+}
+
+/// Machine-readable reason why a user was left alone.
+///
+/// See [`SkipReason::of`] for users that could not be planned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// The user is disabled in control, so its managed block, if any, is
+    /// left alone.
+    Disabled,
+
+    /// Some path of the user is relative to home, but the user has none.
+    NoHome,
+
+    /// User configuration could not be loaded and
+    /// [`Control::strict_user_config`] is set.
+    InsecureConfig,
+
+    /// The username is shared with other users, so a control section naming
+    /// it was skipped, see [`ControlManager::is_ambiguous`].
+    AmbiguousName,
+
+    /// Any other problem; the error of the user has the details.
+    Failed,
+}
+
+impl SkipReason {
+    /// Returns the reason for a user that could not be planned because of
+    /// `error`.
+    #[must_use]
+    pub fn of(error: &Error) -> Self {
+        if let Some(&reason) = error.downcast_ref::<Self>() {
+            reason
+        } else if error.downcast_ref::<NoHomeDir>().is_some() {
+            Self::NoHome
+        } else {
+            Self::Failed
+        }
+    }
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Disabled => "disabled in control",
+            Self::NoHome => "no home directory",
+            Self::InsecureConfig => "user configuration refused",
+            Self::AmbiguousName => "ambiguous username in control",
+            Self::Failed => "could not be planned",
+        })
+    }
+}
+
+/// User that [`apply_batch`] left alone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Skip {
+    /// User ID.
+    pub uid: uid_t,
+
+    /// Why the user was left alone.
+    pub reason: SkipReason,
+}
+
+impl fmt::Display for Skip {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "UID {} skipped: {}", self.uid, self.reason)
+    }
+}
+
+/// Report of [`apply_batch`].
+#[derive(Clone, Debug)]
+pub struct ApplyReport<'a> {
+    /// Outcome of every applied plan, in order.
+    pub outcomes: Vec<(&'a User, Outcome)>,
+
+    /// Problems found after applying, see [`strict_modes_warnings`].
+    pub warnings: Vec<(&'a User, String)>,
+
+    /// Users that were disabled or could not be planned, in order.
+    pub skipped: Vec<Skip>,
+}
+
+/// Writes the successful plans of `batch` with [`apply_plan`].
+///
+/// Files that hold keys afterwards are checked with
+/// [`strict_modes_warnings`].
+///
+/// If `transactional` is set and some user could not be planned, nothing is
+/// written at all. Otherwise, failed users are skipped. Nothing is written
+/// either if several users share an `authorized_keys` file, see
+/// [`Batch::shared_paths`].
+///
+/// Note that writes are only all-or-nothing with respect to planning: once
+/// some file has been written, a later write failure does not undo it.
+///
+/// # Errors
+/// The function will fail if `transactional` is set and [`Batch::failures`]
+/// is not empty, if [`Batch::shared_paths`] is not empty, or if some plan
+/// could not be applied, in which case the remaining plans are not applied.
+pub fn apply_batch<'a, W>(
+    ws: &W,
+    batch: &Batch<'a>,
+    transactional: bool,
+) -> Result<ApplyReport<'a>>
+where
+    W: Workspace,
+{
+    if transactional && !batch.failures.is_empty() {
+        bail!(
+            "{} of {} users could not be refreshed; no changes were made",
+            batch.failures.len(),
+            batch.failures.len() + batch.plans.len()
+        );
+    }
+
+    if let Some((path, users)) = batch.shared_paths().first() {
+        let names: Vec<_> =
+            users.iter().map(|u| u.name().to_string_lossy()).collect();
+        bail!(
+            "users {} would all write {}; no changes were made \
+            [give every user their own authorized_keys, e.g. with %u]",
+            names.join(", "),
+            path.display()
+        );
+    }
+
+    let mut result = ApplyReport {
+        outcomes: Vec::new(),
+        warnings: Vec::new(),
+        skipped: Vec::new(),
+    };
+    for &UserPlan { user, ref plan } in &batch.plans {
+        let outcome = apply_plan(ws, plan).with_context(|| {
+            format!(
+                "could not refresh user {}",
+                user.name().to_string_lossy()
+            )
+        })?;
+
+        if let Some(path) = installed(plan.install, &outcome) {
+            let warnings = strict_modes_warnings(
+                ws,
+                user,
+                path,
+                plan.target_root.as_deref(),
+            );
+            result
+                .warnings
+                .extend(warnings.into_iter().map(|w| (user, w)));
+        }
+        if outcome == Outcome::Disabled {
+            result.skipped.push(Skip {
+                uid: user.uid(),
+                reason: SkipReason::Disabled,
+            });
+        }
+        result.outcomes.push((user, outcome));
+    }
+
+    result
+        .skipped
+        .extend(batch.failures.iter().map(|(user, error)| Skip {
+            uid: user.uid(),
+            reason: SkipReason::of(error),
+        }));
+
+    Ok(result)
+}
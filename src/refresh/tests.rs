@@ -0,0 +1,2277 @@
+pub use crate::authorized_keys::{BEGIN_MARKER, ENDING_PREFIX, END_MARKER};
+pub use crate::workspace::mock::{set_perms, MockWorkspace};
+
+pub use super::*;
+
+const KEY: &str =
+    "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIGd1bW15a2V5Zm9ydGVzdGluZ3B1cnBvc2Vz";
+
+/// Creates a workspace with user alice that has `keys` configured.
+fn workspace(keys: &str) -> Result<MockWorkspace> {
+    let mut ws = MockWorkspace::new()?;
+
+    ws.add_user(1000, "alice", "home/alice")?;
+    ws.add_file(
+        "home/alice/.narrowssh.conf",
+        1000,
+        0o600,
+        format!("keys = [{keys}]"),
+    )?;
+
+    Ok(ws)
+}
+
+/// Returns the [`Control`] of an enabled user.
+fn enabled() -> Control {
+    let mut control = ControlManager::default().get_user_control(1000);
+    control.enable = true;
+    control.commands = vec![String::from("backup")];
+    control
+}
+
+pub use crate::config::{reroot, ControlManager};
+
+#[test]
+fn creates_file() -> Result<()> {
+    let ws = workspace(&format!("{KEY:?}"))?;
+    let alice = ws.users().user_by_uid(1000).unwrap();
+
+    let report =
+        refresh_user(&ws, alice, &enabled(), &VisitOptions::default())?;
+
+    let path = ws.path("home/alice/.ssh/authorized_keys");
+    assert_eq!(report.outcome, Outcome::Updated(path.clone()));
+
+    let content = std::fs::read_to_string(&path)?;
+    assert!(content.starts_with(BEGIN_MARKER));
+    assert!(content.ends_with(&format!("{END_MARKER}\n")));
+    assert!(content.contains(KEY));
+    Ok(())
+}
+
+#[test]
+fn preserves_other_keys() -> Result<()> {
+    let mut ws = workspace(&format!("{KEY:?}"))?;
+    ws.add_file("home/alice/.ssh/authorized_keys", 1000, 0o600, "mine\n")?;
+    let alice = ws.users().user_by_uid(1000).unwrap();
+
+    refresh_user(&ws, alice, &enabled(), &VisitOptions::default())?;
+
+    let content =
+        std::fs::read_to_string(ws.path("home/alice/.ssh/authorized_keys"))?;
+    assert!(content.starts_with(&format!("mine\n\n{BEGIN_MARKER}\n")));
+    Ok(())
+}
+
+#[test]
+fn preserves_cert_authority() -> Result<()> {
+    let mut ws = workspace(&format!("{KEY:?}"))?;
+    let own = format!(
+        "@cert-authority from=\"*.example.com\" {KEY} CA\n@revoked {KEY} old\n"
+    );
+    ws.add_file("home/alice/.ssh/authorized_keys", 1000, 0o600, &own)?;
+    let alice = ws.users().user_by_uid(1000).unwrap();
+
+    refresh_user(&ws, alice, &enabled(), &VisitOptions::default())?;
+    refresh_user(&ws, alice, &enabled(), &VisitOptions::default())?;
+
+    let content =
+        std::fs::read_to_string(ws.path("home/alice/.ssh/authorized_keys"))?;
+    assert!(content.starts_with(&format!("{own}\n{BEGIN_MARKER}\n")));
+    Ok(())
+}
+
+#[test]
+fn target_root() -> Result<()> {
+    let mut ws = workspace(&format!("{KEY:?}"))?;
+    let sandbox = ws.add_dir("sandbox", 0, 0o755)?;
+    let options = VisitOptions {
+        target_root: Some(sandbox.clone()),
+        ..VisitOptions::default()
+    };
+
+    let config =
+        reroot(&ws.path("home/alice/.narrowssh.conf"), Some(&sandbox))?;
+    ws.add_file(
+        &config,
+        1000,
+        0o600,
+        format!("keys = [\"{KEY} sandboxed\"]"),
+    )?;
+    let alice = ws.users().user_by_uid(1000).unwrap();
+
+    let report = refresh_user(&ws, alice, &enabled(), &options)?;
+
+    let path =
+        reroot(&ws.path("home/alice/.ssh/authorized_keys"), Some(&sandbox))?;
+    assert_eq!(report.outcome, Outcome::Updated(path.clone()));
+    assert!(std::fs::read_to_string(&path)?.contains("sandboxed"));
+    assert_eq!(ws.get_mock_owner_uid(&path), Some(1000));
+    assert!(!ws.path("home/alice/.ssh").exists());
+    Ok(())
+}
+
+/// Tests for extension directories of control and user configuration
+mod extensions {
+    use super::*;
+
+    const OTHER_KEY: &str = "ssh-ed25519 \
+        AAAAC3NzaC1lZDI1NTE5AAAAIG90aGVya2V5Zm9ydGVzdGluZ3B1cnBvc2Vz";
+
+    /// Renders the managed block of alice with extensions of control and user
+    /// configuration toggled independently.
+    ///
+    /// Both extension directories are present.
+    fn render(
+        control_extensions: bool,
+        user_extensions: bool,
+    ) -> Result<String> {
+        let mut ws = workspace(&format!("{KEY:?}"))?;
+        ws.add_dir("home/alice/.narrowssh.conf.d", 1000, 0o700)?;
+        ws.add_file(
+            "home/alice/.narrowssh.conf.d/10.conf",
+            1000,
+            0o600,
+            format!("keys = [\"{OTHER_KEY} extension\"]"),
+        )?;
+
+        #[rustfmt::skip]
+        let main = ws.add_file("etc/main.toml", 0, 0o600, r#"
+            ["*"]
+            enable = true
+            commands = ["backup"]
+        "#)?;
+        ws.add_dir("etc/main.toml.d", 0, 0o700)?;
+        #[rustfmt::skip]
+        ws.add_file("etc/main.toml.d/10.toml", 0, 0o600, r#"
+            ["*"]
+            commands = ["restore"]
+        "#)?;
+
+        let control_options = VisitOptions {
+            extensions: control_extensions,
+            ..VisitOptions::default()
+        };
+        let user_options = VisitOptions {
+            extensions: user_extensions,
+            ..VisitOptions::default()
+        };
+
+        let control = ControlManager::load(&ws, main, &control_options)?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+        let block = check_user(
+            &ws,
+            alice,
+            &control.get_user_control(1000),
+            &user_options,
+        )?;
+
+        Ok(block.unwrap().text)
+    }
+
+    #[test]
+    fn both() -> Result<()> {
+        let text = render(true, true)?;
+        assert!(text.contains("restore"));
+        assert!(text.contains("extension"));
+        Ok(())
+    }
+
+    #[test]
+    fn control_only() -> Result<()> {
+        let text = render(true, false)?;
+        assert!(text.contains("restore"));
+        assert!(!text.contains("extension"));
+        Ok(())
+    }
+
+    #[test]
+    fn user_only() -> Result<()> {
+        let text = render(false, true)?;
+        assert!(text.contains("backup"));
+        assert!(text.contains("extension"));
+        Ok(())
+    }
+
+    #[test]
+    fn neither() -> Result<()> {
+        let text = render(false, false)?;
+        assert!(text.contains("backup"));
+        assert!(!text.contains("extension"));
+        Ok(())
+    }
+}
+
+/// Tests for symbolic links in place of `authorized_keys`
+mod links {
+    use super::*;
+
+    const SECRET: &str = "secret contents\n";
+
+    /// Refreshes alice with `authorized_keys` linked to `target`.
+    fn refresh_linked(ws: &MockWorkspace, target: &str) -> Result<Report> {
+        ws.add_symlink("home/alice/.ssh/authorized_keys", target)?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+
+        refresh_user(ws, alice, &enabled(), &VisitOptions::default())
+    }
+
+    #[test]
+    fn outside_home() -> Result<()> {
+        let mut ws = workspace(&format!("{KEY:?}"))?;
+        ws.add_dir("home/alice/.ssh", 1000, 0o700)?;
+        ws.add_file("etc/passwd", 1000, 0o644, SECRET)?;
+
+        let error = refresh_linked(&ws, "etc/passwd").unwrap_err();
+
+        assert!(format!("{error:#}").contains("outside of"), "{error:#}");
+        assert_eq!(std::fs::read_to_string(ws.path("etc/passwd"))?, SECRET);
+        assert!(std::fs::symlink_metadata(
+            ws.path("home/alice/.ssh/authorized_keys")
+        )?
+        .file_type()
+        .is_symlink());
+        Ok(())
+    }
+
+    #[test]
+    fn other_users_file() -> Result<()> {
+        let mut ws = workspace(&format!("{KEY:?}"))?;
+        ws.add_user(1001, "bob", "home/bob")?;
+        ws.add_dir("home/alice/.ssh", 1000, 0o700)?;
+        ws.add_file("home/bob/.ssh/authorized_keys", 1001, 0o600, SECRET)?;
+
+        assert!(refresh_linked(&ws, "home/bob/.ssh/authorized_keys").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn foreign_file_in_home() -> Result<()> {
+        let mut ws = workspace(&format!("{KEY:?}"))?;
+        ws.add_dir("home/alice/.ssh", 1000, 0o700)?;
+        ws.add_file("home/alice/planted", 0, 0o644, SECRET)?;
+
+        let error = refresh_linked(&ws, "home/alice/planted").unwrap_err();
+
+        assert!(
+            format!("{error:#}").contains("must be owned by UID 1000"),
+            "{error:#}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn dangling() -> Result<()> {
+        let mut ws = workspace(&format!("{KEY:?}"))?;
+        ws.add_dir("home/alice/.ssh", 1000, 0o700)?;
+
+        let report = refresh_linked(&ws, "home/alice/missing")?;
+
+        let path = ws.path("home/alice/.ssh/authorized_keys");
+        assert_eq!(report.outcome, Outcome::Updated(path.clone()));
+        assert!(!std::fs::symlink_metadata(path)?.file_type().is_symlink());
+        Ok(())
+    }
+
+    #[test]
+    fn inside_home() -> Result<()> {
+        let mut ws = workspace(&format!("{KEY:?}"))?;
+        ws.add_dir("home/alice/.ssh", 1000, 0o700)?;
+        ws.add_file("home/alice/keys", 1000, 0o600, "mine\n")?;
+
+        let report = refresh_linked(&ws, "home/alice/keys")?;
+
+        let path = ws.path("home/alice/.ssh/authorized_keys");
+        assert_eq!(report.outcome, Outcome::Updated(path.clone()));
+        assert!(std::fs::read_to_string(path)?.starts_with("mine\n"));
+        Ok(())
+    }
+}
+
+#[test]
+fn dangling_ssh_dir() -> Result<()> {
+    let ws = workspace(&format!("{KEY:?}"))?;
+    ws.add_symlink("home/alice/.ssh", "elsewhere")?;
+    let alice = ws.users().user_by_uid(1000).unwrap();
+
+    assert!(
+        refresh_user(&ws, alice, &enabled(), &VisitOptions::default())
+            .is_err()
+    );
+    assert!(!ws.path("elsewhere").exists());
+    Ok(())
+}
+
+#[test]
+fn idempotent() -> Result<()> {
+    let ws = workspace(&format!("{KEY:?}"))?;
+    let alice = ws.users().user_by_uid(1000).unwrap();
+
+    refresh_user(&ws, alice, &enabled(), &VisitOptions::default())?;
+    let report =
+        refresh_user(&ws, alice, &enabled(), &VisitOptions::default())?;
+
+    assert!(matches!(report.outcome, Outcome::Unchanged(_)));
+    Ok(())
+}
+
+#[test]
+fn trailing_newlines_converge() -> Result<()> {
+    let mut contents = Vec::new();
+
+    for original in ["mine", "mine\n", "mine\n\n"] {
+        let mut ws = workspace(&format!("{KEY:?}"))?;
+        let path = ws.add_file(
+            "home/alice/.ssh/authorized_keys",
+            1000,
+            0o600,
+            original,
+        )?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+        let options = VisitOptions::default();
+
+        refresh_user(&ws, alice, &enabled(), &options)?;
+        let content = std::fs::read_to_string(&path)?;
+        assert!(content.starts_with(&format!("mine\n\n{BEGIN_MARKER}\n")));
+        assert!(content.ends_with(&format!("\n{END_MARKER}\n")));
+
+        let report = refresh_user(&ws, alice, &enabled(), &options)?;
+        assert!(matches!(report.outcome, Outcome::Unchanged(_)));
+
+        // Only the line recording the original ending differs
+        let lines: Vec<_> = content
+            .lines()
+            .filter(|l| !l.starts_with(ENDING_PREFIX))
+            .map(str::to_owned)
+            .collect();
+        contents.push(lines);
+    }
+
+    assert!(contents.iter().all(|c| *c == contents[0]));
+    Ok(())
+}
+
+#[test]
+fn disabled() -> Result<()> {
+    let ws = workspace(&format!("{KEY:?}"))?;
+    let alice = ws.users().user_by_uid(1000).unwrap();
+
+    let mut control = enabled();
+    control.enable = false;
+    let report =
+        refresh_user(&ws, alice, &control, &VisitOptions::default())?;
+
+    assert_eq!(report.outcome, Outcome::Disabled);
+    assert!(!ws.path("home/alice/.ssh").exists());
+    Ok(())
+}
+
+#[test]
+fn key_command_conflict() -> Result<()> {
+    let key = format!("command=\"ls\" {KEY} mine");
+    let ws = workspace(&format!("{key:?}"))?;
+    let alice = ws.users().user_by_uid(1000).unwrap();
+
+    assert!(
+        refresh_user(&ws, alice, &enabled(), &VisitOptions::default())
+            .is_err()
+    );
+    assert!(!ws.path("home/alice/.ssh/authorized_keys").exists());
+
+    let mut control = enabled();
+    control.command_conflict = CommandConflict::Skip;
+    let report =
+        refresh_user(&ws, alice, &control, &VisitOptions::default())?;
+
+    assert_eq!(report.warnings.len(), 1);
+    let content =
+        std::fs::read_to_string(ws.path("home/alice/.ssh/authorized_keys"))?;
+    assert!(!content.contains("mine"));
+    Ok(())
+}
+
+#[test]
+fn failed_rename() -> Result<()> {
+    let mut ws = workspace(&format!("{KEY:?}"))?;
+    ws.add_file("home/alice/.ssh/authorized_keys", 1000, 0o600, "mine\n")?;
+    let alice = ws.users().user_by_uid(1000).unwrap();
+
+    ws.set_fail_renames(true);
+    assert!(
+        refresh_user(&ws, alice, &enabled(), &VisitOptions::default())
+            .is_err()
+    );
+
+    let content =
+        std::fs::read_to_string(ws.path("home/alice/.ssh/authorized_keys"))?;
+    assert_eq!(content, "mine\n");
+    assert_eq!(std::fs::read_dir(ws.path("home/alice/.ssh"))?.count(), 1);
+    Ok(())
+}
+
+#[test]
+fn dump_matches_refresh() -> Result<()> {
+    let keys = format!("{KEY:?}, \"from=\\\"10.0.0.1\\\" {KEY} other\"");
+    let ws = workspace(&keys)?;
+    let alice = ws.users().user_by_uid(1000).unwrap();
+
+    let block = check_user(&ws, alice, &enabled(), &VisitOptions::default())?
+        .expect("alice is enabled");
+    assert!(!ws.path("home/alice/.ssh").exists());
+
+    refresh_user(&ws, alice, &enabled(), &VisitOptions::default())?;
+
+    let content =
+        std::fs::read_to_string(ws.path("home/alice/.ssh/authorized_keys"))?;
+    assert_eq!(content, block.text);
+    Ok(())
+}
+
+pub use crate::config::CommandConflict;
+
+/// Tests for `authorized_keys` files in a central root-owned directory
+mod central {
+    use super::*;
+
+    pub use std::os::unix::fs::PermissionsExt;
+
+    /// Returns the mode bits of the FS object at `path`.
+    fn mode(path: &Path) -> Result<u32> {
+        Ok(std::fs::metadata(path)?.permissions().mode() & 0o777)
+    }
+
+    /// Returns the [`Control`] of an enabled user with keys in `etc/keys`.
+    fn central(ws: &MockWorkspace) -> Control {
+        let mut control = enabled();
+        control.authorized_keys =
+            format!("{}/%u", ws.path("etc/keys").display());
+        control.authorized_keys_owner = KeysOwner::Root;
+        control
+    }
+
+    #[test]
+    fn creates_file() -> Result<()> {
+        let mut ws = workspace(&format!("{KEY:?}"))?;
+        ws.add_dir("etc", 0, 0o755)?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+
+        let report = refresh_user(
+            &ws,
+            alice,
+            &central(&ws),
+            &VisitOptions::default(),
+        )?;
+
+        let path = ws.path("etc/keys/alice");
+        assert_eq!(report.outcome, Outcome::Updated(path.clone()));
+        assert!(std::fs::read_to_string(&path)?.contains(KEY));
+
+        assert_eq!(mode(&path)?, 0o644);
+        assert_eq!(mode(&ws.path("etc/keys"))?, 0o711);
+        assert_eq!(ws.get_mock_owner_uid(&path), Some(0));
+        assert_eq!(ws.get_mock_owner_uid(ws.path("etc/keys")), Some(0));
+        assert!(!ws.path("home/alice/.ssh").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn group() -> Result<()> {
+        let mut ws = workspace(&format!("{KEY:?}"))?;
+        ws.add_dir("etc", 0, 0o755)?;
+        ws.add_group(990, "sshkeys", &[]);
+        let alice = ws.users().user_by_uid(1000).unwrap();
+        let path = ws.path("etc/keys/alice");
+
+        refresh_user(&ws, alice, &central(&ws), &VisitOptions::default())?;
+        assert_eq!(ws.get_mock_group_gid(&path), Some(0));
+
+        let control = Control {
+            authorized_keys_gid: Some(990),
+            ..central(&ws)
+        };
+        refresh_user(&ws, alice, &control, &VisitOptions::default())?;
+
+        assert_eq!(ws.get_mock_group_gid(&path), Some(990));
+        assert_eq!(ws.get_mock_owner_uid(&path), Some(0));
+        Ok(())
+    }
+
+    #[test]
+    fn changed_mode() -> Result<()> {
+        let ws = workspace(&format!("{KEY:?}"))?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+        let options = VisitOptions::default();
+        let path = ws.path("home/alice/.ssh/authorized_keys");
+
+        refresh_user(&ws, alice, &enabled(), &options)?;
+        let before = std::fs::read_to_string(&path)?;
+
+        let control = Control {
+            authorized_keys_mode: Some(0o640),
+            ..enabled()
+        };
+        let plan = plan_user(&ws, alice, &control, &options)?;
+        assert_eq!(plan.change, Change::Updated);
+        apply_plan(&ws, &plan)?;
+
+        assert_eq!(mode(&path)?, 0o640);
+        assert_eq!(std::fs::read_to_string(&path)?, before);
+        let plan = plan_user(&ws, alice, &control, &options)?;
+        assert_eq!(plan.change, Change::None);
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_group() -> Result<()> {
+        let mut ws = workspace(&format!("{KEY:?}"))?;
+        ws.add_dir("etc", 0, 0o755)?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+
+        let control = Control {
+            authorized_keys_gid: Some(990),
+            ..central(&ws)
+        };
+        let error =
+            refresh_user(&ws, alice, &control, &VisitOptions::default())
+                .unwrap_err();
+
+        assert!(error.to_string().contains("990"), "{error}");
+        assert!(!ws.path("etc/keys").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn existing_dir() -> Result<()> {
+        let mut ws = workspace(&format!("{KEY:?}"))?;
+        ws.add_dir("etc/keys", 0, 0o755)?;
+        ws.add_file("etc/keys/alice", 0, 0o644, "mine\n")?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+
+        refresh_user(&ws, alice, &central(&ws), &VisitOptions::default())?;
+
+        let content = std::fs::read_to_string(ws.path("etc/keys/alice"))?;
+        assert!(content.starts_with(&format!("mine\n\n{BEGIN_MARKER}\n")));
+        assert_eq!(mode(&ws.path("etc/keys"))?, 0o755);
+        Ok(())
+    }
+
+    #[test]
+    fn custom_mode() -> Result<()> {
+        let mut ws = workspace(&format!("{KEY:?}"))?;
+        ws.add_dir("etc", 0, 0o755)?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+
+        let mut control = central(&ws);
+        control.authorized_keys_mode = Some(0o640);
+        refresh_user(&ws, alice, &control, &VisitOptions::default())?;
+
+        assert_eq!(mode(&ws.path("etc/keys/alice"))?, 0o640);
+        Ok(())
+    }
+
+    #[test]
+    fn user_owned_default() -> Result<()> {
+        let ws = workspace(&format!("{KEY:?}"))?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+
+        refresh_user(&ws, alice, &enabled(), &VisitOptions::default())?;
+
+        let path = ws.path("home/alice/.ssh/authorized_keys");
+        assert_eq!(mode(&path)?, 0o600);
+        assert_eq!(mode(&ws.path("home/alice/.ssh"))?, 0o700);
+        assert_eq!(ws.get_mock_owner_uid(&path), Some(1000));
+        Ok(())
+    }
+
+    #[test]
+    fn group_section() -> Result<()> {
+        let mut ws = workspace(&format!("{KEY:?}"))?;
+        ws.add_user(1001, "bob", "home/bob")?;
+        ws.add_file(
+            "home/bob/.narrowssh.conf",
+            1001,
+            0o600,
+            format!("keys = [{KEY:?}]"),
+        )?;
+        ws.add_user(1002, "carol", "home/carol")?;
+        ws.add_group(100, "contractors", &["alice", "bob"]);
+        ws.add_dir("srv", 0, 0o755)?;
+
+        let template = format!("{}/%u", ws.path("srv/ckeys").display());
+        let main = ws.add_file(
+            "etc/main.toml",
+            0,
+            0o600,
+            format!(
+                "[\"@contractors\"]\nenable = true\n\
+                commands = [\"backup\"]\n\
+                authorized_keys = {template:?}\n\
+                authorized_keys_owner = \"root\"\n"
+            ),
+        )?;
+        let control =
+            ControlManager::load(&ws, main, &VisitOptions::default())?;
+
+        for uid in 1000..=1001 {
+            let user_control = control.get_user_control(uid);
+            assert_eq!(user_control.authorized_keys, template);
+        }
+        assert_eq!(
+            control.get_user_control(1002).authorized_keys,
+            "~/.ssh/authorized_keys"
+        );
+
+        let options = VisitOptions::default();
+        let users: Vec<_> = (1000..=1001)
+            .map(|uid| ws.users().user_by_uid(uid).unwrap())
+            .collect();
+        let batch = plan_users(&ws, &control, &users, &options);
+        assert!(batch.failures.is_empty(), "{:?}", batch.failures);
+        let report = apply_batch(&ws, &batch, true)?;
+
+        let paths: Vec<_> = report
+            .outcomes
+            .iter()
+            .map(|(_, outcome)| outcome.clone())
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                Outcome::Updated(ws.path("srv/ckeys/alice")),
+                Outcome::Updated(ws.path("srv/ckeys/bob")),
+            ]
+        );
+        for name in &["alice", "bob"] {
+            let path = ws.path(format!("srv/ckeys/{name}"));
+            assert!(std::fs::read_to_string(path)?.contains(KEY));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn shared_path() -> Result<()> {
+        let mut ws = workspace(&format!("{KEY:?}"))?;
+        ws.add_user(1001, "bob", "home/bob")?;
+        ws.add_file(
+            "home/bob/.narrowssh.conf",
+            1001,
+            0o600,
+            format!("keys = [{KEY:?}]"),
+        )?;
+        ws.add_dir("srv", 0, 0o755)?;
+
+        let path = ws.path("srv/ckeys/shared");
+        let main = ws.add_file(
+            "etc/main.toml",
+            0,
+            0o600,
+            format!(
+                "[\"*\"]\nenable = true\n\
+                commands = [\"backup\"]\n\
+                authorized_keys = {:?}\n\
+                authorized_keys_owner = \"root\"\n",
+                path.display().to_string()
+            ),
+        )?;
+        let control =
+            ControlManager::load(&ws, main, &VisitOptions::default())?;
+
+        let users: Vec<_> = (1000..=1001)
+            .map(|uid| ws.users().user_by_uid(uid).unwrap())
+            .collect();
+        let batch =
+            plan_users(&ws, &control, &users, &VisitOptions::default());
+        assert!(batch.failures.is_empty(), "{:?}", batch.failures);
+
+        let shared = batch.shared_paths();
+        assert_eq!(shared.len(), 1);
+        assert_eq!(shared[0].0, path);
+        let uids: Vec<_> = shared[0].1.iter().map(|u| u.uid()).collect();
+        assert_eq!(uids, [1000, 1001]);
+
+        let error = apply_batch(&ws, &batch, false).unwrap_err();
+        assert!(error.to_string().contains("alice, bob"), "{error}");
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn shared_by_idle_users() -> Result<()> {
+        let mut ws = workspace(&format!("{KEY:?}"))?;
+        ws.add_user(1001, "bob", "home/bob")?;
+        ws.add_user(1002, "carol", "home/carol")?;
+        ws.add_dir("srv", 0, 0o755)?;
+
+        let path = ws.path("srv/ckeys/shared");
+        let main = ws.add_file(
+            "etc/main.toml",
+            0,
+            0o600,
+            format!(
+                "[\"*\"]\nenable = false\n\
+                commands = [\"backup\"]\n\
+                authorized_keys = {:?}\n\
+                authorized_keys_owner = \"root\"\n\
+                [alice]\nenable = true\n\
+                authorized_keys = \"~/.ssh/authorized_keys\"\n\
+                authorized_keys_owner = \"user\"\n",
+                path.display().to_string()
+            ),
+        )?;
+        let control =
+            ControlManager::load(&ws, main, &VisitOptions::default())?;
+
+        let users: Vec<_> = (1000..=1002)
+            .map(|uid| ws.users().user_by_uid(uid).unwrap())
+            .collect();
+        let batch =
+            plan_users(&ws, &control, &users, &VisitOptions::default());
+
+        assert!(batch.shared_paths().is_empty());
+        apply_batch(&ws, &batch, false)?;
+        Ok(())
+    }
+
+    #[test]
+    fn distinct_paths() -> Result<()> {
+        let mut ws = workspace(&format!("{KEY:?}"))?;
+        ws.add_user(1001, "bob", "home/bob")?;
+        let main =
+            ws.add_file("etc/main.toml", 0, 0o600, "[\"*\"]\nenable = true")?;
+        let control =
+            ControlManager::load(&ws, main, &VisitOptions::default())?;
+        let users: Vec<_> = (1000..=1001)
+            .map(|uid| ws.users().user_by_uid(uid).unwrap())
+            .collect();
+
+        let batch =
+            plan_users(&ws, &control, &users, &VisitOptions::default());
+
+        assert!(batch.shared_paths().is_empty());
+        Ok(())
+    }
+}
+
+pub use crate::config::KeysOwner;
+
+/// Tests for [`Control::history_dir`] and [`rollback_user`]
+mod history {
+    use super::*;
+
+    const OTHER_KEY: &str = "ssh-ed25519 \
+        AAAAC3NzaC1lZDI1NTE5AAAAIG90aGVya2V5Zm9ydGVzdGluZ3B1cnBvc2VzISE=";
+
+    /// Returns the [`Control`] of an enabled user that keeps history in
+    /// `history` of `ws`.
+    fn archived(ws: &MockWorkspace) -> Control {
+        Control {
+            history_dir: Some(ws.path("history").display().to_string()),
+            ..enabled()
+        }
+    }
+
+    /// Configures `key` as the only key of alice.
+    fn set_key(ws: &mut MockWorkspace, key: &str) -> Result<()> {
+        ws.add_file(
+            "home/alice/.narrowssh.conf",
+            1000,
+            0o600,
+            format!("keys = [{key:?}]"),
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn archives_on_change() -> Result<()> {
+        let mut ws = workspace(&format!("{KEY:?}"))?;
+        let control = archived(&ws);
+        let path = ws.path("home/alice/.ssh/authorized_keys");
+        let options = VisitOptions::default();
+
+        refresh_user(
+            &ws,
+            ws.users().user_by_uid(1000).unwrap(),
+            &control,
+            &options,
+        )?;
+        assert!(
+            crate::history::entries(&ws.path("history"), 1000)?.is_empty()
+        );
+        let first = std::fs::read_to_string(&path)?;
+
+        set_key(&mut ws, OTHER_KEY)?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+        refresh_user(&ws, alice, &control, &options)?;
+        refresh_user(&ws, alice, &control, &options)?;
+
+        let entries = crate::history::entries(&ws.path("history"), 1000)?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(std::fs::read_to_string(&entries[0])?, first);
+        Ok(())
+    }
+
+    #[test]
+    fn archives_on_removal() -> Result<()> {
+        let ws = workspace(&format!("{KEY:?}"))?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+        let options = VisitOptions::default();
+
+        refresh_user(&ws, alice, &archived(&ws), &options)?;
+        let uninstall = crate::policy::Uninstall;
+        let plan =
+            plan_user_with(&ws, alice, &archived(&ws), &options, &uninstall)?;
+        apply_plan(&ws, &plan)?;
+
+        let entries = crate::history::entries(&ws.path("history"), 1000)?;
+        assert_eq!(entries.len(), 1);
+        assert!(std::fs::read_to_string(&entries[0])?.contains(KEY));
+        Ok(())
+    }
+
+    #[test]
+    fn disabled_by_default() -> Result<()> {
+        let mut ws = workspace(&format!("{KEY:?}"))?;
+        let options = VisitOptions::default();
+
+        let alice = ws.users().user_by_uid(1000).unwrap();
+        refresh_user(&ws, alice, &enabled(), &options)?;
+        set_key(&mut ws, OTHER_KEY)?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+        refresh_user(&ws, alice, &enabled(), &options)?;
+
+        assert!(!ws.path("history").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn insecure_dir() -> Result<()> {
+        let mut ws = workspace(&format!("{KEY:?}"))?;
+        let options = VisitOptions::default();
+        let path = ws.path("home/alice/.ssh/authorized_keys");
+
+        let alice = ws.users().user_by_uid(1000).unwrap();
+        refresh_user(&ws, alice, &archived(&ws), &options)?;
+        let first = std::fs::read_to_string(&path)?;
+
+        ws.add_dir("history", 1000, 0o700)?;
+        set_key(&mut ws, OTHER_KEY)?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+        assert!(refresh_user(&ws, alice, &archived(&ws), &options).is_err());
+
+        assert_eq!(std::fs::read_to_string(&path)?, first);
+        Ok(())
+    }
+
+    #[test]
+    fn rollback() -> Result<()> {
+        let mut ws = workspace(&format!("{KEY:?}"))?;
+        let path = ws.path("home/alice/.ssh/authorized_keys");
+        ws.add_file(&path, 1000, 0o600, "mine\n")?;
+        let control = archived(&ws);
+        let options = VisitOptions::default();
+
+        let alice = ws.users().user_by_uid(1000).unwrap();
+        refresh_user(&ws, alice, &control, &options)?;
+        let first = std::fs::read_to_string(&path)?;
+
+        set_key(&mut ws, OTHER_KEY)?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+        refresh_user(&ws, alice, &control, &options)?;
+        assert_ne!(std::fs::read_to_string(&path)?, first);
+
+        let outcome = rollback_user(&ws, alice, &control, &options)?;
+
+        assert_eq!(outcome, Outcome::Updated(path.clone()));
+        assert_eq!(std::fs::read_to_string(&path)?, first);
+        assert_eq!(ws.get_mock_owner_uid(&path), Some(1000));
+        assert!(
+            crate::history::entries(&ws.path("history"), 1000)?.is_empty()
+        );
+        assert!(rollback_user(&ws, alice, &control, &options).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn rollback_without_history() -> Result<()> {
+        let ws = workspace(&format!("{KEY:?}"))?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+
+        let error =
+            rollback_user(&ws, alice, &enabled(), &VisitOptions::default())
+                .unwrap_err();
+
+        assert!(error.to_string().contains("history_dir"), "{error}");
+        Ok(())
+    }
+}
+
+/// Tests for [`Control::pub_keys_fallback`]
+mod pub_keys_fallback {
+    use super::*;
+
+    const WORK_KEY: &str = "ssh-ed25519 \
+        AAAAC3NzaC1lZDI1NTE5AAAAIG90aGVya2V5Zm9ydGVzdGluZ3B1cnBvc2VzISE=";
+
+    /// Creates a workspace with user alice that has no configuration but two
+    /// public key files.
+    fn workspace() -> Result<MockWorkspace> {
+        let mut ws = MockWorkspace::new()?;
+
+        ws.add_user(1000, "alice", "home/alice")?;
+        ws.add_dir("home/alice/.ssh", 1000, 0o700)?;
+        ws.add_file(
+            "home/alice/.ssh/id_ed25519.pub",
+            1000,
+            0o644,
+            format!("{KEY} alice@laptop\n"),
+        )?;
+        ws.add_file(
+            "home/alice/.ssh/work.pub",
+            1000,
+            0o600,
+            format!("{WORK_KEY} alice@work\n"),
+        )?;
+
+        Ok(ws)
+    }
+
+    fn fallback() -> Control {
+        Control {
+            pub_keys_fallback: true,
+            ..enabled()
+        }
+    }
+
+    #[test]
+    fn picks_up_pub_files() -> Result<()> {
+        let ws = workspace()?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+
+        let block =
+            check_user(&ws, alice, &fallback(), &VisitOptions::default())?
+                .unwrap();
+
+        assert!(block.text.contains("alice@laptop"), "{}", block.text);
+        assert!(block.text.contains("alice@work"), "{}", block.text);
+        assert!(block.text.contains("backup"), "{}", block.text);
+        assert!(block.warnings.is_empty(), "{:?}", block.warnings);
+        Ok(())
+    }
+
+    #[test]
+    fn disabled_by_default() -> Result<()> {
+        let ws = workspace()?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+
+        let block =
+            check_user(&ws, alice, &enabled(), &VisitOptions::default())?
+                .unwrap();
+
+        assert!(!block.text.contains("alice@laptop"), "{}", block.text);
+        Ok(())
+    }
+
+    #[test]
+    fn config_wins() -> Result<()> {
+        let mut ws = workspace()?;
+        ws.add_file(
+            "home/alice/.narrowssh.conf",
+            1000,
+            0o600,
+            format!("keys = [\"{KEY} configured\"]"),
+        )?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+
+        let block =
+            check_user(&ws, alice, &fallback(), &VisitOptions::default())?
+                .unwrap();
+
+        assert!(block.text.contains("configured"), "{}", block.text);
+        assert!(!block.text.contains("alice@laptop"), "{}", block.text);
+        Ok(())
+    }
+
+    #[test]
+    fn insecure_file() -> Result<()> {
+        let mut ws = workspace()?;
+        ws.add_file("home/alice/.ssh/evil.pub", 1001, 0o644, KEY)?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+
+        let block =
+            check_user(&ws, alice, &fallback(), &VisitOptions::default())?
+                .unwrap();
+        assert!(!block.text.contains("alice@laptop"), "{}", block.text);
+        assert_eq!(block.warnings.len(), 1, "{:?}", block.warnings);
+
+        let strict = Control {
+            strict_user_config: true,
+            ..fallback()
+        };
+        assert!(check_user(&ws, alice, &strict, &VisitOptions::default())
+            .is_err());
+        Ok(())
+    }
+}
+
+/// Tests for [`VisitOptions::durable`]
+mod durable {
+    use super::*;
+
+    #[test]
+    fn central_by_default() -> Result<()> {
+        let mut ws = workspace(&format!("{KEY:?}"))?;
+        ws.add_dir("etc", 0, 0o755)?;
+        let alice = ws.users().user_by_uid(1000).unwrap().clone();
+        let control = Control {
+            authorized_keys: format!("{}/%u", ws.path("etc/keys").display()),
+            authorized_keys_owner: KeysOwner::Root,
+            ..enabled()
+        };
+
+        refresh_user(&ws, &alice, &control, &VisitOptions::default())?;
+        assert_eq!(ws.synced_dirs(), [ws.path("etc/keys")]);
+
+        // Unchanged files are not written, so nothing is synced
+        refresh_user(&ws, &alice, &control, &VisitOptions::default())?;
+        assert_eq!(ws.synced_dirs().len(), 1);
+
+        let options = VisitOptions {
+            durable: Some(false),
+            ..VisitOptions::default()
+        };
+        refresh_user(&ws, &alice, &enabled(), &options)?;
+        assert_eq!(ws.synced_dirs().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn per_user() -> Result<()> {
+        let ws = workspace(&format!("{KEY:?}"))?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+
+        refresh_user(&ws, alice, &enabled(), &VisitOptions::default())?;
+        assert!(ws.synced_dirs().is_empty());
+
+        let options = VisitOptions {
+            durable: Some(true),
+            ..VisitOptions::default()
+        };
+        let uninstall = crate::policy::Uninstall;
+        let plan = plan_user_with(
+            &ws,
+            alice,
+            &Control::default(),
+            &options,
+            &uninstall,
+        )?;
+        apply_plan(&ws, &plan)?;
+        assert_eq!(ws.synced_dirs(), [ws.path("home/alice/.ssh")]);
+        Ok(())
+    }
+
+    #[test]
+    fn failed_rename() -> Result<()> {
+        let ws = workspace(&format!("{KEY:?}"))?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+        let options = VisitOptions {
+            durable: Some(true),
+            ..VisitOptions::default()
+        };
+
+        ws.set_fail_renames(true);
+        assert!(refresh_user(&ws, alice, &enabled(), &options).is_err());
+        assert!(ws.synced_dirs().is_empty());
+        Ok(())
+    }
+}
+
+/// Tests for [`plan_user`] and [`Summary`]
+mod summary {
+    use super::*;
+
+    /// Adds a user with `KEY` configured.
+    fn add_user(
+        ws: &mut MockWorkspace,
+        uid: uid_t,
+        name: &str,
+    ) -> Result<()> {
+        ws.add_user(uid, name, format!("home/{name}"))?;
+        ws.add_file(
+            format!("home/{name}/.narrowssh.conf"),
+            uid,
+            0o600,
+            format!("keys = [{KEY:?}]"),
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn counts() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+        for (uid, name) in [
+            (1000, "alice"),
+            (1001, "bob"),
+            (1002, "carol"),
+            (1003, "dan"),
+            (1004, "erin"),
+            (1005, "frank"),
+        ] {
+            add_user(&mut ws, uid, name)?;
+        }
+
+        let stale = format!("mine\n\n{BEGIN_MARKER}\nold\n{END_MARKER}\n");
+        ws.add_file("home/bob/.ssh/authorized_keys", 1001, 0o600, &stale)?;
+        ws.add_file("home/dan/.ssh/authorized_keys", 1003, 0o600, &stale)?;
+
+        // carol is already up to date
+        let carol = ws.users().user_by_uid(1002).unwrap();
+        refresh_user(&ws, carol, &enabled(), &VisitOptions::default())?;
+
+        let mut disabled = enabled();
+        disabled.enable = false;
+
+        let mut summary = Summary::default();
+        for (uid, control) in [
+            (1000, &enabled()),
+            (1001, &enabled()),
+            (1002, &enabled()),
+            (1003, &disabled),
+            (1004, &disabled),
+            (1005, &enabled()),
+        ] {
+            let user = ws.users().user_by_uid(uid).unwrap();
+            summary.add(&plan_user(
+                &ws,
+                user,
+                control,
+                &VisitOptions::default(),
+            )?);
+        }
+
+        assert_eq!(
+            summary,
+            Summary {
+                total: 6,
+                enabled: 4,
+                new: 2,
+                updated: 1,
+                removed: 0,
+            }
+        );
+        assert_eq!(summary.pending(), 3);
+        assert_eq!(
+            summary.to_string(),
+            "6 users total, 4 enabled, 3 with pending changes \
+            (2 new, 1 updated, 0 removed)"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn plan_writes_nothing() -> Result<()> {
+        let ws = workspace(&format!("{KEY:?}"))?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+
+        let plan =
+            plan_user(&ws, alice, &enabled(), &VisitOptions::default())?;
+
+        assert_eq!(plan.change, Change::New);
+        assert!(!ws.path("home/alice/.ssh").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_disabled() -> Result<()> {
+        let mut ws = workspace(&format!("{KEY:?}"))?;
+        ws.add_dir("home/alice/.ssh", 1000, 0o700)?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+        refresh_user(&ws, alice, &enabled(), &VisitOptions::default())?;
+
+        let path = ws.path("home/alice/.ssh/authorized_keys");
+        let installed = std::fs::read_to_string(&path)?;
+        let mut control = enabled();
+        control.enable = false;
+
+        let plan = plan_user(&ws, alice, &control, &VisitOptions::default())?;
+        assert!(plan.skip);
+        assert_eq!(plan.change, Change::None);
+
+        let report =
+            refresh_user(&ws, alice, &control, &VisitOptions::default())?;
+        assert_eq!(report.outcome, Outcome::Disabled);
+        assert_eq!(std::fs::read_to_string(&path)?, installed);
+        Ok(())
+    }
+}
+
+/// Tests for [`plan_users`] and [`apply_batch`]
+mod batch {
+    use super::*;
+
+    /// Creates a workspace with users alice and bob, where bob is broken and
+    /// user configuration is strict.
+    fn workspace() -> Result<(MockWorkspace, ControlManager)> {
+        let mut ws = MockWorkspace::new()?;
+
+        ws.add_user(0, "root", "root")?;
+        ws.add_user(1000, "alice", "home/alice")?;
+        ws.add_user(1001, "bob", "home/bob")?;
+        ws.add_file(
+            "home/alice/.narrowssh.conf",
+            1000,
+            0o600,
+            format!("keys = [{KEY:?}]"),
+        )?;
+        ws.add_file("home/bob/.narrowssh.conf", 1001, 0o600, "keys = 42")?;
+
+        let main = ws.add_file(
+            "etc/main.toml",
+            0,
+            0o600,
+            "[\"*\"]\nenable = true\ncommands = [\"backup\"]\n\
+            strict_user_config = true",
+        )?;
+        let control =
+            ControlManager::load(&ws, main, &VisitOptions::default())?;
+
+        Ok((ws, control))
+    }
+
+    /// Returns alice and bob.
+    fn users(ws: &MockWorkspace) -> Vec<&User> {
+        [1000, 1001]
+            .iter()
+            .map(|&uid| ws.users().user_by_uid(uid).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn collects_failures() -> Result<()> {
+        let (ws, control) = workspace()?;
+        let users = users(&ws);
+
+        let batch =
+            plan_users(&ws, &control, &users, &VisitOptions::default());
+
+        assert_eq!(batch.plans.len(), 1);
+        assert_eq!(batch.plans[0].user.uid(), 1000);
+        assert_eq!(batch.failures.len(), 1);
+        assert_eq!(batch.failures[0].0.uid(), 1001);
+        assert_eq!(batch.summary().new, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn transactional() -> Result<()> {
+        let (ws, control) = workspace()?;
+        let users = users(&ws);
+
+        let batch =
+            plan_users(&ws, &control, &users, &VisitOptions::default());
+
+        assert!(apply_batch(&ws, &batch, true).is_err());
+        assert!(!ws.path("home/alice/.ssh").exists());
+        assert!(!ws.path("home/bob/.ssh").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn best_effort() -> Result<()> {
+        let (ws, control) = workspace()?;
+        let users = users(&ws);
+
+        let batch =
+            plan_users(&ws, &control, &users, &VisitOptions::default());
+        let outcomes = apply_batch(&ws, &batch, false)?.outcomes;
+
+        let path = ws.path("home/alice/.ssh/authorized_keys");
+        assert_eq!(outcomes.len(), 1);
+        assert_eq!(outcomes[0].1, Outcome::Updated(path.clone()));
+        assert!(path.exists());
+        assert!(!ws.path("home/bob/.ssh").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn transactional_success() -> Result<()> {
+        let (ws, control) = workspace()?;
+        let users = &users(&ws)[..1];
+
+        let batch =
+            plan_users(&ws, &control, users, &VisitOptions::default());
+
+        assert_eq!(apply_batch(&ws, &batch, true)?.outcomes.len(), 1);
+        assert!(ws.path("home/alice/.ssh/authorized_keys").exists());
+        Ok(())
+    }
+}
+
+/// Tests for [`SkipReason`] in [`ApplyReport::skipped`] and [`plan_entries`]
+mod skip_reasons {
+    use super::*;
+
+    use crate::config::AmbiguousNames;
+    use uzers::os::unix::UserExt;
+
+    /// Creates a workspace with users that are left alone for different
+    /// reasons, and alice, who is refreshed.
+    fn workspace() -> Result<(MockWorkspace, ControlManager)> {
+        let mut ws = MockWorkspace::new()?;
+
+        for (uid, name) in &[(1000, "alice"), (1001, "bob"), (1002, "carol")]
+        {
+            ws.add_user(*uid, name, format!("home/{name}"))?;
+            ws.add_file(
+                format!("home/{name}/.narrowssh.conf"),
+                *uid,
+                0o600,
+                format!("keys = [{KEY:?}]"),
+            )?;
+        }
+        ws.add_user(1003, "dave", "home/dave")?;
+        ws.add_user(1005, "erin", "home/erin")?;
+        ws.add_user(1006, "erin", "home/erin2")?;
+        set_perms(ws.path("home/carol/.narrowssh.conf"), 0o644)?;
+
+        let main = ws.add_file(
+            "etc/main.toml",
+            0,
+            0o600,
+            "[\"*\"]\nenable = true\ncommands = [\"backup\"]\n\
+            strict_user_config = true\n\
+            [bob]\nenable = false\n\
+            [dave]\nauthorized_keys_gid = 4242\n\
+            [erin]\ncommands = [\"restore\"]\n",
+        )?;
+        let options = VisitOptions {
+            ambiguous_names: AmbiguousNames::Skip,
+            ..VisitOptions::default()
+        };
+        let control = ControlManager::load(&ws, main, &options)?;
+
+        Ok((ws, control))
+    }
+
+    #[test]
+    fn reasons() -> Result<()> {
+        let (ws, control) = workspace()?;
+        let homeless = User::new(1004, "homeless", 1004).with_home_dir("");
+        let mut users: Vec<_> = (1000..=1003)
+            .map(|uid| ws.users().user_by_uid(uid).unwrap())
+            .collect();
+        users.push(&homeless);
+        users.push(ws.users().user_by_uid(1005).unwrap());
+
+        let batch =
+            plan_users(&ws, &control, &users, &VisitOptions::default());
+        let report = apply_batch(&ws, &batch, false)?;
+
+        let expected = [
+            Skip {
+                uid: 1001,
+                reason: SkipReason::Disabled,
+            },
+            Skip {
+                uid: 1002,
+                reason: SkipReason::InsecureConfig,
+            },
+            Skip {
+                uid: 1003,
+                reason: SkipReason::Failed,
+            },
+            Skip {
+                uid: 1004,
+                reason: SkipReason::NoHome,
+            },
+            Skip {
+                uid: 1005,
+                reason: SkipReason::AmbiguousName,
+            },
+        ];
+        assert_eq!(report.skipped, expected);
+
+        let reasons: Vec<_> = plan_entries(&batch)
+            .iter()
+            .map(|entry| (entry.uid, entry.reason))
+            .collect();
+        assert_eq!(
+            reasons,
+            [
+                (1000, None),
+                (1001, Some(SkipReason::Disabled)),
+                (1002, Some(SkipReason::InsecureConfig)),
+                (1003, Some(SkipReason::Failed)),
+                (1004, Some(SkipReason::NoHome)),
+                (1005, Some(SkipReason::AmbiguousName)),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn report() -> Result<()> {
+        let (ws, control) = workspace()?;
+        let homeless = User::new(1004, "homeless", 1004).with_home_dir("");
+        let mut users: Vec<_> = (1001..=1003)
+            .map(|uid| ws.users().user_by_uid(uid).unwrap())
+            .collect();
+        users.push(&homeless);
+        users.push(ws.users().user_by_uid(1005).unwrap());
+
+        let batch =
+            plan_users(&ws, &control, &users, &VisitOptions::default());
+        let report = apply_batch(&ws, &batch, false)?;
+
+        let lines: Vec<_> =
+            report.skipped.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            lines,
+            [
+                "UID 1001 skipped: disabled in control",
+                "UID 1002 skipped: user configuration refused",
+                "UID 1003 skipped: could not be planned",
+                "UID 1004 skipped: no home directory",
+                "UID 1005 skipped: ambiguous username in control",
+            ]
+        );
+
+        let json = serde_json::to_value(&report.skipped)?;
+        assert_eq!(
+            json,
+            serde_json::json!([
+                {"uid": 1001, "reason": "disabled"},
+                {"uid": 1002, "reason": "insecure_config"},
+                {"uid": 1003, "reason": "failed"},
+                {"uid": 1004, "reason": "no_home"},
+                {"uid": 1005, "reason": "ambiguous_name"},
+            ])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn json() -> Result<()> {
+        let (ws, control) = workspace()?;
+        let users: Vec<_> = [1000, 1001, 1002, 1005]
+            .iter()
+            .map(|&uid| ws.users().user_by_uid(uid).unwrap())
+            .collect();
+
+        let batch =
+            plan_users(&ws, &control, &users, &VisitOptions::default());
+        let json = serde_json::to_value(plan_entries(&batch))?;
+
+        assert!(json[0].get("reason").is_none(), "{json}");
+        assert_eq!(json[1]["reason"], "disabled");
+        assert_eq!(json[2]["reason"], "insecure_config");
+        assert_eq!(json[3]["uid"], 1005);
+        assert_eq!(json[3]["reason"], "ambiguous_name");
+        Ok(())
+    }
+
+    #[test]
+    fn messages_unchanged() {
+        let homeless = User::new(1004, "homeless", 1004).with_home_dir("");
+        let error = resolve_path("~/keys", &homeless).unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "user \"homeless\" has no home directory"
+        );
+        assert_eq!(SkipReason::of(&error), SkipReason::NoHome);
+        assert_eq!(
+            SkipReason::of(&anyhow::anyhow!("other")),
+            SkipReason::Failed
+        );
+    }
+}
+
+/// Tests for [`Control::strict_user_config`]
+mod user_config {
+    use super::*;
+
+    /// Creates a workspace with users alice and bob, where bob has an
+    /// insecure `config.d`.
+    fn workspace(strict: bool) -> Result<(MockWorkspace, ControlManager)> {
+        let mut ws = MockWorkspace::new()?;
+
+        ws.add_user(0, "root", "root")?;
+        ws.add_user(1000, "alice", "home/alice")?;
+        ws.add_user(1001, "bob", "home/bob")?;
+        for (uid, name) in [(1000, "alice"), (1001, "bob")] {
+            ws.add_file(
+                format!("home/{name}/.narrowssh.conf"),
+                uid,
+                0o600,
+                format!("keys = [\"{KEY} {name}\"]"),
+            )?;
+        }
+        ws.add_dir("home/bob/.narrowssh.conf.d", 1001, 0o777)?;
+
+        let main = ws.add_file(
+            "etc/main.toml",
+            0,
+            0o600,
+            format!(
+                "[\"*\"]\nenable = true\ncommands = [\"backup\"]\n\
+                strict_user_config = {strict}"
+            ),
+        )?;
+        let control =
+            ControlManager::load(&ws, main, &VisitOptions::default())?;
+
+        Ok((ws, control))
+    }
+
+    /// Returns alice and bob.
+    fn users(ws: &MockWorkspace) -> Vec<&User> {
+        [1000, 1001]
+            .iter()
+            .map(|&uid| ws.users().user_by_uid(uid).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn ignored() -> Result<()> {
+        let (ws, control) = workspace(false)?;
+        let users = users(&ws);
+
+        let batch =
+            plan_users(&ws, &control, &users, &VisitOptions::default());
+        assert!(batch.failures.is_empty());
+        assert_eq!(batch.plans.len(), 2);
+
+        let alice = &batch.plans[0].plan;
+        assert!(alice.warnings.is_empty());
+        assert!(alice.contents().contains(" alice\n"));
+
+        let bob = &batch.plans[1].plan;
+        assert_eq!(bob.warnings.len(), 1);
+        assert!(bob.warnings[0].starts_with("user configuration ignored"));
+        assert!(bob.warnings[0].contains(".narrowssh.conf.d"));
+        assert_eq!(bob.change, Change::New);
+        assert!(bob.contents().starts_with(BEGIN_MARKER));
+        assert!(!bob.contents().contains(KEY));
+
+        let outcomes = apply_batch(&ws, &batch, true)?.outcomes;
+        assert_eq!(outcomes.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn strict() -> Result<()> {
+        let (ws, control) = workspace(true)?;
+        let users = users(&ws);
+
+        let batch =
+            plan_users(&ws, &control, &users, &VisitOptions::default());
+        assert_eq!(batch.plans.len(), 1);
+        assert_eq!(batch.plans[0].user.uid(), 1000);
+        assert_eq!(batch.failures.len(), 1);
+        assert_eq!(batch.failures[0].0.uid(), 1001);
+        Ok(())
+    }
+
+    #[test]
+    fn checked() -> Result<()> {
+        let (ws, control) = workspace(false)?;
+        let bob = ws.users().user_by_uid(1001).unwrap();
+
+        let block = check_user(
+            &ws,
+            bob,
+            &control.get_user_control(1001),
+            &VisitOptions::default(),
+        )?
+        .unwrap();
+        assert!(!block.text.contains(KEY));
+        assert!(block.warnings[0].starts_with("user configuration ignored"));
+        Ok(())
+    }
+}
+
+/// Tests for [`Control::command_groups`]
+mod command_groups {
+    use super::*;
+
+    /// Returns the [`Control`] of an enabled user who may enable the
+    /// `backup` group.
+    fn offering_backup(strict: bool) -> Control {
+        let mut control = enabled();
+        control.commands = vec![String::from("status")];
+        control.command_groups = vec![String::from("backup")];
+        control.group_commands.insert(
+            String::from("backup"),
+            vec![String::from("/usr/bin/backup *"), String::from("status")],
+        );
+        control.strict_user_config = strict;
+        control
+    }
+
+    /// Creates a workspace with user alice who enables `groups`.
+    fn workspace(groups: &str) -> Result<MockWorkspace> {
+        let mut ws = MockWorkspace::new()?;
+
+        ws.add_user(1000, "alice", "home/alice")?;
+        ws.add_file(
+            "home/alice/.narrowssh.conf",
+            1000,
+            0o600,
+            format!("keys = [{KEY:?}]\nenable_commands = [{groups}]"),
+        )?;
+
+        Ok(ws)
+    }
+
+    #[test]
+    fn enabled_group() -> Result<()> {
+        let ws = workspace("\"backup\"")?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+
+        let plan = plan_user(
+            &ws,
+            alice,
+            &offering_backup(true),
+            &VisitOptions::default(),
+        )?;
+        assert!(plan.warnings.is_empty());
+        assert!(plan.contents().contains(" 'status' '/usr/bin/backup *'\""));
+        Ok(())
+    }
+
+    #[test]
+    fn not_enabled() -> Result<()> {
+        let ws = workspace("")?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+
+        let plan = plan_user(
+            &ws,
+            alice,
+            &offering_backup(true),
+            &VisitOptions::default(),
+        )?;
+        assert!(!plan.contents().contains("backup"));
+        Ok(())
+    }
+
+    #[test]
+    fn unlisted_group() -> Result<()> {
+        let ws = workspace("\"backup\", \"shell\"")?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+        let options = VisitOptions::default();
+
+        let error = plan_user(&ws, alice, &offering_backup(true), &options)
+            .unwrap_err();
+        assert!(format!("{error:#}").contains("\"shell\""), "{error:#}");
+
+        let plan = plan_user(&ws, alice, &offering_backup(false), &options)?;
+        assert_eq!(plan.warnings.len(), 1);
+        assert!(plan.warnings[0].starts_with("command group \"shell\""));
+        assert!(plan.contents().contains("'/usr/bin/backup *'"));
+        assert!(!plan.contents().contains("shell"));
+
+        let block =
+            check_user(&ws, alice, &offering_backup(false), &options)?;
+        assert_eq!(block.unwrap().warnings, plan.warnings);
+        Ok(())
+    }
+
+    #[test]
+    fn from_control() -> Result<()> {
+        let mut ws = workspace("\"backup\"")?;
+        ws.add_user(1001, "bob", "home/bob")?;
+        ws.add_file(
+            "home/bob/.narrowssh.conf",
+            1001,
+            0o600,
+            format!("keys = [{KEY:?}]\nenable_commands = [\"backup\"]"),
+        )?;
+        let main = ws.add_file(
+            "etc/main.toml",
+            0,
+            0o600,
+            "[command-groups]\nbackup = [\"/usr/bin/backup *\"]\n\
+            [\"*\"]\nenable = true\nstrict_user_config = true\n\
+            [alice]\ncommand_groups = [\"backup\"]",
+        )?;
+        let control =
+            ControlManager::load(&ws, main, &VisitOptions::default())?;
+        let users: Vec<_> = [1000, 1001]
+            .iter()
+            .map(|&uid| ws.users().user_by_uid(uid).unwrap())
+            .collect();
+
+        let batch =
+            plan_users(&ws, &control, &users, &VisitOptions::default());
+        assert_eq!(batch.plans.len(), 1);
+        assert!(batch.plans[0].plan.contents().contains("/usr/bin/backup"));
+        assert_eq!(batch.failures.len(), 1);
+        assert_eq!(batch.failures[0].0.uid(), 1001);
+        Ok(())
+    }
+}
+
+/// Tests for [`Control::begin_marker`] and [`Control::end_marker`]
+mod markers {
+    use super::*;
+
+    const BEGIN: &str = "# >>> keys managed by ops";
+    const END: &str = "# <<< keys managed by ops";
+
+    /// Returns the [`Control`] of an enabled user with custom markers.
+    fn custom() -> Control {
+        Control {
+            begin_marker: Some(String::from(BEGIN)),
+            end_marker: Some(String::from(END)),
+            ..enabled()
+        }
+    }
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let mut ws = workspace(&format!("{KEY:?}"))?;
+        ws.add_file(
+            "home/alice/.ssh/authorized_keys",
+            1000,
+            0o600,
+            "mine\n",
+        )?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+        let options = VisitOptions::default();
+        let path = ws.path("home/alice/.ssh/authorized_keys");
+
+        let plan = plan_user(&ws, alice, &custom(), &options)?;
+        assert_eq!(plan.change, Change::New);
+        apply_plan(&ws, &plan)?;
+
+        let content = std::fs::read_to_string(&path)?;
+        assert!(content.starts_with(&format!("mine\n\n{BEGIN}\n")));
+        assert!(content.ends_with(&format!("{END}\n")));
+        assert!(!content.contains(BEGIN_MARKER));
+        assert!(!content.contains(END_MARKER));
+
+        let plan = plan_user(&ws, alice, &custom(), &options)?;
+        assert_eq!(plan.change, Change::None);
+
+        let block = check_user(&ws, alice, &custom(), &options)?.unwrap();
+        assert_eq!(
+            BlockMarkers::of(&custom()).read_hash(&content)?,
+            Some(block.hash)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn uninstall() -> Result<()> {
+        let mut ws = workspace(&format!("{KEY:?}"))?;
+        ws.add_file(
+            "home/alice/.ssh/authorized_keys",
+            1000,
+            0o600,
+            "mine\n",
+        )?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+        let options = VisitOptions::default();
+        let uninstall = crate::policy::Uninstall;
+
+        apply_plan(&ws, &plan_user(&ws, alice, &custom(), &options)?)?;
+
+        // Default markers do not see the custom block
+        let plan =
+            plan_user_with(&ws, alice, &enabled(), &options, &uninstall)?;
+        assert_eq!(plan.change, Change::None);
+
+        let plan =
+            plan_user_with(&ws, alice, &custom(), &options, &uninstall)?;
+        assert_eq!(plan.change, Change::Removed);
+        assert_eq!(plan.contents(), "mine\n");
+        Ok(())
+    }
+}
+
+/// Tests for [`check_target`]
+mod targets {
+    use super::*;
+
+    #[test]
+    fn sane() -> Result<()> {
+        for path in [
+            "/home/alice/.ssh/authorized_keys",
+            "/root/.ssh/authorized_keys",
+            "/etc/ssh/authorized_keys/alice",
+            "/srv/keys/alice",
+        ] {
+            check_target(Path::new(path))?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn dangerous() {
+        for path in [
+            "/",
+            "/authorized_keys",
+            "/etc/alice",
+            "/etc/ssh/sshd_config",
+            "/bin/.ssh/authorized_keys",
+            "/usr/lib/alice/keys",
+            "/etc/sudoers.d/alice",
+        ] {
+            assert!(check_target(Path::new(path)).is_err(), "{path}");
+        }
+    }
+
+    #[test]
+    fn refresh_refuses() -> Result<()> {
+        let mut ws = workspace(&format!("{KEY:?}"))?;
+        let sandbox = ws.add_dir("sandbox", 0, 0o755)?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+
+        for template in ["/%u", "/etc/%u", "/usr/share/%u/keys"] {
+            let control = Control {
+                authorized_keys: String::from(template),
+                ..enabled()
+            };
+
+            let error =
+                refresh_user(&ws, alice, &control, &VisitOptions::default())
+                    .expect_err(template);
+            assert!(error.to_string().starts_with("refusing"), "{error}");
+
+            let options = VisitOptions {
+                target_root: Some(sandbox.clone()),
+                ..VisitOptions::default()
+            };
+            assert!(refresh_user(&ws, alice, &control, &options).is_err());
+        }
+
+        assert!(!Path::new("/alice").exists());
+        assert!(!Path::new("/etc/alice").exists());
+        assert_eq!(std::fs::read_dir(&sandbox)?.count(), 0);
+        Ok(())
+    }
+}
+
+/// Tests for [`plan_entries`] and [`DiffStat`]
+mod plan_entries {
+    use super::*;
+
+    const STALE: &str = "# BEGIN narrowssh\nstale\n# END narrowssh\n";
+
+    #[test]
+    fn json() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+        ws.add_user(0, "root", "root")?;
+        for (uid, name) in [
+            (1000, "alice"),
+            (1001, "bob"),
+            (1002, "carol"),
+            (1004, "erin"),
+        ] {
+            ws.add_user(uid, name, format!("home/{name}"))?;
+            ws.add_file(
+                format!("home/{name}/.narrowssh.conf"),
+                uid,
+                0o600,
+                format!("keys = [{KEY:?}]"),
+            )?;
+        }
+        ws.add_user(1003, "dan", "home/dan")?;
+        ws.add_file("home/dan/.narrowssh.conf", 1003, 0o600, "keys = 42")?;
+        ws.add_file("home/carol/.ssh/authorized_keys", 1002, 0o600, STALE)?;
+        ws.add_file("home/erin/.ssh/authorized_keys", 1004, 0o600, STALE)?;
+
+        #[rustfmt::skip]
+        let main = ws.add_file("etc/main.toml", 0, 0o600, r#"
+            ["*"]
+            enable = true
+            commands = ["backup"]
+
+            [carol]
+            enable = false
+
+            [dan]
+            strict_user_config = true
+        "#)?;
+        let control =
+            ControlManager::load(&ws, main, &VisitOptions::default())?;
+        let options = VisitOptions::default();
+        let users: Vec<_> = ws.users().all_users().skip(1).collect();
+
+        // Bring bob up to date
+        let bob = &users[1..2];
+        apply_batch(&ws, &control.plan(&ws, bob, &options), false)?;
+
+        let batch = control.plan(&ws, &users, &options);
+        let json = serde_json::to_string(&plan_entries(&batch))?;
+        let entries: Vec<serde_json::Value> = serde_json::from_str(&json)?;
+
+        let summary: Vec<_> = entries
+            .iter()
+            .map(|e| {
+                (
+                    e["username"].as_str().unwrap(),
+                    e["action"].as_str().unwrap(),
+                    e["change"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("alice", "install", "create"),
+                ("bob", "install", "noop"),
+                ("carol", "skip", "noop"),
+                ("erin", "install", "update"),
+                ("dan", "fail", "noop"),
+            ]
+        );
+
+        let alice = &entries[0];
+        assert_eq!(alice["uid"], 1000);
+        assert_eq!(
+            alice["target_path"].as_str(),
+            ws.path("home/alice/.ssh/authorized_keys").to_str()
+        );
+        assert_eq!(alice["diff_summary"], "+4 -0 lines");
+        assert_eq!(entries[1]["diff_summary"], "+0 -0 lines");
+        assert_eq!(entries[2]["diff_summary"], "+0 -0 lines");
+        assert_eq!(entries[3]["diff_summary"], "+2 -1 lines");
+
+        assert!(entries[4]["target_path"].is_null());
+        assert!(entries[4]["error"].is_string());
+        Ok(())
+    }
+
+    #[test]
+    fn diff_stat() {
+        assert_eq!(DiffStat::between("", ""), DiffStat::default());
+        assert_eq!(
+            DiffStat::between("a\nb\nb\n", "b\nc\na\n"),
+            DiffStat {
+                added: 1,
+                removed: 1
+            }
+        );
+        assert_eq!(
+            DiffStat {
+                added: 2,
+                removed: 0
+            }
+            .to_string(),
+            "+2 -0 lines"
+        );
+    }
+}
+
+/// Tests for [`ControlManager::plan`] followed by [`apply_batch`]
+mod two_stage {
+    use super::*;
+
+    /// Creates a fleet of users with different states.
+    ///
+    /// alice has no `authorized_keys`, bob has an outdated managed block,
+    /// carol is disabled and has a managed block, and dan is disabled and has
+    /// none.
+    fn fleet() -> Result<(MockWorkspace, ControlManager)> {
+        let mut ws = MockWorkspace::new()?;
+
+        ws.add_user(0, "root", "root")?;
+        for (uid, name) in [
+            (1000, "alice"),
+            (1001, "bob"),
+            (1002, "carol"),
+            (1003, "dan"),
+        ] {
+            ws.add_user(uid, name, format!("home/{name}"))?;
+            ws.add_file(
+                format!("home/{name}/.narrowssh.conf"),
+                uid,
+                0o600,
+                format!("keys = [{KEY:?}]"),
+            )?;
+        }
+
+        let stale = format!("mine\n\n{BEGIN_MARKER}\nold\n{END_MARKER}\n");
+        ws.add_file("home/bob/.ssh/authorized_keys", 1001, 0o600, &stale)?;
+        ws.add_file("home/carol/.ssh/authorized_keys", 1002, 0o600, &stale)?;
+
+        #[rustfmt::skip]
+        let main = ws.add_file("etc/main.toml", 0, 0o600, r#"
+            ["*"]
+            enable = true
+            commands = ["backup"]
+
+            [carol]
+            enable = false
+
+            [dan]
+            enable = false
+        "#)?;
+        let control =
+            ControlManager::load(&ws, main, &VisitOptions::default())?;
+
+        Ok((ws, control))
+    }
+
+    /// Returns all users of the fleet in UID order.
+    fn users(ws: &MockWorkspace) -> Vec<&User> {
+        (1000..1004)
+            .map(|uid| ws.users().user_by_uid(uid).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn plan() -> Result<()> {
+        let (ws, control) = fleet()?;
+        let users = users(&ws);
+
+        let batch = control.plan(&ws, &users, &VisitOptions::default());
+
+        assert!(batch.failures.is_empty());
+        let changes: Vec<_> = batch
+            .plans
+            .iter()
+            .map(|p| (p.user.uid(), p.plan.change))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                (1000, Change::New),
+                (1001, Change::Updated),
+                (1002, Change::None),
+                (1003, Change::None),
+            ]
+        );
+
+        let alice = &batch.plans[0].plan;
+        assert_eq!(
+            alice.path.as_deref(),
+            Some(ws.path("home/alice/.ssh/authorized_keys").as_path())
+        );
+        assert!(alice.contents().starts_with(BEGIN_MARKER));
+        assert!(alice.contents().contains(KEY));
+        assert!(batch.plans[2].plan.skip);
+
+        // Planning wrote nothing
+        assert!(!ws.path("home/alice/.ssh").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn apply() -> Result<()> {
+        let (ws, control) = fleet()?;
+        let users = users(&ws);
+
+        let batch = control.plan(&ws, &users, &VisitOptions::default());
+        let report = apply_batch(&ws, &batch, true)?;
+
+        assert_eq!(report.outcomes.len(), 4);
+        for UserPlan { plan, .. } in &batch.plans {
+            if let Some(path) = &plan.path {
+                if plan.change != Change::None {
+                    assert_eq!(
+                        std::fs::read_to_string(path)?,
+                        plan.contents()
+                    );
+                }
+            }
+        }
+        assert_eq!(report.outcomes[3].1, Outcome::Disabled);
+
+        // A second plan finds nothing to do
+        let batch = control.plan(&ws, &users, &VisitOptions::default());
+        assert_eq!(batch.summary().pending(), 0);
+        Ok(())
+    }
+}
+
+/// Tests for [`plan_prune`]
+mod prune {
+    use super::*;
+
+    /// Creates a workspace where alice is enabled and has a managed block,
+    /// bob was dropped from control but still has one, and carol never had
+    /// one.
+    fn workspace() -> Result<(MockWorkspace, ControlManager)> {
+        let mut ws = MockWorkspace::new()?;
+
+        ws.add_user(0, "root", "root")?;
+        for (uid, name) in [(1000, "alice"), (1001, "bob"), (1002, "carol")] {
+            ws.add_user(uid, name, format!("home/{name}"))?;
+            ws.add_file(
+                format!("home/{name}/.narrowssh.conf"),
+                uid,
+                0o600,
+                format!("keys = [{KEY:?}]"),
+            )?;
+        }
+
+        let block = format!("{BEGIN_MARKER}\nold\n{END_MARKER}\n");
+        ws.add_file("home/alice/.ssh/authorized_keys", 1000, 0o600, &block)?;
+        ws.add_file(
+            "home/bob/.ssh/authorized_keys",
+            1001,
+            0o600,
+            format!("mine\n\n{block}"),
+        )?;
+        ws.add_file(
+            "home/carol/.ssh/authorized_keys",
+            1002,
+            0o600,
+            "mine\n",
+        )?;
+
+        #[rustfmt::skip]
+        let main = ws.add_file("etc/main.toml", 0, 0o600, r"
+            [alice]
+            enable = true
+        ")?;
+        let control =
+            ControlManager::load(&ws, main, &VisitOptions::default())?;
+
+        Ok((ws, control))
+    }
+
+    #[test]
+    fn dropped_user() -> Result<()> {
+        let (ws, control) = workspace()?;
+
+        let batch = plan_prune(&ws, &control, &VisitOptions::default());
+
+        assert!(batch.failures.is_empty());
+        let users: Vec<_> =
+            batch.plans.iter().map(|p| p.user.uid()).collect();
+        assert_eq!(users, [1001]);
+
+        let report = apply_batch(&ws, &batch, false)?;
+        let bob = ws.path("home/bob/.ssh/authorized_keys");
+        assert_eq!(report.outcomes[0].1, Outcome::Removed(bob.clone()));
+        assert_eq!(std::fs::read_to_string(bob)?, "mine\n");
+
+        // Enabled users are left alone
+        let alice = std::fs::read_to_string(
+            ws.path("home/alice/.ssh/authorized_keys"),
+        )?;
+        assert!(alice.contains("old"));
+        Ok(())
+    }
+
+    #[test]
+    fn nothing_to_prune() -> Result<()> {
+        let (ws, control) = workspace()?;
+        let batch = plan_prune(&ws, &control, &VisitOptions::default());
+        apply_batch(&ws, &batch, false)?;
+
+        let batch = plan_prune(&ws, &control, &VisitOptions::default());
+        assert!(batch.plans.is_empty());
+        assert!(batch.failures.is_empty());
+        Ok(())
+    }
+}
+
+/// Tests for [`strict_modes_warnings`]
+mod strict_modes {
+    use super::*;
+
+    #[test]
+    fn group_writable_ssh_dir() -> Result<()> {
+        let mut ws = workspace(&format!("{KEY:?}"))?;
+        ws.add_dir("home/alice/.ssh", 1000, 0o770)?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+
+        let report =
+            refresh_user(&ws, alice, &enabled(), &VisitOptions::default())?;
+
+        assert_eq!(report.warnings.len(), 1, "{:?}", report.warnings);
+        assert!(report.warnings[0].contains(".ssh is writable by group"));
+        assert!(report.warnings[0].contains("[StrictModes]"));
+        Ok(())
+    }
+
+    #[test]
+    fn foreign_home() -> Result<()> {
+        let ws = workspace(&format!("{KEY:?}"))?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+        refresh_user(&ws, alice, &enabled(), &VisitOptions::default())?;
+
+        ws.set_owner(ws.path("home/alice"), 1001, 1001)?;
+        let path = ws.path("home/alice/.ssh/authorized_keys");
+        let warnings = strict_modes_warnings(&ws, alice, &path, None);
+
+        assert_eq!(warnings.len(), 1, "{warnings:?}");
+        assert!(warnings[0].contains("owned by UID 1001"));
+        Ok(())
+    }
+
+    #[test]
+    fn batch() -> Result<()> {
+        let mut ws = workspace(&format!("{KEY:?}"))?;
+        ws.add_dir("home/alice/.ssh", 1000, 0o707)?;
+        let main = ws.add_file(
+            "etc/main.toml",
+            0,
+            0o600,
+            "[\"*\"]\nenable = true\ncommands = [\"backup\"]",
+        )?;
+        let control =
+            ControlManager::load(&ws, main, &VisitOptions::default())?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+
+        let batch =
+            plan_users(&ws, &control, &[alice], &VisitOptions::default());
+        let report = apply_batch(&ws, &batch, false)?;
+
+        assert_eq!(report.warnings.len(), 1, "{:?}", report.warnings);
+        assert_eq!(report.warnings[0].0.uid(), 1000);
+        Ok(())
+    }
+
+    #[test]
+    fn strict() -> Result<()> {
+        let ws = workspace(&format!("{KEY:?}"))?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+
+        let report =
+            refresh_user(&ws, alice, &enabled(), &VisitOptions::default())?;
+
+        assert!(report.warnings.is_empty(), "{:?}", report.warnings);
+        Ok(())
+    }
+}
+
+/// Tests for [`plan_users_with`] and [`apply_batch`] with [`Uninstall`]
+mod uninstall {
+    use super::*;
+
+    #[test]
+    fn ignores_user_config() -> Result<()> {
+        let mut ws = workspace("42")?;
+        ws.add_user(0, "root", "root")?;
+
+        #[rustfmt::skip]
+        let main = ws.add_file("etc/main.toml", 0, 0o600, r#"
+            [alice]
+            enable = true
+            commands = ["backup"]
+            strict_user_config = true
+        "#)?;
+        let control =
+            ControlManager::load(&ws, main, &VisitOptions::default())?;
+        let alice = ws.users().user_by_uid(1000).unwrap();
+        let options = VisitOptions::default();
+
+        let batch = control.plan(&ws, &[alice], &options);
+        assert_eq!(batch.failures.len(), 1);
+
+        let batch =
+            plan_users_with(&ws, &control, &[alice], &options, &Uninstall);
+        assert!(batch.failures.is_empty());
+        assert_eq!(batch.plans[0].plan.change, Change::None);
+
+        let report = apply_batch(&ws, &batch, true)?;
+        assert!(report.warnings.is_empty(), "{:?}", report.warnings);
+        assert!(!ws.path("home/alice/.ssh").exists());
+        Ok(())
+    }
+}
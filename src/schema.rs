@@ -0,0 +1,335 @@
+//! Machine-readable description of control files.
+
+use serde_json::{json, Map, Value};
+
+use crate::config::{Control, ALLOW_KEYS, COMMAND_GROUPS_SECTION};
+
+#[cfg(test)]
+mod tests;
+
+/// Type of a control setting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FieldType {
+    /// A boolean.
+    Boolean,
+
+    /// A string holding a name.
+    Name,
+
+    /// A string holding an absolute or home-relative path.
+    Path,
+
+    /// A string holding a single-line `#` comment.
+    Comment,
+
+    /// An array of strings.
+    StringList,
+
+    /// A positive integer.
+    Count,
+
+    /// Unix permission bits, usually written as an octal integer.
+    Mode,
+
+    /// A numeric user or group ID.
+    Id,
+
+    /// One of the listed strings.
+    Choice(&'static [&'static str]),
+}
+
+/// Description of a single control setting.
+#[derive(Clone, Copy, Debug)]
+pub struct FieldSchema {
+    /// Name of the setting in control files.
+    pub name: &'static str,
+
+    /// Type of the setting.
+    pub field_type: FieldType,
+
+    /// One-line description of the setting for operators.
+    pub description: &'static str,
+}
+
+impl FieldSchema {
+    /// Returns the built-in default of the setting, if it has one.
+    ///
+    /// # Panics
+    /// Panics if the built-in defaults cannot be serialized.
+    #[must_use]
+    pub fn default_value(&self) -> Option<Value> {
+        serde_json::to_value(Control::default())
+            .expect("defaults must be serializable")
+            .get(self.name)
+            .cloned()
+    }
+}
+
+/// All settings that may appear in a section of a control file.
+///
+/// Every field of a control section must be listed here, which is enforced by
+/// tests.
+pub const CONTROL_FIELDS: &[FieldSchema] = &[
+    FieldSchema {
+        name: "profile",
+        field_type: FieldType::Name,
+        description: "Name of a profile to inherit unset settings from.",
+    },
+    FieldSchema {
+        name: "enable",
+        field_type: FieldType::Boolean,
+        description: "Whether narrowssh manages the keys of the user at all.",
+    },
+    FieldSchema {
+        name: "action",
+        field_type: FieldType::Choice(&["allow", "deny"]),
+        description: "Whether keys of the user grant or revoke access.",
+    },
+    FieldSchema {
+        name: "config",
+        field_type: FieldType::Path,
+        description: "Path to the user configuration that lists public keys.",
+    },
+    FieldSchema {
+        name: "authorized_keys",
+        field_type: FieldType::Path,
+        description: "Path to the authorized_keys file to install keys into.",
+    },
+    FieldSchema {
+        name: "authorized_keys_owner",
+        field_type: FieldType::Choice(&["user", "root"]),
+        description:
+            "Owner of authorized_keys and of its directory if created.",
+    },
+    FieldSchema {
+        name: "authorized_keys_mode",
+        field_type: FieldType::Mode,
+        description:
+            "Permissions of authorized_keys; 0600 or 0644 by default.",
+    },
+    FieldSchema {
+        name: "authorized_keys_gid",
+        field_type: FieldType::Id,
+        description:
+            "Group of authorized_keys and of its directory if created.",
+    },
+    FieldSchema {
+        name: "commands",
+        field_type: FieldType::StringList,
+        description: "Commands that keys of the user are allowed to run.",
+    },
+    FieldSchema {
+        name: "commands_file",
+        field_type: FieldType::Path,
+        description:
+            "Absolute path to a root-owned file of further allowed commands.",
+    },
+    FieldSchema {
+        name: "command_conflict",
+        field_type: FieldType::Choice(&["error", "skip", "override"]),
+        description:
+            "Handling of keys that already force a command of their own.",
+    },
+    FieldSchema {
+        name: "max_line_length",
+        field_type: FieldType::Count,
+        description:
+            "Length in bytes above which a key line is considered unsafe.",
+    },
+    FieldSchema {
+        name: "long_line",
+        field_type: FieldType::Choice(&["warn", "error"]),
+        description: "Handling of key lines longer than max_line_length.",
+    },
+    FieldSchema {
+        name: "max_keys",
+        field_type: FieldType::Count,
+        description: "Number of keys above which the keys are suspicious.",
+    },
+    FieldSchema {
+        name: "too_many_keys",
+        field_type: FieldType::Choice(&["error", "truncate"]),
+        description: "Handling of users with more keys than max_keys.",
+    },
+    FieldSchema {
+        name: "extra_key_types",
+        field_type: FieldType::StringList,
+        description: "Key types to recognize besides the built-in ones.",
+    },
+    FieldSchema {
+        name: "port_forwarding",
+        field_type: FieldType::Boolean,
+        description: "Whether keys of the user may forward TCP ports.",
+    },
+    FieldSchema {
+        name: "x11_forwarding",
+        field_type: FieldType::Boolean,
+        description: "Whether keys of the user may forward X11 connections.",
+    },
+    FieldSchema {
+        name: "agent_forwarding",
+        field_type: FieldType::Boolean,
+        description: "Whether keys of the user may forward the SSH agent.",
+    },
+    FieldSchema {
+        name: "pty",
+        field_type: FieldType::Boolean,
+        description: "Whether keys of the user may allocate a terminal.",
+    },
+    FieldSchema {
+        name: "validate_sshd",
+        field_type: FieldType::Boolean,
+        description: "Whether to run sshd -t after authorized_keys changed.",
+    },
+    FieldSchema {
+        name: "sshd_path",
+        field_type: FieldType::Path,
+        description: "Absolute path to a root-owned sshd for validate_sshd.",
+    },
+    FieldSchema {
+        name: "history_dir",
+        field_type: FieldType::Path,
+        description: "Root-owned directory to archive previous managed \
+            blocks in.",
+    },
+    FieldSchema {
+        name: "history_keep",
+        field_type: FieldType::Count,
+        description: "Number of archived managed blocks kept per user.",
+    },
+    FieldSchema {
+        name: "begin_marker",
+        field_type: FieldType::Comment,
+        description: "Line that opens the managed block.",
+    },
+    FieldSchema {
+        name: "end_marker",
+        field_type: FieldType::Comment,
+        description: "Line that closes the managed block.",
+    },
+    FieldSchema {
+        name: "pub_keys_fallback",
+        field_type: FieldType::Boolean,
+        description: "Take keys from ~/.ssh/*.pub if user configuration \
+            is missing.",
+    },
+    FieldSchema {
+        name: "strict_user_config",
+        field_type: FieldType::Boolean,
+        description: "Whether unloadable user configuration is an error.",
+    },
+    FieldSchema {
+        name: "command_groups",
+        field_type: FieldType::StringList,
+        description: "Command groups that the user may enable.",
+    },
+];
+
+impl FieldType {
+    /// Returns the name of the TOML type that values of this type are written
+    /// as, e.g. `"boolean"`.
+    #[must_use]
+    pub fn toml_type(self) -> &'static str {
+        match self {
+            Self::Boolean => "boolean",
+            Self::Name | Self::Path | Self::Comment | Self::Choice(_) => {
+                "string"
+            }
+            Self::StringList => "array of strings",
+            Self::Count | Self::Mode | Self::Id => "integer",
+        }
+    }
+
+    /// Returns whether `value` is of the TOML type given by
+    /// [`toml_type`][Self::toml_type].
+    ///
+    /// Only the type is checked, not whether the value itself is valid.
+    #[must_use]
+    pub fn accepts(self, value: &toml::Value) -> bool {
+        match self {
+            Self::Boolean => value.is_bool(),
+            Self::Name | Self::Path | Self::Comment | Self::Choice(_) => {
+                value.is_str()
+            }
+            Self::StringList => value
+                .as_array()
+                .map_or(false, |items| items.iter().all(toml::Value::is_str)),
+            Self::Count | Self::Mode | Self::Id => value.is_integer(),
+        }
+    }
+
+    /// Returns the JSON Schema describing values of this type.
+    fn json_schema(self) -> Value {
+        match self {
+            Self::Boolean => json!({ "type": "boolean" }),
+            Self::Name => json!({ "type": "string", "minLength": 1 }),
+            Self::Path => json!({ "type": "string", "pattern": "^[/~]" }),
+            Self::Comment => {
+                json!({ "type": "string", "pattern": "^#[^\\n\\r]*[^\\s]$" })
+            }
+            Self::StringList => {
+                json!({ "type": "array", "items": { "type": "string" } })
+            }
+            Self::Count => json!({ "type": "integer", "minimum": 1 }),
+            Self::Id => json!({ "type": "integer", "minimum": 0 }),
+            Self::Mode => {
+                json!({ "type": "integer", "minimum": 0, "maximum": 0o777 })
+            }
+            Self::Choice(values) => {
+                json!({ "type": "string", "enum": values })
+            }
+        }
+    }
+}
+
+/// Returns the JSON Schema of control files.
+///
+/// Every section of a control file is described by the same schema, which
+/// lists [`CONTROL_FIELDS`] along with the built-in defaults, except for the
+/// [`COMMAND_GROUPS_SECTION`] that maps group names to commands and the
+/// [`ALLOW_KEYS`] setting.
+///
+/// # Panics
+/// Panics if the built-in defaults cannot be serialized.
+#[must_use]
+pub fn control_schema() -> Value {
+    let defaults = serde_json::to_value(Control::default())
+        .expect("defaults must be serializable");
+
+    let mut allowed_keys = vec![COMMAND_GROUPS_SECTION];
+    allowed_keys.extend(CONTROL_FIELDS.iter().map(|f| f.name));
+
+    let mut properties = Map::new();
+    for field in CONTROL_FIELDS {
+        let mut schema = field.field_type.json_schema();
+        schema["description"] = Value::from(field.description);
+        if let Some(default) = defaults.get(field.name) {
+            schema["default"] = default.clone();
+        }
+        properties.insert(field.name.to_owned(), schema);
+    }
+
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "narrowssh control file",
+        "type": "object",
+        "properties": {
+            COMMAND_GROUPS_SECTION: {
+                "type": "object",
+                "propertyNames": { "minLength": 1 },
+                "additionalProperties": FieldType::StringList.json_schema(),
+            },
+            ALLOW_KEYS: {
+                "type": "array",
+                "items": { "type": "string", "enum": allowed_keys },
+                "description":
+                    "Fields that extension files may set; main file only.",
+            },
+        },
+        "additionalProperties": {
+            "type": "object",
+            "properties": properties,
+            "additionalProperties": false,
+        },
+    })
+}
@@ -0,0 +1,126 @@
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+
+pub use super::*;
+
+use crate::config::IncompleteControl;
+
+/// A [`Deserializer`] that only records the field names of a struct.
+struct FieldNames(Option<&'static [&'static str]>);
+
+#[derive(Debug)]
+struct Stop;
+
+impl std::fmt::Display for Stop {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("stop")
+    }
+}
+
+impl std::error::Error for Stop {}
+
+impl de::Error for Stop {
+    fn custom<T: std::fmt::Display>(_: T) -> Self {
+        Stop
+    }
+}
+
+impl<'de> Deserializer<'de> for &mut FieldNames {
+    type Error = Stop;
+
+    fn deserialize_any<V: Visitor<'de>>(
+        self,
+        _: V,
+    ) -> Result<V::Value, Stop> {
+        Err(Stop)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        fields: &'static [&'static str],
+        _: V,
+    ) -> Result<V::Value, Stop> {
+        self.0 = Some(fields);
+        Err(Stop)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map enum identifier ignored_any
+    }
+}
+
+/// Returns the names of all fields of `T` as seen by serde.
+fn field_names<'de, T: Deserialize<'de>>() -> &'static [&'static str] {
+    let mut names = FieldNames(None);
+    let _ = T::deserialize(&mut names);
+    names.0.expect("not a struct")
+}
+
+#[test]
+fn lists_every_field() {
+    let mut expected = field_names::<IncompleteControl>().to_vec();
+    let mut actual: Vec<_> = CONTROL_FIELDS.iter().map(|f| f.name).collect();
+
+    expected.sort_unstable();
+    actual.sort_unstable();
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn schema_properties() {
+    let schema = control_schema();
+    let properties = &schema["additionalProperties"]["properties"];
+
+    for name in field_names::<IncompleteControl>() {
+        assert!(properties.get(name).is_some(), "{name} missing from schema");
+    }
+
+    assert_eq!(properties["enable"]["type"], "boolean");
+    assert_eq!(properties["enable"]["default"], false);
+    assert_eq!(
+        properties["authorized_keys"]["default"],
+        "~/.ssh/authorized_keys"
+    );
+    assert_eq!(properties["command_conflict"]["default"], "error");
+    assert_eq!(properties["max_line_length"]["default"], 8192);
+    assert_eq!(properties["long_line"]["default"], "warn");
+}
+
+#[test]
+fn every_field_described() {
+    for field in CONTROL_FIELDS {
+        assert!(!field.description.is_empty(), "{} lacks docs", field.name);
+        assert!(field.description.ends_with('.'), "{}", field.name);
+    }
+}
+
+#[test]
+fn default_values() {
+    let field =
+        |name| CONTROL_FIELDS.iter().find(|f| f.name == name).unwrap();
+
+    assert_eq!(field("enable").default_value(), Some(Value::from(false)));
+    assert_eq!(
+        field("long_line").default_value(),
+        Some(Value::from("warn"))
+    );
+    assert_eq!(field("profile").default_value(), None);
+    assert_eq!(field("authorized_keys_mode").default_value(), None);
+    assert_eq!(field("max_keys").default_value(), None);
+    assert_eq!(
+        field("too_many_keys").default_value(),
+        Some(Value::from("error"))
+    );
+}
+
+#[test]
+fn schema_descriptions() {
+    let schema = control_schema();
+    let properties = &schema["additionalProperties"]["properties"];
+
+    for field in CONTROL_FIELDS {
+        assert_eq!(properties[field.name]["description"], field.description);
+    }
+}
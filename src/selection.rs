@@ -0,0 +1,389 @@
+//! Selection of users affected by a command.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Error, Result};
+use uzers::{uid_t, User};
+
+use crate::allowlist::glob_matches;
+use crate::config::{ControlManager, Target};
+use crate::workspace::{is_group_member, Workspace};
+
+#[cfg(test)]
+mod tests;
+
+/// A description of one or more users.
+///
+/// The textual forms accepted by [`UserSelector::from_str`] are:
+///   - `#{uid}` for a single user ID,
+///   - `@{group}` for all members of a group,
+///   - `{lo}-{hi}` for an inclusive range of user IDs,
+///   - a string containing `*` or `?` for all users whose username matches
+///     it as a [glob][crate::allowlist], e.g. `svc-*`, and
+///   - any other non-empty string for a single username.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UserSelector {
+    /// The user with given username.
+    Name(String),
+
+    /// The user with given UID.
+    Uid(uid_t),
+
+    /// Members of the group with given name.
+    Group(String),
+
+    /// Users with UIDs in given inclusive range.
+    Range(uid_t, uid_t),
+
+    /// Users whose username matches given glob pattern.
+    Pattern(String),
+}
+
+impl FromStr for UserSelector {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.is_empty() {
+            bail!("user selector must not be empty");
+        }
+
+        if s.starts_with('#') {
+            let uid = s[1..]
+                .parse()
+                .map_err(|_| anyhow!("{s:?} is not a valid UID selector"))?;
+            return Ok(Self::Uid(uid));
+        }
+
+        if s.starts_with('@') {
+            let group = &s[1..];
+            if group.is_empty() {
+                bail!("group selector must include a group name");
+            }
+            return Ok(Self::Group(group.to_owned()));
+        }
+
+        if let Some(dash) = s.find('-') {
+            let (lo, hi) = (&s[..dash], &s[dash + 1..]);
+            if let (Ok(lo), Ok(hi)) = (lo.parse(), hi.parse()) {
+                if lo > hi {
+                    bail!("range {s:?} is empty");
+                }
+                return Ok(Self::Range(lo, hi));
+            }
+        }
+
+        if s.contains(|c| c == '*' || c == '?') {
+            return Ok(Self::Pattern(s.to_owned()));
+        }
+
+        Ok(Self::Name(s.to_owned()))
+    }
+}
+
+impl fmt::Display for UserSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Name(name) | Self::Pattern(name) => f.write_str(name),
+            Self::Uid(uid) => write!(f, "#{uid}"),
+            Self::Group(group) => write!(f, "@{group}"),
+            Self::Range(lo, hi) => write!(f, "{lo}-{hi}"),
+        }
+    }
+}
+
+impl UserSelector {
+    /// Returns all users matched by this selector, possibly none.
+    ///
+    /// Groups and ranges are matched against known users, so the cost is
+    /// bounded by the number of users regardless of the size of a range.
+    ///
+    /// # Errors
+    /// The function will fail in these cases:
+    ///   - the selected user or group does not exist, or
+    ///   - the username or group name is not unique.
+    pub fn matching<'a, W>(&self, ws: &'a W) -> Result<Vec<&'a User>>
+    where
+        W: Workspace,
+    {
+        let users = ws.users();
+
+        Ok(match self {
+            Self::Name(name) => vec![users
+                .user_by_username(name)?
+                .ok_or(anyhow!("No such user exists"))?],
+            Self::Uid(uid) => vec![users
+                .user_by_uid(*uid)
+                .ok_or(anyhow!("No such user exists"))?],
+            Self::Group(name) => {
+                let group =
+                    ws.groups().group_by_name(name)?.ok_or_else(|| {
+                        anyhow!("No such group {name:?} exists")
+                    })?;
+                users
+                    .all_users()
+                    .filter(|u| is_group_member(u, group))
+                    .collect()
+            }
+            Self::Range(lo, hi) => users
+                .all_users()
+                .filter(|u| *lo <= u.uid() && u.uid() <= *hi)
+                .collect(),
+            Self::Pattern(pattern) => users
+                .all_users()
+                .filter(|u| {
+                    glob_matches(pattern, &u.name().to_string_lossy(), &[])
+                })
+                .collect(),
+        })
+    }
+
+    /// Returns all users matched by this selector.
+    ///
+    /// # Errors
+    /// The function will fail in these cases:
+    ///   - [`Self::matching`] complains, or
+    ///   - no users are matched.
+    pub fn resolve<'a, W>(&self, ws: &'a W) -> Result<Vec<&'a User>>
+    where
+        W: Workspace,
+    {
+        let result = self.matching(ws)?;
+
+        if result.is_empty() {
+            bail!("No users match {self}");
+        }
+
+        Ok(result)
+    }
+}
+
+/// Returns the user named by `named` after checking that it has UID `uid`.
+///
+/// This lets scripts pass both `--user` and `--uid` to make sure that a
+/// username still refers to the expected account.
+///
+/// # Errors
+/// The function will fail if `named` does not select exactly one user, or
+/// if that user does not have UID `uid`.
+pub fn cross_check<'a, W>(
+    ws: &'a W,
+    named: &UserSelector,
+    uid: uid_t,
+) -> Result<&'a User>
+where
+    W: Workspace,
+{
+    let users = named.matching(ws)?;
+    let user = match users.as_slice() {
+        [user] => *user,
+        _ => bail!("--user {named} must name exactly one user"),
+    };
+
+    if user.uid() != uid {
+        bail!(
+            "--user {named} is UID {}, but --uid {uid} was given",
+            user.uid()
+        );
+    }
+    Ok(user)
+}
+
+/// Returns all users that should be affected.
+///
+/// At most one of `selectors` or `all_users` may be given. If none are
+/// given, the running user is selected, unless the running user is root.
+/// As an exception, a username or `#UID` may be followed by a
+/// [`UserSelector::Uid`], in which case both must refer to the same user,
+/// see [`cross_check`].
+///
+/// If `all_users` is set, users enabled in `control` are selected, except
+/// for the running user unless `include_self` is set. This protects operators
+/// from locking themselves out by accident. Selectors may still name the
+/// running user explicitly.
+///
+/// # Errors
+/// The function will fail in these cases:
+///   - more than one selection is made,
+///   - [`UserSelector::resolve`] or [`cross_check`] complains,
+///   - no selection is made and the running user is root, or
+///   - `all_users` is set and no users are selected.
+///
+/// # Panics
+/// Panics if the running user does not exist in [`Workspace::users`].
+pub fn resolve_users<'a, W>(
+    ws: &'a W,
+    selectors: &[UserSelector],
+    all_users: bool,
+    include_self: bool,
+    control: &ControlManager,
+) -> Result<Vec<&'a User>>
+where
+    W: Workspace,
+{
+    if let [named, UserSelector::Uid(uid)] = selectors {
+        if !all_users
+            && matches!(named, UserSelector::Name(_) | UserSelector::Uid(_))
+        {
+            return cross_check(ws, named, *uid).map(|user| vec![user]);
+        }
+    }
+
+    // Count enabled user selection flags
+    if selectors.len() + usize::from(all_users) > 1 {
+        bail!("Only one of --user, --uid and --all-users is allowed");
+    }
+
+    if let Some(selector) = selectors.first() {
+        return selector.resolve(ws);
+    }
+
+    if all_users {
+        let result: Vec<_> = every_user(ws, include_self)
+            .into_iter()
+            .filter(|u| control.get_user_control(u.uid()).enable)
+            .collect();
+
+        if result.is_empty() {
+            if include_self {
+                bail!("All users are disabled in control");
+            }
+            bail!(
+                "All users except the running user are disabled in control; \
+                use --include-self to select it"
+            );
+        }
+
+        return Ok(result);
+    }
+
+    // Operating on root's own keys is almost never intended
+    let current_uid = ws.users().current_uid();
+    if current_uid == 0 {
+        bail!(
+            "Running as root with no target; \
+            use --all-users, --user or --uid"
+        );
+    }
+
+    Ok(vec![ws
+        .users()
+        .user_by_uid(current_uid)
+        .expect("Current user does not exist")])
+}
+
+/// Returns every system user, except for the running user unless
+/// `include_self` is set.
+///
+/// Unlike `all_users` of [`resolve_users`], users disabled in control are
+/// selected as well. This suits commands that affect such users, too, such as
+/// removing every managed block.
+#[must_use]
+pub fn every_user<W: Workspace>(ws: &W, include_self: bool) -> Vec<&User> {
+    let current_uid = ws.users().current_uid();
+    ws.users()
+        .all_users()
+        .filter(|u| include_self || u.uid() != current_uid)
+        .collect()
+}
+
+/// Returns warnings about explicitly selected `users` that are disabled in
+/// control.
+///
+/// A user is disabled either by a section that names it, or because no
+/// section other than `"*"` matches it and the fallback is disabled. Keys of
+/// such a user are never installed, and refresh leaves its `authorized_keys`
+/// alone. Operators who name such a user most likely expect its keys to be
+/// installed.
+#[must_use]
+pub fn coverage_warnings(
+    users: &[&User],
+    control: &ControlManager,
+) -> Vec<String> {
+    users
+        .iter()
+        .filter(|user| !control.get_user_control(user.uid()).enable)
+        .map(|user| {
+            let name = user.name().to_string_lossy();
+            let covered = control.sections().iter().any(|s| {
+                s.target != Target::All && s.target.matches(user.uid())
+            });
+
+            if covered {
+                format!(
+                    "user {name} is disabled in control; \
+                    its keys will not be installed"
+                )
+            } else {
+                format!(
+                    "user {name} is not named in control and the fallback is \
+                    disabled; its keys will not be installed"
+                )
+            }
+        })
+        .collect()
+}
+
+/// Users selected by a list file, see [`users_from_list`].
+#[derive(Debug)]
+pub struct UserList<'a> {
+    /// Listed users enabled in control, in order of first appearance.
+    pub users: Vec<&'a User>,
+
+    /// Problems with lines that did not resolve to a user.
+    pub warnings: Vec<String>,
+}
+
+/// Returns the users listed in `content` that are enabled in `control`.
+///
+/// Every non-blank line of `content` holds a username or a numeric UID.
+/// Surrounding whitespace is ignored. Lines that do not resolve to exactly
+/// one user are reported in [`UserList::warnings`] and otherwise skipped.
+/// Users listed more than once are selected once.
+#[must_use]
+pub fn users_from_list<'a, W>(
+    ws: &'a W,
+    content: &str,
+    control: &ControlManager,
+) -> UserList<'a>
+where
+    W: Workspace,
+{
+    let users = ws.users();
+    let mut result = UserList {
+        users: Vec::new(),
+        warnings: Vec::new(),
+    };
+
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let user = match line.parse::<uid_t>() {
+            Ok(uid) => users
+                .user_by_uid(uid)
+                .ok_or_else(|| anyhow!("no user with UID {uid}")),
+            Err(_) => users.user_by_username(line).and_then(|user| {
+                user.ok_or_else(|| anyhow!("no user named {line:?}"))
+            }),
+        };
+
+        match user {
+            Ok(user) => {
+                let enabled = control.get_user_control(user.uid()).enable;
+                if enabled
+                    && !result.users.iter().any(|u| u.uid() == user.uid())
+                {
+                    result.users.push(user);
+                }
+            }
+            Err(error) => {
+                result.warnings.push(format!("line {}: {error}", index + 1));
+            }
+        }
+    }
+
+    result
+}
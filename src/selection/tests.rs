@@ -0,0 +1,520 @@
+pub use crate::config::VisitOptions;
+pub use crate::workspace::mock::MockWorkspace;
+pub use uzers::User;
+
+pub use super::*;
+
+/// Tests for [`resolve_users`] without selection flags
+mod default_selection {
+    use super::*;
+
+    fn workspace(current_uid: uid_t) -> Result<MockWorkspace> {
+        let mut ws = MockWorkspace::new()?;
+
+        ws.add_user(0, "root", "root")?;
+        ws.add_user(1000, "alice", "home/alice")?;
+        ws.set_current_uid(current_uid);
+
+        Ok(ws)
+    }
+
+    #[test]
+    fn self_target() -> Result<()> {
+        let ws = workspace(1000)?;
+        let control = ControlManager::default();
+
+        let users = resolve_users(&ws, &[], false, false, &control)?;
+
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].uid(), 1000);
+        Ok(())
+    }
+
+    #[test]
+    fn root_refused() -> Result<()> {
+        let ws = workspace(0)?;
+        let control = ControlManager::default();
+
+        let error = resolve_users(&ws, &[], false, false, &control)
+            .expect_err("root must not be targeted implicitly");
+
+        assert!(error.to_string().contains("--all-users"));
+        Ok(())
+    }
+
+    #[test]
+    fn root_explicit() -> Result<()> {
+        let ws = workspace(0)?;
+        let control = ControlManager::default();
+
+        let users = resolve_users(
+            &ws,
+            &[UserSelector::Uid(0)],
+            false,
+            false,
+            &control,
+        )?;
+
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].uid(), 0);
+        Ok(())
+    }
+}
+
+/// Tests for [`coverage_warnings`]
+mod coverage_warnings {
+    use super::*;
+
+    /// Creates a workspace where alice is enabled, bob is disabled, and
+    /// carol is not named in control.
+    fn workspace(fallback: bool) -> Result<(MockWorkspace, ControlManager)> {
+        let mut ws = MockWorkspace::new()?;
+
+        ws.add_user(0, "root", "root")?;
+        ws.add_user(1000, "alice", "home/alice")?;
+        ws.add_user(1001, "bob", "home/bob")?;
+        ws.add_user(1002, "carol", "home/carol")?;
+
+        let control = format!(
+            "[\"*\"]\nenable = {fallback}\n\
+            [alice]\nenable = true\n\
+            [bob]\nenable = false\n"
+        );
+        let main = ws.add_file("etc/main.toml", 0, 0o600, control)?;
+        let control =
+            ControlManager::load(&ws, main, &VisitOptions::default())?;
+
+        Ok((ws, control))
+    }
+
+    fn warnings(
+        ws: &MockWorkspace,
+        control: &ControlManager,
+        name: &str,
+    ) -> Vec<String> {
+        let selectors = [UserSelector::Name(name.to_owned())];
+        let users =
+            resolve_users(ws, &selectors, false, false, control).unwrap();
+        coverage_warnings(&users, control)
+    }
+
+    #[test]
+    fn enabled() -> Result<()> {
+        let (ws, control) = workspace(false)?;
+        assert!(warnings(&ws, &control, "alice").is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn disabled() -> Result<()> {
+        let (ws, control) = workspace(true)?;
+        assert_eq!(
+            warnings(&ws, &control, "bob"),
+            ["user bob is disabled in control; its keys will not be installed"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn fallback_only() -> Result<()> {
+        let (ws, control) = workspace(false)?;
+        let disabled = warnings(&ws, &control, "carol");
+
+        assert_eq!(disabled.len(), 1);
+        assert!(disabled[0].contains("not named in control"), "{disabled:?}");
+
+        let (ws, control) = workspace(true)?;
+        assert!(warnings(&ws, &control, "carol").is_empty());
+        Ok(())
+    }
+}
+
+/// Tests for [`resolve_users`] with `all_users` set
+mod all_users {
+    use super::*;
+
+    /// Creates a workspace where alice is running and `enabled` users are
+    /// enabled.
+    fn workspace(
+        enabled: &[&str],
+    ) -> Result<(MockWorkspace, ControlManager)> {
+        let mut ws = MockWorkspace::new()?;
+
+        ws.add_user(0, "root", "root")?;
+        ws.add_user(1000, "alice", "home/alice")?;
+        ws.add_user(1001, "bob", "home/bob")?;
+        ws.set_current_uid(1000);
+
+        let control = enabled
+            .iter()
+            .map(|name| format!("[{name}]\nenable = true"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let main = ws.add_file("etc/main.toml", 0, 0o600, control)?;
+        let control =
+            ControlManager::load(&ws, main, &VisitOptions::default())?;
+
+        Ok((ws, control))
+    }
+
+    fn uids(users: &[&User]) -> Vec<uid_t> {
+        let mut result: Vec<_> = users.iter().map(|u| u.uid()).collect();
+        result.sort_unstable();
+        result
+    }
+
+    #[test]
+    fn excludes_self() -> Result<()> {
+        let (ws, control) = workspace(&["alice", "bob"])?;
+
+        let users = resolve_users(&ws, &[], true, false, &control)?;
+
+        assert_eq!(uids(&users), [1001]);
+        Ok(())
+    }
+
+    #[test]
+    fn include_self() -> Result<()> {
+        let (ws, control) = workspace(&["alice", "bob"])?;
+
+        let users = resolve_users(&ws, &[], true, true, &control)?;
+
+        assert_eq!(uids(&users), [1000, 1001]);
+        Ok(())
+    }
+
+    #[test]
+    fn every_user_includes_disabled() -> Result<()> {
+        let (ws, _) = workspace(&["bob"])?;
+
+        assert_eq!(uids(&every_user(&ws, false)), [0, 1001]);
+        assert_eq!(uids(&every_user(&ws, true)), [0, 1000, 1001]);
+        Ok(())
+    }
+
+    #[test]
+    fn explicit_self() -> Result<()> {
+        let (ws, control) = workspace(&["alice", "bob"])?;
+
+        let selectors = [UserSelector::Uid(1000)];
+        let users = resolve_users(&ws, &selectors, false, false, &control)?;
+
+        assert_eq!(uids(&users), [1000]);
+        Ok(())
+    }
+
+    #[test]
+    fn only_self_enabled() -> Result<()> {
+        let (ws, control) = workspace(&["alice"])?;
+
+        let error = resolve_users(&ws, &[], true, false, &control)
+            .expect_err("nobody but the running user is enabled");
+
+        assert!(error.to_string().contains("--include-self"));
+        Ok(())
+    }
+}
+
+/// Tests for [`UserSelector::from_str`]
+mod parse {
+    use super::*;
+
+    fn parse(s: &str) -> UserSelector {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn name() {
+        assert_eq!(parse("alice"), UserSelector::Name("alice".into()));
+        assert_eq!(parse("svc-a"), UserSelector::Name("svc-a".into()));
+        assert_eq!(parse("1000"), UserSelector::Name("1000".into()));
+    }
+
+    #[test]
+    fn uid() {
+        assert_eq!(parse("#0"), UserSelector::Uid(0));
+        assert_eq!(parse("#1000"), UserSelector::Uid(1000));
+    }
+
+    #[test]
+    fn group() {
+        assert_eq!(parse("@devs"), UserSelector::Group("devs".into()));
+    }
+
+    #[test]
+    fn range() {
+        assert_eq!(parse("1000-1999"), UserSelector::Range(1000, 1999));
+        assert_eq!(parse("5-5"), UserSelector::Range(5, 5));
+    }
+
+    #[test]
+    fn pattern() {
+        assert_eq!(parse("svc-*"), UserSelector::Pattern("svc-*".into()));
+        assert_eq!(parse("user?"), UserSelector::Pattern("user?".into()));
+    }
+
+    #[test]
+    fn round_trip() {
+        for s in ["alice", "#1000", "@devs", "1000-1999", "svc-*"] {
+            assert_eq!(parse(s).to_string(), s);
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        for s in ["", "#", "#alice", "#-1", "#99999999999", "@", "10-5"] {
+            assert!(s.parse::<UserSelector>().is_err(), "accepted {s:?}");
+        }
+    }
+}
+
+/// Tests for [`UserSelector::resolve`]
+mod resolve {
+    use super::*;
+
+    fn workspace() -> Result<MockWorkspace> {
+        let mut ws = MockWorkspace::new()?;
+
+        ws.add_user(0, "root", "root")?;
+        ws.add_user(1000, "alice", "home/alice")?;
+        ws.add_user(1001, "bob", "home/bob")?;
+        ws.add_user(2000, "charlie", "home/charlie")?;
+        ws.add_group(100, "devs", &["alice", "charlie"]);
+        ws.add_group(101, "empty", &[]);
+
+        Ok(ws)
+    }
+
+    fn uids(selector: &str, ws: &MockWorkspace) -> Result<Vec<uid_t>> {
+        let selector: UserSelector = selector.parse()?;
+        let mut result: Vec<_> =
+            selector.resolve(ws)?.iter().map(|u| u.uid()).collect();
+        result.sort_unstable();
+        Ok(result)
+    }
+
+    #[test]
+    fn uid_order() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+        for uid in [1005, 1001, 1003, 1000, 1004, 1002] {
+            ws.add_user(uid, format!("user{uid}"), format!("home/{uid}"))?;
+        }
+        ws.add_group(100, "all", &["user1003", "user1000", "user1005"]);
+
+        let range: UserSelector = "1000-1999".parse()?;
+        let resolved: Vec<_> =
+            range.resolve(&ws)?.iter().map(|u| u.uid()).collect();
+        assert_eq!(resolved, [1000, 1001, 1002, 1003, 1004, 1005]);
+
+        let group: UserSelector = "@all".parse()?;
+        let resolved: Vec<_> =
+            group.resolve(&ws)?.iter().map(|u| u.uid()).collect();
+        assert_eq!(resolved, [1000, 1003, 1005]);
+        Ok(())
+    }
+
+    #[test]
+    fn name_and_uid() -> Result<()> {
+        let ws = workspace()?;
+
+        assert_eq!(uids("bob", &ws)?, [1001]);
+        assert_eq!(uids("#1001", &ws)?, [1001]);
+        assert!(uids("mallory", &ws).is_err());
+        assert!(uids("#1234", &ws).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn group() -> Result<()> {
+        let ws = workspace()?;
+
+        assert_eq!(uids("@devs", &ws)?, [1000, 2000]);
+        assert!(uids("@empty", &ws).is_err());
+        assert!(uids("@nobody", &ws).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn primary_group() -> Result<()> {
+        let mut ws = workspace()?;
+        ws.set_primary_group(1001, 100);
+        ws.set_primary_group(2000, 100);
+
+        // Listed and primary members alike, each only once
+        assert_eq!(uids("@devs", &ws)?, [1000, 1001, 2000]);
+
+        ws.set_primary_group(1000, 101);
+        assert_eq!(uids("@empty", &ws)?, [1000]);
+        Ok(())
+    }
+
+    #[test]
+    fn range() -> Result<()> {
+        let ws = workspace()?;
+
+        assert_eq!(uids("1000-1999", &ws)?, [1000, 1001]);
+        assert_eq!(uids("0-0", &ws)?, [0]);
+        assert!(uids("3000-4000", &ws).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn huge_range() -> Result<()> {
+        let ws = workspace()?;
+        let start = std::time::Instant::now();
+
+        assert_eq!(uids(&format!("1001-{}", uid_t::MAX), &ws)?, [1001, 2000]);
+        assert_eq!(uids(&format!("0-{}", uid_t::MAX), &ws)?.len(), 4);
+
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+        Ok(())
+    }
+
+    #[test]
+    fn too_many_selections() -> Result<()> {
+        let ws = workspace()?;
+        let control = ControlManager::default();
+
+        let selectors = [UserSelector::Uid(1000), UserSelector::Uid(1001)];
+        assert!(
+            resolve_users(&ws, &selectors, false, false, &control).is_err()
+        );
+
+        let selectors = [UserSelector::Uid(1000)];
+        assert!(
+            resolve_users(&ws, &selectors, true, false, &control).is_err()
+        );
+        Ok(())
+    }
+}
+
+/// Tests for [`users_from_list`]
+mod list {
+    use super::*;
+
+    fn workspace() -> Result<(MockWorkspace, ControlManager)> {
+        let mut ws = MockWorkspace::new()?;
+
+        ws.add_user(0, "root", "root")?;
+        ws.add_user(1000, "alice", "home/alice")?;
+        ws.add_user(1001, "bob", "home/bob")?;
+        ws.add_user(1002, "charlie", "home/charlie")?;
+        ws.add_user(1003, "dan", "home/dan")?;
+
+        #[rustfmt::skip]
+        let main = ws.add_file("etc/main.toml", 0, 0o600, r#"
+            ["*"]
+            enable = true
+
+            [root]
+            enable = false
+
+            [dan]
+            enable = false
+        "#)?;
+        let control =
+            ControlManager::load(&ws, main, &VisitOptions::default())?;
+
+        Ok((ws, control))
+    }
+
+    #[test]
+    fn names_and_uids() -> Result<()> {
+        let (ws, control) = workspace()?;
+
+        let list = users_from_list(
+            &ws,
+            "alice\n  1001\n\nmallory\n1002\n9999\nalice\n0\ndan\n",
+            &control,
+        );
+
+        let uids: Vec<_> = list.users.iter().map(|u| u.uid()).collect();
+        assert_eq!(uids, [1000, 1001, 1002]);
+        assert_eq!(list.warnings.len(), 2);
+        assert!(list.warnings[0].starts_with("line 4:"));
+        assert!(list.warnings[0].contains("mallory"));
+        assert!(list.warnings[1].starts_with("line 6:"));
+        assert!(list.warnings[1].contains("9999"));
+        Ok(())
+    }
+
+    #[test]
+    fn empty() -> Result<()> {
+        let (ws, control) = workspace()?;
+
+        let list = users_from_list(&ws, "\n \n", &control);
+
+        assert!(list.users.is_empty());
+        assert!(list.warnings.is_empty());
+        Ok(())
+    }
+}
+
+/// Tests for [`resolve_users`] with both `--user` and `--uid`
+mod cross_check {
+    use super::*;
+
+    fn workspace() -> Result<MockWorkspace> {
+        let mut ws = MockWorkspace::new()?;
+
+        ws.add_user(0, "root", "root")?;
+        ws.add_user(1000, "alice", "home/alice")?;
+        ws.add_user(1001, "bob", "home/bob")?;
+        ws.add_group(100, "devs", &["alice"]);
+
+        Ok(ws)
+    }
+
+    fn resolve(ws: &MockWorkspace, user: &str, uid: uid_t) -> Result<uid_t> {
+        let selectors = [user.parse()?, UserSelector::Uid(uid)];
+        let control = ControlManager::default();
+        let users = resolve_users(ws, &selectors, false, false, &control)?;
+        assert_eq!(users.len(), 1);
+        Ok(users[0].uid())
+    }
+
+    #[test]
+    fn matching() -> Result<()> {
+        let ws = workspace()?;
+        assert_eq!(resolve(&ws, "alice", 1000)?, 1000);
+        assert_eq!(resolve(&ws, "bob", 1001)?, 1001);
+        assert_eq!(resolve(&ws, "#1001", 1001)?, 1001);
+        Ok(())
+    }
+
+    #[test]
+    fn mismatching() -> Result<()> {
+        let ws = workspace()?;
+        let error = resolve(&ws, "alice", 1001).unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "--user alice is UID 1000, but --uid 1001 was given"
+        );
+        assert!(resolve(&ws, "#1000", 1001).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_user() -> Result<()> {
+        let ws = workspace()?;
+        assert!(resolve(&ws, "mallory", 1000).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn not_a_single_user() -> Result<()> {
+        let ws = workspace()?;
+        let control = ControlManager::default();
+
+        let selectors = ["@devs".parse()?, UserSelector::Uid(1000)];
+        assert!(
+            resolve_users(&ws, &selectors, false, false, &control).is_err()
+        );
+
+        let selectors = ["alice".parse()?, UserSelector::Uid(1000)];
+        assert!(
+            resolve_users(&ws, &selectors, true, false, &control).is_err()
+        );
+        Ok(())
+    }
+}
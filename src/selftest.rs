@@ -0,0 +1,112 @@
+//! Checks of whether narrowssh is able to work on this host.
+
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::config::{ControlManager, VisitOptions};
+use crate::workspace::Workspace;
+
+#[cfg(test)]
+mod tests;
+
+/// Outcome of a single check run by [`selftest`].
+#[derive(Debug)]
+pub struct Check {
+    /// Short description of what was checked.
+    pub name: &'static str,
+
+    /// Success, or the reason of failure.
+    pub result: Result<()>,
+}
+
+impl Check {
+    /// Returns whether the check passed.
+    #[must_use]
+    pub fn passed(&self) -> bool {
+        self.result.is_ok()
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.result {
+            Ok(()) => write!(f, "PASS {}", self.name),
+            Err(error) => write!(f, "FAIL {}: {error:#}", self.name),
+        }
+    }
+}
+
+/// Runs all checks and returns their outcomes in order.
+///
+/// The checks are:
+///   - `control_file` can be loaded,
+///   - system users can be enumerated,
+///   - the running user can be found, and
+///   - a file can be written to, read back from and removed in `scratch_dir`.
+///
+/// Nothing is modified except for the temporary file, which is always
+/// removed.
+pub fn selftest<W: Workspace>(
+    ws: &W,
+    control_file: &Path,
+    scratch_dir: &Path,
+) -> Vec<Check> {
+    vec![
+        Check {
+            name: "read control files",
+            result: ControlManager::load(
+                ws,
+                control_file,
+                &VisitOptions::default(),
+            )
+            .map(|_| ()),
+        },
+        Check {
+            name: "enumerate users",
+            result: match ws.users().all_users().count() {
+                0 => Err(anyhow!("no users found")),
+                _ => Ok(()),
+            },
+        },
+        Check {
+            name: "resolve running user",
+            result: {
+                let uid = ws.users().current_uid();
+                ws.users()
+                    .user_by_uid(uid)
+                    .map(|_| ())
+                    .ok_or_else(|| anyhow!("UID {uid} is not a known user"))
+            },
+        },
+        Check {
+            name: "write a temporary file",
+            result: write_scratch(ws, scratch_dir),
+        },
+    ]
+}
+
+/// Writes, reads back and removes a file in `dir`.
+fn write_scratch<W: Workspace>(ws: &W, dir: &Path) -> Result<()> {
+    const CONTENT: &[u8] = b"narrowssh selftest\n";
+
+    let (mut file, path) = ws.create_temp_in(dir, 0o600)?;
+
+    let result = || -> Result<()> {
+        file.write_all(CONTENT)?;
+        file.sync_all()?;
+
+        if std::fs::read(&path)? != CONTENT {
+            bail!("{} was not written correctly", path.display());
+        }
+        Ok(())
+    }();
+
+    drop(file);
+    let removed = std::fs::remove_file(&path);
+
+    result?;
+    removed.map_err(|e| anyhow!("could not remove {}: {e}", path.display()))
+}
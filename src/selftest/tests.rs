@@ -0,0 +1,96 @@
+#![allow(clippy::needless_raw_string_hashes)]
+
+pub use std::path::PathBuf;
+
+pub use crate::workspace::mock::MockWorkspace;
+
+pub use super::*;
+
+/// Creates a workspace where every check passes.
+///
+/// Returns the workspace, the control file and the scratch directory.
+fn healthy() -> Result<(MockWorkspace, PathBuf, PathBuf)> {
+    let mut ws = MockWorkspace::new()?;
+
+    ws.add_user(0, "root", "root")?;
+    ws.add_user(1000, "alice", "home/alice")?;
+    ws.set_current_uid(0);
+
+    #[rustfmt::skip]
+    let control = ws.add_file("etc/narrowssh/control.toml", 0, 0o600, r#"
+        [alice]
+        enable = true
+    "#)?;
+    let scratch = ws.path("etc/narrowssh");
+
+    Ok((ws, control, scratch))
+}
+
+/// Returns the names of failed checks.
+fn failures(checks: &[Check]) -> Vec<&'static str> {
+    checks
+        .iter()
+        .filter(|c| !c.passed())
+        .map(|c| c.name)
+        .collect()
+}
+
+#[test]
+fn healthy_setup() -> Result<()> {
+    let (ws, control, scratch) = healthy()?;
+
+    let checks = selftest(&ws, &control, &scratch);
+
+    assert_eq!(checks.len(), 4);
+    assert!(failures(&checks).is_empty(), "{checks:?}");
+    assert!(checks[0].to_string().starts_with("PASS "));
+
+    // Only the control file remains
+    assert_eq!(std::fs::read_dir(&scratch)?.count(), 1);
+    Ok(())
+}
+
+#[test]
+fn missing_control_file() -> Result<()> {
+    let (ws, _, scratch) = healthy()?;
+
+    let checks = selftest(&ws, &ws.path("etc/nope.toml"), &scratch);
+
+    assert_eq!(failures(&checks), ["read control files"]);
+    assert!(checks[0].to_string().starts_with("FAIL "));
+    Ok(())
+}
+
+#[test]
+fn broken_setup() -> Result<()> {
+    let (mut ws, _, _) = healthy()?;
+    ws.set_current_uid(1234);
+
+    #[rustfmt::skip]
+    let control = ws.add_file("etc/broken.toml", 0, 0o644, r#"
+        [alice]
+        enable = true
+    "#)?;
+
+    let checks = selftest(&ws, &control, &ws.path("does/not/exist"));
+
+    assert_eq!(
+        failures(&checks),
+        [
+            "read control files",
+            "resolve running user",
+            "write a temporary file"
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn no_users() -> Result<()> {
+    let ws = MockWorkspace::new()?;
+
+    let checks = selftest(&ws, &ws.path("control.toml"), &ws.path(""));
+
+    assert!(failures(&checks).contains(&"enumerate users"));
+    Ok(())
+}
@@ -0,0 +1,109 @@
+//! Verification of detached signatures of control files.
+//!
+//! Signatures are made and checked with `ssh-keygen(1)`, so operators sign
+//! control files with the SSH keys they already have:
+//!
+//! ```text
+//! ssh-keygen -Y sign -f ~/.ssh/id_ed25519 -n narrowssh control.toml
+//! ```
+//!
+//! This writes `control.toml.sig`. The public keys allowed to sign control
+//! files are listed in an allowed signers file, see `ssh-keygen(1)`, under
+//! the principal [`PRINCIPAL`]:
+//!
+//! ```text
+//! narrowssh ssh-ed25519 AAAA...
+//! ```
+
+use std::ffi::OsString;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context, Result};
+
+#[cfg(test)]
+mod tests;
+
+/// Path to `ssh-keygen(1)`, which verifies signatures.
+pub const SSH_KEYGEN: &str = "/usr/bin/ssh-keygen";
+
+/// Namespace that signatures of control files are made in.
+///
+/// Namespaces keep signatures made for other purposes, such as Git commits,
+/// from being accepted as signatures of control files.
+pub const NAMESPACE: &str = "narrowssh";
+
+/// Principal that allowed signers of control files are listed under.
+pub const PRINCIPAL: &str = "narrowssh";
+
+/// Returns the path of the detached signature of `file`, i.e. `{file}.sig`.
+#[must_use]
+pub fn signature_path(file: &Path) -> PathBuf {
+    let mut path = OsString::from(file.as_os_str());
+    path.push(".sig");
+    PathBuf::from(path)
+}
+
+/// Checks that `content` of `file` is signed by a key in `signers`.
+///
+/// The signature is read from [`signature_path`] of `file`. `signers` is an
+/// allowed signers file of `ssh-keygen(1)`; only keys listed for
+/// [`PRINCIPAL`] and the [`NAMESPACE`] are accepted. `content` is passed in
+/// rather than read again, so that the verified content is the one used.
+///
+/// # Errors
+/// The function will fail if the signature is missing, if `ssh-keygen`
+/// could not be run, or if it rejected the signature, in which case its
+/// output is included.
+pub fn verify_signature(
+    file: &Path,
+    content: &[u8],
+    signers: &Path,
+) -> Result<()> {
+    let signature = signature_path(file);
+    match std::fs::metadata(&signature) {
+        Ok(_) => {}
+        Err(error) if error.kind() == ErrorKind::NotFound => bail!(
+            "signature {} is missing [signatures are required]",
+            signature.display()
+        ),
+        Err(error) => {
+            return Err(error).with_context(|| {
+                format!("inspecting {}", signature.display())
+            })
+        }
+    }
+
+    let mut child = Command::new(SSH_KEYGEN)
+        .args(["-Y", "verify", "-n", NAMESPACE, "-I", PRINCIPAL, "-f"])
+        .arg(signers)
+        .arg("-s")
+        .arg(&signature)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("running {SSH_KEYGEN}"))?;
+
+    // ssh-keygen may exit early without reading all of its input, so a
+    // failure to write is left for the exit status to explain
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = stdin.write_all(content);
+    }
+    let output = child
+        .wait_with_output()
+        .with_context(|| format!("running {SSH_KEYGEN}"))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!(
+            "signature {} was rejected ({}): {}",
+            signature.display(),
+            output.status,
+            stderr.trim()
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,153 @@
+pub use crate::config::{ControlManager, VisitOptions};
+pub use crate::workspace::mock::MockWorkspace;
+
+pub use super::*;
+
+/// Runs `ssh-keygen` with `args`, failing if it does not succeed.
+fn ssh_keygen(args: &[&str]) -> Result<()> {
+    let output = Command::new(SSH_KEYGEN).args(args).output()?;
+    if !output.status.success() {
+        bail!(
+            "ssh-keygen failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(())
+}
+
+/// Creates a key pair at `{name}` and `{name}.pub`, and an allowed signers
+/// file listing the public key at `{name}.signers`.
+///
+/// Returns the paths of the private key and of the allowed signers file.
+fn signer(ws: &mut MockWorkspace, name: &str) -> Result<(PathBuf, PathBuf)> {
+    let key = ws.path(name);
+    ssh_keygen(&["-q", "-t", "ed25519", "-N", "", "-f", path_str(&key)])?;
+
+    let public = std::fs::read_to_string(ws.path(format!("{name}.pub")))?;
+    let signers = ws.add_file(
+        format!("{name}.signers"),
+        0,
+        0o644,
+        format!("{PRINCIPAL} {public}"),
+    )?;
+    Ok((key, signers))
+}
+
+/// Signs `file` with `key` in [`NAMESPACE`], writing `{file}.sig`.
+fn sign(key: &Path, file: &Path) -> Result<()> {
+    ssh_keygen(&[
+        "-q",
+        "-Y",
+        "sign",
+        "-n",
+        NAMESPACE,
+        "-f",
+        path_str(key),
+        path_str(file),
+    ])
+}
+
+/// Returns `path` as a command-line argument.
+fn path_str(path: &Path) -> &str {
+    path.to_str().expect("test paths are UTF-8")
+}
+
+/// Loads control from `main` requiring signatures by `signers`.
+fn load(
+    ws: &MockWorkspace,
+    main: &Path,
+    signers: &Path,
+) -> Result<ControlManager> {
+    let options = VisitOptions {
+        signers: Some(signers.to_path_buf()),
+        ..VisitOptions::default()
+    };
+    ControlManager::load(ws, main, &options)
+}
+
+/// Tests for [`signature_path`]
+mod signature_path {
+    use super::*;
+
+    #[test]
+    fn appends_extension() {
+        assert_eq!(
+            signature_path(Path::new("/etc/narrowssh/control.toml")),
+            Path::new("/etc/narrowssh/control.toml.sig")
+        );
+    }
+}
+
+/// Tests for [`verify_signature`]
+mod verify_signature {
+    use super::*;
+
+    #[test]
+    fn valid() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+        ws.add_user(1000, "alice", "home/alice")?;
+        let (key, signers) = signer(&mut ws, "operator")?;
+        let main =
+            ws.add_file("control.toml", 0, 0o600, "[alice]\nenable = true")?;
+        sign(&key, &main)?;
+
+        let control = load(&ws, &main, &signers)?;
+        assert!(control.get_user_control(1000).enable);
+        Ok(())
+    }
+
+    #[test]
+    fn missing() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+        let (_, signers) = signer(&mut ws, "operator")?;
+        let main = ws.add_file("control.toml", 0, 0o600, "")?;
+
+        let error = format!("{:#}", load(&ws, &main, &signers).unwrap_err());
+        assert!(error.contains("control.toml.sig is missing"), "{error}");
+
+        // Signatures are only required when asked for
+        ControlManager::load(&ws, &main, &VisitOptions::default())?;
+        Ok(())
+    }
+
+    #[test]
+    fn tampered() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+        let (key, signers) = signer(&mut ws, "operator")?;
+        let main =
+            ws.add_file("control.toml", 0, 0o600, "[alice]\nenable = false")?;
+        sign(&key, &main)?;
+        std::fs::write(&main, "[alice]\nenable = true")?;
+
+        let error = format!("{:#}", load(&ws, &main, &signers).unwrap_err());
+        assert!(error.contains("was rejected"), "{error}");
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_signer() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+        let (_, signers) = signer(&mut ws, "operator")?;
+        let (intruder, _) = signer(&mut ws, "intruder")?;
+        let main = ws.add_file("control.toml", 0, 0o600, "")?;
+        sign(&intruder, &main)?;
+
+        let error = format!("{:#}", load(&ws, &main, &signers).unwrap_err());
+        assert!(error.contains("was rejected"), "{error}");
+        Ok(())
+    }
+
+    #[test]
+    fn extensions() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+        let (key, signers) = signer(&mut ws, "operator")?;
+        let main = ws.add_file("control.toml", 0, 0o600, "")?;
+        ws.add_dir("control.toml.d", 0, 0o700)?;
+        ws.add_file("control.toml.d/extra.toml", 0, 0o600, "")?;
+        sign(&key, &main)?;
+
+        let error = format!("{:#}", load(&ws, &main, &signers).unwrap_err());
+        assert!(error.contains("extra.toml.sig is missing"), "{error}");
+        Ok(())
+    }
+}
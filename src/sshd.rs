@@ -0,0 +1,112 @@
+//! Validation of the `sshd(8)` configuration after changes.
+//!
+//! `authorized_keys` files are read by sshd on every connection, so changes
+//! made by narrowssh never require a reload. Administrators that point
+//! `AuthorizedKeysFile` at a central location may still want to confirm that
+//! the configuration of sshd is sound, which [`Control::validate_sshd`]
+//! enables.
+
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+
+#[cfg(doc)]
+use crate::config::Control;
+use crate::config::ControlManager;
+use crate::refresh::{ApplyReport, Outcome};
+use crate::workspace::Workspace;
+
+#[cfg(test)]
+mod tests;
+
+/// Path to `sshd(8)` used when [`Control::sshd_path`] is unset.
+pub const DEFAULT_SSHD: &str = "/usr/sbin/sshd";
+
+/// Returns the `sshd(8)` binaries to validate after `report` was applied.
+///
+/// Only users whose `authorized_keys` file was updated or removed and who
+/// have [`Control::validate_sshd`] set are considered. Every binary is
+/// listed once, in the order the users appear in `report`.
+#[must_use]
+pub fn sshd_to_validate(
+    report: &ApplyReport,
+    control: &ControlManager,
+) -> Vec<PathBuf> {
+    let mut result = Vec::new();
+
+    for (user, outcome) in &report.outcomes {
+        if let Outcome::Disabled | Outcome::Unchanged(_) = outcome {
+            continue;
+        }
+
+        let user_control = control.get_user_control(user.uid());
+        if !user_control.validate_sshd {
+            continue;
+        }
+
+        let sshd = PathBuf::from(
+            user_control.sshd_path.as_deref().unwrap_or(DEFAULT_SSHD),
+        );
+        if !result.contains(&sshd) {
+            result.push(sshd);
+        }
+    }
+
+    result
+}
+
+/// Runs `sshd -t` to test the configuration of sshd.
+///
+/// `sshd` must be an absolute path to a file owned by root and not writable
+/// by group or others, since it is executed with the privileges of
+/// narrowssh.
+///
+/// # Errors
+/// The function will fail in these cases:
+///   - `sshd` is not a root-owned, absolute path,
+///   - `sshd` could not be run, or
+///   - `sshd -t` reported a problem, in which case its output is included.
+pub fn validate_sshd<W: Workspace>(ws: &W, sshd: &Path) -> Result<()> {
+    if !sshd.is_absolute() {
+        bail!("sshd path {} must be absolute", sshd.display());
+    }
+
+    let metadata = std::fs::metadata(sshd)
+        .with_context(|| format!("inspecting {}", sshd.display()))?;
+    let owner = ws
+        .get_mock_owner_uid(sshd)
+        .unwrap_or_else(|| metadata.uid());
+    if owner != 0 {
+        bail!(
+            "{} must be owned by root, not UID {owner} \
+            [security; refusing to run it]",
+            sshd.display()
+        );
+    }
+    if metadata.permissions().mode() & 0o022 != 0 {
+        bail!(
+            "{} must not be writable by group or others \
+            [security; refusing to run it]",
+            sshd.display()
+        );
+    }
+
+    let output = Command::new(sshd)
+        .arg("-t")
+        .output()
+        .with_context(|| format!("running {} -t", sshd.display()))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!(
+            "{} -t failed ({}): {}",
+            sshd.display(),
+            output.status,
+            stderr.trim()
+        );
+    }
+
+    Ok(())
+}
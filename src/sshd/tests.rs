@@ -0,0 +1,123 @@
+pub use std::path::PathBuf;
+
+pub use crate::config::VisitOptions;
+pub use crate::workspace::mock::MockWorkspace;
+pub use crate::workspace::Workspace;
+
+pub use super::*;
+
+/// Adds an executable stub of sshd that exits with `status` and prints
+/// `message` to stderr.
+fn stub(
+    ws: &mut MockWorkspace,
+    owner: u32,
+    mode: u32,
+    status: i32,
+    message: &str,
+) -> Result<PathBuf> {
+    ws.add_file(
+        "usr/sbin/sshd",
+        owner,
+        mode,
+        format!("#!/bin/sh\n[ \"$1\" = -t ] || exit 2\necho '{message}' >&2\nexit {status}\n"),
+    )
+}
+
+/// Tests for [`validate_sshd`]
+mod validate_sshd {
+    use super::*;
+
+    #[test]
+    fn passes() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+        let sshd = stub(&mut ws, 0, 0o755, 0, "")?;
+
+        validate_sshd(&ws, &sshd)
+    }
+
+    #[test]
+    fn fails() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+        let sshd = stub(&mut ws, 0, 0o755, 255, "line 3: Bad option")?;
+
+        let error = validate_sshd(&ws, &sshd).unwrap_err().to_string();
+        assert!(error.contains("-t failed"), "{error}");
+        assert!(error.contains("line 3: Bad option"), "{error}");
+        Ok(())
+    }
+
+    #[test]
+    fn not_root_owned() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+        let sshd = stub(&mut ws, 1000, 0o755, 0, "")?;
+
+        let error = validate_sshd(&ws, &sshd).unwrap_err().to_string();
+        assert!(error.contains("must be owned by root"), "{error}");
+        Ok(())
+    }
+
+    #[test]
+    fn writable() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+        let sshd = stub(&mut ws, 0, 0o775, 0, "")?;
+
+        let error = validate_sshd(&ws, &sshd).unwrap_err().to_string();
+        assert!(error.contains("must not be writable"), "{error}");
+        Ok(())
+    }
+
+    #[test]
+    fn relative() -> Result<()> {
+        let ws = MockWorkspace::new()?;
+        assert!(validate_sshd(&ws, Path::new("sshd")).is_err());
+        Ok(())
+    }
+}
+
+/// Tests for [`sshd_to_validate`]
+mod sshd_to_validate {
+    use super::*;
+
+    #[test]
+    fn changed_users_only() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+        for (uid, name) in (1000..).zip(&["alice", "bob", "carol", "dave"]) {
+            ws.add_user(uid, name, format!("home/{name}"))?;
+        }
+
+        #[rustfmt::skip]
+        let main = ws.add_file("etc/main.toml", 0, 0o600, r#"
+            ["*"]
+            validate_sshd = true
+
+            [bob]
+            sshd_path = "/opt/sshd"
+
+            [carol]
+            validate_sshd = false
+        "#)?;
+        let control =
+            ControlManager::load(&ws, main, &VisitOptions::default())?;
+
+        let users: Vec<_> = (1000..1004)
+            .map(|uid| ws.users().user_by_uid(uid).unwrap())
+            .collect();
+        let path = PathBuf::from("/keys");
+        let report = ApplyReport {
+            outcomes: vec![
+                (users[0], Outcome::Updated(path.clone())),
+                (users[1], Outcome::Removed(path.clone())),
+                (users[2], Outcome::Updated(path.clone())),
+                (users[3], Outcome::Unchanged(path)),
+            ],
+            warnings: Vec::new(),
+            skipped: Vec::new(),
+        };
+
+        assert_eq!(
+            sshd_to_validate(&report, &control),
+            vec![PathBuf::from(DEFAULT_SSHD), PathBuf::from("/opt/sshd")]
+        );
+        Ok(())
+    }
+}
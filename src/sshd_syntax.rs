@@ -0,0 +1,219 @@
+//! Independent check of key lines against the `authorized_keys` rules of
+//! `sshd(8)`.
+//!
+//! sshd silently ignores lines it cannot parse, so a rendering bug in
+//! narrowssh would lock users out without any error. The rules here follow
+//! sshd rather than [`KeyLine`][crate::authorized_keys::KeyLine], so that the
+//! two parsers cross-check each other: options must be known to sshd, values
+//! must be double-quoted with only `\"` escaped, and the key blob must be
+//! base64 of a key of the stated type.
+
+use anyhow::{anyhow, bail, Result};
+
+#[cfg(test)]
+mod tests;
+
+/// Options that sshd accepts without a value.
+const FLAG_OPTIONS: &[&str] = &[
+    "agent-forwarding",
+    "cert-authority",
+    "no-agent-forwarding",
+    "no-port-forwarding",
+    "no-pty",
+    "no-touch-required",
+    "no-user-rc",
+    "no-x11-forwarding",
+    "port-forwarding",
+    "pty",
+    "restrict",
+    "touch-required",
+    "user-rc",
+    "verify-required",
+    "x11-forwarding",
+];
+
+/// Options that sshd requires a quoted value for.
+const VALUE_OPTIONS: &[&str] = &[
+    "command",
+    "environment",
+    "expiry-time",
+    "from",
+    "permitlisten",
+    "permitopen",
+    "principals",
+    "tunnel",
+];
+
+/// Returns a warning for every line of `block` that sshd would reject.
+///
+/// `block` is the text of a managed block including markers. Blank lines
+/// and comments are skipped, as sshd does. Lines are numbered from 1 at the
+/// opening marker.
+#[must_use]
+pub fn check_block(block: &str) -> Vec<String> {
+    let mut warnings = Vec::new();
+
+    for (index, line) in block.lines().enumerate() {
+        let trimmed = line.trim_start_matches(is_blank);
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        if let Err(error) = check_line(trimmed) {
+            warnings.push(format!(
+                "line {} of the managed block would be ignored by sshd: \
+                {error}",
+                index + 1
+            ));
+        }
+    }
+
+    warnings
+}
+
+/// Checks that sshd accepts `line` as a key line of `authorized_keys`.
+///
+/// Like sshd, the line is first read as a bare key and only then as options
+/// followed by a key.
+///
+/// # Errors
+/// The function will fail if sshd would ignore the line, describing why.
+pub fn check_line(line: &str) -> Result<()> {
+    if line.starts_with('@') {
+        bail!("markers such as {:?} are not understood", first_token(line));
+    }
+    if check_key(line).is_ok() {
+        return Ok(());
+    }
+
+    let rest = skip_options(line)?;
+    check_key(rest.trim_start_matches(is_blank))
+}
+
+/// Returns whether `c` separates fields of a key line.
+fn is_blank(c: char) -> bool {
+    c == ' ' || c == '\t'
+}
+
+/// Returns the leading part of `text` up to the first blank.
+fn first_token(text: &str) -> &str {
+    text.split(is_blank).next().unwrap_or_default()
+}
+
+/// Checks that `text` starts with a key type and a matching key blob.
+fn check_key(text: &str) -> Result<()> {
+    let mut fields = text.split(is_blank).filter(|f| !f.is_empty());
+    let (key_type, blob) = match (fields.next(), fields.next()) {
+        (Some(key_type), Some(blob)) => (key_type, blob),
+        _ => bail!("key type or key is missing"),
+    };
+
+    let blob = match decode_base64(blob) {
+        Some(blob) => blob,
+        None => bail!("key of type {key_type:?} is not valid base64"),
+    };
+    if blob.len() < 4 {
+        bail!("key of type {key_type:?} is truncated");
+    }
+    let length =
+        u32::from_be_bytes([blob[0], blob[1], blob[2], blob[3]]) as usize;
+    match blob.get(4..4 + length) {
+        Some(embedded) if embedded == key_type.as_bytes() => Ok(()),
+        Some(embedded) => bail!(
+            "key is labeled {key_type:?} but holds a key of type {:?}",
+            String::from_utf8_lossy(embedded)
+        ),
+        None => bail!("key of type {key_type:?} is truncated"),
+    }
+}
+
+/// Skips the options at the start of `line` and returns the rest.
+fn skip_options(line: &str) -> Result<&str> {
+    let mut rest = line;
+    let mut command_seen = false;
+
+    loop {
+        let end = rest
+            .find(|c: char| c == '=' || c == ',' || is_blank(c))
+            .unwrap_or(rest.len());
+        let name = rest[..end].to_ascii_lowercase();
+        rest = &rest[end..];
+
+        if VALUE_OPTIONS.contains(&name.as_str()) {
+            if !rest.starts_with("=\"") {
+                bail!("option {name:?} needs a double-quoted value");
+            }
+            rest = skip_quoted(&rest[1..]).ok_or_else(|| {
+                anyhow!("value of option {name:?} lacks a closing quote")
+            })?;
+            if name == "command" {
+                if command_seen {
+                    bail!("option \"command\" is given more than once");
+                }
+                command_seen = true;
+            }
+        } else if FLAG_OPTIONS.contains(&name.as_str()) {
+            if rest.starts_with('=') {
+                bail!("option {name:?} does not take a value");
+            }
+        } else if name.is_empty() {
+            bail!("key type or key is missing");
+        } else {
+            bail!("unknown option {name:?}");
+        }
+
+        match rest.chars().next() {
+            Some(',') => rest = &rest[1..],
+            Some(c) if is_blank(c) => return Ok(rest),
+            None => bail!("key type or key is missing"),
+            Some(c) => bail!("unexpected {c:?} after option {name:?}"),
+        }
+    }
+}
+
+/// Skips the double-quoted string at the start of `text` and returns the
+/// rest, or `None` if the string is not terminated.
+///
+/// Like sshd, only `\"` is an escape; other backslashes stand for
+/// themselves.
+fn skip_quoted(text: &str) -> Option<&str> {
+    let bytes = text.as_bytes();
+    let mut index = 1;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'\\' if bytes.get(index + 1) == Some(&b'"') => index += 2,
+            b'"' => return Some(&text[index + 1..]),
+            _ => index += 1,
+        }
+    }
+    None
+}
+
+/// Decodes standard base64 with optional padding.
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    fn value(c: u8) -> Option<u32> {
+        Some(u32::from(match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        }))
+    }
+
+    let text = text.trim_end_matches('=').as_bytes();
+    let mut result = Vec::with_capacity(text.len() * 3 / 4);
+    let (mut buffer, mut bits) = (0_u32, 0);
+
+    for &c in text {
+        buffer = (buffer << 6) | value(c)?;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            result.push(u8::try_from(buffer >> bits).ok()?);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+
+    Some(result)
+}
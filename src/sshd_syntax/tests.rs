@@ -0,0 +1,127 @@
+pub use crate::authorized_keys::render_managed_block;
+pub use crate::config::Control;
+
+pub use super::*;
+
+const BLOB: &str =
+    "AAAAC3NzaC1lZDI1NTE5AAAAIGd1bW15a2V5Zm9ydGVzdGluZ3B1cnBvc2Vz";
+
+/// Returns the reason `line` is rejected.
+fn rejected(line: &str) -> String {
+    check_line(line).unwrap_err().to_string()
+}
+
+/// Tests for [`check_line`]
+mod check_line {
+    use super::*;
+
+    #[test]
+    fn bare_key() -> Result<()> {
+        check_line(&format!("ssh-ed25519 {BLOB}"))?;
+        check_line(&format!("ssh-ed25519 {BLOB} alice@example.com"))
+    }
+
+    #[test]
+    fn options() -> Result<()> {
+        check_line(&format!(
+            "restrict,command=\"echo \\\"hi\\\" \\\\n\",pty ssh-ed25519 {BLOB}"
+        ))?;
+        check_line(&format!("Restrict,No-PTY\tssh-ed25519 {BLOB} c"))
+    }
+
+    #[test]
+    fn malformed_option() {
+        let error = rejected(&format!("command=\"uptime ssh-ed25519 {BLOB}"));
+        assert!(error.contains("closing quote"), "{error}");
+
+        // A backslash before the closing quote escapes it
+        let error = rejected(&format!("command=\"a\\\" ssh-ed25519 {BLOB}"));
+        assert!(error.contains("closing quote"), "{error}");
+
+        let error = rejected(&format!("command=uptime ssh-ed25519 {BLOB}"));
+        assert!(error.contains("double-quoted"), "{error}");
+
+        let error = rejected(&format!("pty=\"yes\" ssh-ed25519 {BLOB}"));
+        assert!(error.contains("does not take a value"), "{error}");
+
+        let error = rejected(&format!("restrict;pty ssh-ed25519 {BLOB}"));
+        assert!(error.contains("unknown option"), "{error}");
+    }
+
+    #[test]
+    fn unknown_option() {
+        let error = rejected(&format!("no-such-thing ssh-ed25519 {BLOB}"));
+        assert!(error.contains("\"no-such-thing\""), "{error}");
+    }
+
+    #[test]
+    fn repeated_command() {
+        let error = rejected(&format!(
+            "command=\"a\",command=\"b\" ssh-ed25519 {BLOB}"
+        ));
+        assert!(error.contains("more than once"), "{error}");
+    }
+
+    #[test]
+    fn mismatched_key() {
+        let error = rejected(&format!("restrict ssh-rsa {BLOB}"));
+        assert!(error.contains("\"ssh-ed25519\""), "{error}");
+
+        // Without options, the key type is taken for an option
+        let error = rejected(&format!("ssh-rsa {BLOB}"));
+        assert!(error.contains("unknown option \"ssh-rsa\""), "{error}");
+
+        let error = rejected("restrict ssh-ed25519 AAAA");
+        assert!(error.contains("truncated"), "{error}");
+
+        let error = rejected("restrict ssh-ed25519 not*base64");
+        assert!(error.contains("base64"), "{error}");
+
+        let error = rejected("restrict");
+        assert!(error.contains("missing"), "{error}");
+    }
+
+    #[test]
+    fn marker() {
+        let error = rejected(&format!("@revoked ssh-ed25519 {BLOB}"));
+        assert!(error.contains("\"@revoked\""), "{error}");
+    }
+}
+
+/// Tests for [`check_block`]
+mod check_block {
+    use super::*;
+
+    #[test]
+    fn rendered() -> Result<()> {
+        let control = Control {
+            enable: true,
+            commands: vec![
+                String::from("echo \"quoted\" 'single'"),
+                String::from("trailing\\"),
+            ],
+            pty: true,
+            ..Control::default()
+        };
+        let keys = [
+            format!("ssh-ed25519 {BLOB} plain"),
+            format!("from=\"10.0.0.1\",no-pty ssh-ed25519 {BLOB}0 from"),
+        ];
+
+        let block = render_managed_block(&keys, &control)?;
+        assert_eq!(check_block(&block.text), Vec::<String>::new());
+        Ok(())
+    }
+
+    #[test]
+    fn numbers_lines() {
+        let block = format!(
+            "# BEGIN\n\nssh-ed25519 {BLOB}\nbogus ssh-ed25519 {BLOB}\n# END\n"
+        );
+
+        let warnings = check_block(&block);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("line 4 "), "{warnings:?}");
+        assert!(warnings[0].contains("\"bogus\""), "{warnings:?}");
+    }
+}
@@ -0,0 +1,327 @@
+//! Machine-readable status of users, streamed one user at a time.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Serialize;
+use uzers::{uid_t, User};
+
+use crate::authorized_keys::BlockMarkers;
+use crate::config::{reroot, resolve_path, ControlManager, VisitOptions};
+use crate::refresh::check_user;
+use crate::workspace::Workspace;
+
+#[cfg(test)]
+mod tests;
+
+/// Outcome of checking a single user, see [`user_statuses`].
+#[derive(Clone, Debug, Serialize)]
+pub struct UserStatus {
+    /// Username, lossily converted to UTF-8.
+    pub user: String,
+
+    /// User ID.
+    pub uid: uid_t,
+
+    /// Whether the user is enabled in control.
+    pub enabled: bool,
+
+    /// Whether the installed managed block matches control and user
+    /// configuration, if the user is enabled and could be checked.
+    ///
+    /// Blocks are compared by their hash only, see
+    /// [`block_hash`][crate::authorized_keys::block_hash].
+    pub up_to_date: Option<bool>,
+
+    /// Number of keys that narrowssh manages for the user, if the user is
+    /// enabled and could be checked.
+    ///
+    /// See [`ManagedBlock::keys`][crate::authorized_keys::ManagedBlock::keys].
+    pub keys: Option<usize>,
+
+    /// Problems that did not prevent the check.
+    pub warnings: Vec<String>,
+
+    /// Reason why the check failed, if it did.
+    pub error: Option<String>,
+}
+
+impl UserStatus {
+    /// Returns whether the check succeeded.
+    #[must_use]
+    pub fn ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Checks `users` lazily, one user per item.
+///
+/// Every user is checked with [`check_user`] when the item is requested, so
+/// that callers can report results before the remaining users are processed.
+/// The installed managed block of enabled users is compared with the
+/// expected one by hash, see
+/// [`block_hash`][crate::authorized_keys::block_hash].
+/// Failures are recorded in [`UserStatus::error`] rather than stopping the
+/// iteration.
+pub fn user_statuses<'a, W>(
+    ws: &'a W,
+    control: &'a ControlManager,
+    users: &'a [&'a User],
+    options: &'a VisitOptions,
+) -> impl Iterator<Item = UserStatus> + 'a
+where
+    W: Workspace,
+{
+    users.iter().map(move |user| {
+        let uid = user.uid();
+        let user_control = control.get_user_control(uid);
+
+        let mut status = UserStatus {
+            user: user.name().to_string_lossy().into_owned(),
+            uid,
+            enabled: user_control.enable,
+            up_to_date: None,
+            keys: None,
+            warnings: Vec::new(),
+            error: None,
+        };
+
+        let result =
+            check_user(ws, user, &user_control, options).and_then(|block| {
+                match block {
+                    None => Ok(()),
+                    Some(block) => {
+                        let path = reroot(
+                            &resolve_path(
+                                &user_control.authorized_keys,
+                                user,
+                            )?,
+                            options.target_root.as_deref(),
+                        )?;
+                        let installed = installed_hash(
+                            &path,
+                            &BlockMarkers::of(&user_control),
+                        )?;
+
+                        status.keys = Some(block.keys());
+                        status.up_to_date =
+                            Some(installed == Some(block.hash));
+                        status.warnings = block.warnings;
+                        Ok(())
+                    }
+                }
+            });
+
+        if let Err(error) = result {
+            status.error = Some(format!("{error:#}"));
+        }
+
+        status
+    })
+}
+
+/// Returns the total number of [keys][UserStatus::keys] managed for all
+/// `statuses`.
+pub fn total_keys<'a, I>(statuses: I) -> usize
+where
+    I: IntoIterator<Item = &'a UserStatus>,
+{
+    statuses.into_iter().filter_map(|s| s.keys).sum()
+}
+
+/// Returns the users among `users` whose `authorized_keys` may have drifted
+/// since `since`, for incremental checks.
+///
+/// Only the modification time of `authorized_keys` is examined through
+/// [`Workspace::modified`], which is much cheaper than [`user_statuses`].
+/// A user is returned if the file was modified after `since`, or if the
+/// user is enabled in control and the file is missing. Users whose file
+/// cannot be located or examined are returned as well, so that a full check
+/// reports the problem. Changes to control or user configuration are not
+/// detected.
+///
+/// `since` is usually derived from [`Workspace::now`].
+pub fn users_changed_since<'a, W>(
+    ws: &W,
+    control: &ControlManager,
+    users: &[&'a User],
+    options: &VisitOptions,
+    since: SystemTime,
+) -> Vec<&'a User>
+where
+    W: Workspace,
+{
+    users
+        .iter()
+        .copied()
+        .filter(|user| {
+            let user_control = control.get_user_control(user.uid());
+            let modified = resolve_path(&user_control.authorized_keys, user)
+                .and_then(|path| {
+                    reroot(&path, options.target_root.as_deref())
+                })
+                .and_then(|path| ws.modified(path));
+
+            match modified {
+                Ok(Some(modified)) => modified > since,
+                Ok(None) => user_control.enable,
+                Err(_) => true,
+            }
+        })
+        .collect()
+}
+
+/// Returns the hash recorded in the managed block at `path`, if any.
+fn installed_hash(
+    path: &Path,
+    markers: &BlockMarkers,
+) -> Result<Option<String>> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(None)
+        }
+        Err(error) => {
+            return Err(error)
+                .with_context(|| format!("reading {}", path.display()))
+        }
+    };
+
+    markers.read_hash(&content)
+}
+
+/// Writes `items` as JSON Lines, flushing after every line.
+///
+/// Returns the number of lines written.
+///
+/// # Errors
+/// The function will fail if some item cannot be serialized or `out` cannot
+/// be written.
+pub fn write_jsonl<I, T, O>(items: I, mut out: O) -> Result<usize>
+where
+    I: IntoIterator<Item = T>,
+    T: Serialize,
+    O: Write,
+{
+    let mut count = 0;
+
+    for item in items {
+        serde_json::to_writer(&mut out, &item)?;
+        out.write_all(b"\n")?;
+        out.flush()?;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+/// Opens `path` for a report, replacing any previous contents.
+///
+/// The file is created with mode `0644` if it does not exist, and its mode is
+/// set to `0644` regardless of the umask. An existing file is truncated.
+///
+/// # Errors
+/// The function will fail if the file could not be opened or its
+/// permissions could not be set.
+pub fn open_output(path: &Path) -> Result<File> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o644)
+        .open(path)
+        .with_context(|| format!("opening {}", path.display()))?;
+
+    file.set_permissions(PermissionsExt::from_mode(0o644))
+        .with_context(|| format!("setting mode of {}", path.display()))?;
+
+    Ok(file)
+}
+
+/// Units accepted by [`parse_age`], largest first.
+const AGE_UNITS: &[(char, u64)] =
+    &[('d', 24 * 60 * 60), ('h', 60 * 60), ('m', 60), ('s', 1)];
+
+/// Parses an age such as `90s`, `30m`, `12h` or `7d`.
+///
+/// # Errors
+/// The function will fail if `text` is not a positive whole number followed
+/// by one of the units `s`, `m`, `h` or `d`.
+pub fn parse_age(text: &str) -> Result<Duration> {
+    let unit = text.chars().last().unwrap_or_default();
+    let scale = AGE_UNITS
+        .iter()
+        .find(|(u, _)| *u == unit)
+        .map(|(_, scale)| *scale)
+        .ok_or_else(|| {
+            anyhow!("age {text:?} must end with one of s, m, h or d")
+        })?;
+
+    let count: u64 = text[..text.len() - 1]
+        .parse()
+        .with_context(|| format!("invalid age {text:?}"))?;
+    if count == 0 {
+        bail!("age {text:?} must be positive");
+    }
+
+    count
+        .checked_mul(scale)
+        .map(Duration::from_secs)
+        .ok_or_else(|| anyhow!("age {text:?} is too large"))
+}
+
+/// Formats `age` in the largest unit of [`parse_age`] that fits, rounding
+/// down.
+fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    let (unit, scale) = AGE_UNITS
+        .iter()
+        .find(|(_, scale)| secs >= *scale)
+        .copied()
+        .unwrap_or(('s', 1));
+    format!("{}{unit}", secs / scale)
+}
+
+/// Returns a warning if no control file was modified within `max_age`.
+///
+/// The newest modification time among [`ControlManager::files`] is compared
+/// with [`Workspace::now`]. Control that has not changed in a long time may
+/// have drifted from its source of truth.
+///
+/// # Errors
+/// The function will fail if the modification time of some control file
+/// could not be read.
+pub fn control_age_warning<W: Workspace>(
+    ws: &W,
+    control: &ControlManager,
+    max_age: Duration,
+) -> Result<Option<String>> {
+    let mut newest = None;
+    for file in control.files() {
+        let modified = std::fs::metadata(file)
+            .and_then(|m| m.modified())
+            .with_context(|| {
+                format!("reading mtime of {}", file.display())
+            })?;
+        newest = newest.max(Some(modified));
+    }
+
+    let newest = match newest {
+        Some(newest) => newest,
+        None => return Ok(None),
+    };
+    let age = ws.now().duration_since(newest).unwrap_or_default();
+
+    if age <= max_age {
+        return Ok(None);
+    }
+    Ok(Some(format!(
+        "control files were last modified {} ago, longer than {}",
+        format_age(age),
+        format_age(max_age)
+    )))
+}
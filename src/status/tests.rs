@@ -0,0 +1,359 @@
+pub use std::time::SystemTime;
+
+pub use serde_json::Value;
+
+pub use crate::selection::UserSelector;
+pub use crate::workspace::mock::MockWorkspace;
+
+pub use super::*;
+
+const KEY: &str =
+    "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIGd1bW15a2V5Zm9ydGVzdGluZ3B1cnBvc2Vz";
+
+/// Creates a workspace with `count` users, every other of them enabled.
+///
+/// Enabled users have a valid configuration, except for the last one, which
+/// fails because user configuration is strict.
+fn workspace(count: uid_t) -> Result<(MockWorkspace, ControlManager)> {
+    use std::fmt::Write as _;
+
+    let mut ws = MockWorkspace::new()?;
+    ws.add_user(0, "root", "root")?;
+
+    let mut control = String::from(
+        "[\"1000-1999\"]\nenable = true\nstrict_user_config = true\n",
+    );
+
+    for uid in 1000..1000 + count {
+        let name = format!("user{uid}");
+        ws.add_user(uid, &name, format!("home/{name}"))?;
+
+        if uid % 2 == 1 {
+            writeln!(control, "[{name}]\nenable = false")?;
+        } else if uid != 1000 + count - 1 {
+            ws.add_file(
+                format!("home/{name}/.narrowssh.conf"),
+                uid,
+                0o600,
+                format!("keys = [{KEY:?}]"),
+            )?;
+        }
+    }
+
+    let main = ws.add_file("etc/main.toml", 0, 0o600, control)?;
+    let control = ControlManager::load(&ws, main, &VisitOptions::default())?;
+
+    Ok((ws, control))
+}
+
+#[test]
+fn jsonl() -> Result<()> {
+    let (ws, control) = workspace(51)?;
+    let users = UserSelector::Range(1000, 1999).resolve(&ws)?;
+    let options = VisitOptions::default();
+
+    let mut out = Vec::new();
+    let count = write_jsonl(
+        user_statuses(&ws, &control, &users, &options),
+        &mut out,
+    )?;
+
+    let lines: Vec<_> = std::str::from_utf8(&out)?.lines().collect();
+    assert_eq!(count, users.len());
+    assert_eq!(lines.len(), users.len());
+
+    for line in &lines {
+        let value: Value = serde_json::from_str(line)?;
+        assert!(value.is_object(), "{line}");
+        assert!(value["uid"].is_u64(), "{line}");
+    }
+
+    let failed: Vec<Value> = lines
+        .iter()
+        .map(|l| serde_json::from_str(l))
+        .collect::<Result<Vec<Value>, _>>()?
+        .into_iter()
+        .filter(|v| !v["error"].is_null())
+        .collect();
+    assert_eq!(failed.len(), 1);
+    assert_eq!(failed[0]["user"], "user1050");
+    Ok(())
+}
+
+#[test]
+fn key_counts() -> Result<()> {
+    let mut ws = MockWorkspace::new()?;
+    ws.add_user(0, "root", "root")?;
+
+    // Users with 0, 2 and 3 keys, and a disabled one with 1 key
+    for (uid, count) in [(1000, 0), (1001, 2), (1002, 3), (1003, 1)] {
+        let name = format!("user{uid}");
+        ws.add_user(uid, &name, format!("home/{name}"))?;
+
+        let keys: Vec<_> = (0..count)
+            .map(|i| format!("\"ssh-ed25519 AAAAkey{uid}x{i}\""))
+            .collect();
+        ws.add_file(
+            format!("home/{name}/.narrowssh.conf"),
+            uid,
+            0o600,
+            format!("keys = [{}]", keys.join(", ")),
+        )?;
+    }
+
+    let control =
+        "[\"1000-1999\"]\nenable = true\n[user1003]\nenable = false";
+    let main = ws.add_file("etc/main.toml", 0, 0o600, control)?;
+    let control = ControlManager::load(&ws, main, &VisitOptions::default())?;
+    let users = UserSelector::Range(1000, 1999).resolve(&ws)?;
+
+    let statuses: Vec<_> =
+        user_statuses(&ws, &control, &users, &VisitOptions::default())
+            .collect();
+    let counts: Vec<_> = statuses.iter().map(|s| s.keys).collect();
+
+    assert_eq!(counts, [Some(0), Some(2), Some(3), None]);
+    assert_eq!(total_keys(&statuses), 5);
+    Ok(())
+}
+
+#[test]
+fn changed_since() -> Result<()> {
+    let (mut ws, control) = workspace(6)?;
+    let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+    let hour = Duration::from_secs(3600);
+    ws.set_now(now);
+
+    // Enabled user1000 and user1002, disabled user1001 have key files
+    for (uid, age) in [(1000, 2 * hour), (1001, hour / 6), (1002, 24 * hour)]
+    {
+        let path = ws.add_file(
+            format!("home/user{uid}/.ssh/authorized_keys"),
+            uid,
+            0o600,
+            "",
+        )?;
+        ws.set_mtime(path, now - age);
+    }
+
+    let users = UserSelector::Range(1000, 1999).resolve(&ws)?;
+    let changed = users_changed_since(
+        &ws,
+        &control,
+        &users,
+        &VisitOptions::default(),
+        ws.now() - hour,
+    );
+    let uids: Vec<_> = changed.iter().map(|u| u.uid()).collect();
+
+    // user1004 is enabled and has no file, so it has drifted, too
+    assert_eq!(uids, [1001, 1004]);
+    Ok(())
+}
+
+#[test]
+fn lazy() -> Result<()> {
+    let (ws, control) = workspace(4)?;
+    let users = UserSelector::Range(1000, 1999).resolve(&ws)?;
+    let options = VisitOptions::default();
+
+    let mut statuses = user_statuses(&ws, &control, &users, &options);
+
+    let first = statuses.next().unwrap();
+    assert_eq!(first.uid, users[0].uid());
+    assert_eq!(first.enabled, first.uid % 2 == 0);
+    assert_eq!(statuses.count(), 3);
+    Ok(())
+}
+
+#[test]
+fn drift() -> Result<()> {
+    let mut ws = MockWorkspace::new()?;
+    ws.add_user(1000, "alice", "home/alice")?;
+    ws.add_file(
+        "home/alice/.narrowssh.conf",
+        1000,
+        0o600,
+        format!("keys = [{KEY:?}]"),
+    )?;
+    let backup = ws.add_file(
+        "etc/backup.toml",
+        0,
+        0o600,
+        "[alice]\nenable = true\ncommands = [\"backup\"]",
+    )?;
+    let restore = ws.add_file(
+        "etc/restore.toml",
+        0,
+        0o600,
+        "[alice]\nenable = true\ncommands = [\"restore\"]",
+    )?;
+
+    let options = VisitOptions::default();
+    let backup = ControlManager::load(&ws, backup, &options)?;
+    let restore = ControlManager::load(&ws, restore, &options)?;
+    let users = [ws.users().user_by_uid(1000).unwrap()];
+
+    let status = |control| {
+        user_statuses(&ws, control, &users, &options)
+            .next()
+            .unwrap()
+    };
+
+    // Nothing is installed yet
+    assert_eq!(status(&backup).up_to_date, Some(false));
+
+    let batch = backup.plan(&ws, &users, &options);
+    crate::refresh::apply_batch(&ws, &batch, true)?;
+
+    assert_eq!(status(&backup).up_to_date, Some(true));
+    assert_eq!(status(&restore).up_to_date, Some(false));
+    Ok(())
+}
+
+#[test]
+fn reproducible() -> Result<()> {
+    let render = || -> Result<Vec<u8>> {
+        let (ws, control) = workspace(20)?;
+        let users = UserSelector::Range(1000, 1999).resolve(&ws)?;
+
+        let mut out = Vec::new();
+        write_jsonl(
+            user_statuses(&ws, &control, &users, &VisitOptions::default()),
+            &mut out,
+        )?;
+        Ok(out)
+    };
+
+    let first = render()?;
+    let uids: Vec<_> = std::str::from_utf8(&first)?
+        .lines()
+        .map(|line| {
+            serde_json::from_str::<Value>(line).map(|v| v["uid"].clone())
+        })
+        .collect::<Result<_, _>>()?;
+    assert_eq!(uids, (1000..1020).map(Value::from).collect::<Vec<_>>());
+
+    for _ in 0..3 {
+        assert_eq!(render()?, first);
+    }
+    Ok(())
+}
+
+/// Tests for [`parse_age`]
+mod parse_age {
+    use super::*;
+
+    #[test]
+    fn units() -> Result<()> {
+        assert_eq!(parse_age("90s")?, Duration::from_secs(90));
+        assert_eq!(parse_age("30m")?, Duration::from_secs(30 * 60));
+        assert_eq!(parse_age("12h")?, Duration::from_secs(12 * 60 * 60));
+        assert_eq!(parse_age("7d")?, Duration::from_secs(7 * 24 * 60 * 60));
+        Ok(())
+    }
+
+    #[test]
+    fn invalid() {
+        for text in [
+            "",
+            "d",
+            "7",
+            "7w",
+            "-7d",
+            "0d",
+            "1.5h",
+            "99999999999999999d",
+        ] {
+            assert!(parse_age(text).is_err(), "accepted {text:?}");
+        }
+    }
+}
+
+/// Tests for [`control_age_warning`]
+mod control_age_warning {
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    /// Returns the workspace and the modification time of its control file.
+    fn workspace() -> Result<(MockWorkspace, ControlManager, SystemTime)> {
+        let (ws, control) = super::workspace(1)?;
+        let mtime = std::fs::metadata(&control.files()[0])?.modified()?;
+        Ok((ws, control, mtime))
+    }
+
+    #[test]
+    fn old() -> Result<()> {
+        let (ws, control, mtime) = workspace()?;
+        ws.set_now(mtime + 40 * DAY);
+
+        let warning = control_age_warning(&ws, &control, 30 * DAY)?;
+
+        assert_eq!(
+            warning.as_deref(),
+            Some("control files were last modified 40d ago, longer than 30d")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn fresh() -> Result<()> {
+        let (ws, control, mtime) = workspace()?;
+
+        ws.set_now(mtime + 29 * DAY);
+        assert_eq!(control_age_warning(&ws, &control, 30 * DAY)?, None);
+
+        // Clock skew does not count as age
+        ws.set_now(mtime - DAY);
+        assert_eq!(control_age_warning(&ws, &control, 30 * DAY)?, None);
+        Ok(())
+    }
+}
+
+/// Tests for [`open_output`]
+mod open_output {
+    use super::*;
+
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn same_as_stdout() -> Result<()> {
+        let (ws, control) = workspace(5)?;
+        let users = UserSelector::Range(1000, 1999).resolve(&ws)?;
+        let options = VisitOptions::default();
+
+        let mut stdout = Vec::new();
+        write_jsonl(
+            user_statuses(&ws, &control, &users, &options),
+            &mut stdout,
+        )?;
+
+        let path = ws.path("report.jsonl");
+        std::fs::write(
+            &path,
+            "stale contents that are longer than the report",
+        )?;
+        let file = open_output(&path)?;
+        write_jsonl(user_statuses(&ws, &control, &users, &options), file)?;
+
+        assert_eq!(std::fs::read(&path)?, stdout);
+        Ok(())
+    }
+
+    #[test]
+    fn mode() -> Result<()> {
+        let ws = MockWorkspace::new()?;
+        let path = ws.path("report.txt");
+
+        open_output(&path)?;
+        let mode = std::fs::metadata(&path)?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o644);
+
+        std::fs::set_permissions(&path, PermissionsExt::from_mode(0o600))?;
+        open_output(&path)?;
+        let mode = std::fs::metadata(&path)?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o644);
+        Ok(())
+    }
+}
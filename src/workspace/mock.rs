@@ -1,15 +1,20 @@
 //! Mock implementation of [`Workspace`].
 
+#![allow(clippy::missing_errors_doc, clippy::missing_panics_doc)]
+
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
+use std::fs::File;
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use assert_fs::{fixture::ChildPath, prelude::*, TempDir};
-use uzers::os::unix::UserExt;
-use uzers::{uid_t, User};
+use uzers::os::unix::{GroupExt, UserExt};
+use uzers::{gid_t, uid_t, Group, User};
 
-use crate::workspace::{UserMap, Workspace};
+use crate::workspace::{create_temp_file, GroupMap, UserMap, Workspace};
 
 /// Mock implementation of [`Workspace`].
 ///
@@ -17,7 +22,13 @@ use crate::workspace::{UserMap, Workspace};
 /// All paths encountered in a test must be owned.
 pub struct MockWorkspace {
     user_map: UserMap,
-    owned_paths: HashMap<PathBuf, uid_t>,
+    group_map: GroupMap,
+    owned_paths: RefCell<HashMap<PathBuf, uid_t>>,
+    groups_of_paths: RefCell<HashMap<PathBuf, gid_t>>,
+    fail_renames: Cell<bool>,
+    now: Cell<Option<SystemTime>>,
+    mtimes: RefCell<HashMap<PathBuf, SystemTime>>,
+    synced_dirs: RefCell<Vec<PathBuf>>,
     temp_dir: TempDir,
 }
 
@@ -51,7 +62,14 @@ impl MockWorkspace {
     {
         let child = self.child(path);
         let path = child.path().to_path_buf();
-        self.owned_paths.entry(path.clone()).or_insert(owner);
+        self.owned_paths
+            .get_mut()
+            .entry(path.clone())
+            .or_insert(owner);
+        self.groups_of_paths
+            .get_mut()
+            .entry(path.clone())
+            .or_insert(owner);
 
         action(&child)?;
 
@@ -149,6 +167,66 @@ impl MockWorkspace {
         Ok(())
     }
 
+    /// Changes the primary group of the user with given UID to `gid`.
+    ///
+    /// Users added by [`Self::add_user`] have a primary GID equal to their
+    /// UID until this is called.
+    pub fn set_primary_group(&mut self, uid: uid_t, gid: gid_t) {
+        let user = self
+            .user_map
+            .user_by_uid(uid)
+            .expect("user must be added first");
+        let user =
+            User::new(uid, user.name(), gid).with_home_dir(user.home_dir());
+        self.user_map.add(user);
+    }
+
+    /// Adds a mock system group with given member usernames.
+    pub fn add_group<S: AsRef<str>>(
+        &mut self,
+        gid: gid_t,
+        name: S,
+        members: &[&str],
+    ) {
+        let group = members
+            .iter()
+            .fold(Group::new(gid, name.as_ref()), GroupExt::add_member);
+        self.group_map.add(group);
+    }
+
+    /// Changes the UID reported by [`UserMap::current_uid`].
+    pub fn set_current_uid(&mut self, uid: uid_t) {
+        self.user_map.current_uid = uid;
+    }
+
+    /// Makes [`Workspace::rename`] fail without touching the filesystem.
+    pub fn set_fail_renames(&self, fail: bool) {
+        self.fail_renames.set(fail);
+    }
+
+    /// Makes [`Workspace::now`] return `now`.
+    ///
+    /// Until this is called, the real time is returned.
+    pub fn set_now(&self, now: SystemTime) {
+        self.now.set(Some(now));
+    }
+
+    /// Makes [`Workspace::modified`] report `mtime` for the file at `path`.
+    ///
+    /// `path` must be as returned by [`Self::path`]. The override is dropped
+    /// when the file is replaced by [`Workspace::rename`].
+    pub fn set_mtime<P: AsRef<Path>>(&self, path: P, mtime: SystemTime) {
+        self.mtimes
+            .borrow_mut()
+            .insert(path.as_ref().to_path_buf(), mtime);
+    }
+
+    /// Returns the directories passed to [`Workspace::sync_dir`] so far, in
+    /// order.
+    pub fn synced_dirs(&self) -> Vec<PathBuf> {
+        self.synced_dirs.borrow().clone()
+    }
+
     /// Constructs a [`MockWorkspace`].
     ///
     /// [`Self::users`] is initialized empty with current UID set to 1000.
@@ -156,13 +234,19 @@ impl MockWorkspace {
         Ok(Self {
             temp_dir: TempDir::new()?,
             user_map: UserMap::new(std::iter::empty(), 1000),
-            owned_paths: HashMap::new(),
+            group_map: GroupMap::new(std::iter::empty()),
+            owned_paths: RefCell::new(HashMap::new()),
+            groups_of_paths: RefCell::new(HashMap::new()),
+            fail_renames: Cell::new(false),
+            now: Cell::new(None),
+            mtimes: RefCell::new(HashMap::new()),
+            synced_dirs: RefCell::new(Vec::new()),
         })
     }
 }
 
 /// Changes the permissions of the FS object to `mode`.
-fn set_perms<P>(path: P, mode: u32) -> Result<()>
+pub fn set_perms<P>(path: P, mode: u32) -> Result<()>
 where
     P: AsRef<Path>,
 {
@@ -180,19 +264,108 @@ impl Workspace for MockWorkspace {
         &self.user_map
     }
 
+    fn groups(&self) -> &GroupMap {
+        &self.group_map
+    }
+
     fn get_mock_owner_uid<P: AsRef<Path>>(&self, path: P) -> Option<uid_t> {
         let path = path.as_ref();
 
         // Find most specific parent that is owned or die trying
         Some(
-            *path
-                .canonicalize()
-                .with_context(|| format!("Could not canonicalize {:?}", path))
+            self.canonicalize(path)
                 .unwrap()
                 .ancestors()
-                .find_map(|p| self.owned_paths.get(p))
+                .find_map(|p| self.owned_paths.borrow().get(p).copied())
                 .with_context(|| format!("{:?} is not owned", path))
                 .unwrap(),
         )
     }
+
+    /// Returns the GID last given to [`Workspace::set_owner`] for `path`, or
+    /// the owner UID given when the path was added, which is the primary
+    /// group of mock users.
+    ///
+    /// Unlike owners, groups are not inherited by descendants.
+    fn get_mock_group_gid<P: AsRef<Path>>(&self, path: P) -> Option<gid_t> {
+        self.groups_of_paths.borrow().get(path.as_ref()).copied()
+    }
+
+    fn now(&self) -> SystemTime {
+        self.now.get().unwrap_or_else(SystemTime::now)
+    }
+
+    fn modified<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Result<Option<SystemTime>> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(None);
+        }
+        match self.mtimes.borrow().get(path) {
+            Some(mtime) => Ok(Some(*mtime)),
+            None => Ok(Some(std::fs::metadata(path)?.modified()?)),
+        }
+    }
+
+    fn set_owner<P: AsRef<Path>>(
+        &self,
+        path: P,
+        uid: uid_t,
+        gid: gid_t,
+    ) -> Result<()> {
+        let path = path.as_ref().to_path_buf();
+        self.groups_of_paths.borrow_mut().insert(path.clone(), gid);
+        self.owned_paths.borrow_mut().insert(path, uid);
+        Ok(())
+    }
+
+    fn create_temp_in<P: AsRef<Path>>(
+        &self,
+        dir: P,
+        mode: u32,
+    ) -> Result<(File, PathBuf)> {
+        create_temp_file(dir.as_ref(), mode)
+    }
+
+    /// Renames `from` to `to` and moves the ownership records along.
+    ///
+    /// Fails without any effect if [`Self::set_fail_renames`] is enabled.
+    fn rename<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        from: P,
+        to: Q,
+    ) -> Result<()> {
+        let (from, to) = (from.as_ref(), to.as_ref());
+
+        if self.fail_renames.get() {
+            bail!("mock rename of {} failed", from.display());
+        }
+
+        std::fs::rename(from, to)?;
+        self.mtimes.borrow_mut().remove(to);
+
+        let mut owned_paths = self.owned_paths.borrow_mut();
+        owned_paths.remove(to);
+        if let Some(owner) = owned_paths.remove(from) {
+            owned_paths.insert(to.to_path_buf(), owner);
+        }
+
+        let mut groups_of_paths = self.groups_of_paths.borrow_mut();
+        groups_of_paths.remove(to);
+        if let Some(gid) = groups_of_paths.remove(from) {
+            groups_of_paths.insert(to.to_path_buf(), gid);
+        }
+
+        Ok(())
+    }
+
+    /// Records `dir` for [`Self::synced_dirs`] and syncs it.
+    fn sync_dir<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+        let dir = dir.as_ref();
+        self.synced_dirs.borrow_mut().push(dir.to_path_buf());
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    }
 }
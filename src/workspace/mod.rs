@@ -1,25 +1,136 @@
-use std::collections::HashMap;
-use std::ffi::OsStr;
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::fs::{File, OpenOptions};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
 
-use anyhow::{bail, Result};
-use uzers::{uid_t, User};
+use anyhow::{bail, Context, Result};
+use uzers::os::unix::{GroupExt, UserExt};
+use uzers::{gid_t, uid_t, Group, User};
 
 #[cfg(test)]
 pub mod mock;
 
+#[cfg(test)]
+mod tests;
+
+/// Returns the home directory of `user`, or `None` if it is unset.
+///
+/// Accounts without a home have an empty home directory in the user
+/// database. All code that needs a home directory should go through this
+/// function rather than inspect [`UserExt::home_dir`] directly.
+#[must_use]
+pub fn home_dir(user: &User) -> Option<&Path> {
+    let home = user.home_dir();
+    if home.as_os_str().is_empty() {
+        None
+    } else {
+        Some(home)
+    }
+}
+
+/// Returns whether `user` belongs to `group`.
+///
+/// Users belong to their primary group, which the member list of a group
+/// does not repeat, and to every group that lists them as a member. All code
+/// that tests group membership should go through this function.
+#[must_use]
+pub fn is_group_member(user: &User, group: &Group) -> bool {
+    user.primary_group_id() == group.gid()
+        || group.members().iter().any(|m| m == user.name())
+}
+
+/// Maximum number of symbolic links followed by [`canonicalize_logical`].
+const MAX_LINK_HOPS: usize = 40;
+
+/// Resolves `path` to an absolute path without symbolic links, `.` or `..`.
+///
+/// This is the algorithm behind [`std::fs::canonicalize`] expressed over an
+/// abstract filesystem, so that workspaces without real paths can implement
+/// [`Workspace::canonicalize`]. `read_link` is called for every intermediate
+/// path and must return the target of the symbolic link at that path,
+/// `None` if the object is not a link, or an error if it does not exist.
+///
+/// # Errors
+/// The function will fail if `path` is relative, if `read_link` fails or if
+/// too many symbolic links are encountered.
+pub fn canonicalize_logical<F>(
+    path: &Path,
+    mut read_link: F,
+) -> Result<PathBuf>
+where
+    F: FnMut(&Path) -> std::io::Result<Option<PathBuf>>,
+{
+    if !path.is_absolute() {
+        bail!("cannot canonicalize relative path {}", path.display());
+    }
+
+    // Single components that remain to be resolved
+    let mut pending: VecDeque<PathBuf> = path
+        .components()
+        .map(|c| PathBuf::from(c.as_os_str()))
+        .collect();
+    let mut resolved = PathBuf::new();
+    let mut hops = 0;
+
+    while let Some(component) = pending.pop_front() {
+        match component.components().next() {
+            None | Some(Component::CurDir) => {}
+            Some(Component::Prefix(_) | Component::RootDir) => {
+                resolved = PathBuf::from("/");
+            }
+            Some(Component::ParentDir) => {
+                resolved.pop();
+            }
+            Some(Component::Normal(name)) => {
+                let candidate = resolved.join(name);
+                let target = read_link(&candidate).with_context(|| {
+                    format!("could not canonicalize {}", path.display())
+                })?;
+
+                match target {
+                    None => resolved = candidate,
+                    Some(target) => {
+                        hops += 1;
+                        if hops > MAX_LINK_HOPS {
+                            bail!(
+                                "too many symbolic links in {}",
+                                path.display()
+                            );
+                        }
+                        for c in target.components().rev() {
+                            pending.push_front(c.as_os_str().into());
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(resolved)
+}
+
 /// Provides access to a snapshot of system users.
 pub struct UserMap {
-    data: HashMap<uid_t, User>,
+    /// Users by UID, ordered so that enumerations are reproducible.
+    data: BTreeMap<uid_t, User>,
+
+    /// UIDs of all users with given username.
+    ///
+    /// Usernames are not necessarily unique, so every entry holds all UIDs.
+    by_name: HashMap<OsString, Vec<uid_t>>,
+
     current_uid: uid_t,
 }
 
 impl UserMap {
-    /// An iterator over all known users in the system.
-    #[must_use]
-    pub fn all_users(
-        &self,
-    ) -> std::collections::hash_map::Values<'_, uid_t, User> {
+    /// An iterator over all known users in the system, in UID order.
+    ///
+    /// Iterating over `&UserMap` yields the same users.
+    pub fn all_users(&self) -> impl Iterator<Item = &User> + '_ {
         self.data.values()
     }
 
@@ -41,20 +152,32 @@ impl UserMap {
         &self,
         name: S,
     ) -> Result<Option<&User>> {
-        let mut iter = self.data.values();
-        let name = name.as_ref();
-
-        let first = iter.find(|&u| u.name() == name);
-        if let Some(result) = first {
-            if iter.any(|u| u.name() == name) {
-                bail!("Username is not unique");
-            }
-            Ok(Some(result))
-        } else {
-            Ok(None)
+        match self.by_name.get(name.as_ref()).map(Vec::as_slice) {
+            None | Some([]) => Ok(None),
+            Some([uid]) => Ok(self.data.get(uid)),
+            Some(_) => bail!("Username is not unique"),
         }
     }
 
+    /// Returns the home directory of the user with given UID.
+    ///
+    /// Returns `None` if no such user exists or the user has no home, see
+    /// [`home_dir`].
+    #[must_use]
+    pub fn home_dir_of(&self, uid: uid_t) -> Option<PathBuf> {
+        self.user_by_uid(uid)
+            .and_then(home_dir)
+            .map(Path::to_path_buf)
+    }
+
+    /// Returns the primary GID of the user with given UID.
+    ///
+    /// Returns `None` if no such user exists.
+    #[must_use]
+    pub fn primary_gid_of(&self, uid: uid_t) -> Option<gid_t> {
+        self.user_by_uid(uid).map(User::primary_group_id)
+    }
+
     /// Returns the current UID of the process.
     #[must_use]
     pub fn current_uid(&self) -> uid_t {
@@ -62,8 +185,24 @@ impl UserMap {
     }
 
     /// Add a [`User`] manually. For use in testing.
+    ///
+    /// A previously added user with the same UID is replaced.
     pub fn add(&mut self, user: User) {
-        self.data.insert(user.uid(), user);
+        let uid = user.uid();
+
+        self.by_name
+            .entry(user.name().to_owned())
+            .or_default()
+            .push(uid);
+
+        if let Some(old) = self.data.insert(uid, user) {
+            if let Some(uids) = self.by_name.get_mut(old.name()) {
+                // Remove the stale entry, which precedes the one just pushed
+                if let Some(index) = uids.iter().position(|&u| u == uid) {
+                    uids.remove(index);
+                }
+            }
+        }
     }
 
     /// Constructs a new `UserMap` from [`User`] values.
@@ -71,9 +210,99 @@ impl UserMap {
         users: I,
         current_uid: uid_t,
     ) -> Self {
-        Self {
-            data: users.map(|u| (u.uid(), u)).collect(),
+        let mut result = Self {
+            data: BTreeMap::new(),
+            by_name: HashMap::new(),
             current_uid,
+        };
+
+        for user in users {
+            result.add(user);
+        }
+
+        result
+    }
+}
+
+/// Iterates over all known users in UID order, like [`UserMap::all_users`].
+///
+/// ```
+/// use narrowssh::workspace::UserMap;
+/// use uzers::User;
+///
+/// let users = [User::new(1001, "bob", 1001), User::new(1000, "alice", 1000)];
+/// let map = UserMap::new(users.into_iter(), 1000);
+///
+/// let mut names = Vec::new();
+/// for user in &map {
+///     names.push(user.name().to_owned());
+/// }
+/// assert_eq!(names, ["alice", "bob"]);
+/// ```
+#[allow(clippy::into_iter_without_iter)] // See all_users
+impl<'a> IntoIterator for &'a UserMap {
+    type Item = &'a User;
+    type IntoIter = std::collections::btree_map::Values<'a, uid_t, User>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.data.values()
+    }
+}
+
+/// Provides access to a snapshot of system groups.
+pub struct GroupMap {
+    data: BTreeMap<gid_t, Group>,
+}
+
+impl GroupMap {
+    /// An iterator over all known groups in the system, in GID order.
+    pub fn all_groups(
+        &self,
+    ) -> std::collections::btree_map::Values<'_, gid_t, Group> {
+        self.data.values()
+    }
+
+    /// Returns the [`Group`] with given GID if one exists.
+    #[must_use]
+    pub fn group_by_gid(&self, gid: gid_t) -> Option<&Group> {
+        self.data.get(&gid)
+    }
+
+    /// Returns the [`Group`] with given name if exactly one exists.
+    ///
+    /// If no groups are found, returns `Ok(None)`. If exactly one group `g`
+    /// has given name, returns `Ok(Some(g))`. If at least two groups share
+    /// the name, returns `Err`.
+    ///
+    /// # Errors
+    /// An error is returned if multiple groups share the provided name.
+    pub fn group_by_name<S: AsRef<OsStr>>(
+        &self,
+        name: S,
+    ) -> Result<Option<&Group>> {
+        let mut iter = self.data.values();
+        let name = name.as_ref();
+
+        let first = iter.find(|&g| g.name() == name);
+        if let Some(result) = first {
+            if iter.any(|g| g.name() == name) {
+                bail!("Group name is not unique");
+            }
+            Ok(Some(result))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Add a [`Group`] manually. For use in testing.
+    pub fn add(&mut self, group: Group) {
+        self.data.insert(group.gid(), group);
+    }
+
+    /// Constructs a new `GroupMap` from [`Group`] values.
+    pub fn new<I: Iterator<Item = Group>>(groups: I) -> Self {
+        Self {
+            data: groups.map(|g| (g.gid(), g)).collect(),
         }
     }
 }
@@ -83,24 +312,168 @@ pub trait Workspace {
     /// Returns a system user manager.
     fn users(&self) -> &UserMap;
 
+    /// Returns a system group manager.
+    fn groups(&self) -> &GroupMap;
+
     /// Returns the mock owner UID of given filesystem object.
     ///
     /// This method is useful for testing purposes and should always return
     /// `None` in release builds.
     fn get_mock_owner_uid<P: AsRef<Path>>(&self, path: P) -> Option<uid_t>;
+
+    /// Returns the mock group GID of given filesystem object.
+    ///
+    /// This method is useful for testing purposes and should always return
+    /// `None` in release builds.
+    fn get_mock_group_gid<P: AsRef<Path>>(&self, path: P) -> Option<gid_t>;
+
+    /// Returns whether a filesystem object exists at `path`.
+    ///
+    /// Unlike [`Path::exists`], symbolic links are not followed: a dangling
+    /// link exists, and a link to a directory is not reported as the
+    /// directory. Use this where a check is followed by an action on the same
+    /// path, so that a link planted in between cannot change the outcome.
+    fn lexists<P: AsRef<Path>>(&self, path: P) -> bool {
+        std::fs::symlink_metadata(path).is_ok()
+    }
+
+    /// Returns the canonical form of `path`, see [`std::fs::canonicalize`].
+    ///
+    /// Workspaces that do not map onto the real filesystem should implement
+    /// this with [`canonicalize_logical`].
+    ///
+    /// # Errors
+    /// An error is returned if `path` does not exist or could not be
+    /// resolved.
+    fn canonicalize<P: AsRef<Path>>(&self, path: P) -> Result<PathBuf> {
+        let path = path.as_ref();
+        std::fs::canonicalize(path).with_context(|| {
+            format!("could not canonicalize {}", path.display())
+        })
+    }
+
+    /// Returns the current time.
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    /// Returns the modification time of the file at `path`, or `None` if it
+    /// does not exist.
+    ///
+    /// Symbolic links are followed, as `sshd(8)` does when it reads files.
+    ///
+    /// # Errors
+    /// An error is returned if the file exists but could not be examined.
+    fn modified<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Result<Option<SystemTime>> {
+        let path = path.as_ref();
+        match std::fs::metadata(path) {
+            Ok(metadata) => metadata.modified().map(Some),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                Ok(None)
+            }
+            Err(error) => Err(error),
+        }
+        .with_context(|| format!("reading mtime of {}", path.display()))
+    }
+
+    /// Changes the owner and group of given filesystem object.
+    ///
+    /// Symbolic links are not followed.
+    ///
+    /// # Errors
+    /// An error is returned if the ownership could not be changed.
+    fn set_owner<P: AsRef<Path>>(
+        &self,
+        path: P,
+        uid: uid_t,
+        gid: gid_t,
+    ) -> Result<()>;
+
+    /// Creates a new empty file with a unique name in directory `dir`.
+    ///
+    /// The file has permissions `mode` and is opened for writing. Returns the
+    /// file along with its path.
+    ///
+    /// # Errors
+    /// An error is returned if the file could not be created.
+    fn create_temp_in<P: AsRef<Path>>(
+        &self,
+        dir: P,
+        mode: u32,
+    ) -> Result<(File, PathBuf)>;
+
+    /// Renames `from` to `to`, atomically replacing `to` if it exists.
+    ///
+    /// # Errors
+    /// An error is returned if the rename failed, in which case neither path
+    /// is affected.
+    fn rename<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        from: P,
+        to: Q,
+    ) -> Result<()>;
+
+    /// Flushes directory `dir` to disk, making renames in it durable.
+    ///
+    /// # Errors
+    /// An error is returned if the directory could not be opened or synced.
+    fn sync_dir<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+        let dir = dir.as_ref();
+        File::open(dir)
+            .and_then(|file| file.sync_all())
+            .with_context(|| format!("syncing directory {}", dir.display()))
+    }
+}
+
+/// Creates a new file with a unique name in `dir` using [`std::fs`].
+///
+/// See [`Workspace::create_temp_in`].
+fn create_temp_file(dir: &Path, mode: u32) -> Result<(File, PathBuf)> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    loop {
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path =
+            dir.join(format!(".narrowssh-{}-{n}.tmp", std::process::id()));
+
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(&path)
+        {
+            Ok(file) => {
+                // Do not let the umask interfere
+                file.set_permissions(PermissionsExt::from_mode(mode))?;
+                return Ok((file, path));
+            }
+            Err(error)
+                if error.kind() == std::io::ErrorKind::AlreadyExists => {}
+            Err(error) => {
+                return Err(error).with_context(|| {
+                    format!("could not create a file in {}", dir.display())
+                })
+            }
+        }
+    }
 }
 
 #[allow(clippy::module_name_repetitions)] // Makes little sense otherwise
 /// The Workspace implementation used in release builds.
 pub struct RealWorkspace {
     user_map: UserMap,
+    group_map: GroupMap,
 }
 
 impl RealWorkspace {
     /// Constructs a [`RealWorkspace`].
     ///
     /// # Safety
-    /// Calls [`all_users()`][uzers::all_users()].
+    /// Calls [`all_users()`][uzers::all_users()] and
+    /// [`all_groups()`][uzers::all_groups()].
     #[must_use]
     pub unsafe fn new() -> Self {
         Self {
@@ -108,7 +481,61 @@ impl RealWorkspace {
                 uzers::all_users(),
                 uzers::get_current_uid(),
             ),
+            group_map: GroupMap::new(uzers::all_groups()),
+        }
+    }
+
+    /// Constructs a [`RealWorkspace`] and checks that it is usable.
+    ///
+    /// Unlike [`RealWorkspace::new`], this constructor is safe: users and
+    /// groups are only enumerated here, before any other thread of narrowssh
+    /// could call into the user database.
+    ///
+    /// ```no_run
+    /// # fn main() -> anyhow::Result<()> {
+    /// use narrowssh::workspace::{RealWorkspace, Workspace};
+    ///
+    /// let ws = RealWorkspace::try_new()?;
+    /// let uid = ws.users().current_uid();
+    /// assert!(ws.users().user_by_uid(uid).is_some());
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    /// The function will fail if the running user is not found among the
+    /// system users.
+    pub fn try_new() -> Result<Self> {
+        // SAFETY: narrowssh does not enumerate users from several threads
+        let result = unsafe { Self::new() };
+        result.validate()?;
+        Ok(result)
+    }
+
+    /// Makes [`UserMap::current_uid`] report `uid` instead of the UID of the
+    /// process.
+    ///
+    /// This only affects which user narrowssh considers to be running, e.g.
+    /// when no users are selected explicitly. Privileges of the process are
+    /// not changed.
+    ///
+    /// # Errors
+    /// The function will fail if no user has UID `uid`.
+    pub fn assume_uid(&mut self, uid: uid_t) -> Result<()> {
+        if self.user_map.user_by_uid(uid).is_none() {
+            bail!("cannot assume UID {uid}: no such user");
+        }
+        self.user_map.current_uid = uid;
+        Ok(())
+    }
+
+    /// Checks that the running user is known.
+    fn validate(&self) -> Result<()> {
+        let uid = self.user_map.current_uid();
+        if self.user_map.user_by_uid(uid).is_none() {
+            bail!("running user (UID {uid}) is not a known user");
         }
+        Ok(())
     }
 }
 
@@ -117,7 +544,53 @@ impl Workspace for RealWorkspace {
         &self.user_map
     }
 
+    fn groups(&self) -> &GroupMap {
+        &self.group_map
+    }
+
     fn get_mock_owner_uid<P: AsRef<Path>>(&self, _: P) -> Option<uid_t> {
         None
     }
+
+    fn get_mock_group_gid<P: AsRef<Path>>(&self, _: P) -> Option<gid_t> {
+        None
+    }
+
+    fn set_owner<P: AsRef<Path>>(
+        &self,
+        path: P,
+        uid: uid_t,
+        gid: gid_t,
+    ) -> Result<()> {
+        let path = path.as_ref();
+        let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+
+        // SAFETY: c_path is a valid NUL-terminated string
+        if unsafe { libc::lchown(c_path.as_ptr(), uid, gid) } != 0 {
+            return Err(std::io::Error::last_os_error()).with_context(|| {
+                format!("could not change owner of {}", path.display())
+            });
+        }
+
+        Ok(())
+    }
+
+    fn create_temp_in<P: AsRef<Path>>(
+        &self,
+        dir: P,
+        mode: u32,
+    ) -> Result<(File, PathBuf)> {
+        create_temp_file(dir.as_ref(), mode)
+    }
+
+    fn rename<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        from: P,
+        to: Q,
+    ) -> Result<()> {
+        let (from, to) = (from.as_ref(), to.as_ref());
+        std::fs::rename(from, to).with_context(|| {
+            format!("could not move {} to {}", from.display(), to.display())
+        })
+    }
 }
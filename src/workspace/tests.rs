@@ -0,0 +1,420 @@
+pub use super::*;
+
+/// Tests for [`UserMap::user_by_username`]
+mod user_by_username {
+    use super::*;
+
+    /// The lookup as implemented before the name index was introduced.
+    fn linear_lookup(map: &UserMap, name: &str) -> Result<Option<uid_t>> {
+        let mut iter = map.all_users();
+
+        let first = iter.find(|&u| u.name() == name);
+        if let Some(result) = first {
+            if iter.any(|u| u.name() == name) {
+                bail!("Username is not unique");
+            }
+            Ok(Some(result.uid()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Builds a map where every tenth username is shared by two users.
+    fn large_map() -> UserMap {
+        let users = (0..5_000).map(|uid: uid_t| {
+            let name = if uid % 10 == 0 {
+                format!("user{}", uid / 2)
+            } else {
+                format!("user{uid}")
+            };
+            User::new(uid, &name, uid)
+        });
+        UserMap::new(users, 0)
+    }
+
+    #[test]
+    fn matches_linear_lookup() {
+        let map = large_map();
+
+        for i in 0..5_500 {
+            let name = format!("user{i}");
+            let expected = linear_lookup(&map, &name);
+            let actual =
+                map.user_by_username(&name).map(|u| u.map(User::uid));
+
+            match (expected, actual) {
+                (Ok(expected), Ok(actual)) => {
+                    assert_eq!(expected, actual, "for {name:?}");
+                }
+                (Err(_), Err(_)) => {}
+                (expected, actual) => {
+                    panic!("for {name:?}: {expected:?} != {actual:?}")
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn replaced_user() -> Result<()> {
+        let mut map = UserMap::new(std::iter::empty(), 0);
+        map.add(User::new(1000, "alice", 1000));
+        map.add(User::new(1000, "bob", 1000));
+
+        assert!(map.user_by_username("alice")?.is_none());
+        assert_eq!(map.user_by_username("bob")?.map(User::uid), Some(1000));
+
+        map.add(User::new(1000, "bob", 1000));
+        assert_eq!(map.user_by_username("bob")?.map(User::uid), Some(1000));
+        Ok(())
+    }
+
+    #[test]
+    fn ambiguous() {
+        let mut map = UserMap::new(std::iter::empty(), 0);
+        map.add(User::new(1000, "alice", 1000));
+        map.add(User::new(1001, "alice", 1001));
+
+        assert!(map.user_by_username("alice").is_err());
+    }
+}
+
+/// Tests for [`Workspace::create_temp_in`] and [`Workspace::rename`]
+mod temp_and_rename {
+    use super::*;
+
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+
+    use crate::workspace::mock::MockWorkspace;
+
+    #[test]
+    fn temp_files() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+        let dir = ws.add_dir("dir", 1000, 0o700)?;
+
+        let (_, first) = ws.create_temp_in(&dir, 0o640)?;
+        let (_, second) = ws.create_temp_in(&dir, 0o600)?;
+
+        assert_ne!(first, second);
+        assert_eq!(first.parent(), Some(dir.as_path()));
+        let mode = std::fs::metadata(&first)?.permissions().mode() & 0o777;
+        assert_eq!(mode, 0o640);
+        Ok(())
+    }
+
+    #[test]
+    fn replaces_target() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+        let dir = ws.add_dir("dir", 1000, 0o700)?;
+        let target = ws.add_file("dir/target", 1000, 0o600, "old")?;
+
+        let (mut file, temp) = ws.create_temp_in(&dir, 0o600)?;
+        file.write_all(b"new")?;
+        ws.set_owner(&temp, 0, 0)?;
+        ws.rename(&temp, &target)?;
+
+        assert!(!temp.exists());
+        assert_eq!(std::fs::read_to_string(&target)?, "new");
+        assert_eq!(ws.get_mock_owner_uid(&target), Some(0));
+        Ok(())
+    }
+
+    #[test]
+    fn failed_rename() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+        let dir = ws.add_dir("dir", 1000, 0o700)?;
+        let target = ws.add_file("dir/target", 1000, 0o600, "old")?;
+
+        let (mut file, temp) = ws.create_temp_in(&dir, 0o600)?;
+        file.write_all(b"new")?;
+        ws.set_owner(&temp, 0, 0)?;
+
+        ws.set_fail_renames(true);
+        assert!(ws.rename(&temp, &target).is_err());
+
+        assert!(temp.exists());
+        assert_eq!(std::fs::read_to_string(&target)?, "old");
+        assert_eq!(ws.get_mock_owner_uid(&target), Some(1000));
+        Ok(())
+    }
+}
+
+/// Tests for [`Workspace::lexists`]
+mod lexists {
+    use super::*;
+
+    use crate::workspace::mock::MockWorkspace;
+
+    #[test]
+    fn regular() -> Result<()> {
+        let mut ws = MockWorkspace::new()?;
+        let file = ws.add_file("file", 1000, 0o600, "")?;
+        let dir = ws.add_dir("dir", 1000, 0o700)?;
+
+        assert!(ws.lexists(&file));
+        assert!(ws.lexists(&dir));
+        assert!(!ws.lexists(ws.path("missing")));
+        Ok(())
+    }
+
+    #[test]
+    fn broken_symlink() -> Result<()> {
+        let ws = MockWorkspace::new()?;
+        let link = ws.add_symlink("link", "does/not/exist")?;
+
+        assert!(ws.lexists(&link));
+        assert!(!link.exists());
+        Ok(())
+    }
+}
+
+/// Tests for [`RealWorkspace::try_new`] and [`RealWorkspace::assume_uid`]
+mod real_workspace {
+    use super::*;
+
+    #[test]
+    fn try_new() -> Result<()> {
+        let ws = RealWorkspace::try_new()?;
+
+        let uid = ws.users().current_uid();
+        assert_eq!(uid, uzers::get_current_uid());
+        assert!(ws.users().user_by_uid(uid).is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn modified() -> Result<()> {
+        let ws = RealWorkspace::try_new()?;
+        let dir = assert_fs::TempDir::new()?;
+        let file = dir.path().join("authorized_keys");
+        std::fs::write(&file, "")?;
+
+        let mtime = ws.modified(&file)?.unwrap();
+        assert!(mtime <= ws.now());
+        assert_eq!(ws.modified(dir.path().join("missing"))?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn sync_dir() -> Result<()> {
+        let ws = RealWorkspace::try_new()?;
+        let dir = assert_fs::TempDir::new()?;
+
+        ws.sync_dir(dir.path())?;
+        assert!(ws.sync_dir(dir.path().join("missing")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_current_user() {
+        let ws = RealWorkspace {
+            user_map: UserMap::new(
+                std::iter::once(User::new(0, "root", 0)),
+                1000,
+            ),
+            group_map: GroupMap::new(std::iter::empty()),
+        };
+
+        assert!(ws.validate().is_err());
+    }
+
+    #[test]
+    fn assume_uid() -> Result<()> {
+        let mut ws = RealWorkspace {
+            user_map: UserMap::new(
+                [User::new(0, "root", 0), User::new(1000, "alice", 1000)]
+                    .into_iter(),
+                0,
+            ),
+            group_map: GroupMap::new(std::iter::empty()),
+        };
+        let control = crate::config::ControlManager::default();
+
+        assert!(crate::selection::resolve_users(
+            &ws,
+            &[],
+            false,
+            false,
+            &control
+        )
+        .is_err());
+
+        ws.assume_uid(1000)?;
+        let users = crate::selection::resolve_users(
+            &ws,
+            &[],
+            false,
+            false,
+            &control,
+        )?;
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].name(), "alice");
+
+        assert!(ws.assume_uid(1001).is_err());
+        assert_eq!(ws.users().current_uid(), 1000);
+        Ok(())
+    }
+}
+
+/// Tests for [`UserMap::home_dir_of`]
+mod home_dir_of {
+    use super::*;
+
+    fn map() -> UserMap {
+        UserMap::new(
+            [
+                User::new(1000, "alice", 1000).with_home_dir("/home/alice"),
+                User::new(1001, "homeless", 1001).with_home_dir(""),
+            ]
+            .into_iter(),
+            1000,
+        )
+    }
+
+    #[test]
+    fn with_home() {
+        assert_eq!(
+            map().home_dir_of(1000),
+            Some(PathBuf::from("/home/alice"))
+        );
+    }
+
+    #[test]
+    fn without_home() {
+        assert_eq!(map().home_dir_of(1001), None);
+    }
+
+    #[test]
+    fn unknown_user() {
+        assert_eq!(map().home_dir_of(2000), None);
+    }
+}
+
+/// Tests for [`UserMap::primary_gid_of`] and [`is_group_member`]
+mod primary_group {
+    use super::*;
+
+    #[test]
+    fn primary_gid_of() {
+        let map = UserMap::new(
+            std::iter::once(User::new(1000, "alice", 100)),
+            1000,
+        );
+        assert_eq!(map.primary_gid_of(1000), Some(100));
+        assert_eq!(map.primary_gid_of(1001), None);
+    }
+
+    #[test]
+    fn membership() {
+        let alice = User::new(1000, "alice", 100);
+        let users = Group::new(100, "users");
+        let devs = Group::new(101, "devs").add_member("alice");
+        let ops = Group::new(102, "ops").add_member("bob");
+
+        assert!(is_group_member(&alice, &users));
+        assert!(is_group_member(&alice, &devs));
+        assert!(!is_group_member(&alice, &ops));
+    }
+}
+
+/// Tests for [`canonicalize_logical`] and [`Workspace::canonicalize`]
+mod canonicalize {
+    use super::*;
+
+    use std::os::unix::fs::symlink;
+
+    use crate::workspace::mock::MockWorkspace;
+
+    /// An in-memory tree: directories and symbolic links by absolute path.
+    struct Tree {
+        dirs: Vec<PathBuf>,
+        links: HashMap<PathBuf, PathBuf>,
+    }
+
+    impl Tree {
+        fn read_link(&self, path: &Path) -> std::io::Result<Option<PathBuf>> {
+            if let Some(target) = self.links.get(path) {
+                Ok(Some(target.clone()))
+            } else if path == Path::new("/")
+                || self.dirs.iter().any(|d| d == path)
+            {
+                Ok(None)
+            } else {
+                Err(std::io::ErrorKind::NotFound.into())
+            }
+        }
+    }
+
+    /// Builds the same layout in `ws` and in memory, relative to `root`.
+    fn layout(ws: &MockWorkspace) -> Result<Tree> {
+        let root = ws.path("");
+        let mut tree = Tree {
+            dirs: root.ancestors().map(Path::to_path_buf).collect(),
+            links: HashMap::new(),
+        };
+
+        for dir in &["home/alice/.ssh", "srv/keys"] {
+            std::fs::create_dir_all(root.join(dir))?;
+            for ancestor in Path::new(dir).ancestors() {
+                tree.dirs.push(root.join(ancestor));
+            }
+        }
+
+        let links: &[(&str, PathBuf)] = &[
+            ("home/alice/abs", root.join("srv/keys")),
+            ("home/alice/rel", "../../srv/keys".into()),
+            ("home/alice/.ssh/chain", "../abs".into()),
+            ("srv/keys/up", "..".into()),
+        ];
+        for (link, target) in links {
+            symlink(target, root.join(link))?;
+            tree.links.insert(root.join(link), target.clone());
+        }
+
+        Ok(tree)
+    }
+
+    #[test]
+    fn matches_mock() -> Result<()> {
+        let ws = MockWorkspace::new()?;
+        let tree = layout(&ws)?;
+
+        for path in &[
+            "home/alice/.ssh",
+            "home/alice/abs",
+            "home/alice/rel",
+            "home/alice/.ssh/chain",
+            "home/alice/.ssh/chain/up/keys/./up",
+            "home/alice/../alice/rel/up",
+        ] {
+            let path = ws.path(path);
+            let logical = canonicalize_logical(&path, |p| tree.read_link(p))?;
+            assert_eq!(logical, ws.canonicalize(&path)?, "{path:?}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn missing() -> Result<()> {
+        let ws = MockWorkspace::new()?;
+        let tree = layout(&ws)?;
+        let path = ws.path("home/alice/abs/missing");
+
+        assert!(canonicalize_logical(&path, |p| tree.read_link(p)).is_err());
+        assert!(ws.canonicalize(&path).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn loop_detected() {
+        let links = |p: &Path| Ok(Some(p.to_path_buf()));
+        let error = canonicalize_logical(Path::new("/a"), links).unwrap_err();
+        assert!(error.to_string().contains("too many"));
+    }
+
+    #[test]
+    fn relative() {
+        assert!(canonicalize_logical(Path::new("a/b"), |_| Ok(None)).is_err());
+    }
+}
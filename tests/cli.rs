@@ -0,0 +1,187 @@
+//! Runs the built binary against a control file in a temporary directory.
+//!
+//! Control must be owned by root, so these tests only run as root and pass
+//! trivially otherwise.
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+use anyhow::Result;
+use assert_fs::prelude::*;
+use assert_fs::TempDir;
+use uzers::os::unix::UserExt;
+
+/// Temporary directory with a control file, also used as target root.
+struct Sandbox {
+    dir: TempDir,
+}
+
+impl Sandbox {
+    /// Returns a sandbox with `control` as control, or `None` if control
+    /// would be refused because the tests do not run as root.
+    fn new(control: &str) -> Result<Option<Self>> {
+        if uzers::get_current_uid() != 0 {
+            return Ok(None);
+        }
+
+        let dir = TempDir::new()?;
+        let file = dir.child("control.toml");
+        file.write_str(control)?;
+        std::fs::set_permissions(&file, PermissionsExt::from_mode(0o600))?;
+        Ok(Some(Self { dir }))
+    }
+
+    /// Returns the path of `authorized_keys` of root inside the sandbox.
+    fn root_keys(&self) -> Result<PathBuf> {
+        self.root_file(".ssh/authorized_keys")
+    }
+
+    /// Returns the path of `name` in the home directory of root inside the
+    /// sandbox.
+    fn root_file(&self, name: &str) -> Result<PathBuf> {
+        let root = uzers::get_user_by_uid(0).expect("root does not exist");
+        let home = root.home_dir().strip_prefix("/")?;
+        Ok(self.dir.path().join(home).join(name))
+    }
+
+    /// Runs the binary with `args`, which may fail.
+    fn try_run(&self, args: &[&str]) -> Result<Output> {
+        Ok(Command::new(env!("CARGO_BIN_EXE_narrowssh"))
+            .arg("--control-file")
+            .arg(self.dir.path().join("control.toml"))
+            .arg("--target-root")
+            .arg(self.dir.path())
+            .arg("--no-color")
+            .args(args)
+            .output()?)
+    }
+
+    /// Runs the binary with `args`.
+    fn run(&self, args: &[&str]) -> Result<Output> {
+        let output = self.try_run(args)?;
+        assert!(output.status.success(), "{output:?}");
+        Ok(output)
+    }
+}
+
+const CONTROL: &str = r#"
+["*"]
+enable = false
+
+[root]
+enable = true
+commands = ["uptime"]
+"#;
+
+#[test]
+fn export_is_toml() -> Result<()> {
+    let sandbox = match Sandbox::new(CONTROL)? {
+        Some(sandbox) => sandbox,
+        None => return Ok(()),
+    };
+
+    let output = sandbox.run(&["--user", "root", "export"])?;
+    let stdout = String::from_utf8(output.stdout)?;
+    let table: toml::Table = toml::from_str(&stdout)?;
+    assert!(table.contains_key("root"), "{stdout}");
+    Ok(())
+}
+
+#[test]
+fn uninstall_all_users() -> Result<()> {
+    let sandbox = match Sandbox::new("[\"*\"]\nenable = false\n")? {
+        Some(sandbox) => sandbox,
+        None => return Ok(()),
+    };
+    let keys = sandbox.root_keys()?;
+    std::fs::create_dir_all(keys.parent().unwrap())?;
+    std::fs::write(&keys, "mine\n# BEGIN narrowssh\nold\n# END narrowssh\n")?;
+
+    sandbox.run(&["--all-users", "--include-self", "uninstall"])?;
+
+    assert_eq!(std::fs::read_to_string(keys)?, "mine\n");
+    Ok(())
+}
+
+#[test]
+fn skipped_disabled() -> Result<()> {
+    let sandbox = match Sandbox::new("[\"*\"]\nenable = false\n")? {
+        Some(sandbox) => sandbox,
+        None => return Ok(()),
+    };
+
+    let output = sandbox.run(&["--user", "root", "refresh"])?;
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(stdout.contains("UID 0 skipped: disabled in control\n"));
+
+    let args = ["--user", "root", "refresh", "--dry-run", "--format", "json"];
+    let output = sandbox.run(&args)?;
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(json[0]["uid"], 0);
+    assert_eq!(json[0]["reason"], "disabled");
+    Ok(())
+}
+
+#[test]
+fn skipped_failed() -> Result<()> {
+    for (extra, reason, text) in [
+        (
+            "strict_user_config = true",
+            "insecure_config",
+            "user configuration refused",
+        ),
+        (
+            "authorized_keys_gid = 4242",
+            "failed",
+            "could not be planned",
+        ),
+    ] {
+        let sandbox = match Sandbox::new(&format!("{CONTROL}{extra}\n"))? {
+            Some(sandbox) => sandbox,
+            None => return Ok(()),
+        };
+        let config = sandbox.root_file(".narrowssh.conf")?;
+        std::fs::create_dir_all(config.parent().unwrap())?;
+        std::fs::write(&config, "keys = 42\n")?;
+
+        let output = sandbox.try_run(&["--user", "root", "refresh"])?;
+        assert!(!output.status.success(), "{output:?}");
+        let stdout = String::from_utf8(output.stdout)?;
+        assert!(stdout.contains(&format!("UID 0 skipped: {text}\n")));
+
+        let args =
+            ["--user", "root", "refresh", "--dry-run", "--format", "json"];
+        let output = sandbox.try_run(&args)?;
+        let json: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+        assert_eq!(json[0]["uid"], 0);
+        assert_eq!(json[0]["reason"], reason);
+    }
+    Ok(())
+}
+
+#[test]
+fn status() -> Result<()> {
+    let sandbox = match Sandbox::new(CONTROL)? {
+        Some(sandbox) => sandbox,
+        None => return Ok(()),
+    };
+
+    let output = sandbox.run(&["--user", "root", "status"])?;
+    let stdout = String::from_utf8(output.stdout)?;
+    let lines: Vec<_> = stdout.lines().collect();
+    assert_eq!(lines.len(), 4, "{stdout}");
+    assert_eq!(lines[0], "root (UID 0): enabled");
+    assert!(
+        lines[1].starts_with("  enable = true (override, "),
+        "{stdout}"
+    );
+    assert_eq!(
+        lines[3],
+        format!(
+            "  authorized_keys = {} (built-in default)",
+            sandbox.root_keys()?.display()
+        )
+    );
+    Ok(())
+}
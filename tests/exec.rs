@@ -0,0 +1,73 @@
+//! Runs the forced command of rendered managed blocks with the built binary.
+
+use std::process::{Command, Output};
+
+use anyhow::{Context, Result};
+use narrowssh::allowlist::ORIGINAL_COMMAND_VAR;
+use narrowssh::authorized_keys::{
+    render_managed_block, KeyLine, EXEC_COMMAND,
+};
+use narrowssh::config::Control;
+
+const KEY: &str =
+    "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIGd1bW15a2V5Zm9ydGVzdGluZ3B1cnBvc2Vz";
+
+/// Returns the forced command of the managed block rendered for `commands`,
+/// pointed at the binary under test.
+fn forced_command(commands: &[&str]) -> Result<String> {
+    let control = Control {
+        enable: true,
+        commands: commands.iter().map(|&c| String::from(c)).collect(),
+        ..Control::default()
+    };
+    let block = render_managed_block(&[String::from(KEY)], &control)?;
+    let line = block.text.lines().nth(2).context("no key line")?;
+    let key = KeyLine::parse(line)?;
+    let command = key.option("command").and_then(|o| o.value.clone());
+    let command = command.context("no forced command")?;
+
+    let program = EXEC_COMMAND.split(' ').next().unwrap_or_default();
+    assert!(command.starts_with(program), "{command}");
+    Ok(command.replacen(program, env!("CARGO_BIN_EXE_narrowssh"), 1))
+}
+
+/// Runs `forced` like sshd would when the client requested `requested`.
+fn login(forced: &str, requested: Option<&str>) -> Result<Output> {
+    let mut command = Command::new("/bin/sh");
+    command
+        .arg("-c")
+        .arg(forced)
+        .env_remove(ORIGINAL_COMMAND_VAR);
+    if let Some(requested) = requested {
+        command.env(ORIGINAL_COMMAND_VAR, requested);
+    }
+    Ok(command.output()?)
+}
+
+#[test]
+fn allowed() -> Result<()> {
+    let forced = forced_command(&["echo *", "!echo secret"])?;
+    let output = login(&forced, Some("echo 'hello  world'"))?;
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(output.stdout, b"hello  world\n");
+    Ok(())
+}
+
+#[test]
+fn denied() -> Result<()> {
+    let forced = forced_command(&["echo *", "!echo secret"])?;
+    for requested in &["echo secret", "id", "echo hi; id"] {
+        let output = login(&forced, Some(requested))?;
+        assert!(!output.status.success(), "{output:?}");
+        assert!(output.stdout.is_empty(), "{output:?}");
+    }
+    Ok(())
+}
+
+#[test]
+fn interactive() -> Result<()> {
+    let forced = forced_command(&["echo *"])?;
+    let output = login(&forced, None)?;
+    assert!(!output.status.success(), "{output:?}");
+    Ok(())
+}